@@ -0,0 +1,128 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+const MAGIC_NEWC: &str = "070701";
+const TRAILER: &str = "TRAILER!!!";
+const MODE_REGULAR_FILE: u32 = 0o100644;
+
+/// Writer of cpio archives in "newc" format, as used by e.g. SWUpdate.
+pub struct CpioWriter<W: Write> {
+    writer: W,
+    ino: u32,
+    mtime: u32,
+}
+
+fn padding(len: u64) -> usize {
+    ((4 - len % 4) % 4) as usize
+}
+
+impl<W: Write> CpioWriter<W> {
+    pub fn new(writer: W) -> CpioWriter<W> {
+        let mtime = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs() as u32);
+
+        CpioWriter {
+            writer,
+            ino: 0,
+            mtime,
+        }
+    }
+
+    fn write_header(&mut self, name: &str, mode: u32, size: u64) -> Result<()> {
+        anyhow::ensure!(
+            size <= u32::MAX as u64,
+            "cpio: {name} exceeds the maximum file size of 4GiB"
+        );
+
+        self.ino += 1;
+
+        let header = format!(
+            "{MAGIC_NEWC}{:08X}{mode:08X}{:08X}{:08X}{:08X}{:08X}{size:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{name}\0",
+            self.ino,
+            0,
+            0,
+            1,
+            self.mtime,
+            0,
+            0,
+            0,
+            0,
+            name.len() + 1,
+            0,
+        );
+
+        self.writer.write_all(header.as_bytes())?;
+        self.writer
+            .write_all(&[0; 3][..padding(header.len() as u64)])?;
+
+        Ok(())
+    }
+
+    /// Appends `data` as regular file `name`.
+    pub fn append_data(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        self.write_header(name, MODE_REGULAR_FILE, data.len() as u64)?;
+        self.writer.write_all(data)?;
+        self.writer
+            .write_all(&[0; 3][..padding(data.len() as u64)])?;
+
+        Ok(())
+    }
+
+    /// Appends the content of `file` as regular file `name`.
+    pub fn append_file(&mut self, name: &str, file: &Path) -> Result<()> {
+        let mut input =
+            File::open(file).context(format!("cpio: cannot open {}", file.to_string_lossy()))?;
+        let size = input.metadata()?.len();
+
+        self.write_header(name, MODE_REGULAR_FILE, size)?;
+
+        let copied = std::io::copy(&mut (&mut input).take(size), &mut self.writer)?;
+
+        anyhow::ensure!(
+            copied == size,
+            "cpio: {} changed while archiving",
+            file.to_string_lossy()
+        );
+
+        self.writer.write_all(&[0; 3][..padding(size)])?;
+
+        Ok(())
+    }
+
+    /// Writes the trailer and returns the underlying writer.
+    pub fn finish(mut self) -> Result<W> {
+        self.write_header(TRAILER, 0, 0)?;
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn newc_archive_layout() {
+        let mut cpio = CpioWriter::new(vec![]);
+
+        cpio.append_data("sw-description", b"software = {};")
+            .unwrap();
+
+        let archive = cpio.finish().unwrap();
+
+        // header (110) + name (15) padded to 128, data (14) padded to 16
+        assert_eq!(&archive[..6], b"070701");
+        assert_eq!(&archive[110..125], b"sw-description\0");
+        assert_eq!(&archive[128..142], b"software = {};");
+        assert_eq!(&archive[144..150], b"070701");
+        assert_eq!(&archive[254..265], b"TRAILER!!!\0");
+        assert_eq!(archive.len() % 4, 0);
+
+        // file size of the first entry
+        assert_eq!(&archive[54..62], b"0000000E");
+    }
+}
@@ -0,0 +1,117 @@
+use crate::error::ErrorKind;
+use crate::file::functions::{extract_partition, Partition};
+use anyhow::{Context, Result};
+use log::debug;
+use std::path::Path;
+use std::process::Command;
+
+const ROOTFS_FILE: &str = "rootfs.ext4";
+
+/// Identification of a Mender artifact.
+pub struct MenderArtifact<'a> {
+    pub name: &'a str,
+    pub device_types: &'a [String],
+    pub software_version: Option<&'a str>,
+}
+
+fn write_args(artifact: &MenderArtifact, rootfs: &Path, output: &Path) -> Result<Command> {
+    anyhow::ensure!(
+        !artifact.device_types.is_empty(),
+        "write_args: at least one device type is required"
+    );
+
+    let mut mender_artifact = Command::new("mender-artifact");
+    mender_artifact
+        .args(["write", "rootfs-image", "--artifact-name", artifact.name])
+        .arg("--file")
+        .arg(rootfs)
+        .arg("--output-path")
+        .arg(output);
+
+    for device_type in artifact.device_types {
+        mender_artifact.args(["--device-type", device_type]);
+    }
+
+    if let Some(software_version) = artifact.software_version {
+        mender_artifact.args(["--software-version", software_version]);
+    }
+
+    Ok(mender_artifact)
+}
+
+/// Creates a Mender rootfs-image artifact `output` of the rootfs partition
+/// of `image_file` by means of the mender-artifact tool. The artifact is
+/// signed if `key` is given.
+pub fn create_artifact(
+    image_file: &Path,
+    artifact: &MenderArtifact,
+    key: Option<&Path>,
+    output: &Path,
+) -> Result<()> {
+    let rootfs = image_file
+        .parent()
+        .context("create_artifact: cannot get directory of image")?
+        .join(ROOTFS_FILE);
+
+    extract_partition(&Partition::rootA, image_file, &rootfs)?;
+
+    let mut mender_artifact = write_args(artifact, &rootfs, output)?;
+
+    if let Some(key) = key {
+        mender_artifact.arg("--key").arg(key);
+    }
+
+    debug!("run {mender_artifact:?}");
+
+    let result = mender_artifact
+        .output()
+        .context(ErrorKind::Environment)
+        .context("create_artifact: cannot run mender-artifact, is it installed?")?;
+
+    if !result.status.success() {
+        return Err(anyhow::anyhow!(
+            "mender-artifact failed: {}",
+            String::from_utf8_lossy(&result.stderr).trim()
+        )
+        .context(ErrorKind::User));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_args_contain_device_types() {
+        let device_types = ["raspberrypi4".to_string(), "raspberrypi5".to_string()];
+        let artifact = MenderArtifact {
+            name: "release-1",
+            device_types: &device_types,
+            software_version: Some("4.0.17"),
+        };
+
+        let command =
+            write_args(&artifact, Path::new("rootfs.ext4"), Path::new("out.mender")).unwrap();
+        let args = command
+            .get_args()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        assert_eq!(
+            args,
+            "write rootfs-image --artifact-name release-1 --file rootfs.ext4 \
+            --output-path out.mender --device-type raspberrypi4 \
+            --device-type raspberrypi5 --software-version 4.0.17"
+        );
+
+        let artifact = MenderArtifact {
+            device_types: &[],
+            ..artifact
+        };
+
+        assert!(write_args(&artifact, Path::new("rootfs.ext4"), Path::new("out.mender")).is_err());
+    }
+}
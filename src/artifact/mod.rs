@@ -0,0 +1,4 @@
+pub mod cpio;
+pub mod mender;
+pub mod rauc;
+pub mod swupdate;
@@ -0,0 +1,151 @@
+use crate::error::ErrorKind;
+use crate::file::functions::{extract_partition, Partition};
+use anyhow::{Context, Result};
+use log::debug;
+use std::path::Path;
+use std::process::Command;
+
+#[derive(clap::ValueEnum, Clone, Debug, PartialEq)]
+#[clap(rename_all = "verbatim")]
+#[allow(non_camel_case_types)]
+pub enum BundleFormat {
+    plain,
+    verity,
+}
+
+/// Update described by the manifest of a RAUC bundle.
+pub struct RaucManifest<'a> {
+    pub compatible: &'a str,
+    pub version: Option<&'a str>,
+    pub slot_class: &'a str,
+    pub format: &'a BundleFormat,
+}
+
+/// Key and certificate the bundle is signed with.
+pub struct RaucSigning<'a> {
+    pub cert: &'a Path,
+    pub key: &'a Path,
+    pub keyring: Option<&'a Path>,
+}
+
+fn manifest(manifest: &RaucManifest, filename: &str) -> Result<String> {
+    for (key, value) in [
+        ("compatible", Some(manifest.compatible)),
+        ("version", manifest.version),
+        ("slot class", Some(manifest.slot_class)),
+    ] {
+        anyhow::ensure!(
+            !value.is_some_and(|value| value.is_empty() || value.contains(['\n', '[', ']'])),
+            "manifest: invalid {key}"
+        );
+    }
+
+    let mut content = format!("[update]\ncompatible={}\n", manifest.compatible);
+
+    if let Some(version) = manifest.version {
+        content.push_str(&format!("version={version}\n"));
+    }
+
+    content.push_str(&format!(
+        "\n[bundle]\nformat={:?}\n\n[image.{}]\nfilename={filename}\n",
+        manifest.format, manifest.slot_class
+    ));
+
+    Ok(content)
+}
+
+/// Creates a signed RAUC bundle `output` of `partition` of `image_file` by
+/// means of the rauc tool.
+pub fn create_bundle(
+    image_file: &Path,
+    partition: &Partition,
+    manifest_info: &RaucManifest,
+    signing: &RaucSigning,
+    output: &Path,
+) -> Result<()> {
+    let working_dir = image_file
+        .parent()
+        .context("create_bundle: cannot get directory of image")?;
+    let content_dir = working_dir.join("rauc-bundle");
+    let filename = match partition {
+        Partition::boot => "boot.vfat",
+        _ => "rootfs.ext4",
+    };
+
+    std::fs::create_dir_all(&content_dir)
+        .context("create_bundle: cannot create bundle directory")?;
+    std::fs::write(
+        content_dir.join("manifest.raucm"),
+        manifest(manifest_info, filename)?,
+    )
+    .context("create_bundle: cannot write manifest")?;
+
+    extract_partition(partition, image_file, &content_dir.join(filename))?;
+
+    // rauc refuses to overwrite an existing bundle
+    if output.try_exists().is_ok_and(|exists| exists) {
+        std::fs::remove_file(output).context(format!(
+            "create_bundle: cannot remove {}",
+            output.to_string_lossy()
+        ))?;
+    }
+
+    let mut rauc = Command::new("rauc");
+    rauc.arg("bundle")
+        .arg("--cert")
+        .arg(signing.cert)
+        .arg("--key")
+        .arg(signing.key);
+
+    if let Some(keyring) = signing.keyring {
+        rauc.arg("--keyring").arg(keyring);
+    }
+
+    rauc.arg(&content_dir).arg(output);
+
+    debug!("run {rauc:?}");
+
+    let result = rauc
+        .output()
+        .context(ErrorKind::Environment)
+        .context("create_bundle: cannot run rauc, is it installed?")?;
+
+    if !result.status.success() {
+        return Err(anyhow::anyhow!(
+            "rauc failed: {}",
+            String::from_utf8_lossy(&result.stderr).trim()
+        )
+        .context(ErrorKind::User));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest_contains_update_and_image() {
+        let mut info = RaucManifest {
+            compatible: "omnect-raspberrypi4-64",
+            version: Some("4.0.17.1"),
+            slot_class: "rootfs",
+            format: &BundleFormat::verity,
+        };
+
+        assert_eq!(
+            manifest(&info, "rootfs.ext4").unwrap(),
+            "[update]\ncompatible=omnect-raspberrypi4-64\nversion=4.0.17.1\n\n\
+            [bundle]\nformat=verity\n\n[image.rootfs]\nfilename=rootfs.ext4\n"
+        );
+
+        info.version = None;
+
+        assert!(!manifest(&info, "rootfs.ext4").unwrap().contains("version="));
+
+        info.slot_class = "rootfs]\n[hooks";
+
+        assert!(manifest(&info, "rootfs.ext4").is_err());
+    }
+}
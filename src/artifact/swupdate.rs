@@ -0,0 +1,198 @@
+use super::cpio::CpioWriter;
+use crate::error::ErrorKind;
+use crate::file::functions::{extract_partition, Partition};
+use crate::provenance::sha256;
+use anyhow::{Context, Result};
+use log::debug;
+use regex::{Captures, Regex};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const SW_DESCRIPTION: &str = "sw-description";
+const SW_DESCRIPTION_SIG: &str = "sw-description.sig";
+
+lazy_static::lazy_static! {
+    // placeholder as supported by meta-swupdate
+    static ref RE_SHA256: Regex = Regex::new(r"\$swupdate_get_sha256\(([^)]+)\)").unwrap();
+}
+
+/// Key to sign the sw-description with. Without certificate a raw RSA
+/// signature is created, otherwise a CMS signature.
+pub struct SwuSigning<'a> {
+    pub key: &'a Path,
+    pub cert: Option<&'a Path>,
+}
+
+/// Replaces `$swupdate_get_sha256(<file>)` placeholders by the sha256 of the
+/// archived file.
+fn resolve_sha256(sw_description: &str, checksums: &HashMap<String, String>) -> Result<String> {
+    let mut unknown = vec![];
+
+    let resolved = RE_SHA256.replace_all(sw_description, |caps: &Captures| {
+        let file = caps[1].trim();
+
+        checksums.get(file).cloned().unwrap_or_else(|| {
+            unknown.push(file.to_string());
+            String::new()
+        })
+    });
+
+    anyhow::ensure!(
+        unknown.is_empty(),
+        "resolve_sha256: sw-description references files which aren't part of the artifact: {}",
+        unknown.join(", ")
+    );
+
+    Ok(resolved.to_string())
+}
+
+fn sign(sw_description: &Path, signature: &Path, signing: &SwuSigning) -> Result<()> {
+    let mut openssl = Command::new("openssl");
+
+    match signing.cert {
+        Some(cert) => openssl
+            .args(["cms", "-sign", "-in"])
+            .arg(sw_description)
+            .arg("-out")
+            .arg(signature)
+            .arg("-signer")
+            .arg(cert)
+            .arg("-inkey")
+            .arg(signing.key)
+            .args(["-outform", "DER", "-nosmimecap", "-binary"]),
+        None => openssl
+            .args(["dgst", "-sha256", "-sign"])
+            .arg(signing.key)
+            .arg("-out")
+            .arg(signature)
+            .arg(sw_description),
+    };
+
+    debug!("run {openssl:?}");
+
+    let output = openssl
+        .output()
+        .context(ErrorKind::Environment)
+        .context("sign: cannot run openssl, is it installed?")?;
+
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "openssl failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .context(ErrorKind::User));
+    }
+
+    Ok(())
+}
+
+/// Creates a SWUpdate artifact `output` of `partition` of `image_file`,
+/// which is archived as `name`, and the additional `files`. Checksum
+/// placeholders in `sw_description` are resolved before it is signed.
+pub fn create_swu(
+    image_file: &Path,
+    partition: &Partition,
+    name: &str,
+    sw_description: &Path,
+    files: &[PathBuf],
+    signing: Option<SwuSigning>,
+    output: &Path,
+) -> Result<()> {
+    let working_dir = image_file
+        .parent()
+        .context("create_swu: cannot get directory of image")?;
+    let description = std::fs::read_to_string(sw_description).context(format!(
+        "create_swu: cannot read {}",
+        sw_description.to_string_lossy()
+    ))?;
+
+    anyhow::ensure!(
+        !name.contains('/') && ![SW_DESCRIPTION, SW_DESCRIPTION_SIG].contains(&name),
+        "create_swu: invalid name {name}"
+    );
+    anyhow::ensure!(
+        description.contains(&format!("\"{name}\"")),
+        "create_swu: sw-description doesn't reference \"{name}\""
+    );
+
+    let partition_file = working_dir.join(name);
+
+    extract_partition(partition, image_file, &partition_file)?;
+
+    let mut entries = vec![(name.to_string(), partition_file)];
+
+    for file in files {
+        let file_name = file
+            .file_name()
+            .context("create_swu: cannot get file name")?
+            .to_string_lossy()
+            .to_string();
+
+        anyhow::ensure!(
+            !entries.iter().any(|(entry, _)| *entry == file_name)
+                && ![SW_DESCRIPTION, SW_DESCRIPTION_SIG].contains(&file_name.as_str()),
+            "create_swu: duplicate file name {file_name}"
+        );
+
+        entries.push((file_name, file.to_path_buf()));
+    }
+
+    let checksums = entries
+        .iter()
+        .map(|(entry, path)| Ok((entry.clone(), sha256(path)?)))
+        .collect::<Result<HashMap<_, _>>>()?;
+
+    let description_file = working_dir.join(SW_DESCRIPTION);
+
+    std::fs::write(&description_file, resolve_sha256(&description, &checksums)?)
+        .context("create_swu: cannot write sw-description")?;
+
+    let mut cpio = CpioWriter::new(BufWriter::new(File::create(output).context(format!(
+        "create_swu: cannot create {}",
+        output.to_string_lossy()
+    ))?));
+
+    // sw-description and its signature have to be the first entries
+    cpio.append_file(SW_DESCRIPTION, &description_file)?;
+
+    if let Some(signing) = signing {
+        let signature_file = working_dir.join(SW_DESCRIPTION_SIG);
+
+        sign(&description_file, &signature_file, &signing)?;
+        cpio.append_file(SW_DESCRIPTION_SIG, &signature_file)?;
+    }
+
+    for (entry, path) in &entries {
+        cpio.append_file(entry, path)?;
+    }
+
+    cpio.finish()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sha256_placeholders_are_resolved() {
+        let checksums = HashMap::from([("rootfs.ext4".to_string(), "abc".to_string())]);
+
+        assert_eq!(
+            resolve_sha256(
+                r#"filename = "rootfs.ext4"; sha256 = "$swupdate_get_sha256(rootfs.ext4)";"#,
+                &checksums
+            )
+            .unwrap(),
+            r#"filename = "rootfs.ext4"; sha256 = "abc";"#
+        );
+
+        let err = resolve_sha256("$swupdate_get_sha256(boot.vfat)", &checksums).unwrap_err();
+
+        assert!(err.to_string().contains("boot.vfat"));
+    }
+}
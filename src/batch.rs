@@ -0,0 +1,368 @@
+use crate::cli::{self, Cli};
+use crate::error::ErrorKind;
+use crate::runtime;
+use anyhow::{Context, Result};
+use log::{error, info};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+const WILDCARDS: [char; 2] = ['*', '?'];
+
+/// Image injections, which can be run for several images.
+const BATCHABLE: [&str; 10] = [
+    "boot",
+    "docker",
+    "file",
+    "identity",
+    "image",
+    "network",
+    "secureboot",
+    "ssh",
+    "system",
+    "user",
+];
+
+/// Whether the command given by the names of its subcommands, e.g.
+/// `["identity", "set-config"]`, can be run for several images: image
+/// injections, except those creating several images on their own, running
+/// interactively or writing to one output path given on the command line.
+pub(crate) fn is_batchable(path: &[&str]) -> bool {
+    match path {
+        ["image", "clone" | "flash" | "mount", ..] => false,
+        ["image", "convert" | "create-mender-artifact" | "create-rauc-bundle" | "create-swu" | "sbom", ..] => {
+            false
+        }
+        ["file", "copy-from-image", ..] => false,
+        [command, ..] => BATCHABLE.contains(command),
+        [] => false,
+    }
+}
+
+/// Matches `name` against `pattern` with the wildcards `*` and `?`.
+fn matches(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some('*'), _) => {
+            matches(&pattern[1..], name) || (!name.is_empty() && matches(pattern, &name[1..]))
+        }
+        (Some('?'), Some(_)) => matches(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => matches(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+/// Whether `pattern` contains wildcards. URLs, i.e. http(s) and object
+/// storage urls, are never expanded, since `?` starts their query.
+fn has_wildcards(pattern: &str) -> bool {
+    pattern.contains(WILDCARDS) && !pattern.contains("://")
+}
+
+/// Images matching `pattern`, which may contain wildcards in its file name,
+/// e.g. `images/*.wic.xz`, sorted by name. Hidden files are only matched by
+/// patterns starting with a dot.
+fn expand(pattern: &str) -> Result<Vec<PathBuf>> {
+    let path = Path::new(pattern);
+    let Some(file_pattern) = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .filter(|_| has_wildcards(pattern))
+    else {
+        return Ok(vec![path.to_path_buf()]);
+    };
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    anyhow::ensure!(
+        !dir.to_string_lossy().contains(WILDCARDS),
+        "batch: wildcards are only supported in file names: {pattern}"
+    );
+
+    let file_pattern = file_pattern.chars().collect::<Vec<_>>();
+    let mut images = fs::read_dir(dir)
+        .context(format!("batch: cannot read {}", dir.to_string_lossy()))?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_file())
+        .filter(|entry| {
+            let name = entry
+                .file_name()
+                .to_string_lossy()
+                .chars()
+                .collect::<Vec<_>>();
+
+            (file_pattern[0] == '.' || name.first() != Some(&'.')) && matches(&file_pattern, &name)
+        })
+        .map(|entry| path.with_file_name(entry.file_name()))
+        .collect::<Vec<_>>();
+
+    anyhow::ensure!(!images.is_empty(), "batch: no image matches {pattern}");
+
+    images.sort();
+
+    Ok(images)
+}
+
+/// Command run for several images, given by repeated `-i`/`--image` options
+/// or a quoted glob pattern like `-i "images/*.wic"`.
+pub struct Batch {
+    pub jobs: usize,
+    pub invocations: Vec<(PathBuf, Cli)>,
+}
+
+impl Batch {
+    /// Parses the command line `args` and creates a command line per image.
+    /// Returns `None` for a single image without wildcards, which isn't a
+    /// batch, as well as for invalid arguments, which are reported by the
+    /// regular parser. `-j`/`--jobs` limits the number of images processed in
+    /// parallel.
+    pub fn from_args(args: &[String]) -> Result<Option<Batch>> {
+        let Ok(matches) = cli::command().try_get_matches_from(args) else {
+            return Ok(None);
+        };
+        let leaf = cli::leaf_matches(&matches);
+        let patterns = leaf
+            .try_get_many::<PathBuf>("image")
+            .ok()
+            .flatten()
+            .into_iter()
+            .flatten()
+            .map(|pattern| pattern.to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+
+        if patterns.len() < 2 && !patterns.iter().any(|p| has_wildcards(p)) {
+            return Ok(None);
+        }
+
+        let cli = cli::from_matches(&matches)
+            .map_err(|e| anyhow::anyhow!("{}", e.render()))
+            .context(ErrorKind::User)?;
+
+        if !is_batchable(&cli.invocation.command.split(' ').collect::<Vec<_>>()) {
+            return Err(
+                anyhow::anyhow!("batch: command doesn't support several images")
+                    .context(ErrorKind::User),
+            );
+        }
+
+        if cli.output_device.is_some() {
+            return Err(
+                anyhow::anyhow!("batch: --output-device doesn't support several images")
+                    .context(ErrorKind::User),
+            );
+        }
+
+        // all images would be written to the same file, e.g. by `image
+        // transcode -o`
+        if leaf
+            .try_get_one::<PathBuf>("output")
+            .ok()
+            .flatten()
+            .is_some()
+        {
+            return Err(
+                anyhow::anyhow!("batch: --output doesn't support several images")
+                    .context(ErrorKind::User),
+            );
+        }
+
+        let jobs = leaf
+            .try_get_one::<usize>("jobs")
+            .ok()
+            .flatten()
+            .copied()
+            .unwrap_or(1);
+
+        if jobs == 0 {
+            return Err(
+                anyhow::anyhow!("batch: invalid number of jobs {jobs}").context(ErrorKind::User)
+            );
+        }
+
+        let mut images = vec![];
+
+        for pattern in &patterns {
+            images.extend(expand(pattern).context(ErrorKind::User)?);
+        }
+
+        let invocations = images
+            .into_iter()
+            .map(|image| {
+                let mut cli = cli.clone();
+
+                *cli.command
+                    .image_mut()
+                    .context("batch: command has no image")? = image.clone();
+
+                Ok((image, cli))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Some(Batch { jobs, invocations }))
+    }
+
+    /// Command line of the first image, e.g. for the log settings, which are
+    /// the same for all images.
+    pub fn first(&self) -> &Cli {
+        &self.invocations[0].1
+    }
+
+    /// Runs the command for all images, up to `jobs` in parallel. A failing
+    /// image doesn't abort the others, the batch fails after all images were
+    /// processed.
+    pub fn run(self) -> Result<()> {
+        let total = self.invocations.len();
+        let queue = Mutex::new(self.invocations.into_iter());
+        let failed = Mutex::new(vec![]);
+
+        std::thread::scope(|scope| {
+            for _ in 0..self.jobs.clamp(1, total) {
+                scope.spawn(|| loop {
+                    let next = queue.lock().unwrap().next();
+                    let Some((image, cli)) = next else {
+                        break;
+                    };
+                    let image = image.to_string_lossy().to_string();
+
+                    if runtime::is_cancelled() {
+                        failed.lock().unwrap().push(image);
+                        continue;
+                    }
+
+                    info!("batch: process {image}");
+
+                    if let Err(e) = crate::run(cli) {
+                        error!("batch: {image} failed: {e:#}");
+                        failed.lock().unwrap().push(image);
+                    }
+                });
+            }
+        });
+
+        let mut failed = failed.into_inner().unwrap();
+
+        failed.sort();
+
+        anyhow::ensure!(
+            failed.is_empty(),
+            "batch: {} of {total} images failed: {}",
+            failed.len(),
+            failed.join(", ")
+        );
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn wildcards() {
+        let m = |pattern: &str, name: &str| {
+            matches(
+                &pattern.chars().collect::<Vec<_>>(),
+                &name.chars().collect::<Vec<_>>(),
+            )
+        };
+
+        assert!(m("*.wic", "image.wic"));
+        assert!(m("image-?.wic*", "image-1.wic.xz"));
+        assert!(!m("*.wic", "image.wic.xz"));
+        assert!(!m("image-?.wic", "image-10.wic"));
+    }
+
+    #[test]
+    fn batchable_commands() {
+        assert!(is_batchable(&["identity", "set-config"]));
+        assert!(is_batchable(&["image", "check"]));
+        assert!(!is_batchable(&["image", "clone"]));
+        assert!(!is_batchable(&["image", "convert"]));
+        assert!(!is_batchable(&["image", "create-swu"]));
+        assert!(!is_batchable(&["image", "sbom"]));
+        assert!(!is_batchable(&["file", "copy-from-image"]));
+        assert!(is_batchable(&["file", "copy-to-image"]));
+        assert!(!is_batchable(&["fleet", "provision"]));
+        assert!(!is_batchable(&[]));
+    }
+
+    #[test]
+    fn split_images() {
+        let dir = tempfile::tempdir().unwrap();
+
+        for name in ["b.wic", "a.wic", ".hidden.wic", "c.img"] {
+            fs::write(dir.path().join(name), "").unwrap();
+        }
+
+        let batch = Batch::from_args(&args(&format!(
+            "omnect-cli identity set-hostname --hostname device -i {}/*.wic --jobs 4",
+            dir.path().to_string_lossy()
+        )))
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(batch.jobs, 4);
+        assert_eq!(
+            batch
+                .invocations
+                .iter()
+                .map(|(image, _)| image.file_name().unwrap().to_string_lossy().to_string())
+                .collect::<Vec<_>>(),
+            ["a.wic", "b.wic"]
+        );
+
+        let batch = Batch::from_args(&args(
+            "omnect-cli identity set-hostname -n device -i a.wic --image=b.wic",
+        ))
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(batch.jobs, 1);
+        assert_eq!(batch.invocations.len(), 2);
+        assert_eq!(batch.invocations[1].0, PathBuf::from("b.wic"));
+
+        assert!(Batch::from_args(&args("omnect-cli image check -i a.wic"))
+            .unwrap()
+            .is_none());
+        assert!(Batch::from_args(&args(
+            "omnect-cli image check -i https://artifacts.example.com/a.wic?token=1"
+        ))
+        .unwrap()
+        .is_none());
+        // several images aren't accepted by clap
+        assert!(Batch::from_args(&args(
+            "omnect-cli image clone -i a.wic -i b.wic -n 2 -o out"
+        ))
+        .unwrap()
+        .is_none());
+        assert!(Batch::from_args(&args(&format!(
+            "omnect-cli image clone -i {}/*.wic -n 2 -o out",
+            dir.path().to_string_lossy()
+        )))
+        .is_err());
+        assert!(Batch::from_args(&args("omnect-cli image check -i a.wic -i b.wic -j 0")).is_err());
+        // the images would overwrite each other's output
+        assert!(Batch::from_args(&args(
+            "omnect-cli image convert -i a.wic -i b.wic -o out.vhdx -t vhdx"
+        ))
+        .is_err());
+        assert!(Batch::from_args(&args(
+            "omnect-cli image transcode -i a.wic.xz -i b.wic.xz -o out.wic.zst"
+        ))
+        .is_err());
+        assert!(Batch::from_args(&args(
+            "omnect-cli file copy-from-image -f factory:/a,out -i a.wic -i b.wic"
+        ))
+        .is_err());
+        assert!(Batch::from_args(&args(
+            "omnect-cli --output-device /dev/sdb image check -i a.wic -i b.wic"
+        ))
+        .is_err());
+    }
+}
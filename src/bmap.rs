@@ -0,0 +1,769 @@
+use crate::error::ErrorKind;
+use crate::file::compression::{self, Compression};
+use crate::file::sparse::data_extents;
+use crate::progress::Progress;
+use anyhow::{Context, Result};
+use log::debug;
+use regex::Regex;
+use sha2::Digest;
+use std::fmt::{self, Display};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::fs::{FileExt, FileTypeExt};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const CHECKSUM_TYPE: &str = "sha256";
+const COPY_BUFFER_SIZE: usize = 1024 * 1024;
+const BLOCK_SIZE: u64 = 4096;
+const BMAP_VERSION: &str = "2.0";
+
+/// Range of mapped blocks, `first` and `last` are inclusive.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BmapRange {
+    pub first: u64,
+    pub last: u64,
+    pub checksum: String,
+}
+
+/// Block map as created by bmaptool (version 2 with sha256 checksums): only
+/// the mapped blocks of an image have to be written to a device.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bmap {
+    pub image_size: u64,
+    pub block_size: u64,
+    pub blocks_count: u64,
+    pub ranges: Vec<BmapRange>,
+}
+
+fn element(bmap: &str, name: &str) -> Result<String> {
+    let re = Regex::new(&format!(r"<{name}>\s*([^<]*?)\s*</{name}>"))
+        .context("element: failed to create regex")?;
+
+    re.captures(bmap)
+        .map(|matches| matches[1].to_string())
+        .context(format!("bmap has no {name}"))
+}
+
+fn number(bmap: &str, name: &str) -> Result<u64> {
+    element(bmap, name)?
+        .parse()
+        .context(format!("bmap has invalid {name}"))
+}
+
+/// Checks the checksum of the bmap file itself, which is calculated with
+/// the checksum replaced by zeros.
+fn check_bmap_checksum(bmap: &str) -> Result<()> {
+    let checksum = element(bmap, "BmapFileChecksum")?;
+    let zeroed = bmap.replacen(&checksum, &"0".repeat(checksum.len()), 1);
+
+    anyhow::ensure!(
+        format!("{:x}", sha2::Sha256::digest(zeroed.as_bytes())) == checksum,
+        "bmap file is corrupted: checksum mismatch"
+    );
+
+    Ok(())
+}
+
+impl FromStr for Bmap {
+    type Err = anyhow::Error;
+
+    fn from_str(bmap: &str) -> Result<Self> {
+        let checksum_type = element(bmap, "ChecksumType")?;
+
+        anyhow::ensure!(
+            checksum_type == CHECKSUM_TYPE,
+            "unsupported bmap checksum type {checksum_type}: only bmap version 2 with {CHECKSUM_TYPE} is supported"
+        );
+
+        check_bmap_checksum(bmap)?;
+
+        let re = Regex::new(
+            r#"<Range\s+chksum="([0-9a-fA-F]+)"\s*>\s*(\d+)(?:\s*-\s*(\d+))?\s*</Range>"#,
+        )
+        .context("bmap: failed to create regex")?;
+        let ranges = re
+            .captures_iter(bmap)
+            .map(|range| {
+                let first = range[2].parse()?;
+                let last = match range.get(3) {
+                    Some(last) => last.as_str().parse()?,
+                    None => first,
+                };
+
+                anyhow::ensure!(first <= last, "bmap has invalid range {first}-{last}");
+
+                Ok(BmapRange {
+                    first,
+                    last,
+                    checksum: range[1].to_lowercase(),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let bmap = Bmap {
+            image_size: number(bmap, "ImageSize")?,
+            block_size: number(bmap, "BlockSize")?,
+            blocks_count: number(bmap, "BlocksCount")?,
+            ranges,
+        };
+
+        anyhow::ensure!(bmap.block_size > 0, "bmap has invalid BlockSize");
+        anyhow::ensure!(
+            bmap.ranges
+                .windows(2)
+                .all(|pair| pair[0].last < pair[1].first)
+                && bmap
+                    .ranges
+                    .last()
+                    .is_none_or(|range| range.last < bmap.blocks_count),
+            "bmap ranges are not ordered or exceed BlocksCount"
+        );
+
+        Ok(bmap)
+    }
+}
+
+impl Bmap {
+    pub fn read(bmap_file: &Path) -> Result<Bmap> {
+        fs::read_to_string(bmap_file)
+            .context(ErrorKind::User)
+            .context(format!("cannot read {}", bmap_file.to_string_lossy()))?
+            .parse()
+            .context(ErrorKind::User)
+            .context(format!("invalid bmap {}", bmap_file.to_string_lossy()))
+    }
+
+    /// Byte offset and length of `range` in the image.
+    fn extent(&self, range: &BmapRange) -> (u64, u64) {
+        let offset = range.first * self.block_size;
+        let end = ((range.last + 1) * self.block_size).min(self.image_size);
+
+        (offset, end - offset)
+    }
+
+    /// bmap file including its BmapFileChecksum.
+    pub fn to_xml(&self) -> String {
+        let bmap = self.to_string();
+        let checksum = format!("{:x}", sha2::Sha256::digest(bmap.as_bytes()));
+
+        bmap.replacen(&"0".repeat(64), &checksum, 1)
+    }
+
+    /// Number of bytes of all mapped blocks.
+    pub fn mapped_size(&self) -> u64 {
+        self.ranges.iter().map(|range| self.extent(range).1).sum()
+    }
+}
+
+impl Display for Bmap {
+    /// bmap file as written by `bmaptool create`, with zeros as
+    /// BmapFileChecksum, see [`Bmap::to_xml`].
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mapped_blocks: u64 = self
+            .ranges
+            .iter()
+            .map(|range| range.last - range.first + 1)
+            .sum();
+
+        writeln!(f, "<?xml version=\"1.0\" ?>")?;
+        writeln!(f, "<bmap version=\"{BMAP_VERSION}\">")?;
+        writeln!(f, "    <!-- Image size in bytes -->")?;
+        writeln!(f, "    <ImageSize> {} </ImageSize>", self.image_size)?;
+        writeln!(f, "    <!-- Size of a block in bytes -->")?;
+        writeln!(f, "    <BlockSize> {} </BlockSize>", self.block_size)?;
+        writeln!(f, "    <!-- Count of blocks in the image file -->")?;
+        writeln!(f, "    <BlocksCount> {} </BlocksCount>", self.blocks_count)?;
+        writeln!(f, "    <!-- Count of mapped blocks -->")?;
+        writeln!(
+            f,
+            "    <MappedBlocksCount> {mapped_blocks} </MappedBlocksCount>"
+        )?;
+        writeln!(f, "    <!-- Type of checksum used in this file -->")?;
+        writeln!(f, "    <ChecksumType> {CHECKSUM_TYPE} </ChecksumType>")?;
+        writeln!(
+            f,
+            "    <!-- The checksum of this bmap file, calculated with all zeros as value -->"
+        )?;
+        writeln!(
+            f,
+            "    <BmapFileChecksum> {} </BmapFileChecksum>",
+            "0".repeat(64)
+        )?;
+        writeln!(
+            f,
+            "    <!-- Mapped block ranges with the checksums of their data -->"
+        )?;
+        writeln!(f, "    <BlockMap>")?;
+
+        for range in &self.ranges {
+            if range.first == range.last {
+                writeln!(
+                    f,
+                    "        <Range chksum=\"{}\"> {} </Range>",
+                    range.checksum, range.first
+                )?;
+            } else {
+                writeln!(
+                    f,
+                    "        <Range chksum=\"{}\"> {}-{} </Range>",
+                    range.checksum, range.first, range.last
+                )?;
+            }
+        }
+
+        writeln!(f, "    </BlockMap>")?;
+        writeln!(f, "</bmap>")
+    }
+}
+
+/// `image_file` as stream, decompressed if necessary.
+fn open_image(image_file: &Path) -> Result<Box<dyn Read + Send>> {
+    let file = File::open(image_file)
+        .context(ErrorKind::User)
+        .context(format!("cannot open {}", image_file.to_string_lossy()))?;
+
+    Ok(match Compression::from_file(&image_file.to_path_buf())? {
+        Some(compression) => compression.reader(file)?,
+        None => Box::new(file),
+    })
+}
+
+/// Reads the next `len` bytes of `image` into `write`, e.g. a device or a
+/// sink, and returns their sha256.
+fn copy_range(
+    image: &mut dyn Read,
+    len: u64,
+    mut write: impl FnMut(&[u8]) -> std::io::Result<()>,
+    progress: &mut Progress,
+) -> Result<String> {
+    let mut hasher = sha2::Sha256::new();
+    let mut buf = vec![0u8; COPY_BUFFER_SIZE];
+    let mut remaining = len;
+
+    while remaining > 0 {
+        let chunk = &mut buf[..remaining.min(COPY_BUFFER_SIZE as u64) as usize];
+
+        image
+            .read_exact(chunk)
+            .context("image is smaller than described by the bmap")?;
+        hasher.update(&chunk[..]);
+        write(chunk)?;
+        progress.inc(chunk.len() as u64);
+        remaining -= chunk.len() as u64;
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Skips the next `len` bytes of `image`, which are not mapped.
+fn skip(image: &mut dyn Read, len: u64) -> Result<()> {
+    let skipped = std::io::copy(&mut image.take(len), &mut std::io::sink())?;
+
+    anyhow::ensure!(
+        skipped == len,
+        "image is smaller than described by the bmap"
+    );
+
+    Ok(())
+}
+
+/// Kernel names of the block device `name`, its partitions and the devices
+/// holding them, e.g. sdb, sdb1 and dm-0 for an encrypted volume on sdb1.
+fn block_devices(name: &str) -> Vec<String> {
+    let sys = Path::new("/sys/class/block").join(name);
+    let partitions = fs::read_dir(&sys)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|entry| entry.path().join("partition").exists());
+    let holders = fs::read_dir(sys.join("holders"))
+        .into_iter()
+        .flatten()
+        .flatten();
+    let mut names = vec![name.to_string()];
+
+    for child in partitions.chain(holders) {
+        for name in block_devices(&child.file_name().to_string_lossy()) {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+
+    names
+}
+
+/// Sources of `mounts` (in the format of /proc/mounts) which are one of the
+/// block devices `names`. Symlinks like /dev/mapper/* are resolved.
+fn mounted_devices(mounts: &str, names: &[String]) -> Vec<String> {
+    mounts
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .filter(|source| source.starts_with("/dev/"))
+        .filter(|source| {
+            fs::canonicalize(source)
+                .unwrap_or_else(|_| PathBuf::from(source))
+                .file_name()
+                .is_some_and(|name| names.iter().any(|n| name == n.as_str()))
+        })
+        .map(str::to_string)
+        .collect()
+}
+
+/// Mounted filesystems of `device`, its partitions and the devices holding
+/// them, e.g. /dev/sdb1 or a device mapper volume on it for /dev/sdb.
+fn mounts(device: &Path) -> Result<Vec<String>> {
+    let device = fs::canonicalize(device)?;
+    let name = device
+        .file_name()
+        .context("mounts: invalid device path")?
+        .to_string_lossy();
+
+    Ok(mounted_devices(
+        &fs::read_to_string("/proc/mounts").unwrap_or_default(),
+        &block_devices(&name),
+    ))
+}
+
+/// Checks the target of a flash operation: a block device must not be
+/// mounted and must hold `image_size` bytes. Returns the size of a block
+/// device, `None` for a regular file.
+pub fn check_target(device: &Path, image_size: u64) -> Result<Option<u64>> {
+    let is_block_device = fs::metadata(device)
+        .map(|metadata| metadata.file_type().is_block_device())
+        .unwrap_or(false);
+
+    if !is_block_device {
+        return Ok(None);
+    }
+
+    let mounted = mounts(device)?;
+
+    if !mounted.is_empty() {
+        return Err(anyhow::anyhow!(
+            "{} is mounted ({}), unmount it first",
+            device.to_string_lossy(),
+            mounted.join(", ")
+        )
+        .context(ErrorKind::User));
+    }
+
+    let device_size = File::open(device)
+        .and_then(|mut file| file.seek(SeekFrom::End(0)))
+        .context(ErrorKind::Environment)
+        .context(format!(
+            "cannot open {}, missing permissions?",
+            device.to_string_lossy()
+        ))?;
+
+    if device_size < image_size {
+        return Err(anyhow::anyhow!(
+            "{} has {device_size} bytes, the image needs {image_size} bytes",
+            device.to_string_lossy()
+        )
+        .context(ErrorKind::User));
+    }
+
+    Ok(Some(device_size))
+}
+
+/// Opens the target of a flash operation: a block device which passes
+/// [`check_target`], or a regular file, which is created or resized to the
+/// size of the image.
+fn open_target(device: &Path, image_size: u64) -> Result<File> {
+    if check_target(device, image_size)?.is_none() {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(device)
+            .context(format!("cannot create {}", device.to_string_lossy()))?;
+
+        // unmapped blocks stay holes
+        file.set_len(image_size)?;
+
+        return Ok(file);
+    }
+
+    OpenOptions::new()
+        .write(true)
+        .open(device)
+        .context(ErrorKind::Environment)
+        .context(format!(
+            "cannot open {}, missing permissions?",
+            device.to_string_lossy()
+        ))
+}
+
+/// Writes the blocks of `image_file` (optionally compressed with xz, bzip2,
+/// gzip or zstd) mapped by `bmap` to `device`, a block device or a regular
+/// file.
+/// The checksum of every range is verified. Returns the number of bytes
+/// written.
+pub fn flash(image_file: &Path, bmap: &Bmap, device: &Path) -> Result<u64> {
+    let mut image = open_image(image_file)?;
+    let target = open_target(device, bmap.image_size)?;
+    let mut progress = Progress::new(
+        format!("flash {}", device.to_string_lossy()),
+        Some(bmap.mapped_size()),
+    );
+    let mut position = 0;
+
+    for range in &bmap.ranges {
+        let (offset, len) = bmap.extent(range);
+
+        skip(&mut image, offset - position)?;
+
+        let mut write_offset = offset;
+        let checksum = copy_range(
+            &mut image,
+            len,
+            |chunk| {
+                target.write_all_at(chunk, write_offset)?;
+                write_offset += chunk.len() as u64;
+                Ok(())
+            },
+            &mut progress,
+        )
+        .context(format!(
+            "flash: cannot write blocks {}-{}",
+            range.first, range.last
+        ))?;
+
+        anyhow::ensure!(
+            checksum == range.checksum,
+            "flash: checksum mismatch of blocks {}-{}, the image doesn't match the bmap",
+            range.first,
+            range.last
+        );
+
+        position = offset + len;
+    }
+
+    target
+        .sync_all()
+        .context(format!("flash: cannot sync {}", device.to_string_lossy()))?;
+    progress.finish();
+
+    debug!("flash: wrote {} bytes", bmap.mapped_size());
+
+    Ok(bmap.mapped_size())
+}
+
+/// Checks the mapped blocks of `image_file` (optionally compressed with xz,
+/// bzip2, gzip or zstd), or of a flashed block device, against the checksums of
+/// `bmap`. Returns the ranges which don't match.
+pub fn verify(image_file: &Path, bmap: &Bmap) -> Result<Vec<BmapRange>> {
+    let mut image = open_image(image_file)?;
+    let mut progress = Progress::new(
+        format!("verify {}", image_file.to_string_lossy()),
+        Some(bmap.mapped_size()),
+    );
+    let mut position = 0;
+    let mut mismatches = vec![];
+
+    for range in &bmap.ranges {
+        let (offset, len) = bmap.extent(range);
+
+        skip(&mut image, offset - position)?;
+
+        let checksum = copy_range(&mut image, len, |_| Ok(()), &mut progress).context(format!(
+            "verify: cannot read blocks {}-{}",
+            range.first, range.last
+        ))?;
+
+        if checksum != range.checksum {
+            debug!(
+                "verify: checksum mismatch of blocks {}-{}",
+                range.first, range.last
+            );
+            mismatches.push(range.clone());
+        }
+
+        position = offset + len;
+    }
+
+    progress.finish();
+
+    Ok(mismatches)
+}
+
+/// Creates the bmap of the uncompressed image `image_file` from its data
+/// extents: holes, e.g. free blocks discarded by `image shrink`, are not
+/// mapped. Unlike `bmaptool create` no loop devices or host tools are
+/// needed, so that bmap files can be generated in containers too.
+pub fn create(image_file: &Path) -> Result<Bmap> {
+    let image = File::open(image_file).context(format!(
+        "create: cannot open {}",
+        image_file.to_string_lossy()
+    ))?;
+    let image_size = image.metadata()?.len();
+    let mut blocks: Vec<(u64, u64)> = vec![];
+
+    for (start, end) in data_extents(&image)? {
+        let (first, last) = (start / BLOCK_SIZE, end.div_ceil(BLOCK_SIZE) - 1);
+
+        match blocks.last_mut() {
+            // extents sharing or touching a block are merged
+            Some((_, previous)) if *previous + 1 >= first => *previous = last.max(*previous),
+            _ => blocks.push((first, last)),
+        }
+    }
+
+    let mut bmap = Bmap {
+        image_size,
+        block_size: BLOCK_SIZE,
+        blocks_count: image_size.div_ceil(BLOCK_SIZE),
+        ranges: vec![],
+    };
+    let mut progress = Progress::new("generate bmap file", None);
+
+    for (first, last) in blocks {
+        let mut range = BmapRange {
+            first,
+            last,
+            checksum: String::new(),
+        };
+        let (offset, len) = bmap.extent(&range);
+        let mut reader = ReadAt {
+            file: &image,
+            offset,
+        };
+
+        range.checksum = copy_range(&mut reader, len, |_| Ok(()), &mut progress)?;
+        bmap.ranges.push(range);
+    }
+
+    progress.finish();
+
+    Ok(bmap)
+}
+
+/// Writes the bmap of `image_file` to `bmap_file`.
+pub fn write(image_file: &Path, bmap_file: &Path) -> Result<()> {
+    let bmap = create(image_file)?;
+
+    debug!(
+        "write: {} of {} bytes mapped",
+        bmap.mapped_size(),
+        bmap.image_size
+    );
+
+    fs::write(bmap_file, bmap.to_xml()).context(format!(
+        "write: cannot write {}",
+        bmap_file.to_string_lossy()
+    ))
+}
+
+/// Reads `file` from `offset` on without changing its position.
+struct ReadAt<'a> {
+    file: &'a File,
+    offset: u64,
+}
+
+impl Read for ReadAt<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let bytes = self.file.read_at(buf, self.offset)?;
+
+        self.offset += bytes as u64;
+        Ok(bytes)
+    }
+}
+
+/// Default bmap file of `image_file`, e.g. image.wic.bmap for image.wic.xz.
+pub fn default_bmap_file(image_file: &Path) -> Result<PathBuf> {
+    let bmap_file = match Compression::from_file(&image_file.to_path_buf())? {
+        Some(compression) => compression::without_extension(image_file, &compression),
+        None => image_file.to_path_buf(),
+    };
+    let mut bmap_file = bmap_file.into_os_string();
+    bmap_file.push(".bmap");
+
+    Ok(PathBuf::from(bmap_file))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mounted_devices_match_exactly() {
+        let mounts = "/dev/sdb1 /media/boot vfat rw 0 0\n\
+                      /dev/sdb10 /media/data ext4 rw 0 0\n\
+                      /dev/sdba1 /media/other ext4 rw 0 0\n\
+                      sdb1 /media/relative ext4 rw 0 0\n\
+                      tmpfs /tmp tmpfs rw 0 0\n";
+        let names = ["sdb".to_string(), "sdb1".to_string()];
+
+        assert_eq!(mounted_devices(mounts, &names), vec!["/dev/sdb1"]);
+        assert!(mounted_devices(mounts, &["sdc".to_string()]).is_empty());
+    }
+
+    pub(crate) fn bmap_xml(ranges: &str) -> String {
+        let bmap = format!(
+            r#"<?xml version="1.0" ?>
+<bmap version="2.0">
+    <ImageSize> 10000 </ImageSize>
+    <BlockSize> 4096 </BlockSize>
+    <BlocksCount> 3 </BlocksCount>
+    <MappedBlocksCount> 2 </MappedBlocksCount>
+    <ChecksumType> sha256 </ChecksumType>
+    <BmapFileChecksum> {} </BmapFileChecksum>
+    <BlockMap>
+{ranges}
+    </BlockMap>
+</bmap>
+"#,
+            "0".repeat(64)
+        );
+        let checksum = format!("{:x}", sha2::Sha256::digest(bmap.as_bytes()));
+
+        bmap.replacen(&"0".repeat(64), &checksum, 1)
+    }
+
+    fn sha256(data: &[u8]) -> String {
+        format!("{:x}", sha2::Sha256::digest(data))
+    }
+
+    #[test]
+    fn parse_bmap() {
+        let bmap: Bmap = bmap_xml(&format!(
+            "        <Range chksum=\"{}\"> 0 </Range>\n        <Range chksum=\"{}\"> 2 </Range>",
+            "a".repeat(64),
+            "b".repeat(64)
+        ))
+        .parse()
+        .unwrap();
+
+        assert_eq!(bmap.image_size, 10000);
+        assert_eq!(bmap.block_size, 4096);
+        assert_eq!(bmap.ranges.len(), 2);
+        assert_eq!(bmap.ranges[1].first, 2);
+        assert_eq!(bmap.ranges[1].last, 2);
+        // the last block is partial
+        assert_eq!(bmap.mapped_size(), 4096 + 10000 - 8192);
+    }
+
+    #[test]
+    fn corrupted_bmap() {
+        let bmap = bmap_xml(&format!(
+            "        <Range chksum=\"{}\"> 0-1 </Range>",
+            "a".repeat(64)
+        ));
+
+        assert!(bmap.parse::<Bmap>().is_ok());
+        assert!(bmap.replace("0-1", "0-2").parse::<Bmap>().is_err());
+        assert!(bmap_xml(&format!(
+            "        <Range chksum=\"{}\"> 0-3 </Range>",
+            "a".repeat(64)
+        ))
+        .parse::<Bmap>()
+        .is_err());
+    }
+
+    #[test]
+    fn flash_to_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let image_file = dir.path().join("image.wic");
+        let target = dir.path().join("target.wic");
+        let image: Vec<u8> = (0..10000u32).map(|i| (i % 251) as u8).collect();
+
+        fs::write(&image_file, &image).unwrap();
+
+        let bmap: Bmap = bmap_xml(&format!(
+            "        <Range chksum=\"{}\"> 0 </Range>\n        <Range chksum=\"{}\"> 2 </Range>",
+            sha256(&image[..4096]),
+            sha256(&image[8192..])
+        ))
+        .parse()
+        .unwrap();
+
+        assert_eq!(flash(&image_file, &bmap, &target).unwrap(), 4096 + 1808);
+
+        let flashed = fs::read(&target).unwrap();
+        assert_eq!(flashed.len(), 10000);
+        assert_eq!(&flashed[..4096], &image[..4096]);
+        assert!(flashed[4096..8192].iter().all(|b| *b == 0));
+        assert_eq!(&flashed[8192..], &image[8192..]);
+
+        let mut wrong = bmap.clone();
+        wrong.ranges[1].checksum = "c".repeat(64);
+        assert!(flash(&image_file, &wrong, &target).is_err());
+    }
+
+    #[test]
+    fn verify_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let image_file = dir.path().join("image.wic");
+        let mut image: Vec<u8> = (0..10000u32).map(|i| (i % 251) as u8).collect();
+
+        fs::write(&image_file, &image).unwrap();
+
+        let bmap: Bmap = bmap_xml(&format!(
+            "        <Range chksum=\"{}\"> 0 </Range>\n        <Range chksum=\"{}\"> 2 </Range>",
+            sha256(&image[..4096]),
+            sha256(&image[8192..])
+        ))
+        .parse()
+        .unwrap();
+
+        assert!(verify(&image_file, &bmap).unwrap().is_empty());
+
+        // unmapped blocks are not checked
+        image[5000] ^= 0xff;
+        fs::write(&image_file, &image).unwrap();
+        assert!(verify(&image_file, &bmap).unwrap().is_empty());
+
+        image[9000] ^= 0xff;
+        fs::write(&image_file, &image).unwrap();
+        assert_eq!(
+            verify(&image_file, &bmap).unwrap(),
+            vec![bmap.ranges[1].clone()]
+        );
+
+        fs::write(&image_file, &image[..9000]).unwrap();
+        assert!(verify(&image_file, &bmap).is_err());
+    }
+
+    #[test]
+    fn create_bmap() {
+        let dir = tempfile::tempdir().unwrap();
+        let image_file = dir.path().join("image.wic");
+        let target = dir.path().join("target.wic");
+        let bmap_file = dir.path().join("image.wic.bmap");
+        let file = File::create(&image_file).unwrap();
+
+        // partial last block
+        file.set_len(16 * 1024 * 1024 + 100).unwrap();
+        file.write_all_at(b"MBR", 0).unwrap();
+        file.write_all_at(b"rootfs", 8 * 1024 * 1024).unwrap();
+        file.write_all_at(b"data", 16 * 1024 * 1024 + 10).unwrap();
+
+        write(&image_file, &bmap_file).unwrap();
+
+        let bmap = Bmap::read(&bmap_file).unwrap();
+
+        assert_eq!(bmap, create(&image_file).unwrap());
+        assert_eq!(bmap.blocks_count, 4097);
+        assert_eq!(bmap.ranges.first().unwrap().first, 0);
+        assert_eq!(bmap.ranges.last().unwrap().last, 4096);
+        assert!(bmap.mapped_size() < 16 * 1024 * 1024);
+        assert!(verify(&image_file, &bmap).unwrap().is_empty());
+
+        flash(&image_file, &bmap, &target).unwrap();
+        assert_eq!(fs::read(&image_file).unwrap(), fs::read(&target).unwrap());
+    }
+
+    #[test]
+    fn bmap_file_of_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let image_file = dir.path().join("image.wic");
+
+        fs::write(&image_file, [0u8; 512]).unwrap();
+
+        assert_eq!(
+            default_bmap_file(&image_file).unwrap(),
+            dir.path().join("image.wic.bmap")
+        );
+    }
+}
@@ -0,0 +1,423 @@
+use crate::cli::{self, Cli, Command, Image, IotHubDeviceUpdate, OutputFormat};
+use crate::error::ErrorKind;
+use crate::runtime;
+use actix_web::{delete, error, get, post, web, App, HttpRequest, HttpResponse, HttpServer};
+use anyhow::{Context, Result};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{BTreeMap, VecDeque};
+use std::net::SocketAddr;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Mutex};
+use time::format_description::well_known::Rfc3339;
+use uuid::Uuid;
+
+/// Finished jobs kept for `GET /jobs`, older ones are removed.
+const MAX_FINISHED_JOBS: usize = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[allow(non_camel_case_types)]
+pub enum JobStatus {
+    queued,
+    running,
+    succeeded,
+    failed,
+}
+
+/// Command run by the service. `result` contains the json results of the
+/// command as printed with `--output json`.
+#[derive(Clone, Debug, Serialize)]
+pub struct Job {
+    pub id: String,
+    pub args: Vec<String>,
+    pub status: JobStatus,
+    pub created: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub result: Vec<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct JobRequest {
+    args: Vec<String>,
+}
+
+struct Service {
+    token: String,
+    jobs: Mutex<BTreeMap<String, Job>>,
+    /// ids of the finished jobs, the oldest first
+    finished: Mutex<VecDeque<String>>,
+    queue: Mutex<mpsc::Sender<(String, Cli)>>,
+}
+
+/// Commands that can be run as jobs: image injections and device update
+/// operations. Commands which need a browser login, modify the user
+/// configuration, run interactively or until they are stopped, or write to
+/// block devices are excluded.
+fn is_allowed(cli: &Cli) -> bool {
+    if cli.output_device.is_some() {
+        return false;
+    }
+
+    match &cli.command {
+        Command::Image(Image::Mount { .. } | Image::Flash { .. })
+        | Command::IotHubDeviceUpdate(IotHubDeviceUpdate::Serve { .. }) => false,
+        command => matches!(
+            command,
+            Command::Boot(_)
+                | Command::Docker(_)
+                | Command::Edge(_)
+                | Command::File(_)
+                | Command::Fleet(_)
+                | Command::Identity(_)
+                | Command::Image(_)
+                | Command::IotHubDeviceUpdate(_)
+                | Command::Network(_)
+                | Command::Secureboot(_)
+                | Command::System(_)
+                | Command::User(_)
+        ),
+    }
+}
+
+fn now() -> String {
+    time::OffsetDateTime::now_utc()
+        .format(&Rfc3339)
+        .unwrap_or_default()
+}
+
+/// Compares in constant time, so that the token can't be guessed by timing.
+fn token_matches(token: &str, expected: &str) -> bool {
+    token.len() == expected.len()
+        && token
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+fn authorize(request: &HttpRequest, service: &Service) -> Result<(), error::Error> {
+    let token = request
+        .headers()
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match token {
+        Some(token) if token_matches(token, &service.token) => Ok(()),
+        _ => Err(error::ErrorUnauthorized("missing or invalid bearer token")),
+    }
+}
+
+/// Parses `args` like the command line of omnect-cli, without the program
+/// name.
+fn parse_job(args: &[String]) -> Result<Cli> {
+    let mut cli =
+        cli::try_parse_from(std::iter::once("omnect-cli").chain(args.iter().map(String::as_str)))
+            .map_err(|e| anyhow::anyhow!("{}", e.render()))?;
+
+    anyhow::ensure!(is_allowed(&cli), "command is not supported by the service");
+
+    cli.output = OutputFormat::json;
+
+    Ok(cli)
+}
+
+/// Runs `cli` like [`crate::run_collecting_results`], a panic fails the job
+/// instead of the worker.
+fn run_catching_panics<F>(run: F) -> Result<Vec<serde_json::Value>>
+where
+    F: FnOnce() -> Result<Vec<serde_json::Value>>,
+{
+    panic::catch_unwind(AssertUnwindSafe(run)).unwrap_or_else(|panic| {
+        let message = panic
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+
+        Err(anyhow::anyhow!("job panicked: {message}").context(ErrorKind::Internal))
+    })
+}
+
+/// Records `id` as finished and removes the oldest finished jobs beyond
+/// [`MAX_FINISHED_JOBS`].
+fn retire(service: &Service, id: &str) {
+    let mut finished = service.finished.lock().unwrap();
+
+    finished.push_back(id.to_string());
+
+    while finished.len() > MAX_FINISHED_JOBS {
+        if let Some(oldest) = finished.pop_front() {
+            service.jobs.lock().unwrap().remove(&oldest);
+        }
+    }
+}
+
+fn run_job(service: &Service, id: &str, cli: Cli) {
+    let update = |f: &dyn Fn(&mut Job)| {
+        if let Some(job) = service.jobs.lock().unwrap().get_mut(id) {
+            f(job)
+        }
+    };
+
+    update(&|job| job.status = JobStatus::running);
+    info!("run job {id}");
+
+    let result = run_catching_panics(|| crate::run_collecting_results(cli));
+
+    update(&|job| {
+        job.finished = Some(now());
+
+        match &result {
+            Ok(result) => {
+                job.status = JobStatus::succeeded;
+                job.result = result.clone();
+            }
+            Err(e) => {
+                let kind = ErrorKind::classify(e);
+
+                job.status = JobStatus::failed;
+                job.error = Some(json!({
+                    "code": kind.code(),
+                    "transient": kind.is_transient(),
+                    "message": format!("{e:#}"),
+                }));
+            }
+        }
+    });
+    retire(service, id);
+
+    debug!("finished job {id}");
+}
+
+#[get("/health")]
+async fn health() -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "status": "ok" }))
+}
+
+#[post("/jobs")]
+async fn create_job(
+    request: HttpRequest,
+    body: web::Json<JobRequest>,
+    service: web::Data<Arc<Service>>,
+) -> Result<HttpResponse, error::Error> {
+    authorize(&request, &service)?;
+
+    let cli = parse_job(&body.args).map_err(|e| error::ErrorBadRequest(format!("{e:#}")))?;
+    let job = Job {
+        id: Uuid::new_v4().to_string(),
+        args: body.args.clone(),
+        status: JobStatus::queued,
+        created: now(),
+        finished: None,
+        result: vec![],
+        error: None,
+    };
+
+    service
+        .jobs
+        .lock()
+        .unwrap()
+        .insert(job.id.clone(), job.clone());
+    service
+        .queue
+        .lock()
+        .unwrap()
+        .send((job.id.clone(), cli))
+        .map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Accepted().json(job))
+}
+
+#[get("/jobs")]
+async fn list_jobs(
+    request: HttpRequest,
+    service: web::Data<Arc<Service>>,
+) -> Result<HttpResponse, error::Error> {
+    authorize(&request, &service)?;
+
+    let jobs: Vec<Job> = service.jobs.lock().unwrap().values().cloned().collect();
+
+    Ok(HttpResponse::Ok().json(jobs))
+}
+
+#[get("/jobs/{id}")]
+async fn get_job(
+    request: HttpRequest,
+    id: web::Path<String>,
+    service: web::Data<Arc<Service>>,
+) -> Result<HttpResponse, error::Error> {
+    authorize(&request, &service)?;
+
+    match service.jobs.lock().unwrap().get(id.as_str()) {
+        Some(job) => Ok(HttpResponse::Ok().json(job)),
+        None => Err(error::ErrorNotFound("unknown job")),
+    }
+}
+
+#[delete("/jobs/{id}")]
+async fn delete_job(
+    request: HttpRequest,
+    id: web::Path<String>,
+    service: web::Data<Arc<Service>>,
+) -> Result<HttpResponse, error::Error> {
+    authorize(&request, &service)?;
+
+    let mut jobs = service.jobs.lock().unwrap();
+
+    match jobs.get(id.as_str()).map(|job| job.status) {
+        Some(JobStatus::queued | JobStatus::running) => {
+            Err(error::ErrorConflict("job didn't finish yet"))
+        }
+        Some(_) => Ok(HttpResponse::Ok().json(jobs.remove(id.as_str()))),
+        None => Err(error::ErrorNotFound("unknown job")),
+    }
+}
+
+/// Serves the http api on `listen`, clients authenticate by `token` as
+/// bearer token. Up to `workers` jobs run in parallel, further jobs are
+/// queued. The proxy of the user configuration has to be applied by
+/// [`crate::apply_proxy`] before, since jobs running in parallel mustn't
+/// modify the environment.
+pub fn serve(listen: SocketAddr, token: String, workers: usize) -> Result<()> {
+    anyhow::ensure!(!token.is_empty(), "serve: token must not be empty");
+    anyhow::ensure!(workers > 0, "serve: jobs must be at least 1");
+
+    let (tx, rx) = mpsc::channel::<(String, Cli)>();
+    let rx = Arc::new(Mutex::new(rx));
+    let service = Arc::new(Service {
+        token,
+        jobs: Mutex::new(BTreeMap::new()),
+        finished: Mutex::new(VecDeque::new()),
+        queue: Mutex::new(tx),
+    });
+
+    for _ in 0..workers {
+        let rx = rx.clone();
+        let service = service.clone();
+
+        std::thread::spawn(move || loop {
+            let next = rx.lock().unwrap().recv();
+
+            match next {
+                Ok((id, cli)) => run_job(&service, &id, cli),
+                Err(_) => return,
+            }
+        });
+    }
+
+    runtime::block_on(async move {
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(web::Data::new(service.clone()))
+                .service(health)
+                .service(create_job)
+                .service(list_jobs)
+                .service(get_job)
+                .service(delete_job)
+        })
+        .bind(listen)
+        .context(ErrorKind::Environment)
+        .context(format!("serve: cannot bind {listen}"))?
+        .disable_signals() // cancellation is handled by the runtime
+        .run();
+
+        info!("serving on http://{listen}");
+
+        server.await.context("serve: server failed")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_allowed_jobs() {
+        let cli = parse_job(&[
+            "boot".to_string(),
+            "set-cmdline".to_string(),
+            "-i".to_string(),
+            "image.wic".to_string(),
+            "-a".to_string(),
+            "quiet".to_string(),
+        ])
+        .unwrap();
+
+        assert_eq!(cli.output, OutputFormat::json);
+        assert!(parse_job(&["completions".to_string(), "bash".to_string()]).is_err());
+        assert!(parse_job(&["file".to_string(), "unknown".to_string()]).is_err());
+    }
+
+    #[test]
+    fn reject_blocking_jobs() {
+        let args = |line: &str| {
+            line.split_whitespace()
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        };
+
+        assert!(parse_job(&args("image check -i image.wic")).is_ok());
+        assert!(parse_job(&args("image mount -i image.wic -p boot mnt")).is_err());
+        assert!(parse_job(&args("image flash -i image.wic -d /dev/sdb")).is_err());
+        assert!(parse_job(&args("iot-hub-device-update serve -a update.swu")).is_err());
+        assert!(parse_job(&args("--output-device /dev/sdb image check -i image.wic")).is_err());
+    }
+
+    #[test]
+    fn finished_jobs_are_limited() {
+        let (tx, _rx) = mpsc::channel();
+        let service = Service {
+            token: "secret".to_string(),
+            jobs: Mutex::new(BTreeMap::new()),
+            finished: Mutex::new(VecDeque::new()),
+            queue: Mutex::new(tx),
+        };
+
+        for i in 0..MAX_FINISHED_JOBS + 2 {
+            let id = i.to_string();
+
+            service.jobs.lock().unwrap().insert(
+                id.clone(),
+                Job {
+                    id: id.clone(),
+                    args: vec![],
+                    status: JobStatus::succeeded,
+                    created: now(),
+                    finished: Some(now()),
+                    result: vec![],
+                    error: None,
+                },
+            );
+            retire(&service, &id);
+        }
+
+        let jobs = service.jobs.lock().unwrap();
+
+        assert_eq!(jobs.len(), MAX_FINISHED_JOBS);
+        assert!(!jobs.contains_key("0"));
+        assert!(!jobs.contains_key("1"));
+        assert!(jobs.contains_key("2"));
+    }
+
+    #[test]
+    fn panicking_job_fails() {
+        let e = run_catching_panics(|| panic!("broken")).unwrap_err();
+
+        assert_eq!(ErrorKind::classify(&e), ErrorKind::Internal);
+        assert!(format!("{e:#}").contains("broken"));
+    }
+
+    #[test]
+    fn token_comparison() {
+        assert!(token_matches("secret", "secret"));
+        assert!(!token_matches("secreT", "secret"));
+        assert!(!token_matches("secret2", "secret"));
+        assert!(!token_matches("", "secret"));
+    }
+}
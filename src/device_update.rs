@@ -1,18 +1,34 @@
+use crate::error::ErrorKind;
+use crate::file::functions::{read_file_from_image, Partition};
+use crate::keyvault;
+use crate::progress::Progress;
 use anyhow::{Context, Result};
-use azure_identity::{ClientSecretCredential, TokenCredentialOptions};
+use azure_core::auth::TokenCredential;
+use azure_identity::{ClientSecretCredential, DefaultAzureCredential, TokenCredentialOptions};
 use azure_iot_deviceupdate::DeviceUpdateClient;
 use azure_storage::{shared_access_signature::service_sas::BlobSasPermissions, StorageCredentials};
 use azure_storage_blobs::prelude::{BlobServiceClient, ContainerClient};
 use log::{debug, info};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use sha2::Digest;
-use std::{borrow::Cow, collections::HashMap, fs::OpenOptions, path::Path};
+use std::{
+    borrow::Cow,
+    collections::{hash_map::Entry, HashMap},
+    fs::OpenOptions,
+    path::{Component, Path},
+    sync::Arc,
+};
 use time::format_description::well_known::Rfc3339;
 use url::Url;
 
 // See https://docs.microsoft.com/en-us/azure/iot-hub-device-update/device-update-limits
 const MAX_DEVICE_UPDATE_SIZE: u64 = 2000000000; // 2GB, may also actually be 2^32 - 1?
 const MANIFEST_VERSION: &str = "5.0";
+const API_VERSION: &str = "2022-10-01";
+const DEVICE_UPDATE_SCOPE: &str = "https://api.adu.microsoft.com/.default";
+const ADU_GROUP_TAG: &str = "ADUGroup";
+const DU_CONFIG_PATH: &str = "/etc/adu/du-config.json";
+const EXPORTED_UPDATE_FILE: &str = "update.json";
 
 #[derive(Serialize)]
 struct UpdateId<'a> {
@@ -109,7 +125,361 @@ struct ImportUpdate<'a> {
     files: Vec<FileNameUrl<'a>>,
 }
 
-#[tokio::main]
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ManifestFile {
+    filename: String,
+    size_in_bytes: u64,
+    hashes: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct ManifestFiles {
+    files: Vec<ManifestFile>,
+}
+
+#[derive(clap::ValueEnum, Clone, Debug)]
+#[clap(rename_all = "verbatim")]
+#[allow(non_camel_case_types)]
+pub enum ReportFormat {
+    table,
+    csv,
+    json,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ComplianceEntry {
+    device_id: String,
+    group_id: Option<String>,
+    device_class_id: Option<String>,
+    installed_version: Option<String>,
+    latest_version: Option<String>,
+    on_latest_update: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GroupEntry {
+    group_id: String,
+    group_type: Option<String>,
+    device_count: u64,
+    created_date_time: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GroupDeviceEntry {
+    device_id: String,
+    device_class_id: Option<String>,
+    installed_version: Option<String>,
+    deployment_status: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DuConfigAgent {
+    manufacturer: String,
+    model: String,
+    additional_device_properties: Option<HashMap<String, String>>,
+}
+
+#[derive(Deserialize)]
+struct DuConfig {
+    manufacturer: String,
+    agents: Vec<DuConfigAgent>,
+}
+
+/// update provider and compatibility properties configured in a du-config.json
+#[derive(Debug, Default, PartialEq)]
+pub struct DuConfigCompatibility {
+    pub provider: Option<String>,
+    pub manufacturer: Option<String>,
+    pub model: Option<String>,
+    pub compatibilityid: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DeviceClassEntry {
+    device_class_id: String,
+    friendly_name: Option<String>,
+    compat_properties: serde_json::Map<String, serde_json::Value>,
+    best_compatible_update: Option<String>,
+}
+
+/// blob storage location of the update files referenced by an import manifest
+pub enum BlobStorage {
+    /// storage account name, storage account key and container name
+    AccessKey {
+        account: String,
+        key: String,
+        container: String,
+    },
+    /// container SAS url, e.g. a user-delegation SAS with read permission
+    ContainerSasUrl(Url),
+}
+
+impl BlobStorage {
+    pub fn new(
+        container_sas_url: Option<Url>,
+        account: Option<String>,
+        key: Option<String>,
+        container: Option<String>,
+    ) -> Result<BlobStorage> {
+        match (container_sas_url, account, key, container) {
+            (Some(url), _, _, _) => Ok(BlobStorage::ContainerSasUrl(url)),
+            (None, Some(account), Some(key), Some(container)) => Ok(BlobStorage::AccessKey {
+                account,
+                key: keyvault::resolve(key)?,
+                container,
+            }),
+            _ => anyhow::bail!(
+                "either blob storage sas url or blob storage account, key and container name must be provided"
+            ),
+        }
+    }
+
+    async fn blob_url(&self, blob_name: &str) -> Result<Url> {
+        match self {
+            BlobStorage::AccessKey {
+                account,
+                key,
+                container,
+            } => {
+                let storage_credentials = StorageCredentials::access_key(account, key.to_string());
+                let storage_account_client = BlobServiceClient::new(account, storage_credentials);
+                let container_client = storage_account_client.container_client(container);
+
+                generate_sas_url(&container_client, blob_name).await
+            }
+            BlobStorage::ContainerSasUrl(container_url) => {
+                container_sas_to_blob_url(container_url, blob_name)
+            }
+        }
+    }
+}
+
+/// credentials used to authenticate against "Azure Device Update for IoT Hub"
+pub enum AzureCredentials {
+    /// service principal authenticated by client secret
+    ClientSecret {
+        tenant_id: String,
+        client_id: String,
+        client_secret: String,
+    },
+    /// azure credential chain: environment, managed identity and azure cli ("az login")
+    Default,
+}
+
+impl AzureCredentials {
+    pub fn new(
+        tenant_id: Option<String>,
+        client_id: Option<String>,
+        client_secret: Option<String>,
+    ) -> Result<AzureCredentials> {
+        match (tenant_id, client_id, client_secret) {
+            (Some(tenant_id), Some(client_id), Some(client_secret)) => {
+                Ok(AzureCredentials::ClientSecret {
+                    tenant_id,
+                    client_id,
+                    client_secret: keyvault::resolve(client_secret)?,
+                })
+            }
+            (None, None, None) => Ok(AzureCredentials::Default),
+            _ => anyhow::bail!(
+                "either tenant id, client id and client secret must be provided or none of them"
+            ),
+        }
+    }
+
+    pub(crate) fn token_credential(&self) -> Result<Arc<dyn TokenCredential>> {
+        match self {
+            AzureCredentials::ClientSecret {
+                tenant_id,
+                client_id,
+                client_secret,
+            } => Ok(Arc::new(ClientSecretCredential::new(
+                azure_core::new_http_client(),
+                TokenCredentialOptions::default().authority_host()?,
+                tenant_id.to_string(),
+                client_id.to_string(),
+                client_secret.to_string(),
+            ))),
+            AzureCredentials::Default => {
+                debug!("use azure credential chain");
+
+                Ok(Arc::new(
+                    DefaultAzureCredential::create(TokenCredentialOptions::default())
+                        .context("cannot create azure credential chain")?,
+                ))
+            }
+        }
+    }
+
+    pub(crate) async fn access_token(&self, scope: &str) -> Result<String> {
+        let token = self
+            .token_credential()?
+            .get_token(&[scope])
+            .await
+            .context(ErrorKind::Auth)
+            .context(format!("cannot get access token for {scope}"))?;
+
+        Ok(token.token.secret().to_string())
+    }
+}
+
+fn device_update_client(
+    credentials: &AzureCredentials,
+    device_update_endpoint_url: &Url,
+) -> Result<DeviceUpdateClient> {
+    DeviceUpdateClient::new(
+        device_update_endpoint_url.as_str(),
+        credentials.token_credential()?,
+    )
+    .map_err(|e| e.into())
+}
+
+fn rest_url(device_update_endpoint_url: &Url, instance_id: &str, path: &str) -> Result<Url> {
+    let mut url = device_update_endpoint_url.clone();
+
+    url.path_segments_mut()
+        .map_err(|_| {
+            anyhow::anyhow!("invalid device update endpoint: {device_update_endpoint_url}")
+        })?
+        .pop_if_empty()
+        .extend(["deviceUpdate", instance_id])
+        .extend(path.split('/'));
+    url.query_pairs_mut()
+        .append_pair("api-version", API_VERSION);
+
+    Ok(url)
+}
+
+/// REST url of the device group `group_id`, which is percent-encoded as a
+/// single path segment.
+fn group_url(device_update_endpoint_url: &Url, instance_id: &str, group_id: &str) -> Result<Url> {
+    let mut url = rest_url(device_update_endpoint_url, instance_id, "management/groups")?;
+
+    url.path_segments_mut()
+        .map_err(|_| {
+            anyhow::anyhow!("invalid device update endpoint: {device_update_endpoint_url}")
+        })?
+        .push(group_id);
+
+    Ok(url)
+}
+
+/// OData filter of the devices of `group_id`. Quotes are escaped by doubling.
+fn group_filter(group_id: &str) -> String {
+    format!("groupId eq '{}'", group_id.replace('\'', "''"))
+}
+
+struct RestClient {
+    client: reqwest::Client,
+    device_update_endpoint_url: Url,
+    instance_id: String,
+    access_token: String,
+}
+
+impl RestClient {
+    async fn new(
+        credentials: &AzureCredentials,
+        device_update_endpoint_url: &Url,
+        instance_id: &str,
+    ) -> Result<RestClient> {
+        Ok(RestClient {
+            client: reqwest::Client::new(),
+            device_update_endpoint_url: device_update_endpoint_url.clone(),
+            instance_id: instance_id.to_string(),
+            access_token: credentials.access_token(DEVICE_UPDATE_SCOPE).await?,
+        })
+    }
+
+    async fn execute(
+        &self,
+        method: reqwest::Method,
+        url: Url,
+    ) -> Result<(reqwest::StatusCode, String)> {
+        debug!("{method} {url}");
+
+        let response = self
+            .client
+            .request(method, url)
+            .bearer_auth(&self.access_token)
+            .send()
+            .await
+            .context("device update request failed")?;
+
+        let status = response.status();
+
+        Ok((status, response.text().await?))
+    }
+
+    fn parse(status: reqwest::StatusCode, body: String) -> Result<serde_json::Value> {
+        if !status.is_success() {
+            return Err(anyhow::anyhow!(
+                "device update request failed. status: {status}, message: {body}"
+            )
+            .context(ErrorKind::from_status(status)));
+        }
+
+        if body.is_empty() {
+            return Ok(serde_json::Value::Null);
+        }
+
+        serde_json::from_str(&body).context("cannot parse device update response")
+    }
+
+    async fn send(&self, method: reqwest::Method, url: Url) -> Result<serde_json::Value> {
+        let (status, body) = self.execute(method, url).await?;
+
+        Self::parse(status, body)
+    }
+
+    /// GET request returning `None` if the resource doesn't exist.
+    async fn get_optional(&self, path: &str) -> Result<Option<serde_json::Value>> {
+        let url = rest_url(&self.device_update_endpoint_url, &self.instance_id, path)?;
+        let (status, body) = self.execute(reqwest::Method::GET, url).await?;
+
+        if status == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        Self::parse(status, body).map(Some)
+    }
+
+    async fn request(&self, method: reqwest::Method, path: &str) -> Result<serde_json::Value> {
+        let url = rest_url(&self.device_update_endpoint_url, &self.instance_id, path)?;
+
+        self.send(method, url).await
+    }
+
+    async fn list(&self, path: &str, filter: Option<&str>) -> Result<Vec<serde_json::Value>> {
+        let mut url = rest_url(&self.device_update_endpoint_url, &self.instance_id, path)?;
+        let mut values = vec![];
+
+        if let Some(filter) = filter {
+            url.query_pairs_mut().append_pair("filter", filter);
+        }
+
+        loop {
+            let mut page = self.send(reqwest::Method::GET, url).await?;
+
+            if let serde_json::Value::Array(page_values) = page["value"].take() {
+                values.extend(page_values);
+            }
+
+            match page["nextLink"].as_str() {
+                Some(next_link) => url = self.device_update_endpoint_url.join(next_link)?,
+                None => break,
+            }
+        }
+
+        Ok(values)
+    }
+}
+
 #[allow(clippy::too_many_arguments)]
 pub async fn create_import_manifest(
     image_path: &Path,
@@ -185,27 +555,14 @@ pub async fn create_import_manifest(
     Ok(())
 }
 
-#[allow(clippy::too_many_arguments)]
-#[tokio::main]
 pub async fn import_update(
     import_manifest_path: &Path,
-    container_name: &str,
-    tenant_id: &str,
-    client_id: &str,
-    client_secret: &str,
+    credentials: &AzureCredentials,
     instance_id: &str,
     device_update_endpoint_url: &Url,
-    blob_storage_account: &str,
-    blob_storage_key: &str,
+    blob_storage: &BlobStorage,
 ) -> Result<()> {
-    let creds = std::sync::Arc::new(ClientSecretCredential::new(
-        azure_core::new_http_client(),
-        TokenCredentialOptions::default().authority_host()?,
-        tenant_id.to_string(),
-        client_id.to_string(),
-        client_secret.to_string(),
-    ));
-    let client = DeviceUpdateClient::new(device_update_endpoint_url.as_str(), creds)?;
+    let client = device_update_client(credentials, device_update_endpoint_url)?;
     let manifest_file_size = std::fs::metadata(import_manifest_path)
         .context(format!(
             "cannot get file metadata of {}",
@@ -237,14 +594,10 @@ pub async fn import_update(
         .context("step2 file not found")?
         .to_string();
 
-    let storage_credentials =
-        StorageCredentials::access_key(blob_storage_account, blob_storage_key.to_string());
-    let storage_account_client = BlobServiceClient::new(blob_storage_account, storage_credentials);
-    let container_client = storage_account_client.container_client(container_name);
     let import_manifest_path = import_manifest_path.file_name().unwrap().to_str().unwrap();
-    let manifest_url = generate_sas_url(&container_client, import_manifest_path).await?;
-    let file_url1 = generate_sas_url(&container_client, file_name1.clone()).await?;
-    let file_url2 = generate_sas_url(&container_client, file_name2.clone()).await?;
+    let manifest_url = blob_storage.blob_url(import_manifest_path).await?;
+    let file_url1 = blob_storage.blob_url(&file_name1).await?;
+    let file_url2 = blob_storage.blob_url(&file_name2).await?;
     let import_update = vec![ImportUpdate {
         import_manifest: FileUrl {
             url: manifest_url,
@@ -268,32 +621,235 @@ pub async fn import_update(
 
     debug!("import update: {import_update}");
 
-    let import_update_response = client.import_update(instance_id, import_update).await?;
+    let mut progress = Progress::new("import update", None);
+    let import_update_response = progress
+        .wait(client.import_update(instance_id, import_update))
+        .await?;
+    progress.finish();
+    info!("Result of import update: {:?}", &import_update_response);
+
+    Ok(())
+}
+
+pub async fn import_update_from_url(
+    import_manifest_url: &Url,
+    credentials: &AzureCredentials,
+    instance_id: &str,
+    device_update_endpoint_url: &Url,
+) -> Result<()> {
+    let client = device_update_client(credentials, device_update_endpoint_url)?;
+
+    let response = reqwest::get(import_manifest_url.clone())
+        .await
+        .context("cannot download import manifest")?;
+    let status = response.status();
+
+    if !status.is_success() {
+        return Err(
+            anyhow::anyhow!("cannot download import manifest. status: {status}")
+                .context(ErrorKind::from_status(status)),
+        );
+    }
+
+    let manifest = response.bytes().await.context("read import manifest")?;
+    let manifest_sha256 = base64::encode_config(sha2::Sha256::digest(&manifest), base64::STANDARD);
+    let manifest_files: ManifestFiles =
+        serde_json::from_slice(&manifest).context("parse import manifest")?;
+
+    let mut files = vec![];
+
+    for file in manifest_files.files.iter() {
+        let url = sibling_blob_url(import_manifest_url, &file.filename)?;
+
+        verify_blob(&url, file).await?;
+
+        files.push(FileNameUrl {
+            filename: &file.filename,
+            url,
+        });
+    }
+
+    let import_update = vec![ImportUpdate {
+        import_manifest: FileUrl {
+            url: import_manifest_url.clone(),
+            size_in_bytes: manifest.len() as u64,
+            hashes: HashMap::from([("sha256", manifest_sha256)]),
+        },
+        files,
+    }];
+
+    let import_update =
+        serde_json::to_string_pretty(&import_update).context("Cannot parse import_update")?;
+
+    let mut progress = Progress::new("import update", None);
+    let import_update_response = progress
+        .wait(client.import_update(instance_id, import_update))
+        .await?;
+    progress.finish();
     info!("Result of import update: {:?}", &import_update_response);
 
     Ok(())
 }
 
+async fn verify_blob(url: &Url, file: &ManifestFile) -> Result<()> {
+    download_blob(url, file, std::io::sink()).await
+}
+
+async fn download_blob<W: std::io::Write>(
+    url: &Url,
+    file: &ManifestFile,
+    mut writer: W,
+) -> Result<()> {
+    debug!("download blob {}", file.filename);
+
+    let expected_sha256 = file
+        .hashes
+        .get("sha256")
+        .context(format!("no sha256 hash found for {}", file.filename))?;
+
+    let mut response = reqwest::get(url.clone())
+        .await
+        .context(format!("cannot download {}", file.filename))?;
+    let status = response.status();
+
+    if !status.is_success() {
+        return Err(
+            anyhow::anyhow!("cannot download {}. status: {status}", file.filename)
+                .context(ErrorKind::from_status(status)),
+        );
+    }
+
+    let mut hasher = sha2::Sha256::new();
+    let mut size_in_bytes = 0u64;
+    let mut progress = Progress::new(
+        format!("download {}", file.filename),
+        Some(file.size_in_bytes),
+    );
+
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .context(format!("cannot download {}", file.filename))?
+    {
+        size_in_bytes += chunk.len() as u64;
+        progress.inc(chunk.len() as u64);
+        hasher.update(&chunk);
+        writer
+            .write_all(&chunk)
+            .context(format!("cannot write {}", file.filename))?;
+    }
+
+    writer
+        .flush()
+        .context(format!("cannot write {}", file.filename))?;
+    progress.finish();
+
+    anyhow::ensure!(
+        size_in_bytes == file.size_in_bytes,
+        "size of {} doesn't match: expected {}, found {size_in_bytes}",
+        file.filename,
+        file.size_in_bytes
+    );
+
+    let sha256 = base64::encode_config(hasher.finalize(), base64::STANDARD);
+
+    anyhow::ensure!(
+        &sha256 == expected_sha256,
+        "sha256 of {} doesn't match: expected {expected_sha256}, found {sha256}",
+        file.filename
+    );
+
+    Ok(())
+}
+
+/// File name of an update file as reported by device update, which must not
+/// escape the output directory.
+fn exported_file_name(filename: &str) -> Result<&str> {
+    let mut components = Path::new(filename).components();
+
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Ok(filename),
+        _ => Err(
+            anyhow::anyhow!("export_update: invalid file name {filename}")
+                .context(ErrorKind::Remote),
+        ),
+    }
+}
+
 #[allow(clippy::too_many_arguments)]
-#[tokio::main]
+pub async fn export_update(
+    credentials: &AzureCredentials,
+    instance_id: &str,
+    device_update_endpoint_url: &Url,
+    provider: &str,
+    name: &str,
+    version: &str,
+    blob_storage: &BlobStorage,
+    output_dir: &Path,
+) -> Result<()> {
+    let client = RestClient::new(credentials, device_update_endpoint_url, instance_id).await?;
+    let update_path = format!("updates/providers/{provider}/names/{name}/versions/{version}");
+    let update = client.request(reqwest::Method::GET, &update_path).await?;
+
+    std::fs::create_dir_all(output_dir).context(format!(
+        "export_update: cannot create output dir {}",
+        output_dir.to_string_lossy()
+    ))?;
+
+    serde_json::to_writer_pretty(
+        std::fs::File::create(output_dir.join(EXPORTED_UPDATE_FILE))
+            .context("export_update: create update file")?,
+        &update,
+    )
+    .context("export_update: write update file")?;
+
+    for file_id in client.list(&format!("{update_path}/files"), None).await? {
+        let file_id = file_id.as_str().context("export_update: invalid file id")?;
+        let file = client
+            .request(
+                reqwest::Method::GET,
+                &format!("{update_path}/files/{file_id}"),
+            )
+            .await?;
+        let file = ManifestFile {
+            filename: file["fileName"]
+                .as_str()
+                .context("export_update: file name missing")?
+                .to_string(),
+            size_in_bytes: file["sizeInBytes"]
+                .as_u64()
+                .context("export_update: file size missing")?,
+            hashes: serde_json::from_value(file["hashes"].clone())
+                .context("export_update: file hashes missing")?,
+        };
+        let filename = exported_file_name(&file.filename)?;
+        let url = blob_storage.blob_url(filename).await?;
+        let out_file = output_dir.join(filename);
+        // download to a temporary file which is only renamed after size and hash are verified
+        let tmp_file = tempfile::NamedTempFile::new_in(output_dir)
+            .context(format!("export_update: cannot create {out_file:?}"))?;
+
+        download_blob(&url, &file, std::io::BufWriter::new(tmp_file.as_file())).await?;
+
+        tmp_file
+            .persist(&out_file)
+            .context(format!("export_update: cannot create {out_file:?}"))?;
+
+        info!("exported {}", out_file.to_string_lossy());
+    }
+
+    Ok(())
+}
+
 pub async fn remove_update(
-    tenant_id: &str,
-    client_id: &str,
-    client_secret: &str,
+    credentials: &AzureCredentials,
     instance_id: &str,
     device_update_endpoint_url: &Url,
     provider: &str,
     name: &str,
     version: &str,
 ) -> Result<()> {
-    let creds = std::sync::Arc::new(ClientSecretCredential::new(
-        azure_core::new_http_client(),
-        TokenCredentialOptions::default().authority_host()?,
-        tenant_id.to_string(),
-        client_id.to_string(),
-        client_secret.to_string(),
-    ));
-    let client = DeviceUpdateClient::new(device_update_endpoint_url.as_str(), creds)?;
+    let client = device_update_client(credentials, device_update_endpoint_url)?;
 
     debug!("remove update");
 
@@ -305,6 +861,428 @@ pub async fn remove_update(
     Ok(())
 }
 
+pub async fn cancel_deployment(
+    credentials: &AzureCredentials,
+    instance_id: &str,
+    device_update_endpoint_url: &Url,
+    group_id: &str,
+    device_class_id: &str,
+    deployment_id: &str,
+) -> Result<()> {
+    debug!("cancel deployment");
+
+    let response = RestClient::new(credentials, device_update_endpoint_url, instance_id)
+        .await?
+        .request(
+            reqwest::Method::POST,
+            &format!("management/groups/{group_id}/deviceClassSubgroups/{device_class_id}/deployments/{deployment_id}:cancel"),
+        )
+        .await?;
+    info!("Result of cancel deployment: {response}");
+
+    Ok(())
+}
+
+pub async fn retry_deployment(
+    credentials: &AzureCredentials,
+    instance_id: &str,
+    device_update_endpoint_url: &Url,
+    group_id: &str,
+    device_class_id: &str,
+    deployment_id: &str,
+) -> Result<()> {
+    debug!("retry deployment");
+
+    let response = RestClient::new(credentials, device_update_endpoint_url, instance_id)
+        .await?
+        .request(
+            reqwest::Method::POST,
+            &format!("management/groups/{group_id}/deviceClassSubgroups/{device_class_id}/deployments/{deployment_id}:retry"),
+        )
+        .await?;
+    info!("Result of retry deployment: {response}");
+
+    Ok(())
+}
+
+pub async fn compliance_report(
+    credentials: &AzureCredentials,
+    instance_id: &str,
+    device_update_endpoint_url: &Url,
+    group_id: Option<&str>,
+    format: &ReportFormat,
+) -> Result<()> {
+    let client = RestClient::new(credentials, device_update_endpoint_url, instance_id).await?;
+    let filter = group_id.map(group_filter);
+    let devices = client.list("management/devices", filter.as_deref()).await?;
+    let mut best_updates: HashMap<(String, String), Option<String>> = HashMap::new();
+    let mut entries = vec![];
+
+    for device in devices.iter() {
+        let group_id = device["groupId"].as_str().map(str::to_string);
+        let device_class_id = device["deviceClassId"].as_str().map(str::to_string);
+        let installed_version = device["installedUpdate"]["updateId"]["version"]
+            .as_str()
+            .map(str::to_string);
+        let on_latest_update = device["onLatestUpdate"].as_bool().unwrap_or(false);
+
+        let latest_version = match (&group_id, &device_class_id) {
+            (Some(group_id), Some(device_class_id)) => {
+                let key = (group_id.clone(), device_class_id.clone());
+
+                if let Entry::Vacant(e) = best_updates.entry(key.clone()) {
+                    e.insert(best_update_version(&client, group_id, device_class_id).await?);
+                }
+
+                best_updates[&key].clone()
+            }
+            _ => None,
+        };
+
+        entries.push(ComplianceEntry {
+            device_id: device["deviceId"].as_str().unwrap_or_default().to_string(),
+            latest_version: latest_version
+                .or_else(|| installed_version.clone().filter(|_| on_latest_update)),
+            group_id,
+            device_class_id,
+            installed_version,
+            on_latest_update,
+        });
+    }
+
+    write_report(
+        std::io::stdout(),
+        format,
+        &[
+            "device id",
+            "group id",
+            "device class id",
+            "installed version",
+            "latest version",
+            "on latest update",
+        ],
+        &entries,
+        |e| {
+            vec![
+                e.device_id.clone(),
+                e.group_id.clone().unwrap_or_default(),
+                e.device_class_id.clone().unwrap_or_default(),
+                e.installed_version.clone().unwrap_or_default(),
+                e.latest_version.clone().unwrap_or_default(),
+                e.on_latest_update.to_string(),
+            ]
+        },
+    )
+}
+
+pub async fn list_groups(
+    credentials: &AzureCredentials,
+    instance_id: &str,
+    device_update_endpoint_url: &Url,
+    format: &ReportFormat,
+) -> Result<()> {
+    let groups = RestClient::new(credentials, device_update_endpoint_url, instance_id)
+        .await?
+        .list("management/groups", None)
+        .await?;
+
+    let entries: Vec<GroupEntry> = groups
+        .iter()
+        .map(|group| GroupEntry {
+            group_id: group["groupId"].as_str().unwrap_or_default().to_string(),
+            group_type: group["groupType"].as_str().map(str::to_string),
+            device_count: group["deviceCount"].as_u64().unwrap_or_default(),
+            created_date_time: group["createdDateTime"].as_str().map(str::to_string),
+        })
+        .collect();
+
+    write_report(
+        std::io::stdout(),
+        format,
+        &["group id", "group type", "device count", "created"],
+        &entries,
+        |e| {
+            vec![
+                e.group_id.clone(),
+                e.group_type.clone().unwrap_or_default(),
+                e.device_count.to_string(),
+                e.created_date_time.clone().unwrap_or_default(),
+            ]
+        },
+    )
+}
+
+pub async fn list_group_devices(
+    credentials: &AzureCredentials,
+    instance_id: &str,
+    device_update_endpoint_url: &Url,
+    group_id: &str,
+    format: &ReportFormat,
+) -> Result<()> {
+    let devices = RestClient::new(credentials, device_update_endpoint_url, instance_id)
+        .await?
+        .list("management/devices", Some(&group_filter(group_id)))
+        .await?;
+
+    let entries: Vec<GroupDeviceEntry> = devices
+        .iter()
+        .map(|device| GroupDeviceEntry {
+            device_id: device["deviceId"].as_str().unwrap_or_default().to_string(),
+            device_class_id: device["deviceClassId"].as_str().map(str::to_string),
+            installed_version: device["installedUpdate"]["updateId"]["version"]
+                .as_str()
+                .map(str::to_string),
+            deployment_status: device["deploymentStatus"].as_str().map(str::to_string),
+        })
+        .collect();
+
+    write_report(
+        std::io::stdout(),
+        format,
+        &[
+            "device id",
+            "device class id",
+            "installed version",
+            "deployment status",
+        ],
+        &entries,
+        |e| {
+            vec![
+                e.device_id.clone(),
+                e.device_class_id.clone().unwrap_or_default(),
+                e.installed_version.clone().unwrap_or_default(),
+                e.deployment_status.clone().unwrap_or_default(),
+            ]
+        },
+    )
+}
+
+pub async fn list_device_classes(
+    credentials: &AzureCredentials,
+    instance_id: &str,
+    device_update_endpoint_url: &Url,
+    format: &ReportFormat,
+) -> Result<()> {
+    let device_classes = RestClient::new(credentials, device_update_endpoint_url, instance_id)
+        .await?
+        .list("management/deviceClasses", None)
+        .await?;
+
+    let entries: Vec<DeviceClassEntry> = device_classes
+        .iter()
+        .map(|device_class| {
+            let update_id = &device_class["bestCompatibleUpdate"]["updateId"];
+
+            DeviceClassEntry {
+                device_class_id: device_class["deviceClassId"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string(),
+                friendly_name: device_class["friendlyName"].as_str().map(str::to_string),
+                compat_properties: device_class["deviceClassProperties"]["compatProperties"]
+                    .as_object()
+                    .cloned()
+                    .unwrap_or_default(),
+                best_compatible_update: update_id["version"].as_str().map(|version| {
+                    format!(
+                        "{}/{}/{version}",
+                        update_id["provider"].as_str().unwrap_or_default(),
+                        update_id["name"].as_str().unwrap_or_default()
+                    )
+                }),
+            }
+        })
+        .collect();
+
+    write_report(
+        std::io::stdout(),
+        format,
+        &[
+            "device class id",
+            "friendly name",
+            "compat properties",
+            "best compatible update",
+        ],
+        &entries,
+        |e| {
+            vec![
+                e.device_class_id.clone(),
+                e.friendly_name.clone().unwrap_or_default(),
+                e.compat_properties
+                    .iter()
+                    .map(|(k, v)| format!("{k}={}", v.as_str().unwrap_or_default()))
+                    .collect::<Vec<_>>()
+                    .join(";"),
+                e.best_compatible_update.clone().unwrap_or_default(),
+            ]
+        },
+    )
+}
+
+/// device groups are created implicitly by tagging devices with "ADUGroup" in their device twin
+pub async fn create_group(
+    credentials: &AzureCredentials,
+    iot_hub_hostname: &str,
+    group_id: &str,
+    devices: &[String],
+) -> Result<()> {
+    let tags = serde_json::json!({ (ADU_GROUP_TAG): group_id });
+
+    for device in devices {
+        crate::iot_hub::patch_twin_tags(credentials, iot_hub_hostname, device, &tags).await?;
+        info!("added {device} to group {group_id}");
+    }
+
+    Ok(())
+}
+
+pub async fn delete_group(
+    credentials: &AzureCredentials,
+    instance_id: &str,
+    device_update_endpoint_url: &Url,
+    group_id: &str,
+) -> Result<()> {
+    let url = group_url(device_update_endpoint_url, instance_id, group_id)?;
+    let response = RestClient::new(credentials, device_update_endpoint_url, instance_id)
+        .await?
+        .send(reqwest::Method::DELETE, url)
+        .await?;
+    info!("Result of delete group: {response}");
+
+    Ok(())
+}
+
+async fn best_update_version(
+    client: &RestClient,
+    group_id: &str,
+    device_class_id: &str,
+) -> Result<Option<String>> {
+    // there is no best update if all devices of the subgroup are up to date
+    let best_update = client
+        .get_optional(&format!(
+            "management/groups/{group_id}/deviceClassSubgroups/{device_class_id}/bestUpdate"
+        ))
+        .await?;
+
+    if best_update.is_none() {
+        debug!("no best update for {group_id}/{device_class_id}");
+    }
+
+    Ok(best_update.and_then(|best_update| {
+        best_update["update"]["updateId"]["version"]
+            .as_str()
+            .map(str::to_string)
+    }))
+}
+
+fn write_report<T, W, F>(
+    mut writer: W,
+    format: &ReportFormat,
+    header: &[&str],
+    entries: &[T],
+    fields: F,
+) -> Result<()>
+where
+    T: Serialize,
+    W: std::io::Write,
+    F: Fn(&T) -> Vec<String>,
+{
+    match format {
+        ReportFormat::json => {
+            serde_json::to_writer_pretty(&mut writer, entries).context("write json report")?;
+            writeln!(writer)?;
+        }
+        ReportFormat::csv => {
+            let escape = |field: &str| {
+                if field.contains([',', '"', '\n']) {
+                    format!("\"{}\"", field.replace('"', "\"\""))
+                } else {
+                    field.to_string()
+                }
+            };
+
+            writeln!(writer, "{}", header.join(","))?;
+
+            for entry in entries {
+                let row: Vec<String> = fields(entry).iter().map(|f| escape(f)).collect();
+                writeln!(writer, "{}", row.join(","))?;
+            }
+        }
+        ReportFormat::table => {
+            let rows: Vec<Vec<String>> = entries.iter().map(fields).collect();
+            let widths: Vec<usize> = header
+                .iter()
+                .enumerate()
+                .map(|(i, h)| {
+                    rows.iter()
+                        .map(|r| r[i].len())
+                        .chain([h.len()])
+                        .max()
+                        .unwrap_or_default()
+                })
+                .collect();
+            let header = header.iter().map(|h| h.to_string()).collect();
+
+            for row in [header].iter().chain(rows.iter()) {
+                let line: Vec<String> = row
+                    .iter()
+                    .zip(widths.iter())
+                    .map(|(field, &width)| format!("{field:<width$}"))
+                    .collect();
+                writeln!(writer, "{}", line.join("  ").trim_end())?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub fn du_config_compatibility(image_file: &Path) -> Result<DuConfigCompatibility> {
+    // du-config.json is either injected into factory partition or part of the rootfs
+    let du_config = read_file_from_image(DU_CONFIG_PATH, Partition::factory, image_file)
+        .or_else(|_| read_file_from_image(DU_CONFIG_PATH, Partition::rootA, image_file))
+        .context("du_config_compatibility: cannot read du-config.json from image")?;
+
+    parse_du_config_compatibility(&du_config)
+}
+
+fn parse_du_config_compatibility(du_config: &str) -> Result<DuConfigCompatibility> {
+    let du_config: DuConfig =
+        serde_json::from_str(du_config).context("du_config_compatibility: parse du-config.json")?;
+    let agent = du_config
+        .agents
+        .into_iter()
+        .next()
+        .context("du_config_compatibility: du-config.json doesn't contain an agent")?;
+
+    Ok(DuConfigCompatibility {
+        provider: Some(du_config.manufacturer),
+        manufacturer: Some(agent.manufacturer),
+        model: Some(agent.model),
+        compatibilityid: agent
+            .additional_device_properties
+            .and_then(|p| p.get("compatibilityid").cloned()),
+    })
+}
+
+/// merges an explicitly passed property with the one derived from du-config.json
+pub fn resolve_compatibility_property(
+    name: &str,
+    explicit: Option<String>,
+    derived: Option<String>,
+) -> Result<Option<String>> {
+    match (explicit, derived) {
+        (Some(explicit), Some(derived)) if explicit != derived => anyhow::bail!(
+            "{name} \"{explicit}\" conflicts with \"{derived}\" configured in du-config.json of image"
+        ),
+        (explicit, derived) => Ok(explicit.or(derived)),
+    }
+}
+
+pub fn validate_import_manifest(import_manifest_path: &Path) -> Result<()> {
+    crate::validators::device_update::validate_import_manifest(import_manifest_path)
+}
+
 fn get_file_attributes(file: &Path) -> Result<File> {
     debug!("get file attributes for {file:#?}");
 
@@ -357,3 +1335,220 @@ pub async fn generate_sas_url(
         .generate_signed_blob_url(&token)
         .map_err(|e| e.into())
 }
+
+fn container_sas_to_blob_url(container_url: &Url, blob_name: &str) -> Result<Url> {
+    anyhow::ensure!(
+        container_url.query_pairs().any(|(k, _)| k == "sig"),
+        "container sas url doesn't contain a signature"
+    );
+
+    let mut blob_url = container_url.clone();
+
+    blob_url
+        .path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("invalid container sas url: {container_url}"))?
+        .pop_if_empty()
+        .push(blob_name);
+
+    Ok(blob_url)
+}
+
+fn sibling_blob_url(blob_url: &Url, blob_name: &str) -> Result<Url> {
+    let mut sibling_url = blob_url.clone();
+
+    sibling_url
+        .path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("invalid blob url: {blob_url}"))?
+        .pop()
+        .push(blob_name);
+
+    Ok(sibling_url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exported_file_name_ok() {
+        assert_eq!(exported_file_name("image.swu").unwrap(), "image.swu");
+        assert!(exported_file_name("../image.swu").is_err());
+        assert!(exported_file_name("/etc/passwd").is_err());
+        assert!(exported_file_name("dir/image.swu").is_err());
+        assert!(exported_file_name("..").is_err());
+        assert!(exported_file_name("").is_err());
+    }
+
+    #[test]
+    fn group_filter_ok() {
+        assert_eq!(group_filter("beta"), "groupId eq 'beta'");
+        assert_eq!(
+            group_filter("x' or groupId ne '"),
+            "groupId eq 'x'' or groupId ne '''"
+        );
+    }
+
+    #[test]
+    fn container_sas_to_blob_url_ok() {
+        let container_url = Url::parse(
+            "https://account.blob.core.windows.net/container?sv=2022-11-02&sr=c&sp=r&sig=abc",
+        )
+        .unwrap();
+
+        assert_eq!(
+            container_sas_to_blob_url(&container_url, "image.swu")
+                .unwrap()
+                .as_str(),
+            "https://account.blob.core.windows.net/container/image.swu?sv=2022-11-02&sr=c&sp=r&sig=abc"
+        );
+    }
+
+    #[test]
+    fn container_sas_to_blob_url_trailing_slash_ok() {
+        let container_url =
+            Url::parse("https://account.blob.core.windows.net/container/?sp=r&sig=abc").unwrap();
+
+        assert_eq!(
+            container_sas_to_blob_url(&container_url, "image.swu")
+                .unwrap()
+                .as_str(),
+            "https://account.blob.core.windows.net/container/image.swu?sp=r&sig=abc"
+        );
+    }
+
+    #[test]
+    fn sibling_blob_url_ok() {
+        let manifest_url = Url::parse(
+            "https://account.blob.core.windows.net/container/image.swu.importManifest.json?sp=r&sig=abc",
+        )
+        .unwrap();
+
+        assert_eq!(
+            sibling_blob_url(&manifest_url, "image.swu")
+                .unwrap()
+                .as_str(),
+            "https://account.blob.core.windows.net/container/image.swu?sp=r&sig=abc"
+        );
+    }
+
+    #[test]
+    fn rest_url_ok() {
+        let endpoint = Url::parse("https://my-account.api.adu.microsoft.com").unwrap();
+
+        assert_eq!(
+            rest_url(
+                &endpoint,
+                "my-instance",
+                "management/groups/my-group/deviceClassSubgroups/my-class/deployments/my-deployment:cancel"
+            )
+            .unwrap()
+            .as_str(),
+            "https://my-account.api.adu.microsoft.com/deviceUpdate/my-instance/management/groups/my-group/deviceClassSubgroups/my-class/deployments/my-deployment:cancel?api-version=2022-10-01"
+        );
+        assert_eq!(
+            group_url(&endpoint, "my-instance", "lab/group 1?")
+                .unwrap()
+                .as_str(),
+            "https://my-account.api.adu.microsoft.com/deviceUpdate/my-instance/management/groups/lab%2Fgroup%201%3F?api-version=2022-10-01"
+        );
+    }
+
+    #[derive(Serialize)]
+    struct ReportEntry {
+        name: String,
+        value: String,
+    }
+
+    fn report(format: ReportFormat) -> String {
+        let entries = vec![
+            ReportEntry {
+                name: "device-1".to_string(),
+                value: "1.0.0".to_string(),
+            },
+            ReportEntry {
+                name: "long-device-name".to_string(),
+                value: "a,b".to_string(),
+            },
+        ];
+        let mut out = vec![];
+
+        write_report(&mut out, &format, &["name", "value"], &entries, |e| {
+            vec![e.name.clone(), e.value.clone()]
+        })
+        .unwrap();
+
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn write_report_table_ok() {
+        assert_eq!(
+            report(ReportFormat::table),
+            "name              value\ndevice-1          1.0.0\nlong-device-name  a,b\n"
+        );
+    }
+
+    #[test]
+    fn write_report_csv_ok() {
+        assert_eq!(
+            report(ReportFormat::csv),
+            "name,value\ndevice-1,1.0.0\nlong-device-name,\"a,b\"\n"
+        );
+    }
+
+    #[test]
+    fn write_report_json_ok() {
+        let report: serde_json::Value = serde_json::from_str(&report(ReportFormat::json)).unwrap();
+
+        assert_eq!(report[1]["value"], "a,b");
+    }
+
+    #[test]
+    fn parse_du_config_compatibility_ok() {
+        let du_config = std::fs::read_to_string("conf/du-config.json.template").unwrap();
+
+        assert_eq!(
+            parse_du_config_compatibility(&du_config).unwrap(),
+            DuConfigCompatibility {
+                provider: Some("<Place your device info manufacturer here>".to_string()),
+                manufacturer: Some("<Place your device property manufacturer here>".to_string()),
+                model: Some("<Place your device property model here>".to_string()),
+                compatibilityid: Some("<Place your compatibility id here>".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn resolve_compatibility_property_ok() {
+        assert_eq!(
+            resolve_compatibility_property("model", None, Some("derived".to_string())).unwrap(),
+            Some("derived".to_string())
+        );
+        assert_eq!(
+            resolve_compatibility_property("model", Some("explicit".to_string()), None).unwrap(),
+            Some("explicit".to_string())
+        );
+        assert_eq!(
+            resolve_compatibility_property(
+                "model",
+                Some("same".to_string()),
+                Some("same".to_string())
+            )
+            .unwrap(),
+            Some("same".to_string())
+        );
+        assert!(resolve_compatibility_property(
+            "model",
+            Some("explicit".to_string()),
+            Some("derived".to_string())
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn container_sas_to_blob_url_missing_signature() {
+        let container_url = Url::parse("https://account.blob.core.windows.net/container").unwrap();
+
+        assert!(container_sas_to_blob_url(&container_url, "image.swu").is_err());
+    }
+}
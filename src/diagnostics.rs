@@ -0,0 +1,473 @@
+use crate::error::ErrorKind;
+use anyhow::{Context, Result};
+use log::debug;
+use regex::Regex;
+use serde::Serialize;
+use std::fs::File;
+use std::path::Path;
+use std::process::{Command, Output};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// ssh exits with 255 if the connection itself failed
+const SSH_CONNECTION_ERROR: i32 = 255;
+
+lazy_static::lazy_static! {
+    static ref RE_SINCE: Regex = Regex::new(r"^[A-Za-z0-9 :.+-]+$").unwrap();
+}
+
+/// Diagnostic collected from the device: the name of the file in the bundle
+/// and the shell command producing its content.
+struct Diagnostic {
+    file: &'static str,
+    command: String,
+}
+
+/// Result of a diagnostic command as listed in the summary of the bundle.
+#[derive(Debug, Serialize)]
+pub struct CollectedFile {
+    pub file: String,
+    pub command: String,
+    pub exit_code: Option<i32>,
+}
+
+fn diagnostics(since: Option<&str>) -> Result<Vec<Diagnostic>> {
+    let journal = match since {
+        Some(since) => {
+            anyhow::ensure!(
+                RE_SINCE.is_match(since),
+                "invalid since {since:?}, expected e.g. \"2024-05-01 10:00\" or \"-2h\""
+            );
+            format!("journalctl --no-pager --since '{since}'")
+        }
+        None => "journalctl --no-pager -b".to_string(),
+    };
+
+    Ok(vec![
+        Diagnostic {
+            file: "system.txt",
+            command: "cat /etc/os-release; uname -a; uptime".to_string(),
+        },
+        Diagnostic {
+            file: "journal.log",
+            command: format!("{journal} -n 10000"),
+        },
+        Diagnostic {
+            file: "failed-units.txt",
+            command: "systemctl --failed --no-pager".to_string(),
+        },
+        Diagnostic {
+            file: "aziot.log",
+            command: format!("{journal} -u 'aziot-*'"),
+        },
+        Diagnostic {
+            file: "adu-agent.log",
+            command: format!("{journal} -u deviceupdate-agent; tail -n 2000 /var/log/adu/*.log"),
+        },
+        Diagnostic {
+            file: "df.txt",
+            command: "df -h".to_string(),
+        },
+        Diagnostic {
+            file: "top.txt",
+            command: "top -b -n 1".to_string(),
+        },
+        Diagnostic {
+            file: "network.txt",
+            command: "ip addr show; ip route show; cat /etc/resolv.conf".to_string(),
+        },
+    ])
+}
+
+fn append_file(
+    builder: &mut tar::Builder<flate2::write::GzEncoder<File>>,
+    name: &str,
+    content: &[u8],
+) -> Result<()> {
+    let mut header = tar::Header::new_gnu();
+
+    header.set_size(content.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or_default(),
+    );
+    header.set_cksum();
+
+    builder
+        .append_data(&mut header, name, content)
+        .context(format!("collect_logs: cannot add {name} to bundle"))
+}
+
+/// Runs `command` on `destination` via the ssh configuration `ssh_config`.
+/// Only a failing connection is an error, not a failing command.
+fn run_ssh(ssh_config: &Path, destination: &str, command: &str) -> Result<Output> {
+    let mut ssh = Command::new("ssh");
+    ssh.arg("-F")
+        .arg(ssh_config)
+        .args(["-o", "BatchMode=yes"])
+        .arg(destination)
+        .arg(command);
+
+    debug!("run {ssh:?}");
+
+    let result = ssh
+        .output()
+        .context(ErrorKind::Environment)
+        .context("cannot run ssh, is it installed?")?;
+
+    if result.status.code() == Some(SSH_CONNECTION_ERROR) {
+        return Err(anyhow::anyhow!(
+            "ssh connection to {destination} failed: {}",
+            String::from_utf8_lossy(&result.stderr).trim()
+        )
+        .context(ErrorKind::Remote));
+    }
+
+    Ok(result)
+}
+
+/// Runs the diagnostic commands on `destination` via the ssh configuration
+/// `ssh_config` and writes their output as tar.gz support bundle to `output`.
+/// Commands failing on the device, e.g. for services that are not installed,
+/// don't abort the collection, their exit code is listed in `summary.json`.
+pub fn collect_logs(
+    ssh_config: &Path,
+    destination: &str,
+    since: Option<&str>,
+    output: &Path,
+) -> Result<Vec<CollectedFile>> {
+    let diagnostics = diagnostics(since)?;
+    let bundle = File::create(output).context(format!(
+        "collect_logs: cannot create {}",
+        output.to_string_lossy()
+    ))?;
+    let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+        bundle,
+        flate2::Compression::default(),
+    ));
+    let mut collected = vec![];
+
+    for diagnostic in diagnostics {
+        let result = run_ssh(ssh_config, destination, &diagnostic.command)?;
+        let mut content = result.stdout;
+
+        if !result.status.success() {
+            content.extend_from_slice(b"\n--- stderr ---\n");
+            content.extend_from_slice(&result.stderr);
+        }
+
+        append_file(&mut builder, diagnostic.file, &content)?;
+
+        collected.push(CollectedFile {
+            file: diagnostic.file.to_string(),
+            command: diagnostic.command,
+            exit_code: result.status.code(),
+        });
+    }
+
+    append_file(
+        &mut builder,
+        "summary.json",
+        &serde_json::to_vec_pretty(&collected)?,
+    )?;
+
+    builder
+        .into_inner()
+        .and_then(|encoder| encoder.finish())
+        .context("collect_logs: cannot write bundle")?;
+
+    Ok(collected)
+}
+
+// sections of the output of STATUS_COMMAND
+const STATUS_COMMAND: &str = "echo '== os-release'; cat /etc/os-release; \
+echo '== uptime'; cat /proc/uptime; \
+echo '== df'; df -P -k; \
+echo '== deviceupdate-agent'; systemctl is-active deviceupdate-agent";
+
+/// Usage of a mounted filesystem of the device.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct DiskUsage {
+    pub mount: String,
+    pub size_kib: u64,
+    pub used_kib: u64,
+}
+
+impl DiskUsage {
+    fn percent(&self) -> u64 {
+        (self.used_kib * 100)
+            .checked_div(self.size_kib)
+            .unwrap_or_default()
+    }
+}
+
+/// Health of a device as reported by its twin and/or via ssh tunnel, fields
+/// neither of them provides are `None`.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct DeviceStatus {
+    pub device: String,
+    pub online: Option<bool>,
+    pub last_activity: Option<String>,
+    pub os_version: Option<String>,
+    pub update_agent_state: Option<String>,
+    pub installed_update: Option<String>,
+    pub update_agent_service: Option<String>,
+    pub uptime_secs: Option<u64>,
+    pub disk_usage: Vec<DiskUsage>,
+}
+
+impl DeviceStatus {
+    pub fn new(device: &str) -> DeviceStatus {
+        DeviceStatus {
+            device: device.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Takes online status, os version and update agent state of the twin.
+    /// The update agent state is reported by Device Update for IoT Hub, the
+    /// os version by the device information interface.
+    pub fn with_twin(mut self, twin: &serde_json::Value) -> DeviceStatus {
+        let reported = &twin["properties"]["reported"];
+        let agent = &reported["deviceUpdate"]["agent"];
+
+        self.online = twin["connectionState"]
+            .as_str()
+            .map(|state| state == "Connected");
+        self.last_activity = twin["lastActivityTime"].as_str().map(str::to_string);
+        self.os_version = reported["deviceInformation"]["swVersion"]
+            .as_str()
+            .map(str::to_string)
+            .or(self.os_version);
+        self.update_agent_state = agent["state"].as_i64().map(|state| {
+            match state {
+                0 => "idle",
+                6 => "deployment in progress",
+                255 => "failed",
+                _ => return format!("state {state}"),
+            }
+            .to_string()
+        });
+        self.installed_update = match &agent["installedUpdateId"] {
+            serde_json::Value::String(update) => Some(update.clone()),
+            serde_json::Value::Null => None,
+            update => Some(update.to_string()),
+        };
+
+        self
+    }
+
+    /// Takes os version, uptime, disk usage and the state of the update agent
+    /// service of the output of `STATUS_COMMAND`.
+    fn with_ssh_output(mut self, output: &str) -> DeviceStatus {
+        let mut section = "";
+
+        // the device answered, so it is online
+        self.online = Some(true);
+
+        for line in output.lines() {
+            if let Some(name) = line.strip_prefix("== ") {
+                section = name;
+                continue;
+            }
+
+            match section {
+                "os-release" => {
+                    if let Some(name) = line.strip_prefix("PRETTY_NAME=") {
+                        self.os_version = Some(name.trim_matches('"').to_string());
+                    }
+                }
+                "uptime" => {
+                    self.uptime_secs = line
+                        .split_whitespace()
+                        .next()
+                        .and_then(|secs| secs.parse::<f64>().ok())
+                        .map(|secs| secs as u64);
+                }
+                "df" => {
+                    let fields = line.split_whitespace().collect::<Vec<_>>();
+
+                    // skip the header and pseudo filesystems
+                    if let [_, size, used, _, _, mount] = fields[..] {
+                        if let (Ok(size_kib), Ok(used_kib)) = (size.parse(), used.parse()) {
+                            if size_kib > 0 {
+                                self.disk_usage.push(DiskUsage {
+                                    mount: mount.to_string(),
+                                    size_kib,
+                                    used_kib,
+                                });
+                            }
+                        }
+                    }
+                }
+                "deviceupdate-agent" if !line.trim().is_empty() => {
+                    self.update_agent_service = Some(line.trim().to_string());
+                }
+                _ => {}
+            }
+        }
+
+        self
+    }
+
+    /// Queries os version, uptime, disk usage and the update agent service
+    /// on `destination` via the ssh configuration `ssh_config`.
+    pub fn with_ssh(self, ssh_config: &Path, destination: &str) -> Result<DeviceStatus> {
+        let result = run_ssh(ssh_config, destination, STATUS_COMMAND)?;
+
+        Ok(self.with_ssh_output(&String::from_utf8_lossy(&result.stdout)))
+    }
+}
+
+impl std::fmt::Display for DeviceStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let unknown = "unknown".to_string();
+
+        writeln!(f, "Device:               {}", self.device)?;
+        writeln!(
+            f,
+            "Online:               {}{}",
+            match self.online {
+                Some(true) => "yes",
+                Some(false) => "no",
+                None => "unknown",
+            },
+            self.last_activity
+                .as_ref()
+                .map(|activity| format!(" (last activity {activity})"))
+                .unwrap_or_default()
+        )?;
+        writeln!(
+            f,
+            "OS version:           {}",
+            self.os_version.as_ref().unwrap_or(&unknown)
+        )?;
+
+        if self.update_agent_state.is_some() || self.update_agent_service.is_none() {
+            writeln!(
+                f,
+                "Update agent:         {}{}",
+                self.update_agent_state.as_ref().unwrap_or(&unknown),
+                self.installed_update
+                    .as_ref()
+                    .map(|update| format!(", installed update {update}"))
+                    .unwrap_or_default()
+            )?;
+        }
+
+        if let Some(service) = &self.update_agent_service {
+            writeln!(f, "Update agent service: {service}")?;
+        }
+
+        match self.uptime_secs {
+            Some(secs) => writeln!(
+                f,
+                "Uptime:               {}d {}h {}m",
+                secs / 86400,
+                secs % 86400 / 3600,
+                secs % 3600 / 60
+            )?,
+            None => writeln!(f, "Uptime:               unknown")?,
+        }
+
+        write!(f, "Disk usage:")?;
+
+        if self.disk_usage.is_empty() {
+            write!(f, "           unknown")?;
+        }
+
+        for usage in &self.disk_usage {
+            write!(
+                f,
+                "\n  {:<20}{:>3}% of {} MiB",
+                usage.mount,
+                usage.percent(),
+                usage.size_kib / 1024
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diagnostics_since() {
+        let all = diagnostics(None).unwrap();
+
+        assert!(all.iter().any(|diagnostic| diagnostic.file == "df.txt"));
+        assert_eq!(
+            all.iter()
+                .find(|diagnostic| diagnostic.file == "aziot.log")
+                .unwrap()
+                .command,
+            "journalctl --no-pager -b -u 'aziot-*'"
+        );
+
+        let since = diagnostics(Some("2024-05-01 10:00")).unwrap();
+
+        assert_eq!(
+            since
+                .iter()
+                .find(|diagnostic| diagnostic.file == "journal.log")
+                .unwrap()
+                .command,
+            "journalctl --no-pager --since '2024-05-01 10:00' -n 10000"
+        );
+        assert!(diagnostics(Some("-2h")).is_ok());
+        assert!(diagnostics(Some("today'; rm -rf /; '")).is_err());
+    }
+
+    #[test]
+    fn device_status() {
+        let twin = serde_json::json!({
+            "connectionState": "Disconnected",
+            "lastActivityTime": "2024-05-01T10:00:00Z",
+            "properties": { "reported": {
+                "deviceInformation": { "swVersion": "4.0.10" },
+                "deviceUpdate": { "agent": { "state": 255, "installedUpdateId": "omnect-4.0.10" } },
+            } },
+        });
+        let output = "== os-release\nNAME=\"omnect-os\"\nPRETTY_NAME=\"omnect-os 4.0.11\"\n\
+            == uptime\n93784.52 180000.10\n\
+            == df\nFilesystem 1024-blocks Used Available Capacity Mounted on\n\
+            /dev/root 1048576 524288 524288 50% /\n\
+            tmpfs 0 0 0 - /sys/fs/cgroup\n\
+            == deviceupdate-agent\nactive\n";
+
+        let status = DeviceStatus::new("my-device").with_twin(&twin);
+
+        assert_eq!(status.online, Some(false));
+        assert_eq!(status.os_version.as_deref(), Some("4.0.10"));
+        assert_eq!(status.update_agent_state.as_deref(), Some("failed"));
+        assert_eq!(status.installed_update.as_deref(), Some("omnect-4.0.10"));
+
+        let status = status.with_ssh_output(output);
+
+        assert_eq!(status.online, Some(true));
+        assert_eq!(status.os_version.as_deref(), Some("omnect-os 4.0.11"));
+        assert_eq!(status.uptime_secs, Some(93784));
+        assert_eq!(
+            status.disk_usage,
+            vec![DiskUsage {
+                mount: "/".to_string(),
+                size_kib: 1048576,
+                used_kib: 524288,
+            }]
+        );
+        assert_eq!(status.update_agent_service.as_deref(), Some("active"));
+
+        let text = status.to_string();
+
+        assert!(text.contains("Uptime:               1d 2h 3m"));
+        assert!(text.contains("50% of 1024 MiB"));
+        assert!(DeviceStatus::new("my-device")
+            .to_string()
+            .contains("Online:               unknown"));
+    }
+}
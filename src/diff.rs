@@ -0,0 +1,279 @@
+use crate::error::ErrorKind;
+use crate::file::functions::{extract_partition, Partition};
+use crate::provenance::sha256;
+use crate::validators::image::{filesystem, Filesystem};
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use log::debug;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Display};
+use std::fs::{self, File};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::Command;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[allow(non_camel_case_types)]
+pub enum Change {
+    added,
+    removed,
+    changed,
+}
+
+/// Difference of a file between two images. `base` and `image` contain the
+/// sha256 of the file or `-> <target>` for a symbolic link.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FileDiff {
+    pub partition: String,
+    pub path: String,
+    pub change: Change,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub base: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+}
+
+impl Display for FileDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let change = format!("{:?}", self.change);
+
+        write!(f, "{change:<8}{}:{}", self.partition, self.path)?;
+
+        match (&self.base, &self.image) {
+            (Some(base), Some(image)) => write!(f, " ({base} => {image})"),
+            (Some(content), None) | (None, Some(content)) => write!(f, " ({content})"),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+/// Files below `dir` by their absolute path in the partition `root`. The
+/// extracted files belong to the user, so permissions are widened in order to
+/// read them.
+fn manifest(root: &Path, dir: &Path, files: &mut BTreeMap<String, String>) -> Result<()> {
+    fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+
+    for entry in fs::read_dir(dir).context(format!("manifest: cannot read {}", dir.display()))? {
+        let path = entry?.path();
+        let file_type = fs::symlink_metadata(&path)?.file_type();
+        let name = format!(
+            "/{}",
+            path.strip_prefix(root)
+                .context("manifest: invalid path")?
+                .to_string_lossy()
+        );
+
+        if file_type.is_dir() {
+            manifest(root, &path, files)?;
+        } else if file_type.is_symlink() {
+            files.insert(
+                name,
+                format!("-> {}", fs::read_link(&path)?.to_string_lossy()),
+            );
+        } else if file_type.is_file() {
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+            files.insert(name, sha256(&path)?);
+        }
+    }
+
+    Ok(())
+}
+
+/// Extracts the files of `partition` to a directory next to `image_file`.
+/// Returns `None` for partitions without filesystem, e.g. an empty rootB.
+fn partition_files(
+    partition: &Partition,
+    image_file: &Path,
+) -> Result<Option<BTreeMap<String, String>>> {
+    let working_dir = image_file
+        .parent()
+        .context("partition_files: cannot get directory of image")?;
+    let partition_file = working_dir.join(format!("diff-{partition}.img"));
+    let root = working_dir.join(format!("diff-{partition}"));
+
+    extract_partition(partition, image_file, &partition_file)?;
+
+    let partition_filesystem = filesystem(&mut File::open(&partition_file)?, 0)?;
+    let mut command = match partition_filesystem {
+        Some(Filesystem::Ext) => {
+            let mut debugfs = Command::new("debugfs");
+            debugfs
+                .arg("-R")
+                .arg(format!("rdump / {}", root.to_string_lossy()))
+                .arg(&partition_file);
+            debugfs
+        }
+        Some(Filesystem::Vfat) => {
+            let mut mcopy = Command::new("mcopy");
+            mcopy
+                .arg("-s")
+                .arg("-n")
+                .arg("-i")
+                .arg(&partition_file)
+                .arg("::/*")
+                .arg(&root);
+            mcopy
+        }
+        None => {
+            fs::remove_file(&partition_file)?;
+            return Ok(None);
+        }
+    };
+
+    fs::create_dir(&root).context("partition_files: cannot create directory")?;
+
+    debug!("partition_files: {command:?}");
+
+    anyhow::ensure!(
+        command
+            .status()
+            .context(ErrorKind::Environment)
+            .context(format!("partition_files: cannot run {command:?}"))?
+            .success(),
+        "partition_files: cmd failed: {command:?}"
+    );
+
+    fs::remove_file(&partition_file)?;
+
+    let mut files = BTreeMap::new();
+
+    manifest(&root, &root, &mut files)?;
+    fs::remove_dir_all(&root)?;
+
+    Ok(Some(files))
+}
+
+/// Compares two manifests of a partition.
+fn diff_files(
+    partition: &Partition,
+    base: &BTreeMap<String, String>,
+    image: &BTreeMap<String, String>,
+) -> Vec<FileDiff> {
+    base.keys()
+        .chain(image.keys())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .filter_map(|path| {
+            let (base, image) = (base.get(path), image.get(path));
+            let change = match (base, image) {
+                (None, Some(_)) => Change::added,
+                (Some(_), None) => Change::removed,
+                (Some(base), Some(image)) if base != image => Change::changed,
+                _ => return None,
+            };
+
+            Some(FileDiff {
+                partition: partition.to_string(),
+                path: path.clone(),
+                change,
+                base: base.cloned(),
+                image: image.cloned(),
+            })
+        })
+        .collect()
+}
+
+/// Files of all partitions of the uncompressed image `image_file` by
+/// partition name.
+pub(crate) fn image_files(image_file: &Path) -> Result<BTreeMap<String, BTreeMap<String, String>>> {
+    let mut files = BTreeMap::new();
+
+    for partition in Partition::value_variants() {
+        files.insert(
+            partition.to_string(),
+            partition_files(partition, image_file)?.unwrap_or_default(),
+        );
+    }
+
+    Ok(files)
+}
+
+/// Compares the files of all partitions as returned by [`image_files`].
+pub(crate) fn diff_image_files(
+    base: &BTreeMap<String, BTreeMap<String, String>>,
+    image: &BTreeMap<String, BTreeMap<String, String>>,
+) -> Vec<FileDiff> {
+    let empty = BTreeMap::new();
+
+    Partition::value_variants()
+        .iter()
+        .flat_map(|partition| {
+            let name = partition.to_string();
+
+            diff_files(
+                partition,
+                base.get(&name).unwrap_or(&empty),
+                image.get(&name).unwrap_or(&empty),
+            )
+        })
+        .collect()
+}
+
+/// Compares the files of all partitions of the uncompressed images
+/// `base_image` and `image`.
+pub fn diff_images(base_image: &Path, image: &Path) -> Result<Vec<FileDiff>> {
+    Ok(diff_image_files(
+        &image_files(base_image)?,
+        &image_files(image)?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_are_compared() {
+        let base = BTreeMap::from([
+            ("/etc/hostname".to_string(), "a".to_string()),
+            ("/etc/motd".to_string(), "b".to_string()),
+            ("/etc/link".to_string(), "-> /etc/motd".to_string()),
+        ]);
+        let image = BTreeMap::from([
+            ("/etc/hostname".to_string(), "c".to_string()),
+            ("/etc/hosts".to_string(), "d".to_string()),
+            ("/etc/link".to_string(), "-> /etc/motd".to_string()),
+        ]);
+        let diffs = diff_files(&Partition::factory, &base, &image);
+
+        assert_eq!(
+            diffs
+                .iter()
+                .map(|diff| (diff.path.as_str(), diff.change))
+                .collect::<Vec<_>>(),
+            vec![
+                ("/etc/hostname", Change::changed),
+                ("/etc/hosts", Change::added),
+                ("/etc/motd", Change::removed),
+            ]
+        );
+        assert_eq!(
+            diffs[0].to_string(),
+            "changed factory:/etc/hostname (a => c)"
+        );
+    }
+
+    #[test]
+    fn manifest_of_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut files = BTreeMap::new();
+
+        fs::create_dir(dir.path().join("etc")).unwrap();
+        fs::write(dir.path().join("etc/motd"), "").unwrap();
+        fs::set_permissions(
+            dir.path().join("etc/motd"),
+            fs::Permissions::from_mode(0o000),
+        )
+        .unwrap();
+        std::os::unix::fs::symlink("/etc/motd", dir.path().join("etc/link")).unwrap();
+
+        manifest(dir.path(), dir.path(), &mut files).unwrap();
+
+        assert_eq!(
+            files.get("/etc/motd").unwrap(),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(files.get("/etc/link").unwrap(), "-> /etc/motd");
+    }
+}
@@ -3,7 +3,9 @@ use std::path::PathBuf;
 
 use crate::file::compression::Compression;
 use crate::image::Architecture;
+use crate::progress::Progress;
 use std::fs::{self, File};
+use std::io::Read;
 use std::os::fd::AsFd;
 use std::process::{Command, Stdio};
 
@@ -22,18 +24,33 @@ pub fn pull_image(name: impl AsRef<str>, arch: Architecture) -> Result<PathBuf>
         anyhow::bail!("pull_docker_image: not supported in containerized environments.");
     }
 
-    let cmd_out = Command::new("docker")
+    let mut progress = Progress::new(format!("docker pull {}", name.as_ref()), None);
+    let mut child = Command::new("docker")
         .args(["pull"])
         .args(["--platform", arch.into()])
         .arg(name.as_ref())
-        .output()
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
         .context("pull_docker_image: could not run \"docker pull\" command")?;
+    let mut stderr = child.stderr.take().unwrap();
+    let stderr = std::thread::spawn(move || {
+        let mut stderr_out = String::new();
+        let _ = stderr.read_to_string(&mut stderr_out);
+        stderr_out
+    });
+    // docker pull only reports its progress on a terminal
+    let status = progress
+        .wait_child(&mut child)
+        .context("pull_docker_image: could not wait for \"docker pull\" command")?;
 
-    if !cmd_out.status.success() {
-        let cmd_out = std::str::from_utf8(&cmd_out.stderr).unwrap();
-        anyhow::bail!("Could not pull docker image: {cmd_out}");
+    if !status.success() {
+        let stderr_out = stderr.join().unwrap_or_default();
+        anyhow::bail!("Could not pull docker image: {stderr_out}");
     }
 
+    progress.finish();
+
     let mut child = Command::new("docker")
         .args(["save"])
         .arg(name.as_ref())
@@ -59,8 +76,7 @@ pub fn pull_image(name: impl AsRef<str>, arch: Architecture) -> Result<PathBuf>
     let error_code = child.wait()?;
 
     if !error_code.success() {
-        let cmd_out = std::str::from_utf8(&cmd_out.stderr).unwrap();
-        anyhow::bail!("Could not save docker image: {cmd_out}");
+        anyhow::bail!("Could not save docker image: {error_code}");
     }
 
     Ok(out_path)
@@ -0,0 +1,208 @@
+use crate::cli;
+use crate::file::functions::Partition;
+use anyhow::{Context, Result};
+use clap::{Arg, ValueEnum};
+use std::io::Write;
+use std::path::Path;
+
+/// Fully built command tree, so that global arguments are propagated to the
+/// subcommands.
+fn command_tree() -> clap::Command {
+    let mut command = cli::command().name(env!("CARGO_PKG_NAME"));
+    command.build();
+    command
+}
+
+fn visible_subcommands(command: &clap::Command) -> impl Iterator<Item = &clap::Command> {
+    command
+        .get_subcommands()
+        .filter(|sub| !sub.is_hide_set() && sub.get_name() != "help")
+}
+
+/// Writes one man page per command to `output_dir`, e.g.
+/// `omnect-cli-file-copy-to-image.1`.
+pub fn write_man_pages(output_dir: &Path) -> Result<()> {
+    fn write_man_page(command: &clap::Command, page_name: &str, output_dir: &Path) -> Result<()> {
+        let path = output_dir.join(format!("{page_name}.1"));
+        let mut file = std::fs::File::create(&path)
+            .context(format!("cannot create man page {}", path.to_string_lossy()))?;
+
+        clap_mangen::Man::new(command.clone().name(page_name.to_string()))
+            .render(&mut file)
+            .context(format!("cannot write man page {}", path.to_string_lossy()))?;
+
+        for sub in visible_subcommands(command) {
+            write_man_page(sub, &format!("{page_name}-{}", sub.get_name()), output_dir)?;
+        }
+
+        Ok(())
+    }
+
+    std::fs::create_dir_all(output_dir).context(format!(
+        "cannot create directory {}",
+        output_dir.to_string_lossy()
+    ))?;
+
+    write_man_page(&command_tree(), env!("CARGO_PKG_NAME"), output_dir)
+}
+
+fn arg_name(arg: &Arg) -> String {
+    let value = arg
+        .get_value_names()
+        .and_then(|names| names.first())
+        .map_or_else(
+            || arg.get_id().as_str().to_uppercase(),
+            |name| name.to_string(),
+        );
+
+    if arg.is_positional() {
+        return format!("<{value}>");
+    }
+
+    let mut name = match (arg.get_short(), arg.get_long()) {
+        (Some(short), Some(long)) => format!("-{short}, --{long}"),
+        (Some(short), None) => format!("-{short}"),
+        (None, Some(long)) => format!("--{long}"),
+        (None, None) => arg.get_id().to_string(),
+    };
+
+    if arg.get_action().takes_values() {
+        name.push_str(&format!(" <{value}>"));
+    }
+
+    name
+}
+
+fn arg_description(arg: &Arg) -> String {
+    let mut description = arg
+        .get_long_help()
+        .or(arg.get_help())
+        .map_or_else(String::new, |help| help.to_string().replace('\n', " "));
+
+    let possible_values = arg
+        .get_possible_values()
+        .iter()
+        .filter(|value| !value.is_hide_set())
+        .map(|value| value.get_name().to_string())
+        .collect::<Vec<_>>();
+
+    if !possible_values.is_empty() && arg.get_action().takes_values() {
+        description.push_str(&format!(
+            " [possible values: {}]",
+            possible_values.join(", ")
+        ));
+    }
+
+    let defaults = arg
+        .get_default_values()
+        .iter()
+        .map(|value| value.to_string_lossy())
+        .collect::<Vec<_>>();
+
+    if !defaults.is_empty() {
+        description.push_str(&format!(" [default: {}]", defaults.join(", ")));
+    }
+
+    if let Some(env) = arg.get_env() {
+        description.push_str(&format!(" [env: {}]", env.to_string_lossy()));
+    }
+
+    description.replace('|', "\\|")
+}
+
+fn write_command_markdown<W: Write>(
+    command: &clap::Command,
+    path: &str,
+    depth: usize,
+    writer: &mut W,
+) -> Result<()> {
+    writeln!(writer, "{} `{path}`\n", "#".repeat(depth.min(6)))?;
+
+    if let Some(about) = command.get_long_about().or(command.get_about()) {
+        writeln!(writer, "{about}\n")?;
+    }
+
+    writeln!(
+        writer,
+        "```\n{}\n```\n",
+        command.clone().bin_name(path).render_usage()
+    )?;
+
+    let args = command
+        .get_arguments()
+        .filter(|arg| !arg.is_hide_set() && !["help", "version"].contains(&arg.get_id().as_str()))
+        .collect::<Vec<_>>();
+
+    if !args.is_empty() {
+        writeln!(writer, "| Argument | Description |\n| --- | --- |")?;
+
+        for arg in args {
+            writeln!(writer, "| `{}` | {} |", arg_name(arg), arg_description(arg))?;
+        }
+
+        writeln!(writer)?;
+    }
+
+    for sub in visible_subcommands(command) {
+        write_command_markdown(
+            sub,
+            &format!("{path} {}", sub.get_name()),
+            depth + 1,
+            writer,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Writes the reference of all commands as markdown, followed by the syntax
+/// of file copy parameters.
+pub fn write_markdown<W: Write>(writer: &mut W) -> Result<()> {
+    write_command_markdown(&command_tree(), env!("CARGO_PKG_NAME"), 1, writer)?;
+
+    let partitions = Partition::value_variants()
+        .iter()
+        .filter_map(|p| p.to_possible_value())
+        .map(|p| format!("`{}`", p.get_name()))
+        .collect::<Vec<_>>();
+
+    writeln!(
+        writer,
+        "## File copy parameters\n\n\
+        `file copy-to-image` expects `<in-file-path>,<partition>:<out-file-path>` and \
+        `file copy-from-image` expects `<partition>:<in-file-path>,<out-file-path>`. \
+        Supported partitions: {}.",
+        partitions.join(", ")
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn markdown_contains_command_tree() {
+        let mut markdown = Vec::new();
+
+        write_markdown(&mut markdown).unwrap();
+
+        let markdown = String::from_utf8(markdown).unwrap();
+
+        assert!(markdown.contains("# `omnect-cli`"));
+        assert!(markdown.contains("### `omnect-cli file copy-to-image`"));
+        assert!(markdown.contains("| `-i, --image <IMAGE>` |"));
+        assert!(markdown.contains("Supported partitions: `boot`, `rootA`, `cert`, `factory`."));
+    }
+
+    #[test]
+    fn man_pages_for_all_commands() {
+        let dir = tempfile::tempdir().unwrap();
+
+        write_man_pages(dir.path()).unwrap();
+
+        assert!(dir.path().join("omnect-cli.1").exists());
+        assert!(dir.path().join("omnect-cli-file-copy-to-image.1").exists());
+    }
+}
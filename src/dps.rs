@@ -0,0 +1,277 @@
+use crate::device_update::AzureCredentials;
+use crate::error::ErrorKind;
+use crate::iot_hub::certificate_der;
+use anyhow::{Context, Result};
+use log::debug;
+use std::path::Path;
+use url::Url;
+
+const API_VERSION: &str = "2021-10-01";
+const DPS_SCOPE: &str = "https://azure-devices-provisioning.net/.default";
+const DPS_DOMAIN: &str = "azure-devices-provisioning.net";
+
+/// Hostname of a device provisioning service given either by its hostname or
+/// by its name.
+pub fn dps_hostname(dps: &str) -> String {
+    if dps.contains('.') {
+        dps.to_string()
+    } else {
+        format!("{dps}.{DPS_DOMAIN}")
+    }
+}
+
+fn dps_url(dps_hostname: &str, segments: &[&str]) -> Result<Url> {
+    let mut url = Url::parse(&format!("https://{dps_hostname}"))
+        .context(format!("invalid DPS hostname: {dps_hostname}"))?;
+
+    url.path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("invalid DPS hostname: {dps_hostname}"))?
+        .pop_if_empty()
+        .extend(segments);
+    url.query_pairs_mut()
+        .append_pair("api-version", API_VERSION);
+
+    Ok(url)
+}
+
+/// Whether DPS has an individual enrollment of `registration_id`.
+pub async fn enrollment_exists(
+    credentials: &AzureCredentials,
+    dps_hostname: &str,
+    registration_id: &str,
+) -> Result<bool> {
+    let response = reqwest::Client::new()
+        .get(dps_url(dps_hostname, &["enrollments", registration_id])?)
+        .bearer_auth(credentials.access_token(DPS_SCOPE).await?)
+        .send()
+        .await
+        .context("DPS enrollment request failed")?;
+
+    let status = response.status();
+
+    if status == reqwest::StatusCode::NOT_FOUND {
+        return Ok(false);
+    }
+
+    if !status.is_success() {
+        return Err(anyhow::anyhow!(
+            "cannot get enrollment {registration_id}. status: {status}, message: {}",
+            response.text().await.unwrap_or_default()
+        )
+        .context(ErrorKind::from_status(status)));
+    }
+
+    Ok(true)
+}
+
+/// x509 enrollment group of all devices with certificates signed by
+/// `signing_cert`, e.g. the intermediate ca of `identity set-device-certificate`.
+/// `etag` is the etag of an existing group to update.
+fn enrollment_group(
+    group_id: &str,
+    signing_cert: &str,
+    tags: Option<&serde_json::Value>,
+    iotedge: bool,
+    etag: Option<&str>,
+) -> Result<serde_json::Value> {
+    let mut group = serde_json::json!({
+        "enrollmentGroupId": group_id,
+        "attestation": {
+            "type": "x509",
+            "x509": {
+                "signingCertificates": {
+                    "primary": { "certificate": base64::encode(certificate_der(signing_cert)?) },
+                },
+            },
+        },
+        "capabilities": { "iotEdge": iotedge },
+        "provisioningStatus": "enabled",
+    });
+
+    if let Some(tags) = tags {
+        anyhow::ensure!(
+            tags.is_object(),
+            "enrollment_group: tags must be a json object"
+        );
+
+        group["initialTwin"] = serde_json::json!({ "tags": tags });
+    }
+
+    if let Some(etag) = etag {
+        group["etag"] = serde_json::json!(etag);
+    }
+
+    Ok(group)
+}
+
+/// Returns the enrollment group `group_id`, or `None` if it doesn't exist.
+pub async fn get_enrollment_group(
+    credentials: &AzureCredentials,
+    dps_hostname: &str,
+    group_id: &str,
+) -> Result<Option<serde_json::Value>> {
+    let response = reqwest::Client::new()
+        .get(dps_url(dps_hostname, &["enrollmentGroups", group_id])?)
+        .bearer_auth(credentials.access_token(DPS_SCOPE).await?)
+        .send()
+        .await
+        .context("DPS enrollment group request failed")?;
+
+    let status = response.status();
+
+    if status == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+
+    if !status.is_success() {
+        return Err(anyhow::anyhow!(
+            "cannot get enrollment group {group_id}. status: {status}, message: {}",
+            response.text().await.unwrap_or_default()
+        )
+        .context(ErrorKind::from_status(status)));
+    }
+
+    response
+        .json()
+        .await
+        .map(Some)
+        .context("get_enrollment_group: invalid response")
+}
+
+/// Creates the x509 enrollment group `group_id`, respectively replaces its
+/// signing certificate, capabilities and initial twin if it exists.
+pub async fn set_enrollment_group(
+    credentials: &AzureCredentials,
+    dps_hostname: &str,
+    group_id: &str,
+    signing_cert: &Path,
+    tags: Option<&serde_json::Value>,
+    iotedge: bool,
+) -> Result<serde_json::Value> {
+    let signing_cert = std::fs::read_to_string(signing_cert).context(format!(
+        "set_enrollment_group: cannot read {}",
+        signing_cert.to_string_lossy()
+    ))?;
+    let existing = get_enrollment_group(credentials, dps_hostname, group_id).await?;
+    let etag = existing.as_ref().and_then(|group| group["etag"].as_str());
+    let group = enrollment_group(group_id, &signing_cert, tags, iotedge, etag)?;
+
+    debug!("set enrollment group {group_id}: {group}");
+
+    let mut request = reqwest::Client::new()
+        .put(dps_url(dps_hostname, &["enrollmentGroups", group_id])?)
+        .bearer_auth(credentials.access_token(DPS_SCOPE).await?)
+        .json(&group);
+
+    if let Some(etag) = etag {
+        request = request.header(reqwest::header::IF_MATCH, etag);
+    }
+
+    let response = request
+        .send()
+        .await
+        .context("DPS enrollment group request failed")?;
+
+    let status = response.status();
+
+    if status == reqwest::StatusCode::PRECONDITION_FAILED {
+        return Err(anyhow::anyhow!(
+            "enrollment group {group_id} was modified concurrently, retry"
+        )
+        .context(ErrorKind::Remote));
+    }
+
+    if !status.is_success() {
+        return Err(anyhow::anyhow!(
+            "cannot set enrollment group {group_id}. status: {status}, message: {}",
+            response.text().await.unwrap_or_default()
+        )
+        .context(ErrorKind::from_status(status)));
+    }
+
+    response
+        .json()
+        .await
+        .context("set_enrollment_group: invalid response")
+}
+
+pub async fn delete_enrollment_group(
+    credentials: &AzureCredentials,
+    dps_hostname: &str,
+    group_id: &str,
+) -> Result<()> {
+    let response = reqwest::Client::new()
+        .delete(dps_url(dps_hostname, &["enrollmentGroups", group_id])?)
+        .bearer_auth(credentials.access_token(DPS_SCOPE).await?)
+        .send()
+        .await
+        .context("DPS enrollment group request failed")?;
+
+    let status = response.status();
+
+    if status == reqwest::StatusCode::NOT_FOUND {
+        return Err(
+            anyhow::anyhow!("enrollment group {group_id} not found in {dps_hostname}")
+                .context(ErrorKind::User),
+        );
+    }
+
+    if !status.is_success() {
+        return Err(anyhow::anyhow!(
+            "cannot delete enrollment group {group_id}. status: {status}, message: {}",
+            response.text().await.unwrap_or_default()
+        )
+        .context(ErrorKind::from_status(status)));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dps_url_ok() {
+        assert_eq!(
+            dps_hostname("my-dps"),
+            "my-dps.azure-devices-provisioning.net"
+        );
+        assert_eq!(
+            dps_url(&dps_hostname("my-dps"), &["enrollments", "my-device"])
+                .unwrap()
+                .as_str(),
+            "https://my-dps.azure-devices-provisioning.net/enrollments/my-device?api-version=2021-10-01"
+        );
+    }
+
+    #[test]
+    fn enrollment_group_ok() {
+        // "test" as certificate content
+        let cert = "-----BEGIN CERTIFICATE-----\ndGVz\ndA==\n-----END CERTIFICATE-----\n";
+        let tags = serde_json::json!({ "ADUGroup": "beta" });
+        let group = enrollment_group("omnect-devices", cert, Some(&tags), true, None).unwrap();
+
+        assert_eq!(group["enrollmentGroupId"], "omnect-devices");
+        assert_eq!(
+            group["attestation"]["x509"]["signingCertificates"]["primary"]["certificate"],
+            "dGVzdA=="
+        );
+        assert_eq!(group["capabilities"]["iotEdge"], true);
+        assert_eq!(group["initialTwin"]["tags"]["ADUGroup"], "beta");
+        assert!(group.get("etag").is_none());
+        assert_eq!(
+            enrollment_group("omnect-devices", cert, None, false, Some("\"1\"")).unwrap()["etag"],
+            "\"1\""
+        );
+        assert!(enrollment_group(
+            "omnect-devices",
+            cert,
+            Some(&serde_json::json!("beta")),
+            false,
+            None
+        )
+        .is_err());
+        assert!(enrollment_group("omnect-devices", "no certificate", None, false, None).is_err());
+    }
+}
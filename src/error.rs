@@ -0,0 +1,181 @@
+use reqwest::StatusCode;
+use std::fmt;
+
+// ENOSPC on Linux
+const NO_SPACE_LEFT_ON_DEVICE: i32 = 28;
+
+/// Class of a failure, which determines the exit code of omnect-cli. Errors
+/// are tagged by adding the kind as context, e.g.
+/// `.context(ErrorKind::Auth)`. Untagged errors are classified by their
+/// underlying error types.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorKind {
+    /// unexpected failure, e.g. a bug in omnect-cli
+    Internal,
+    /// bad arguments, missing or invalid input files
+    User,
+    /// the host lacks a requirement, e.g. a tool, loop device support or disk space
+    Environment,
+    /// authentication or authorization failed
+    Auth,
+    /// a remote api request failed, retrying might help
+    Remote,
+    /// the operation was cancelled, e.g. by Ctrl-C
+    Cancelled,
+}
+
+impl ErrorKind {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ErrorKind::Internal => 1,
+            ErrorKind::User => 2,
+            ErrorKind::Environment => 3,
+            ErrorKind::Auth => 4,
+            ErrorKind::Remote => 5,
+            // as for processes terminated by SIGINT
+            ErrorKind::Cancelled => 130,
+        }
+    }
+
+    /// Machine-readable error code as reported in json output.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErrorKind::Internal => "internal_error",
+            ErrorKind::User => "user_error",
+            ErrorKind::Environment => "environment_error",
+            ErrorKind::Auth => "auth_error",
+            ErrorKind::Remote => "remote_error",
+            ErrorKind::Cancelled => "cancelled",
+        }
+    }
+
+    pub fn is_transient(&self) -> bool {
+        *self == ErrorKind::Remote
+    }
+
+    /// Kind of a failed http request which was answered with `status`.
+    pub fn from_status(status: reqwest::StatusCode) -> ErrorKind {
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => ErrorKind::Auth,
+            StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS => ErrorKind::Remote,
+            status if status.is_client_error() => ErrorKind::User,
+            status if status.is_server_error() => ErrorKind::Remote,
+            _ => ErrorKind::Internal,
+        }
+    }
+
+    pub fn classify(error: &anyhow::Error) -> ErrorKind {
+        if let Some(kind) = error.downcast_ref::<ErrorKind>() {
+            return *kind;
+        }
+
+        for cause in error.chain() {
+            if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+                return e.status().map_or(ErrorKind::Remote, ErrorKind::from_status);
+            }
+
+            if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+                if e.raw_os_error() == Some(NO_SPACE_LEFT_ON_DEVICE) {
+                    return ErrorKind::Environment;
+                }
+
+                return match e.kind() {
+                    std::io::ErrorKind::NotFound
+                    | std::io::ErrorKind::PermissionDenied
+                    | std::io::ErrorKind::AlreadyExists
+                    | std::io::ErrorKind::InvalidInput
+                    | std::io::ErrorKind::InvalidData => ErrorKind::User,
+                    _ => ErrorKind::Environment,
+                };
+            }
+
+            if cause.is::<serde_json::Error>() || cause.is::<toml::de::Error>() {
+                return ErrorKind::User;
+            }
+
+            if cause.is::<azure_core::Error>() {
+                return ErrorKind::Remote;
+            }
+        }
+
+        ErrorKind::Internal
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::Internal => write!(f, "internal error"),
+            ErrorKind::User => write!(f, "invalid input"),
+            ErrorKind::Environment => write!(f, "unsuitable environment"),
+            ErrorKind::Auth => write!(f, "authentication failed"),
+            ErrorKind::Remote => write!(f, "remote request failed"),
+            ErrorKind::Cancelled => write!(f, "interrupted"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn classify_tagged_error() {
+        let error = anyhow::anyhow!("token expired")
+            .context(ErrorKind::Auth)
+            .context("create ssh tunnel");
+
+        assert_eq!(ErrorKind::classify(&error), ErrorKind::Auth);
+        assert_eq!(ErrorKind::classify(&error).exit_code(), 4);
+    }
+
+    #[test]
+    fn classify_untagged_errors() {
+        let not_found: anyhow::Result<String> =
+            std::fs::read_to_string("/nonexistent").context("cannot read image");
+
+        assert_eq!(
+            ErrorKind::classify(&not_found.unwrap_err()),
+            ErrorKind::User
+        );
+
+        let no_space =
+            anyhow::Error::new(std::io::Error::from_raw_os_error(NO_SPACE_LEFT_ON_DEVICE))
+                .context("copy image");
+
+        assert_eq!(ErrorKind::classify(&no_space), ErrorKind::Environment);
+        assert_eq!(
+            ErrorKind::classify(&anyhow::anyhow!("unexpected")),
+            ErrorKind::Internal
+        );
+    }
+
+    #[test]
+    fn kind_from_status() {
+        assert_eq!(
+            ErrorKind::from_status(StatusCode::UNAUTHORIZED),
+            ErrorKind::Auth
+        );
+        assert_eq!(
+            ErrorKind::from_status(StatusCode::FORBIDDEN),
+            ErrorKind::Auth
+        );
+        assert_eq!(
+            ErrorKind::from_status(StatusCode::NOT_FOUND),
+            ErrorKind::User
+        );
+        assert_eq!(
+            ErrorKind::from_status(StatusCode::TOO_MANY_REQUESTS),
+            ErrorKind::Remote
+        );
+        assert_eq!(
+            ErrorKind::from_status(StatusCode::SERVICE_UNAVAILABLE),
+            ErrorKind::Remote
+        );
+        assert_eq!(
+            ErrorKind::from_status(StatusCode::PERMANENT_REDIRECT),
+            ErrorKind::Internal
+        );
+    }
+}
@@ -0,0 +1,580 @@
+use super::{copy_from_image, copy_to_image, get_file_path};
+use crate::file::functions::{
+    read_file_from_image, FileCopyFromParams, FileCopyToParams, Partition,
+};
+use anyhow::{Context, Result};
+use log::debug;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+const GRUBENV_HEADER: &str = "# GRUB Environment Block\n";
+const GRUBENV_SIZE: usize = 1024;
+pub(crate) const UBOOT_ENV_FILE: &str = "/uboot.env";
+const UBOOT_ENV_TXT: &str = "/uEnv.txt";
+
+/// Boot configurations of the boot partition that contain the kernel
+/// command line, in the order they are looked up.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BootConfig {
+    /// raspberry pi firmware: the file is the command line
+    CmdlineTxt,
+    /// APPEND lines of all labels
+    Extlinux,
+    /// u-boot environment: variable of key=value lines
+    UEnv,
+    /// grub environment block: variable of key=value lines
+    GrubEnv,
+}
+
+impl BootConfig {
+    const ALL: [(BootConfig, &'static str); 5] = [
+        (BootConfig::CmdlineTxt, "/cmdline.txt"),
+        (BootConfig::Extlinux, "/extlinux/extlinux.conf"),
+        (BootConfig::UEnv, "/uEnv.txt"),
+        (BootConfig::GrubEnv, "/EFI/BOOT/grubenv"),
+        (BootConfig::GrubEnv, "/grubenv"),
+    ];
+}
+
+/// Removes parameters matching `remove`, either exactly or by key if given
+/// without value (e.g. "console" removes "console=ttyS0,115200"), and
+/// appends parameters of `append` that are not present yet.
+fn edit_cmdline(cmdline: &str, append: &[String], remove: &[String]) -> String {
+    let remove = remove
+        .iter()
+        .flat_map(|r| r.split_whitespace())
+        .collect::<Vec<_>>();
+    let mut params = cmdline
+        .split_whitespace()
+        .filter(|param| {
+            !remove
+                .iter()
+                .any(|r| param == r || (!r.contains('=') && param.split('=').next() == Some(*r)))
+        })
+        .collect::<Vec<_>>();
+
+    for param in append.iter().flat_map(|a| a.split_whitespace()) {
+        if !params.contains(&param) {
+            params.push(param);
+        }
+    }
+
+    params.join(" ")
+}
+
+/// Edits the value of `variable` in key=value lines, the variable is added
+/// if missing.
+fn edit_variable(content: &str, variable: &str, edit: impl Fn(&str) -> String) -> String {
+    let prefix = format!("{variable}=");
+    let mut found = false;
+    let mut lines = content
+        .lines()
+        .map(|line| match line.strip_prefix(&prefix) {
+            Some(value) => {
+                found = true;
+                format!("{prefix}{}", edit(value))
+            }
+            None => line.to_string(),
+        })
+        .collect::<Vec<_>>();
+
+    if !found {
+        lines.push(format!("{prefix}{}", edit("")));
+    }
+
+    lines.join("\n") + "\n"
+}
+
+fn edit_grubenv(content: &str, variable: &str, edit: impl Fn(&str) -> String) -> Result<String> {
+    let variables = content
+        .strip_prefix(GRUBENV_HEADER)
+        .context("edit_grubenv: invalid grub environment block")?
+        .trim_end_matches('#');
+    let variables = edit_variable(variables, variable, edit);
+    let block = format!("{GRUBENV_HEADER}{variables}");
+
+    anyhow::ensure!(
+        block.len() <= GRUBENV_SIZE,
+        "edit_grubenv: grub environment block exceeds {GRUBENV_SIZE} bytes"
+    );
+
+    // the block must keep its size, since grub writes it in place
+    Ok(format!("{block:#<GRUBENV_SIZE$}"))
+}
+
+fn edit_boot_config(
+    config: BootConfig,
+    content: &str,
+    variable: &str,
+    append: &[String],
+    remove: &[String],
+) -> Result<String> {
+    let edit = |cmdline: &str| edit_cmdline(cmdline, append, remove);
+
+    match config {
+        BootConfig::CmdlineTxt => Ok(edit(content) + "\n"),
+        BootConfig::Extlinux => {
+            let mut found = false;
+            let content = content
+                .lines()
+                .map(|line| {
+                    let trimmed = line.trim_start();
+
+                    match trimmed
+                        .get(..6)
+                        .filter(|keyword| keyword.eq_ignore_ascii_case("append"))
+                    {
+                        Some(keyword) => {
+                            found = true;
+                            let indent = &line[..line.len() - trimmed.len()];
+                            format!("{indent}{keyword} {}", edit(&trimmed[6..]))
+                        }
+                        None => line.to_string(),
+                    }
+                })
+                .collect::<Vec<_>>();
+
+            anyhow::ensure!(found, "extlinux.conf doesn't contain an APPEND line");
+
+            Ok(content.join("\n") + "\n")
+        }
+        BootConfig::UEnv => Ok(edit_variable(content, variable, edit)),
+        BootConfig::GrubEnv => edit_grubenv(content, variable, edit),
+    }
+}
+
+/// Edits the kernel command line of the first boot configuration found in
+/// the boot partition.
+pub fn set_cmdline(
+    append: &[String],
+    remove: &[String],
+    variable: &str,
+    image_file: &Path,
+) -> Result<()> {
+    let (config, path, content) = BootConfig::ALL
+        .iter()
+        .find_map(
+            |(config, path)| match read_file_from_image(path, Partition::boot, image_file) {
+                Ok(content) => Some((*config, *path, content)),
+                Err(e) => {
+                    debug!("set_cmdline: no {path}: {e:#}");
+                    None
+                }
+            },
+        )
+        .context(format!(
+            "set_cmdline: boot partition contains none of {}",
+            BootConfig::ALL
+                .iter()
+                .map(|(_, path)| *path)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))?;
+
+    debug!("set_cmdline: edit {config:?} {path}");
+
+    let content = edit_boot_config(config, &content, variable, append, remove)?;
+    let local = get_file_path(image_file, "boot-config")?;
+
+    fs::write(&local, content).context("set_cmdline: cannot write boot configuration")?;
+
+    copy_to_image(
+        &[FileCopyToParams::new(
+            &local,
+            Partition::boot,
+            Path::new(path),
+        )],
+        image_file,
+    )
+}
+
+/// U-Boot environment variable as given on the command line: "key=value"
+/// sets the variable, "key=" deletes it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EnvVariable {
+    key: String,
+    value: Option<String>,
+}
+
+impl FromStr for EnvVariable {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (key, value) = s.split_once('=').context("format not matched: key=value")?;
+
+        anyhow::ensure!(
+            !key.is_empty() && !key.contains(char::is_whitespace),
+            "invalid variable name {key:?}"
+        );
+        anyhow::ensure!(
+            !value.contains(['\0', '\n']),
+            "value of {key} must not contain line breaks"
+        );
+
+        Ok(EnvVariable {
+            key: key.to_string(),
+            value: (!value.is_empty()).then(|| value.to_string()),
+        })
+    }
+}
+
+fn set_variables(vars: &mut Vec<(String, String)>, updates: &[EnvVariable]) {
+    for update in updates {
+        let existing = vars.iter().position(|(key, _)| *key == update.key);
+
+        match (existing, &update.value) {
+            (Some(i), Some(value)) => vars[i].1 = value.clone(),
+            (Some(i), None) => {
+                vars.remove(i);
+            }
+            (None, Some(value)) => vars.push((update.key.clone(), value.clone())),
+            (None, None) => {}
+        }
+    }
+}
+
+/// Binary U-Boot environment as written by mkenvimage or fw_setenv: a crc32
+/// of the data, a flags byte for redundant environments, and "key=value"
+/// entries separated by zero bytes.
+struct UbootEnv {
+    /// flags byte of redundant environments
+    flags: Option<u8>,
+    vars: Vec<(String, String)>,
+    size: usize,
+    padding: u8,
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = flate2::Crc::new();
+    crc.update(data);
+    crc.sum()
+}
+
+impl UbootEnv {
+    fn parse(blob: &[u8]) -> Result<UbootEnv> {
+        anyhow::ensure!(blob.len() > 5, "u-boot environment too small");
+
+        let crc = u32::from_le_bytes(blob[..4].try_into()?);
+
+        // the crc determines whether the environment is redundant
+        let (flags, data) = if crc32(&blob[4..]) == crc {
+            (None, &blob[4..])
+        } else if crc32(&blob[5..]) == crc {
+            (Some(blob[4]), &blob[5..])
+        } else {
+            anyhow::bail!("u-boot environment has an invalid crc");
+        };
+
+        let vars = data
+            .split(|b| *b == 0)
+            .take_while(|entry| !entry.is_empty())
+            .map(|entry| {
+                let entry = String::from_utf8_lossy(entry);
+                let (key, value) = entry.split_once('=').unwrap_or((&entry, ""));
+                (key.to_string(), value.to_string())
+            })
+            .collect();
+
+        Ok(UbootEnv {
+            flags,
+            vars,
+            size: blob.len(),
+            padding: blob[blob.len() - 1],
+        })
+    }
+
+    fn serialize(&self) -> Result<Vec<u8>> {
+        let header = if self.flags.is_some() { 5 } else { 4 };
+        let mut data = vec![];
+
+        for (key, value) in &self.vars {
+            data.extend_from_slice(format!("{key}={value}").as_bytes());
+            data.push(0);
+        }
+
+        data.push(0);
+
+        anyhow::ensure!(
+            header + data.len() <= self.size,
+            "u-boot environment exceeds its size of {} bytes",
+            self.size
+        );
+
+        data.resize(self.size - header, self.padding);
+
+        let mut blob = crc32(&data).to_le_bytes().to_vec();
+        blob.extend(self.flags);
+        blob.extend(data);
+        Ok(blob)
+    }
+}
+
+fn edit_uboot_env(blob: &[u8], updates: &[EnvVariable]) -> Result<Vec<u8>> {
+    let mut env = UbootEnv::parse(blob)?;
+    set_variables(&mut env.vars, updates);
+    env.serialize()
+}
+
+/// Applies `edit` to the values of all variables of the binary u-boot
+/// environment `blob`, e.g. to replace references to a partition.
+pub(crate) fn edit_uboot_env_values(blob: &[u8], edit: impl Fn(&str) -> String) -> Result<Vec<u8>> {
+    let mut env = UbootEnv::parse(blob)?;
+
+    for (_, value) in env.vars.iter_mut() {
+        *value = edit(value);
+    }
+
+    env.serialize()
+}
+
+fn edit_uenv_txt(content: &str, updates: &[EnvVariable]) -> String {
+    let mut lines = content.lines().map(str::to_string).collect::<Vec<_>>();
+
+    for update in updates {
+        let prefix = format!("{}=", update.key);
+        let existing = lines.iter().position(|line| line.starts_with(&prefix));
+
+        match (existing, &update.value) {
+            (Some(i), Some(value)) => lines[i] = format!("{prefix}{value}"),
+            (Some(i), None) => {
+                lines.remove(i);
+            }
+            (None, Some(value)) => lines.push(format!("{prefix}{value}")),
+            (None, None) => {}
+        }
+    }
+
+    lines.join("\n") + "\n"
+}
+
+/// Edits the U-Boot environment of the image: at `offset` of the raw image
+/// if given (e.g. on IMX devices), otherwise uboot.env or uEnv.txt of the
+/// boot partition.
+pub fn set_uboot_env(
+    updates: &[EnvVariable],
+    offset: Option<(u64, usize)>,
+    image_file: &Path,
+) -> Result<()> {
+    if let Some((offset, size)) = offset {
+        let mut image = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(image_file)
+            .context("set_uboot_env: cannot open image")?;
+        let mut blob = vec![0u8; size];
+
+        image.seek(SeekFrom::Start(offset))?;
+        image
+            .read_exact(&mut blob)
+            .context("set_uboot_env: cannot read u-boot environment")?;
+
+        let blob = edit_uboot_env(&blob, updates)?;
+
+        image.seek(SeekFrom::Start(offset))?;
+        image
+            .write_all(&blob)
+            .context("set_uboot_env: cannot write u-boot environment")?;
+
+        return Ok(());
+    }
+
+    let local = get_file_path(image_file, "uboot-env")?;
+
+    // the binary environment isn't text, so it's copied instead of read
+    let path = match copy_from_image(
+        &[FileCopyFromParams::new(
+            Path::new(UBOOT_ENV_FILE),
+            Partition::boot,
+            &local,
+        )],
+        image_file,
+    ) {
+        Ok(()) => {
+            let blob = fs::read(&local).context("set_uboot_env: cannot read uboot.env")?;
+
+            fs::write(&local, edit_uboot_env(&blob, updates)?)
+                .context("set_uboot_env: cannot write uboot.env")?;
+            UBOOT_ENV_FILE
+        }
+        Err(e) => {
+            debug!("set_uboot_env: no {UBOOT_ENV_FILE}: {e:#}");
+
+            let content = read_file_from_image(UBOOT_ENV_TXT, Partition::boot, image_file)
+                .context(format!(
+                    "set_uboot_env: boot partition contains neither {UBOOT_ENV_FILE} nor {UBOOT_ENV_TXT}"
+                ))?;
+
+            fs::write(&local, edit_uenv_txt(&content, updates))
+                .context("set_uboot_env: cannot write uEnv.txt")?;
+            UBOOT_ENV_TXT
+        }
+    };
+
+    copy_to_image(
+        &[FileCopyToParams::new(
+            &local,
+            Partition::boot,
+            Path::new(path),
+        )],
+        image_file,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(params: &[&str]) -> Vec<String> {
+        params.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn edit_cmdline_append_and_remove() {
+        assert_eq!(
+            edit_cmdline(
+                "console=ttyS0,115200 quiet rootwait\n",
+                &params(&["console=tty1 watchdog.nowayout=1", "rootwait"]),
+                &params(&["quiet", "console"]),
+            ),
+            "rootwait console=tty1 watchdog.nowayout=1"
+        );
+        assert_eq!(
+            edit_cmdline(
+                "console=ttyS0 console=tty1",
+                &[],
+                &params(&["console=tty1"])
+            ),
+            "console=ttyS0"
+        );
+    }
+
+    #[test]
+    fn edit_boot_configs() {
+        let append = params(&["debug"]);
+        let remove = params(&["quiet"]);
+
+        assert_eq!(
+            edit_boot_config(
+                BootConfig::Extlinux,
+                "LABEL omnect\n  KERNEL /Image\n  APPEND root=/dev/mmcblk0p2 quiet\n",
+                "bootargs",
+                &append,
+                &remove
+            )
+            .unwrap(),
+            "LABEL omnect\n  KERNEL /Image\n  APPEND root=/dev/mmcblk0p2 debug\n"
+        );
+        assert!(edit_boot_config(
+            BootConfig::Extlinux,
+            "LABEL omnect\n",
+            "bootargs",
+            &append,
+            &remove
+        )
+        .is_err());
+        assert_eq!(
+            edit_boot_config(
+                BootConfig::UEnv,
+                "bootdelay=0\nbootargs=quiet\n",
+                "bootargs",
+                &append,
+                &remove
+            )
+            .unwrap(),
+            "bootdelay=0\nbootargs=debug\n"
+        );
+        assert_eq!(
+            edit_boot_config(
+                BootConfig::UEnv,
+                "bootdelay=0\n",
+                "extra_bootargs",
+                &append,
+                &remove
+            )
+            .unwrap(),
+            "bootdelay=0\nextra_bootargs=debug\n"
+        );
+    }
+
+    #[test]
+    fn edit_grubenv_keeps_size() {
+        let grubenv = format!("{:#<1024}", "# GRUB Environment Block\nbootargs=quiet\n");
+        let edited = edit_boot_config(
+            BootConfig::GrubEnv,
+            &grubenv,
+            "bootargs",
+            &params(&["debug"]),
+            &params(&["quiet"]),
+        )
+        .unwrap();
+
+        assert_eq!(edited.len(), 1024);
+        assert!(edited.starts_with("# GRUB Environment Block\nbootargs=debug\n#"));
+        assert!(edit_grubenv("invalid", "bootargs", |v| v.to_string()).is_err());
+    }
+
+    fn env_variables(vars: &[&str]) -> Vec<EnvVariable> {
+        vars.iter()
+            .map(|v| EnvVariable::from_str(v).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn env_variable_from_str() {
+        assert_eq!(
+            EnvVariable::from_str("bootdelay=3").unwrap(),
+            EnvVariable {
+                key: "bootdelay".to_string(),
+                value: Some("3".to_string())
+            }
+        );
+        assert_eq!(EnvVariable::from_str("bootdelay=").unwrap().value, None);
+        assert!(EnvVariable::from_str("bootdelay").is_err());
+        assert!(EnvVariable::from_str("=3").is_err());
+    }
+
+    #[test]
+    fn edit_uboot_env_blob() {
+        for header in [vec![], vec![1u8]] {
+            let mut data = b"bootdelay=0\0bootpart=2\0\0".to_vec();
+            data.resize(64 - 4 - header.len(), 0xff);
+
+            let mut blob = crc32(&data).to_le_bytes().to_vec();
+            blob.extend(&header);
+            blob.extend(data);
+
+            let edited = edit_uboot_env(
+                &blob,
+                &env_variables(&["bootdelay=3", "bootpart=", "console=ttymxc1"]),
+            )
+            .unwrap();
+            let env = UbootEnv::parse(&edited).unwrap();
+
+            assert_eq!(edited.len(), 64);
+            assert_eq!(env.flags, header.first().copied());
+            assert_eq!(env.padding, 0xff);
+            assert_eq!(
+                env.vars,
+                vec![
+                    ("bootdelay".to_string(), "3".to_string()),
+                    ("console".to_string(), "ttymxc1".to_string())
+                ]
+            );
+        }
+
+        assert!(edit_uboot_env(&[0u8; 64], &[]).is_err());
+    }
+
+    #[test]
+    fn edit_uenv_txt_variables() {
+        assert_eq!(
+            edit_uenv_txt(
+                "bootdelay=0\nbootpart=2\n",
+                &env_variables(&["bootdelay=3", "bootpart=", "console=ttymxc1"])
+            ),
+            "bootdelay=3\nconsole=ttymxc1\n"
+        );
+    }
+}
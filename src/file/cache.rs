@@ -0,0 +1,96 @@
+use crate::file::compression::Compression;
+use crate::progress::{Progress, ProgressReader};
+use anyhow::{Context, Result};
+use log::debug;
+use sha2::Digest;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+fn sha256(file: &Path) -> Result<String> {
+    let source = File::open(file).context(format!("cannot open {}", file.to_string_lossy()))?;
+    let total = source.metadata().ok().map(|metadata| metadata.len());
+    let mut source = ProgressReader::new(source, Progress::new("hash image", total));
+    let mut hasher = sha2::Sha256::new();
+
+    std::io::copy(&mut source, &mut hasher)
+        .context(format!("cannot read {}", file.to_string_lossy()))?;
+    source.finish();
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Returns the decompressed version of `image_file` stored in `cache_dir`.
+/// Entries are keyed by the sha256 of the compressed image, so that a cache
+/// miss decompresses the image once and subsequent commands reuse it.
+pub fn decompressed_image(
+    cache_dir: &Path,
+    image_file: &Path,
+    compression: &Compression,
+) -> Result<PathBuf> {
+    let cached = cache_dir.join(format!("{}.wic", sha256(image_file)?));
+
+    if cached.try_exists().is_ok_and(|exists| exists) {
+        debug!("cache hit {cached:?} for {image_file:?}");
+        return Ok(cached);
+    }
+
+    debug!("cache miss {cached:?} for {image_file:?}");
+
+    fs::create_dir_all(cache_dir).context(format!(
+        "cannot create cache dir {}",
+        cache_dir.to_string_lossy()
+    ))?;
+
+    // decompress to a temporary file first, so that an aborted run doesn't
+    // leave an incomplete cache entry
+    let tmp = cache_dir.join(format!(".{}.tmp", Uuid::new_v4()));
+    let result = File::open(image_file)
+        .and_then(|mut source| {
+            let mut destination = File::create(&tmp)?;
+            compression.decompress(&mut source, &mut destination)
+        })
+        .and_then(|_| fs::rename(&tmp, &cached));
+
+    if let Err(e) = result {
+        let _ = fs::remove_file(&tmp);
+        return Err(e).context(format!(
+            "cannot store decompressed {} in cache",
+            image_file.to_string_lossy()
+        ));
+    }
+
+    Ok(cached)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn decompressed_image_is_cached() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache_dir = dir.path().join("cache");
+        let image = dir.path().join("image.wic.gz");
+
+        let mut encoder = flate2::write::GzEncoder::new(
+            File::create(&image).unwrap(),
+            flate2::Compression::default(),
+        );
+        encoder.write_all(b"image content").unwrap();
+        encoder.finish().unwrap();
+
+        let cached = decompressed_image(&cache_dir, &image, &Compression::gzip).unwrap();
+
+        assert_eq!(fs::read(&cached).unwrap(), b"image content");
+
+        // a cache hit doesn't decompress again
+        fs::write(&cached, b"cached content").unwrap();
+
+        let cached = decompressed_image(&cache_dir, &image, &Compression::gzip).unwrap();
+
+        assert_eq!(fs::read(cached).unwrap(), b"cached content");
+        assert_eq!(fs::read_dir(&cache_dir).unwrap().count(), 1);
+    }
+}
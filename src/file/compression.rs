@@ -1,19 +1,27 @@
+use crate::file::sparse::{SparseReader, SparseWriter};
+use crate::progress::{Progress, ProgressReader};
 use anyhow::{Context, Result};
-use filemagic::Magic;
-use log::debug;
+use log::{debug, warn};
 use std::env;
 use std::fs::File;
-use std::path::PathBuf;
+use std::io::Read;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::thread::JoinHandle;
 use strum::IntoEnumIterator;
 use strum_macros::EnumIter;
 
+const MAGIC_MAX_LEN: u64 = 6;
+const ZSTD_LEVEL: i32 = 19;
+
 #[derive(Clone, Debug, EnumIter)]
 #[allow(non_camel_case_types)]
 pub enum Compression {
     xz { compression_level: u32 },
     bzip2,
     gzip,
+    zstd,
 }
 
 impl FromStr for Compression {
@@ -35,7 +43,8 @@ impl FromStr for Compression {
             }
             "bzip2" => Ok(Compression::bzip2),
             "gzip" => Ok(Compression::gzip),
-            _ => anyhow::bail!("unknown compression: use either xz, bzip2 or gzip"),
+            "zstd" => Ok(Compression::zstd),
+            _ => anyhow::bail!("unknown compression: use either xz, bzip2, gzip or zstd"),
         }
     }
 }
@@ -64,10 +73,21 @@ impl Compression {
                     .encoder()?;
                 Box::new(xz2::write::XzEncoder::new_stream(destination, stream))
             }
+            Compression::zstd => {
+                let mut encoder = zstd::stream::write::Encoder::new(destination, ZSTD_LEVEL)?;
+                encoder.multithread(num_cpus::get() as u32)?;
+                Box::new(encoder.auto_finish())
+            }
         };
 
-        let bytes_written = std::io::copy(source, &mut enc)?;
+        let total = file_size(source);
+        let mut source = ProgressReader::new(
+            ReadAhead::new(SparseReader::new(source.try_clone()?)?),
+            Progress::new(format!("compress {}", self.extension()), total),
+        );
+        let bytes_written = std::io::copy(&mut source, &mut enc)?;
         enc.flush()?;
+        source.finish();
         Ok(bytes_written)
     }
 
@@ -76,23 +96,45 @@ impl Compression {
         source: &mut std::fs::File,
         destination: &mut std::fs::File,
     ) -> std::io::Result<u64> {
+        // zero blocks of the decompressed image become holes
+        let destination = SparseWriter::new(destination);
         let mut dec: Box<dyn std::io::Write> = match &self {
             Compression::bzip2 => Box::new(bzip2::write::BzDecoder::new(destination)),
             Compression::gzip => Box::new(flate2::write::GzDecoder::new(destination)),
             Compression::xz { .. } => Box::new(xz2::write::XzDecoder::new(destination)),
+            Compression::zstd => Box::new(zstd::stream::write::Decoder::new(destination)?),
         };
 
-        let bytes_written = std::io::copy(source, &mut dec)?;
+        let total = file_size(source);
+        let mut source = ProgressReader::new(
+            ReadAhead::new(source.try_clone()?),
+            Progress::new(format!("decompress {}", self.extension()), total),
+        );
+        let bytes_written = std::io::copy(&mut source, &mut dec)?;
         dec.write_all(&[])?;
         dec.flush()?;
+        source.finish();
         Ok(bytes_written)
     }
 
-    fn marker(&self) -> &'static str {
+    /// Decompressing reader of `source`, e.g. in order to stream an image.
+    pub fn reader(&self, source: File) -> std::io::Result<Box<dyn Read + Send>> {
+        let source = ReadAhead::new(source);
+
+        Ok(match &self {
+            Compression::bzip2 => Box::new(bzip2::read::BzDecoder::new(source)),
+            Compression::gzip => Box::new(flate2::read::GzDecoder::new(source)),
+            Compression::xz { .. } => Box::new(xz2::read::XzDecoder::new(source)),
+            Compression::zstd => Box::new(zstd::stream::read::Decoder::new(source)?),
+        })
+    }
+
+    fn magic(&self) -> &'static [u8] {
         match &self {
-            Compression::bzip2 => "bzip2 compressed data",
-            Compression::gzip => "gzip compressed data",
-            Compression::xz { .. } => "XZ compressed data",
+            Compression::bzip2 => b"BZh",
+            Compression::gzip => &[0x1f, 0x8b],
+            Compression::xz { .. } => &[0xfd, b'7', b'z', b'X', b'Z', 0x00],
+            Compression::zstd => &[0x28, 0xb5, 0x2f, 0xfd],
         }
     }
 
@@ -101,40 +143,182 @@ impl Compression {
             Compression::bzip2 => "bzip2",
             Compression::gzip => "gzip",
             Compression::xz { .. } => "xz",
+            Compression::zstd => "zst",
         }
     }
 
+    /// File extensions the compression is commonly known by.
+    fn known_extensions(&self) -> &'static [&'static str] {
+        match &self {
+            Compression::bzip2 => &["bz2", "bzip2"],
+            Compression::gzip => &["gz", "gzip"],
+            Compression::xz { .. } => &["xz"],
+            Compression::zstd => &["zst", "zstd"],
+        }
+    }
+
+    /// Compression of the content starting with `header`.
+    fn from_header(header: &[u8]) -> Option<Compression> {
+        Compression::iter().find(|c| header.starts_with(c.magic()))
+    }
+
+    /// Compression the extension of `file` stands for.
+    fn from_extension(file: &Path) -> Option<Compression> {
+        let extension = file.extension()?.to_str()?.to_lowercase();
+
+        Compression::iter().find(|c| c.known_extensions().contains(&extension.as_str()))
+    }
+
+    /// Detects the compression of `image_file_name` by the magic bytes of its
+    /// content, the file extension is only used to warn about misleading file
+    /// names.
     pub fn from_file(image_file_name: &PathBuf) -> Result<Option<Compression>> {
-        let detector = Magic::open(Default::default())
-            .context("image::compression: failed to open libmagic")?;
+        let mut header = vec![];
+
+        File::open(image_file_name)
+            .context(format!(
+                "image::compression: cannot open {}",
+                image_file_name.to_string_lossy()
+            ))?
+            .take(MAGIC_MAX_LEN)
+            .read_to_end(&mut header)
+            .context("image::compression: cannot read image")?;
+
+        let compression = Compression::from_header(&header);
+        let by_extension = Compression::from_extension(image_file_name);
+
+        match (&compression, &by_extension) {
+            (Some(c), Some(e)) if c.extension() == e.extension() => {}
+            (None, None) => {}
+            _ => warn!(
+                "{} is {}, but its extension suggests {}",
+                image_file_name.to_string_lossy(),
+                compression
+                    .as_ref()
+                    .map_or("uncompressed".to_string(), |c| format!(
+                        "{} compressed",
+                        c.name()
+                    )),
+                by_extension
+                    .as_ref()
+                    .map_or("no compression".to_string(), |c| c.name().to_string())
+            ),
+        }
+
+        Ok(compression)
+    }
+
+    /// Name as accepted by `--pack-image`.
+    pub fn name(&self) -> &'static str {
+        match &self {
+            Compression::bzip2 => "bzip2",
+            Compression::gzip => "gzip",
+            Compression::xz { .. } => "xz",
+            Compression::zstd => "zstd",
+        }
+    }
+}
+
+/// Size of a regular file, which is unknown for pipes.
+fn file_size(file: &File) -> Option<u64> {
+    file.metadata()
+        .ok()
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+}
+
+/// Reads its source in a background thread, so that reading the next chunks
+/// overlaps with (de)compressing and writing the current one.
+struct ReadAhead {
+    chunks: Receiver<std::io::Result<Vec<u8>>>,
+    current: Vec<u8>,
+    pos: usize,
+    reader: Option<JoinHandle<()>>,
+}
+
+impl ReadAhead {
+    const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+    const CHUNKS_IN_FLIGHT: usize = 4;
 
-        detector
-            .load::<String>(&[])
-            .context("image::compression: failed to load libmagic")?;
+    fn new<R: Read + Send + 'static>(mut inner: R) -> ReadAhead {
+        let (sender, chunks) = sync_channel(Self::CHUNKS_IN_FLIGHT);
 
-        let magic = detector
-            .file(image_file_name)
-            .context("image::compression: failed to open image")?;
+        let reader = std::thread::spawn(move || loop {
+            let mut chunk = vec![0u8; Self::CHUNK_SIZE];
+            let result = match inner.read(&mut chunk) {
+                Ok(0) => return,
+                Ok(bytes) => {
+                    chunk.truncate(bytes);
+                    Ok(chunk)
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => Err(e),
+            };
+            let failed = result.is_err();
 
-        for c in Compression::iter() {
-            if magic.contains(c.marker()) {
-                return Ok(Some(c));
+            // a failed send means the consumer is gone
+            if sender.send(result).is_err() || failed {
+                return;
+            }
+        });
+
+        ReadAhead {
+            chunks,
+            current: vec![],
+            pos: 0,
+            reader: Some(reader),
+        }
+    }
+}
+
+impl Read for ReadAhead {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos == self.current.len() {
+            match self.chunks.recv() {
+                Ok(chunk) => {
+                    self.current = chunk?;
+                    self.pos = 0;
+                }
+                // reader thread finished: end of file
+                Err(_) => return Ok(0),
             }
         }
 
-        Ok(None)
+        let bytes = buf.len().min(self.current.len() - self.pos);
+        buf[..bytes].copy_from_slice(&self.current[self.pos..self.pos + bytes]);
+        self.pos += bytes;
+        Ok(bytes)
     }
 }
 
-pub fn decompress(image_file_name: &PathBuf, compression: &Compression) -> Result<PathBuf> {
-    let mut new_image_file = PathBuf::from(image_file_name);
+impl Drop for ReadAhead {
+    fn drop(&mut self) {
+        // unblock a reader waiting for a free slot before joining it
+        let (_, empty) = sync_channel(0);
+        drop(std::mem::replace(&mut self.chunks, empty));
 
-    if let Some(extension) = new_image_file.extension() {
-        if extension == compression.extension() {
-            new_image_file.set_extension("");
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
         }
     }
+}
 
+/// Decompresses `image_file_name` into `destination_dir`, stripping the
+/// compression extension from the file name. Decompressing straight from the
+/// source saves copying the compressed image first.
+pub fn decompress(
+    image_file_name: &Path,
+    destination_dir: &Path,
+    compression: &Compression,
+) -> Result<PathBuf> {
+    let new_image_file = without_extension(
+        &destination_dir.join(
+            image_file_name
+                .file_name()
+                .context("decompress: cannot get image file name")?,
+        ),
+        compression,
+    );
     let mut destination = File::create(&new_image_file)?;
     let mut source = File::open(image_file_name)?;
     debug!("decompress {image_file_name:?} to {new_image_file:?}");
@@ -143,6 +327,21 @@ pub fn decompress(image_file_name: &PathBuf, compression: &Compression) -> Resul
     Ok(new_image_file)
 }
 
+/// `image_file_name` without the extension of `compression`, e.g. image.wic
+/// for image.wic.xz. Other extensions are kept, since the compression is
+/// detected by the content of the image.
+pub fn without_extension(image_file_name: &Path, compression: &Compression) -> PathBuf {
+    let mut new_image_file = image_file_name.to_path_buf();
+
+    if Compression::from_extension(image_file_name)
+        .is_some_and(|c| c.extension() == compression.extension())
+    {
+        new_image_file.set_extension("");
+    }
+
+    new_image_file
+}
+
 pub fn compress(image_file_name: &PathBuf, compression: &Compression) -> Result<PathBuf> {
     let new_image_file = PathBuf::from(format!(
         "{}.{}",
@@ -156,3 +355,227 @@ pub fn compress(image_file_name: &PathBuf, compression: &Compression) -> Result<
     debug!("image::compress: copied {} bytes.", bytes_written);
     Ok(new_image_file)
 }
+
+/// Default file name of `image_file_name` transcoded to `compression`, e.g.
+/// image.wic.zst for image.wic.xz, or image.wic if it is only decompressed.
+pub fn transcoded_file_name(image_file_name: &Path, compression: Option<&Compression>) -> PathBuf {
+    let mut new_image_file = image_file_name.to_path_buf();
+
+    if Compression::from_extension(image_file_name).is_some() {
+        new_image_file.set_extension("");
+    }
+
+    match compression {
+        Some(compression) => PathBuf::from(format!(
+            "{}.{}",
+            new_image_file.to_string_lossy(),
+            compression.extension()
+        )),
+        None => new_image_file,
+    }
+}
+
+/// Converts `image_file_name` to `compression`, or only decompresses it if
+/// `None`, and stores the result to `output`. Returns the compression of the
+/// source image.
+pub fn transcode(
+    image_file_name: &Path,
+    compression: Option<&Compression>,
+    output: &Path,
+) -> Result<Option<Compression>> {
+    let source_compression = Compression::from_file(&image_file_name.to_path_buf())?;
+    let output_dir = output
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    // decompressed next to the output, so that it is moved in place by a rename
+    let tmp_dir = tempfile::tempdir_in(output_dir).context(format!(
+        "transcode: cannot create temporary directory in {}",
+        output_dir.to_string_lossy()
+    ))?;
+    let raw_image = match &source_compression {
+        Some(source_compression) => {
+            decompress(image_file_name, tmp_dir.path(), source_compression)?
+        }
+        None => image_file_name.to_path_buf(),
+    };
+
+    match compression {
+        Some(compression) => {
+            let mut destination = File::create(output).context(format!(
+                "transcode: cannot create {}",
+                output.to_string_lossy()
+            ))?;
+            debug!("transcode {raw_image:?} to {output:?}");
+            compression.compress(&mut File::open(&raw_image)?, &mut destination)?;
+        }
+        None if source_compression.is_some() => std::fs::rename(&raw_image, output)?,
+        None => {
+            std::fs::copy(&raw_image, output)?;
+        }
+    }
+
+    Ok(source_compression)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_ahead_preserves_content() {
+        let content = (0..(2 * ReadAhead::CHUNK_SIZE + 17))
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let mut read = vec![];
+
+        ReadAhead::new(std::io::Cursor::new(content.clone()))
+            .read_to_end(&mut read)
+            .unwrap();
+
+        assert_eq!(read, content);
+    }
+
+    #[test]
+    fn decompress_into_destination_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let destination_dir = dir.path().join("tmp");
+        let image = dir.path().join("image.wic.xz");
+
+        std::fs::create_dir(&destination_dir).unwrap();
+        Compression::xz {
+            compression_level: 1,
+        }
+        .compress(
+            &mut File::open(file!()).unwrap(),
+            &mut File::create(&image).unwrap(),
+        )
+        .unwrap();
+
+        let decompressed = decompress(
+            &image,
+            &destination_dir,
+            &Compression::from_str("xz").unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(decompressed, destination_dir.join("image.wic"));
+        assert_eq!(
+            std::fs::read(decompressed).unwrap(),
+            std::fs::read(file!()).unwrap()
+        );
+    }
+
+    #[test]
+    fn compression_detected_by_content() {
+        let dir = tempfile::tempdir().unwrap();
+
+        for compression in Compression::iter() {
+            // misleading extension
+            let image = dir.path().join(format!("image-{}.img", compression.name()));
+
+            compression
+                .compress(
+                    &mut File::open(file!()).unwrap(),
+                    &mut File::create(&image).unwrap(),
+                )
+                .unwrap();
+
+            assert_eq!(
+                Compression::from_file(&image).unwrap().unwrap().name(),
+                compression.name()
+            );
+
+            let mut content = vec![];
+            compression
+                .reader(File::open(&image).unwrap())
+                .unwrap()
+                .read_to_end(&mut content)
+                .unwrap();
+            assert_eq!(content, std::fs::read(file!()).unwrap());
+        }
+
+        let image = dir.path().join("image.wic.gz");
+        std::fs::write(&image, "uncompressed").unwrap();
+        assert!(Compression::from_file(&image).unwrap().is_none());
+    }
+
+    #[test]
+    fn transcode_to_other_compression() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.wic.xz");
+        let transcoded = dir.path().join("image.wic.zst");
+        let decompressed = dir.path().join("image.wic");
+
+        Compression::xz {
+            compression_level: 1,
+        }
+        .compress(
+            &mut File::open(file!()).unwrap(),
+            &mut File::create(&image).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            transcoded_file_name(&image, Some(&Compression::zstd)),
+            transcoded
+        );
+        assert_eq!(transcoded_file_name(&image, None), decompressed);
+        assert_eq!(
+            transcode(&image, Some(&Compression::zstd), &transcoded)
+                .unwrap()
+                .unwrap()
+                .name(),
+            "xz"
+        );
+        assert_eq!(
+            Compression::from_file(&transcoded).unwrap().unwrap().name(),
+            "zstd"
+        );
+        assert_eq!(
+            transcode(&transcoded, None, &decompressed)
+                .unwrap()
+                .unwrap()
+                .name(),
+            "zstd"
+        );
+        assert_eq!(
+            std::fs::read(decompressed).unwrap(),
+            std::fs::read(file!()).unwrap()
+        );
+    }
+
+    #[test]
+    fn strip_extension_of_compression() {
+        assert_eq!(
+            without_extension(Path::new("image.wic.gz"), &Compression::gzip),
+            Path::new("image.wic")
+        );
+        // a compressed image with a misleading name keeps its name
+        assert_eq!(
+            without_extension(Path::new("image.img"), &Compression::zstd),
+            Path::new("image.img")
+        );
+        assert_eq!(
+            without_extension(Path::new("image.wic.xz"), &Compression::zstd),
+            Path::new("image.wic.xz")
+        );
+    }
+
+    #[test]
+    fn compression_of_extension() {
+        assert_eq!(
+            Compression::from_extension(Path::new("image.wic.bz2"))
+                .unwrap()
+                .name(),
+            "bzip2"
+        );
+        assert_eq!(
+            Compression::from_extension(Path::new("image.wic.ZST"))
+                .unwrap()
+                .name(),
+            "zstd"
+        );
+        assert!(Compression::from_extension(Path::new("image.wic")).is_none());
+    }
+}
@@ -0,0 +1,457 @@
+use crate::error::ErrorKind;
+use crate::progress::Progress;
+use crate::runtime;
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use reqwest::header::{CONTENT_RANGE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE};
+use reqwest::{RequestBuilder, StatusCode};
+use sha2::Digest;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use url::Url;
+
+// attempts of a download, interrupted downloads are resumed
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Returns the URL of an image given as `http://` or `https://` address,
+/// e.g. `https://artifacts.example.com/omnect.wic.xz`.
+pub fn url(image_file: &Path) -> Option<Url> {
+    let image = image_file.to_str()?;
+
+    if !(image.starts_with("http://") || image.starts_with("https://")) {
+        return None;
+    }
+
+    Url::parse(image).ok()
+}
+
+/// Name of the downloaded image, which is the last segment of the URL path.
+fn file_name(url: &Url) -> Result<String> {
+    url.path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+        .context(format!("download: cannot get image file name of {url}"))
+        .context(ErrorKind::User)
+}
+
+/// Expected sha256 given as URL fragment, e.g. `omnect.wic.xz#sha256=<hex>`.
+fn fragment_sha256(url: &Url) -> Result<Option<String>> {
+    let Some(fragment) = url.fragment() else {
+        return Ok(None);
+    };
+    let sha256 = fragment
+        .strip_prefix("sha256=")
+        .filter(|sha256| sha256.len() == 64 && sha256.chars().all(|c| c.is_ascii_hexdigit()))
+        .context(format!(
+            "download: invalid checksum \"#{fragment}\", expected \"#sha256=<hex>\""
+        ))
+        .context(ErrorKind::User)?;
+
+    Ok(Some(sha256.to_ascii_lowercase()))
+}
+
+/// URL of a file next to the image, e.g. `omnect.wic.xz.sha256`. The query
+/// is kept, since it may authorize the request, e.g. a SAS token.
+fn sidecar_url(url: &Url, suffix: &str) -> Url {
+    let mut sidecar = url.clone();
+
+    sidecar.set_path(&format!("{}{suffix}", url.path()));
+    sidecar.set_fragment(None);
+
+    sidecar
+}
+
+/// Fetches a small file next to the image. Returns `None` if there is none.
+async fn fetch_sidecar(url: &Url) -> Result<Option<Vec<u8>>> {
+    let response = reqwest::get(url.clone())
+        .await
+        .context(format!("download: cannot get {url}"))?;
+    let status = response.status();
+
+    if status == reqwest::StatusCode::NOT_FOUND {
+        debug!("no {url}");
+        return Ok(None);
+    }
+
+    if !status.is_success() {
+        return Err(
+            anyhow::anyhow!("download: cannot get {url}. status: {status}")
+                .context(ErrorKind::from_status(status)),
+        );
+    }
+
+    Ok(Some(
+        response
+            .bytes()
+            .await
+            .context(format!("download: cannot get {url}"))?
+            .to_vec(),
+    ))
+}
+
+/// Path of the validator of a partial download, i.e. the ETag or
+/// Last-Modified header of the response, which a resumed download has to
+/// match.
+fn validator_path(file: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.validator", file.to_string_lossy()))
+}
+
+fn sha256_of(file: &Path) -> Result<sha2::Sha256> {
+    let mut hasher = sha2::Sha256::new();
+
+    std::io::copy(
+        &mut File::open(file)
+            .context(format!("download: cannot open {}", file.to_string_lossy()))?,
+        &mut hasher,
+    )
+    .context(format!("download: cannot read {}", file.to_string_lossy()))?;
+
+    Ok(hasher)
+}
+
+/// Error of a failed response with status `status`.
+fn status_error(url: &Url, status: StatusCode) -> anyhow::Error {
+    anyhow::anyhow!("download: cannot get {url}. status: {status}")
+        .context(ErrorKind::from_status(status))
+}
+
+/// Continues the download of `url` to `file` at the end of `file` by a
+/// range request. A server ignoring the range or reporting a changed
+/// resource restarts the download. Returns the sha256 of the complete file,
+/// `None` if there is no resource at `url`.
+async fn fetch_once(
+    request: &impl Fn() -> Result<RequestBuilder>,
+    url: &Url,
+    file: &Path,
+) -> Result<Option<String>> {
+    let offset = fs::metadata(file)
+        .map(|metadata| metadata.len())
+        .unwrap_or(0);
+    let validator = fs::read_to_string(validator_path(file)).ok();
+    let mut request = request()?;
+
+    if offset > 0 {
+        request = request.header(RANGE, format!("bytes={offset}-"));
+
+        if let Some(validator) = &validator {
+            request = request.header(IF_RANGE, validator);
+        }
+    }
+
+    let mut response = request
+        .send()
+        .await
+        .context(format!("download: cannot get {url}"))
+        .context(ErrorKind::Remote)?;
+    let status = response.status();
+    let resumed = status == StatusCode::PARTIAL_CONTENT
+        && response
+            .headers()
+            .get(CONTENT_RANGE)
+            .and_then(|range| range.to_str().ok())
+            .is_some_and(|range| range.starts_with(&format!("bytes {offset}-")));
+
+    match status {
+        StatusCode::NOT_FOUND => return Ok(None),
+        // the resource shrank, start over on the next attempt
+        StatusCode::RANGE_NOT_SATISFIABLE if offset > 0 => {
+            let _ = fs::remove_file(file);
+            return Err(anyhow::anyhow!("download: {url} changed").context(ErrorKind::Remote));
+        }
+        status if !status.is_success() => return Err(status_error(url, status)),
+        // a range other than requested, start over on the next attempt
+        StatusCode::PARTIAL_CONTENT if !resumed => {
+            let _ = fs::remove_file(file);
+            return Err(
+                anyhow::anyhow!("download: unexpected range of {url}").context(ErrorKind::Remote)
+            );
+        }
+        _ => {}
+    }
+
+    let (mut writer, mut hasher, offset) = if resumed {
+        debug!("download: resume {url} at {offset} bytes");
+
+        let writer = OpenOptions::new()
+            .append(true)
+            .open(file)
+            .context(format!("download: cannot open {}", file.to_string_lossy()))?;
+
+        (writer, sha256_of(file)?, offset)
+    } else {
+        let writer = File::create(file).context(format!(
+            "download: cannot create {}",
+            file.to_string_lossy()
+        ))?;
+
+        match response
+            .headers()
+            .get(ETAG)
+            .or(response.headers().get(LAST_MODIFIED))
+            .and_then(|validator| validator.to_str().ok())
+        {
+            Some(validator) => fs::write(validator_path(file), validator)?,
+            None => {
+                let _ = fs::remove_file(validator_path(file));
+            }
+        }
+
+        (writer, sha2::Sha256::new(), 0)
+    };
+    let mut progress = Progress::new(
+        format!("download {url}"),
+        response.content_length().map(|length| length + offset),
+    );
+
+    progress.inc(offset);
+
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .context(format!("download: cannot get {url}"))
+        .context(ErrorKind::Remote)?
+    {
+        progress.inc(chunk.len() as u64);
+        hasher.update(&chunk);
+        writer
+            .write_all(&chunk)
+            .context(format!("download: cannot write {}", file.to_string_lossy()))?;
+    }
+
+    writer
+        .flush()
+        .context(format!("download: cannot write {}", file.to_string_lossy()))?;
+    progress.finish();
+
+    let _ = fs::remove_file(validator_path(file));
+
+    Ok(Some(format!("{:x}", hasher.finalize())))
+}
+
+/// Downloads `url` to `file` by the requests created by `request`. An
+/// interrupted download is resumed up to [`MAX_ATTEMPTS`] times by range
+/// requests, a partial `file` of a previous run is continued as well.
+/// Returns the sha256 of the file, `None` if there is no resource at `url`.
+pub(crate) async fn fetch(
+    request: impl Fn() -> Result<RequestBuilder>,
+    url: &Url,
+    file: &Path,
+) -> Result<Option<String>> {
+    let mut attempt = 1;
+
+    loop {
+        match fetch_once(&request, url, file).await {
+            Err(e) if attempt < MAX_ATTEMPTS && ErrorKind::classify(&e).is_transient() => {
+                let delay = Duration::from_secs(1 << attempt);
+
+                warn!(
+                    "download: {url} interrupted, resume in {}s: {e:#}",
+                    delay.as_secs()
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Downloads the image at `url` to `dir` and returns the path of the
+/// downloaded image. The image is verified against the sha256 given as
+/// `#sha256=<hex>` fragment of the URL or, if there is none, by a
+/// `<url>.sha256` file next to the image. If `with_signature`, the
+/// signature `<url>.sig` and if present the attestation `<url>.att` are
+/// downloaded next to the image as well. With a `cache_dir`, an interrupted
+/// download is kept there and resumed by the next download of `url`.
+pub fn download(
+    url: &Url,
+    dir: &Path,
+    with_signature: bool,
+    cache_dir: Option<&Path>,
+) -> Result<PathBuf> {
+    let expected_sha256 = fragment_sha256(url)?;
+    let mut url = url.clone();
+
+    url.set_fragment(None);
+
+    let image_file = dir.join(file_name(&url)?);
+    let partial_file = match cache_dir {
+        Some(cache_dir) => {
+            let downloads = cache_dir.join("downloads");
+
+            fs::create_dir_all(&downloads).context(format!(
+                "download: cannot create {}",
+                downloads.to_string_lossy()
+            ))?;
+
+            downloads.join(format!(
+                "{:x}.part",
+                sha2::Sha256::digest(url.as_str().as_bytes())
+            ))
+        }
+        None => image_file.clone(),
+    };
+
+    runtime::block_on(async {
+        let expected_sha256 = match expected_sha256 {
+            Some(sha256) => Some(sha256),
+            None => fetch_sidecar(&sidecar_url(&url, ".sha256"))
+                .await?
+                .map(|content| {
+                    // sha256sum format: "<hex>  <file name>"
+                    String::from_utf8_lossy(&content)
+                        .split_whitespace()
+                        .next()
+                        .unwrap_or_default()
+                        .to_ascii_lowercase()
+                }),
+        };
+
+        let client = reqwest::Client::new();
+        let sha256 = fetch(|| Ok(client.get(url.clone())), &url, &partial_file)
+            .await?
+            .ok_or_else(|| status_error(&url, StatusCode::NOT_FOUND))?;
+
+        match expected_sha256 {
+            Some(expected) if sha256 != expected => {
+                let _ = fs::remove_file(&partial_file);
+
+                return Err(anyhow::anyhow!(
+                    "download: sha256 of {url} doesn't match: expected {expected}, found {sha256}"
+                )
+                .context(ErrorKind::User));
+            }
+            Some(_) => debug!("download: verified sha256 of {url}"),
+            None => warn!("download: no checksum for {url}, image isn't verified"),
+        }
+
+        if partial_file != image_file {
+            crate::move_file(&partial_file, &image_file)?;
+        }
+
+        if with_signature {
+            for (suffix, required) in [(".sig", true), (".att", false)] {
+                let sidecar = sidecar_url(&url, suffix);
+
+                match fetch_sidecar(&sidecar).await? {
+                    Some(content) => {
+                        let path =
+                            PathBuf::from(format!("{}{suffix}", image_file.to_string_lossy()));
+
+                        fs::write(&path, content).context(format!(
+                            "download: cannot write {}",
+                            path.to_string_lossy()
+                        ))?;
+                    }
+                    None if required => {
+                        return Err(anyhow::anyhow!("download: no signature {sidecar}")
+                            .context(ErrorKind::User));
+                    }
+                    None => {}
+                }
+            }
+        }
+
+        Ok(())
+    })?;
+
+    Ok(image_file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_url() {
+        assert!(url(Path::new("image.wic.xz")).is_none());
+        assert!(url(Path::new("/tmp/http/image.wic")).is_none());
+
+        let sha256 = "ab".repeat(32);
+        let image_url = url(Path::new(&format!(
+            "https://artifacts.example.com/omnect/image.wic.xz#sha256={}",
+            sha256.to_uppercase()
+        )))
+        .unwrap();
+
+        assert_eq!(file_name(&image_url).unwrap(), "image.wic.xz");
+        assert_eq!(fragment_sha256(&image_url).unwrap(), Some(sha256));
+
+        let image_url = url(Path::new("http://artifacts.example.com/image.wic#md5=00")).unwrap();
+
+        assert!(fragment_sha256(&image_url).is_err());
+        assert!(file_name(&url(Path::new("https://artifacts.example.com/")).unwrap()).is_err());
+    }
+
+    #[test]
+    fn sidecar_keeps_query() {
+        let image_url = Url::parse(
+            "https://account.blob.core.windows.net/images/image.wic.xz?sv=2021-08-06&sig=abc%2B",
+        )
+        .unwrap();
+
+        assert_eq!(
+            sidecar_url(&image_url, ".sha256").as_str(),
+            "https://account.blob.core.windows.net/images/image.wic.xz.sha256?sv=2021-08-06&sig=abc%2B"
+        );
+        assert_eq!(
+            sidecar_url(
+                &Url::parse("https://artifacts.example.com/image.wic#sha256=00").unwrap(),
+                ".sig"
+            )
+            .as_str(),
+            "https://artifacts.example.com/image.wic.sig"
+        );
+    }
+
+    #[test]
+    fn resume_download() {
+        let server = httpmock::MockServer::start();
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("image.wic");
+        let url = Url::parse(&server.url("/image.wic")).unwrap();
+        let content = b"0123456789";
+        let expected = format!("{:x}", sha2::Sha256::digest(content));
+        let fetch_file = || {
+            runtime::block_on(fetch(
+                || Ok(reqwest::Client::new().get(url.clone())),
+                &url,
+                &file,
+            ))
+        };
+
+        // a partial download is continued by a range request
+        fs::write(&file, &content[..4]).unwrap();
+        fs::write(validator_path(&file), "\"v1\"").unwrap();
+
+        let mut resume = server.mock(|when, then| {
+            when.path("/image.wic")
+                .header("range", "bytes=4-")
+                .header("if-range", "\"v1\"");
+            then.status(206)
+                .header("content-range", "bytes 4-9/10")
+                .body(&content[4..]);
+        });
+
+        assert_eq!(fetch_file().unwrap(), Some(expected.clone()));
+        assert_eq!(fs::read(&file).unwrap(), content);
+        assert!(!validator_path(&file).exists());
+        resume.assert();
+        resume.delete();
+
+        // a server ignoring the range restarts the download
+        fs::write(&file, b"xxxx").unwrap();
+
+        let restart = server.mock(|when, then| {
+            when.path("/image.wic");
+            then.status(200).header("etag", "\"v2\"").body(content);
+        });
+
+        assert_eq!(fetch_file().unwrap(), Some(expected));
+        assert_eq!(fs::read(&file).unwrap(), content);
+        restart.assert();
+    }
+}
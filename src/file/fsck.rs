@@ -0,0 +1,177 @@
+use super::get_file_path;
+use crate::error::ErrorKind;
+use crate::file::functions::{extract_partition, Partition};
+use crate::validators::image::{filesystem, Filesystem};
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use log::debug;
+use serde::Serialize;
+use std::fmt::{self, Display};
+use std::fs::{self, File};
+use std::path::Path;
+use std::process::Command;
+
+/// Inconsistency of the filesystem of a partition as reported by fsck.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FilesystemError {
+    pub partition: String,
+    pub report: String,
+}
+
+impl Display for FilesystemError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.partition, self.report.trim())
+    }
+}
+
+/// Checks `partition_file` without modifying it. Returns the report of fsck
+/// if the filesystem is inconsistent.
+fn check_filesystem(
+    partition_file: &Path,
+    partition_filesystem: Filesystem,
+) -> Result<Option<String>> {
+    let mut fsck = match partition_filesystem {
+        Filesystem::Ext => {
+            let mut e2fsck = Command::new("e2fsck");
+            e2fsck.arg("-f").arg("-n").arg(partition_file);
+            e2fsck
+        }
+        Filesystem::Vfat => {
+            let mut fsck_vfat = Command::new("fsck.vfat");
+            fsck_vfat.arg("-n").arg(partition_file);
+            fsck_vfat
+        }
+    };
+
+    debug!("check_filesystem: {fsck:?}");
+
+    let output = fsck
+        .output()
+        .context(ErrorKind::Environment)
+        .context(format!("check_filesystem: cannot run {fsck:?}"))?;
+
+    // both exit with 0 for a consistent filesystem, e2fsck with 4 and
+    // fsck.vfat with 1 if errors were found
+    match output.status.code() {
+        Some(0) => Ok(None),
+        Some(1 | 4) => Ok(Some(format!(
+            "{}{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        ))),
+        _ => anyhow::bail!(
+            "check_filesystem: cmd failed: {fsck:?}: {}",
+            String::from_utf8_lossy(&output.stderr)
+        ),
+    }
+}
+
+/// Checks the filesystems of all partitions of the uncompressed image
+/// `image_file`, partitions without filesystem, e.g. an empty rootB, are
+/// skipped. Returns the inconsistent filesystems.
+pub fn check_image(image_file: &Path) -> Result<Vec<FilesystemError>> {
+    let mut errors = vec![];
+
+    for partition in Partition::value_variants() {
+        let partition_file = get_file_path(image_file, &format!("fsck-{partition}.img"))?;
+
+        extract_partition(partition, image_file, &partition_file)?;
+
+        let report = match filesystem(&mut File::open(&partition_file)?, 0)? {
+            Some(partition_filesystem) => check_filesystem(&partition_file, partition_filesystem)?,
+            None => None,
+        };
+
+        fs::remove_file(&partition_file)?;
+
+        if let Some(report) = report {
+            errors.push(FilesystemError {
+                partition: partition.to_string(),
+                report,
+            });
+        }
+    }
+
+    Ok(errors)
+}
+
+/// Fails with all inconsistent filesystems of `image_file`.
+pub fn ensure_consistent(image_file: &Path) -> Result<()> {
+    let errors = check_image(image_file)?;
+
+    if errors.is_empty() {
+        return Ok(());
+    }
+
+    Err(anyhow::anyhow!(
+        "filesystem check failed for {}:\n{}",
+        errors
+            .iter()
+            .map(|error| error.partition.as_str())
+            .collect::<Vec<_>>()
+            .join(", "),
+        errors
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join("\n")
+    )
+    .context(ErrorKind::User))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn corrupted_ext_filesystem() {
+        let dir = tempfile::tempdir().unwrap();
+        let partition_file = dir.path().join("factory.img");
+
+        assert!(Command::new("mkfs.ext4")
+            .arg("-F")
+            .arg("-q")
+            .arg(&partition_file)
+            .arg("1024")
+            .status()
+            .unwrap()
+            .success());
+        assert_eq!(
+            check_filesystem(&partition_file, Filesystem::Ext).unwrap(),
+            None
+        );
+
+        // clear the inode bitmap of the first block group
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .open(&partition_file)
+            .unwrap();
+        let (block_size, _) =
+            crate::validators::image::ext_size(&mut File::open(&partition_file).unwrap(), 0)
+                .unwrap();
+        let bitmap = inode_bitmap_block(&partition_file) * block_size;
+        std::os::unix::fs::FileExt::write_all_at(&file, &vec![0u8; block_size as usize], bitmap)
+            .unwrap();
+
+        assert!(check_filesystem(&partition_file, Filesystem::Ext)
+            .unwrap()
+            .is_some());
+    }
+
+    /// Block of the inode bitmap of the first block group as listed by
+    /// dumpe2fs.
+    fn inode_bitmap_block(partition_file: &Path) -> u64 {
+        let output = Command::new("dumpe2fs")
+            .arg(partition_file)
+            .output()
+            .unwrap();
+
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .find_map(|line| line.trim().strip_prefix("Inode bitmap at "))
+            .and_then(|rest| rest.split(|c: char| !c.is_ascii_digit()).next())
+            .unwrap()
+            .parse()
+            .unwrap()
+    }
+}
@@ -1,3 +1,5 @@
+use crate::error::ErrorKind;
+use crate::validators;
 use anyhow::{Context, Result};
 use log::{debug, warn};
 use regex::Regex;
@@ -16,6 +18,7 @@ use uuid::Uuid;
 pub enum Partition {
     boot,
     rootA,
+    rootB,
     cert,
     factory,
 }
@@ -32,6 +35,7 @@ impl Display for Partition {
         match self {
             Partition::boot => write!(f, "boot"),
             Partition::rootA => write!(f, "rootA"),
+            Partition::rootB => write!(f, "rootB"),
             Partition::cert => write!(f, "cert"),
             Partition::factory => write!(f, "factory"),
         }
@@ -45,9 +49,10 @@ impl FromStr for Partition {
         match input {
             "boot" => Ok(Partition::boot),
             "rootA" => Ok(Partition::rootA),
+            "rootB" => Ok(Partition::rootB),
             "cert" => Ok(Partition::cert),
             "factory" => Ok(Partition::factory),
-            _ => anyhow::bail!("unknown partition: use either boot, rootA, cert or factory"),
+            _ => anyhow::bail!("unknown partition: use either boot, rootA, rootB, cert or factory"),
         }
     }
 }
@@ -58,6 +63,8 @@ pub struct FileCopyToParams {
     in_file: std::path::PathBuf,
     partition: Partition,
     out_file: std::path::PathBuf,
+    mode: Option<u32>,
+    owner: Option<(u32, u32)>,
 }
 
 impl FileCopyToParams {
@@ -70,8 +77,33 @@ impl FileCopyToParams {
             in_file: in_file.to_path_buf(),
             partition,
             out_file: out_file.to_path_buf(),
+            mode: None,
+            owner: None,
         }
     }
+
+    /// Sets the permissions of the copied file, e.g. 0o600 for secrets.
+    /// Ignored for the boot partition, since FAT has no permissions.
+    pub fn with_mode(mut self, mode: u32) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// Sets uid and gid of the copied file. Ignored for the boot partition.
+    pub fn with_owner(mut self, uid: u32, gid: u32) -> Self {
+        self.owner = Some((uid, gid));
+        self
+    }
+
+    pub fn in_file(&self) -> &std::path::Path {
+        &self.in_file
+    }
+
+    /// Replaces the file to copy, e.g. by a rendered template.
+    pub fn with_in_file(mut self, in_file: &std::path::Path) -> Self {
+        self.in_file = in_file.to_path_buf();
+        self
+    }
 }
 
 impl FromStr for FileCopyToParams {
@@ -80,21 +112,23 @@ impl FromStr for FileCopyToParams {
     fn from_str(s: &str) -> Result<Self> {
         let err_msg = "format not matched: in-file-path,out-partition:out-file-path";
 
+        // vault references contain a colon themselves
+        let reference = crate::file::secret::is_reference(s);
+
         anyhow::ensure!(
-            s.matches(',').count() == 1 && s.matches(':').count() == 1,
+            s.matches(',').count() == 1 && s.matches(':').count() == 1 + usize::from(reference),
             err_msg
         );
 
-        let v: Vec<&str> = s.split(&[',', ':']).collect();
+        let (in_file, destination) = s.split_once(',').context(err_msg)?;
+        let (partition, out_file) = destination.split_once(':').context(err_msg)?;
 
-        anyhow::ensure!(v.len() == 3, err_msg);
-
-        let in_file = std::path::PathBuf::from(v[0]);
-        let partition = Partition::from_str(v[1])?;
-        let out_file = std::path::PathBuf::from(v[2]);
+        let in_file = std::path::PathBuf::from(in_file);
+        let partition = Partition::from_str(partition)?;
+        let out_file = std::path::PathBuf::from(out_file);
 
         anyhow::ensure!(
-            in_file.try_exists().is_ok_and(|exists| exists),
+            reference || in_file.try_exists().is_ok_and(|exists| exists),
             "in-file-path doesn't exist"
         );
         anyhow::ensure!(
@@ -106,6 +140,8 @@ impl FromStr for FileCopyToParams {
             in_file,
             partition,
             out_file,
+            mode: None,
+            owner: None,
         })
     }
 }
@@ -162,6 +198,7 @@ macro_rules! exec_cmd {
     ($cmd:ident) => {
         anyhow::ensure!(
             $cmd.status()
+                .context(ErrorKind::Environment)
                 .context(format!("{}: status failed: {:?}", function_name!(), $cmd))?
                 .success(),
             format!("{}: cmd failed: {:?}", function_name!(), $cmd)
@@ -188,6 +225,7 @@ macro_rules! exec_cmd_with_output {
     ($cmd:expr) => {{
         let res = $cmd
             .output()
+            .context(ErrorKind::Environment)
             .context(format!("{}: spawn {:?}", function_name!(), $cmd))?;
 
         let output =
@@ -201,6 +239,45 @@ macro_rules! exec_cmd_with_output {
     }};
 }
 
+/// Adds a copy to rootB for each copy to rootA, so that the files survive the
+/// first A/B switch. rootB is skipped with a warning, if it doesn't contain a
+/// filesystem yet, since the first update writes it completely.
+pub fn with_all_slots(
+    file_copy_params: &[FileCopyToParams],
+    image_file: &Path,
+) -> Result<Vec<FileCopyToParams>> {
+    let mut all_slots = file_copy_params.to_vec();
+    let root_b = file_copy_params
+        .iter()
+        .filter(|params| params.partition == Partition::rootA)
+        .map(|params| FileCopyToParams {
+            partition: Partition::rootB,
+            ..params.clone()
+        })
+        .collect::<Vec<_>>();
+
+    if root_b.is_empty() {
+        return Ok(all_slots);
+    }
+
+    let partition_info = get_partition_info(image_file.to_str().unwrap(), &Partition::rootB)?;
+    let start = partition_info
+        .start
+        .parse()
+        .context("with_all_slots: invalid start of rootB")?;
+    let mut image = fs::File::open(image_file).context("with_all_slots: cannot open image")?;
+
+    if validators::image::filesystem(&mut image, start)? != Some(validators::image::Filesystem::Ext)
+    {
+        warn!("rootB contains no filesystem, files are only copied to rootA");
+        return Ok(all_slots);
+    }
+
+    all_slots.extend(root_b);
+
+    Ok(all_slots)
+}
+
 pub fn copy_to_image(file_copy_params: &[FileCopyToParams], image_file: &Path) -> Result<()> {
     // we use the folder the image is located in
     // the caller is responsible to create a /tmp/ directory if needed
@@ -209,11 +286,11 @@ pub fn copy_to_image(file_copy_params: &[FileCopyToParams], image_file: &Path) -
         .context("copy_to_image: cannot get directory of image")?
         .to_path_buf();
     let image_file = image_file.to_str().unwrap();
-    let mut partition_map: HashMap<&Partition, Vec<(&PathBuf, &PathBuf)>> = HashMap::new();
+    let mut partition_map: HashMap<&Partition, Vec<&FileCopyToParams>> = HashMap::new();
 
     // create map with partition as key
     for params in file_copy_params.iter() {
-        let e = (&params.in_file, &params.out_file);
+        let e = params;
         partition_map
             .entry(&params.partition)
             .and_modify(|v| v.push(e))
@@ -232,7 +309,9 @@ pub fn copy_to_image(file_copy_params: &[FileCopyToParams], image_file: &Path) -
         read_partition(image_file, partition_file, &partition_info)?;
 
         // 3. copy files
-        for (in_file, out_file) in partition_map.get(partition).unwrap().iter() {
+        for params in partition_map.get(partition).unwrap().iter() {
+            let in_file = &params.in_file;
+            let out_file = &params.out_file;
             let dir_path = out_file.parent().context(format!(
                 "copy_to_image: invalid destination path {}",
                 out_file.to_str().unwrap()
@@ -270,6 +349,15 @@ pub fn copy_to_image(file_copy_params: &[FileCopyToParams], image_file: &Path) -
                 exec_cmd!(e2mkdir);
 
                 let mut e2cp = Command::new("e2cp");
+                if let Some(mode) = params.mode {
+                    e2cp.arg("-P").arg(format!("{mode:o}"));
+                }
+                if let Some((uid, gid)) = params.owner {
+                    e2cp.arg("-O")
+                        .arg(uid.to_string())
+                        .arg("-G")
+                        .arg(gid.to_string());
+                }
                 e2cp.arg(in_file)
                     .arg(format!("{partition_file}:{out_file}"));
                 exec_cmd!(e2cp);
@@ -283,6 +371,10 @@ pub fn copy_to_image(file_copy_params: &[FileCopyToParams], image_file: &Path) -
     Ok(())
 }
 
+/// Copies files out of the image. Paths inside the image have to be absolute
+/// without `..` components and output files are only created within their
+/// existing output dir, never following symbolic links, since images may come
+/// from third parties.
 pub fn copy_from_image(file_copy_params: &[FileCopyFromParams], image_file: &Path) -> Result<()> {
     // we use the folder the image is located in
     // the caller is responsible to create a /tmp/ directory if needed
@@ -293,6 +385,13 @@ pub fn copy_from_image(file_copy_params: &[FileCopyFromParams], image_file: &Pat
     let image_file = image_file.to_str().unwrap();
 
     for param in file_copy_params.iter() {
+        validators::path::validate_image_path(&param.in_file)
+            .context(ErrorKind::User)
+            .context("copy_from_image: invalid input file")?;
+        validators::path::validate_output_file(&param.out_file)
+            .context(ErrorKind::User)
+            .context("copy_from_image: invalid output file")?;
+
         let mut partition_file = working_dir.clone();
 
         let partition_info = get_partition_info(image_file, &param.partition)?;
@@ -303,26 +402,17 @@ pub fn copy_from_image(file_copy_params: &[FileCopyFromParams], image_file: &Pat
 
         read_partition(image_file, partition_file, &partition_info)?;
 
-        anyhow::ensure!(
-            param
-                .out_file
-                .parent()
-                .unwrap()
-                .try_exists()
-                .is_ok_and(|exists| exists),
-            "copy_from_image: output dir does not exist."
-        );
+        // the tools write to a temp file in the working dir, which is copied
+        // to the destination afterwards:
+        // - mcopy deadlocks when target file is not residing in workingdir
+        // - e2cp would follow a symbolic link at the destination
+        let tmp_out_file = working_dir.join(format!(
+            "{}-{}",
+            Uuid::new_v4(),
+            param.out_file.file_name().unwrap().to_str().unwrap()
+        ));
 
-        // copy
         if param.partition == Partition::boot {
-            let mut tmp_out_file = working_dir.clone();
-            // mcopy deadlocks when target file is not residing in workingdir so we copy to a temp file
-            tmp_out_file.push(format!(
-                "{}-{}",
-                Uuid::new_v4(),
-                param.out_file.file_name().unwrap().to_str().unwrap()
-            ));
-
             let mut mcopy = Command::new("mcopy");
             mcopy
                 .arg("-o")
@@ -331,36 +421,110 @@ pub fn copy_from_image(file_copy_params: &[FileCopyFromParams], image_file: &Pat
                 .arg(format!("::{in_file}"))
                 .arg(&tmp_out_file);
             exec_cmd!(mcopy);
-            // instead of rename we copy and delete to prevent "Invalid cross-device link" errors
-            let bytes_copied = fs::copy(&tmp_out_file, &param.out_file).context(format!(
-                "copy_from_image: couldn't copy temp file {} to destination {}",
-                tmp_out_file.to_str().unwrap(),
-                param.out_file.to_str().unwrap()
-            ))?;
-            anyhow::ensure!(
-                tmp_out_file.metadata().unwrap().len() == bytes_copied,
-                "copy_from_image: copy temp file failed"
-            );
-            fs::remove_file(&tmp_out_file).context(format!(
-                "copy_from_image: couldn't delete temp file {}",
-                tmp_out_file.to_str().unwrap()
-            ))?;
         } else {
             let mut e2cp = Command::new("e2cp");
             e2cp.arg(format!("{partition_file}:{in_file}"))
-                .arg(param.out_file.to_str().unwrap());
+                .arg(&tmp_out_file);
             exec_cmd!(e2cp);
             // since e2cp doesn't return errors in any case we check if output file exists
             anyhow::ensure!(
-                param.out_file.try_exists().is_ok_and(|exists| exists),
+                tmp_out_file.try_exists().is_ok_and(|exists| exists),
                 format!("copy_from_image: cmd failed: {:?}", e2cp)
             )
         }
+
+        // instead of rename we copy and delete to prevent "Invalid cross-device link" errors
+        let mut out_file = validators::path::create_output_file(&param.out_file)
+            .context(ErrorKind::User)
+            .context("copy_from_image: cannot create output file")?;
+        let bytes_copied = std::io::copy(
+            &mut fs::File::open(&tmp_out_file).context(format!(
+                "copy_from_image: couldn't open temp file {}",
+                tmp_out_file.to_str().unwrap()
+            ))?,
+            &mut out_file,
+        )
+        .context(format!(
+            "copy_from_image: couldn't copy temp file {} to destination {}",
+            tmp_out_file.to_str().unwrap(),
+            param.out_file.to_str().unwrap()
+        ))?;
+        anyhow::ensure!(
+            tmp_out_file.metadata().unwrap().len() == bytes_copied,
+            "copy_from_image: copy temp file failed"
+        );
+        fs::remove_file(&tmp_out_file).context(format!(
+            "copy_from_image: couldn't delete temp file {}",
+            tmp_out_file.to_str().unwrap()
+        ))?;
     }
 
     Ok(())
 }
 
+/// Creates a symbolic link `link` pointing to `target` in an ext4 partition,
+/// e.g. in order to enable a systemd unit.
+pub fn create_symlink(
+    partition: Partition,
+    link: &Path,
+    target: &Path,
+    image_file: &Path,
+) -> Result<()> {
+    anyhow::ensure!(
+        partition != Partition::boot,
+        "create_symlink: boot partition doesn't support symbolic links"
+    );
+
+    let working_dir = image_file
+        .parent()
+        .context("create_symlink: cannot get directory of image")?
+        .to_path_buf();
+    let image_file = image_file.to_str().unwrap();
+    let partition_info = get_partition_info(image_file, &partition)?;
+    let partition_file = working_dir.join(format!("{}.img", partition_info.num));
+    let partition_file = partition_file.to_str().unwrap();
+    let link = link.to_str().context("create_symlink: invalid link path")?;
+    let dir_path = Path::new(link)
+        .parent()
+        .context(format!("create_symlink: invalid link path {link}"))?;
+
+    read_partition(image_file, partition_file, &partition_info)?;
+
+    let mut e2mkdir = Command::new("e2mkdir");
+    e2mkdir.arg(format!("{partition_file}:{}", dir_path.to_str().unwrap()));
+    exec_cmd!(e2mkdir);
+
+    // replace an existing link, debugfs only prints an error if there is none
+    let mut rm = Command::new("debugfs");
+    rm.arg("-w")
+        .arg("-R")
+        .arg(format!("rm {link}"))
+        .arg(partition_file);
+    try_exec_cmd!(rm);
+
+    let mut debugfs = Command::new("debugfs");
+    debugfs
+        .arg("-w")
+        .arg("-R")
+        .arg(format!("symlink {link} {}", target.to_str().unwrap()))
+        .arg(partition_file);
+    exec_cmd!(debugfs);
+
+    // debugfs doesn't return errors of its commands, so check the result
+    let mut stat = Command::new("debugfs");
+    stat.arg("-R")
+        .arg(format!("stat {link}"))
+        .arg(partition_file);
+    let output = exec_cmd_with_output!(stat);
+
+    anyhow::ensure!(
+        output.contains("Type: symlink"),
+        "create_symlink: cannot create {link}"
+    );
+
+    write_partition(image_file, partition_file, &partition_info)
+}
+
 pub fn read_file_from_image(
     path: impl AsRef<Path>,
     partition: Partition,
@@ -380,6 +544,62 @@ pub fn read_file_from_image(
     Ok(content)
 }
 
+/// Extracts the raw filesystem of `partition` to `out_file`, e.g. in order
+/// to package it as update artifact.
+pub fn extract_partition(partition: &Partition, image_file: &Path, out_file: &Path) -> Result<()> {
+    let image_file = image_file
+        .to_str()
+        .context("extract_partition: invalid image path")?;
+    let partition_info = get_partition_info(image_file, partition)?;
+    let start: u64 = partition_info.start.parse()?;
+    let end: u64 = partition_info.end.parse()?;
+
+    let mut dd = Command::new("dd");
+    dd.arg(format!("if={image_file}"))
+        .arg(format!(
+            "of={}",
+            out_file
+                .to_str()
+                .context("extract_partition: invalid output path")?
+        ))
+        .arg("bs=512")
+        .arg(format!("skip={start}"))
+        .arg(format!("count={}", end - start + 1))
+        .arg("conv=sparse")
+        .arg("status=none");
+    exec_cmd!(dd);
+
+    Ok(())
+}
+
+/// Writes the raw filesystem `in_file`, e.g. extracted by
+/// [`extract_partition`] and modified, back to `partition`.
+pub fn write_back_partition(
+    partition: &Partition,
+    image_file: &Path,
+    in_file: &Path,
+) -> Result<()> {
+    let image_file = image_file
+        .to_str()
+        .context("write_back_partition: invalid image path")?;
+    let in_file = in_file
+        .to_str()
+        .context("write_back_partition: invalid input path")?;
+    let partition_info = get_partition_info(image_file, partition)?;
+    let start: u64 = partition_info.start.parse()?;
+    let end: u64 = partition_info.end.parse()?;
+    let size = std::fs::metadata(in_file)
+        .context("write_back_partition: cannot get size of input")?
+        .len();
+
+    anyhow::ensure!(
+        size <= (end - start + 1) * 512,
+        "write_back_partition: {in_file} exceeds partition {partition}"
+    );
+
+    write_partition(image_file, in_file, &partition_info)
+}
+
 fn get_partition_info(image_file: &str, partition: &Partition) -> Result<PartitionInfo> {
     let mut fdisk = Command::new("fdisk");
     fdisk
@@ -392,6 +612,7 @@ fn get_partition_info(image_file: &str, partition: &Partition) -> Result<Partiti
     let partition_num = match partition {
         Partition::boot => 1,
         Partition::rootA => 2,
+        Partition::rootB => 3,
         p @ (Partition::factory | Partition::cert) => {
             let re = Regex::new(r"Disklabel type: (\D{3})").unwrap();
 
@@ -492,13 +713,8 @@ fn write_partition(
 }
 
 pub fn generate_bmap_file(image_file: &str) -> Result<()> {
-    let mut bmaptool = Command::new("bmaptool");
-    bmaptool
-        .arg("create")
-        .arg("-o")
-        .arg(format!("{image_file}.bmap"))
-        .arg(image_file);
-    exec_cmd!(bmaptool);
-
-    Ok(())
+    crate::bmap::write(
+        Path::new(image_file),
+        Path::new(&format!("{image_file}.bmap")),
+    )
 }
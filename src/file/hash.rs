@@ -0,0 +1,134 @@
+use super::get_file_path;
+use crate::file::functions::{copy_from_image, FileCopyFromParams, Partition};
+use anyhow::{Context, Result};
+use sha2::Digest;
+use std::fmt::{self, Display};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+#[clap(rename_all = "verbatim")]
+#[allow(non_camel_case_types)]
+pub enum HashAlgorithm {
+    sha256,
+    sha384,
+    sha512,
+}
+
+impl Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+impl HashAlgorithm {
+    /// Hex encoded digest of `file`.
+    pub fn digest(&self, file: &Path) -> Result<String> {
+        let mut file =
+            File::open(file).context(format!("digest: cannot open {}", file.to_string_lossy()))?;
+
+        fn hash<D: Digest + std::io::Write>(mut hasher: D, file: &mut File) -> Result<String> {
+            std::io::copy(file, &mut hasher)?;
+            Ok(format!("{:x}", hasher.finalize()))
+        }
+
+        match self {
+            HashAlgorithm::sha256 => hash(sha2::Sha256::new(), &mut file),
+            HashAlgorithm::sha384 => hash(sha2::Sha384::new(), &mut file),
+            HashAlgorithm::sha512 => hash(sha2::Sha512::new(), &mut file),
+        }
+    }
+}
+
+/// File in a partition of the image, given as `<partition>:<path>`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImageFile {
+    pub partition: Partition,
+    pub path: PathBuf,
+}
+
+impl FromStr for ImageFile {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (partition, path) = s
+            .split_once(':')
+            .context("format not matched: partition:file-path")?;
+        let path = PathBuf::from(path);
+
+        anyhow::ensure!(path.is_absolute(), "file-path isn't an absolute path");
+
+        Ok(ImageFile {
+            partition: Partition::from_str(partition)?,
+            path,
+        })
+    }
+}
+
+impl Display for ImageFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.partition, self.path.to_string_lossy())
+    }
+}
+
+/// Digests of `files` of the uncompressed image `image_file` in the same
+/// order. The files are copied next to the image in order to hash them.
+pub fn hash_files(
+    files: &[ImageFile],
+    algorithm: HashAlgorithm,
+    image_file: &Path,
+) -> Result<Vec<String>> {
+    let out_files = (0..files.len())
+        .map(|i| get_file_path(image_file, &format!("hash-{i}")))
+        .collect::<Result<Vec<_>>>()?;
+    let params = files
+        .iter()
+        .zip(&out_files)
+        .map(|(file, out_file)| {
+            FileCopyFromParams::new(&file.path, file.partition.clone(), out_file)
+        })
+        .collect::<Vec<_>>();
+
+    copy_from_image(&params, image_file)?;
+
+    out_files
+        .iter()
+        .map(|out_file| {
+            let digest = algorithm.digest(out_file)?;
+            fs::remove_file(out_file)?;
+            Ok(digest)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_file_from_str() {
+        let file = ImageFile::from_str("factory:/etc/aziot/config.toml").unwrap();
+
+        assert_eq!(file.partition, Partition::factory);
+        assert_eq!(file.path, PathBuf::from("/etc/aziot/config.toml"));
+        assert_eq!(file.to_string(), "factory:/etc/aziot/config.toml");
+        assert!(ImageFile::from_str("factory:etc/hostname").is_err());
+        assert!(ImageFile::from_str("/etc/hostname").is_err());
+    }
+
+    #[test]
+    fn digests_of_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+
+        fs::write(&file, "").unwrap();
+
+        assert_eq!(
+            HashAlgorithm::sha256.digest(&file).unwrap(),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(HashAlgorithm::sha384.digest(&file).unwrap().len(), 96);
+        assert_eq!(HashAlgorithm::sha512.digest(&file).unwrap().len(), 128);
+    }
+}
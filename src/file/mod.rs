@@ -1,14 +1,35 @@
+pub mod boot;
+pub mod cache;
 pub mod compression;
+pub mod download;
+pub mod fsck;
 pub mod functions;
+pub mod hash;
+pub mod mount;
+pub mod network;
+pub mod object_storage;
+pub mod qcow2;
+pub mod resize;
+pub mod reuuid;
+pub mod secret;
+pub mod secureboot;
+pub mod sparse;
+pub mod system;
+pub mod template;
+pub mod user;
+pub mod verity;
+pub mod vhd;
 use super::validators::{
     device_update,
-    identity::{validate_identity, IdentityConfig, IdentityType},
+    identity::{validate_hostname, validate_identity, IdentityConfig, IdentityType},
     ssh::validate_ssh_pub_key,
 };
 use crate::file::functions::{FileCopyFromParams, FileCopyToParams, Partition};
+use crate::iot_hub::ConnectionString;
 use anyhow::{Context, Result};
 use log::warn;
 use regex::Regex;
+use serde::Serialize;
 use std::fs;
 use std::path::{Path, PathBuf};
 
@@ -115,6 +136,52 @@ pub fn set_identity_config(
     copy_to_image(&file_copies, image_file)
 }
 
+#[derive(Serialize)]
+struct ManualProvisioning<'a> {
+    source: &'a str,
+    connection_string: String,
+}
+
+#[derive(Serialize)]
+struct ConnectionStringConfig<'a> {
+    hostname: &'a str,
+    provisioning: ManualProvisioning<'a>,
+}
+
+/// config.toml provisioning the device of `connection_string` manually.
+fn connection_string_config(
+    connection_string: &ConnectionString,
+    hostname: &str,
+) -> Result<String> {
+    anyhow::ensure!(
+        connection_string.shared_access_key.is_some(),
+        "connection string of {} has no SharedAccessKey",
+        connection_string.device_id
+    );
+
+    Ok(toml::to_string(&ConnectionStringConfig {
+        hostname,
+        provisioning: ManualProvisioning {
+            source: "manual",
+            connection_string: connection_string.to_string(),
+        },
+    })?)
+}
+
+/// Injects a config.toml provisioning the device of `connection_string`
+/// manually. The config is kept in memory, since it contains the shared
+/// access key.
+pub fn set_connection_string(
+    connection_string: &ConnectionString,
+    hostname: &str,
+    image_file: &Path,
+) -> Result<()> {
+    let config =
+        secret::in_memory(connection_string_config(connection_string, hostname)?.as_bytes())?;
+
+    set_identity_config(config.path(), image_file, None)
+}
+
 pub fn set_device_cert(
     intermediate_full_chain_cert_path: Option<&Path>,
     device_cert_path: &Path,
@@ -165,13 +232,53 @@ pub fn copy_from_image(file_copy_params: &[FileCopyFromParams], image_file: &Pat
     functions::copy_from_image(file_copy_params, image_file)
 }
 
+pub fn set_hostname(
+    hostname: &str,
+    machine_id_salt: Option<&str>,
+    image_file: &Path,
+) -> Result<()> {
+    validate_hostname(hostname)?;
+
+    let mut file_copies = hostname_files(hostname, image_file)?;
+
+    if let Some(salt) = machine_id_salt {
+        let machine_id_file = get_file_path(image_file, "machine-id")?;
+
+        fs::write(
+            &machine_id_file,
+            format!("{}\n", machine_id(salt, hostname)),
+        )
+        .context("set_hostname: cannot write to machine-id file")?;
+
+        file_copies.push(
+            FileCopyToParams::new(
+                &machine_id_file,
+                Partition::factory,
+                Path::new("/etc/machine-id"),
+            )
+            .with_mode(0o444),
+        );
+    }
+
+    copy_to_image(&file_copies, image_file)
+}
+
+/// Derives a reproducible machine-id from `salt` and `hostname`, so that
+/// devices provisioned with the same salt get distinct but predictable ids.
+fn machine_id(salt: &str, hostname: &str) -> String {
+    use sha2::Digest;
+
+    sha2::Sha256::digest(format!("{salt}:{hostname}"))
+        .iter()
+        .take(16)
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
 fn configure_hostname(
     identity_config_file: &Path,
     image_file: &Path,
 ) -> Result<Vec<FileCopyToParams>> {
-    let hostname_file = get_file_path(image_file, "hostname")?;
-    let hosts_file = get_file_path(image_file, "hosts")?;
-
     // get hostname from identity_config_file
     let identity: IdentityConfig = serde_path_to_error::deserialize(toml::Deserializer::new(
         fs::read_to_string(identity_config_file.to_str().unwrap())
@@ -180,7 +287,15 @@ fn configure_hostname(
     ))
     .context("configure_hostname: couldn't read identity")?;
 
-    fs::write(&hostname_file, &identity.hostname)
+    hostname_files(&identity.hostname, image_file)
+}
+
+/// Creates /etc/hostname and patches /etc/hosts of the image for `hostname`.
+fn hostname_files(hostname: &str, image_file: &Path) -> Result<Vec<FileCopyToParams>> {
+    let hostname_file = get_file_path(image_file, "hostname")?;
+    let hosts_file = get_file_path(image_file, "hosts")?;
+
+    fs::write(&hostname_file, hostname)
         .context("configure_hostname: cannot write to hostname file")?;
 
     // read /etc/hosts from rootA
@@ -201,7 +316,7 @@ fn configure_hostname(
     let reg =
         Regex::new(r"(127\.0\.1\.1.*)").context("configure_hostname: create hostname regex")?;
 
-    let content = reg.replace_all(content.as_str(), format!("127.0.1.1 {}", identity.hostname));
+    let content = reg.replace_all(content.as_str(), format!("127.0.1.1 {hostname}"));
 
     fs::write(&hosts_file, content.to_string())
         .context("configure_hostname: cannot write to hosts file")?;
@@ -228,3 +343,48 @@ pub(crate) fn get_file_path(image_path: &Path, file_name: &str) -> Result<PathBu
     file_path.push(file_name);
     Ok(file_path)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn machine_id_is_reproducible() {
+        let id = machine_id("customer-a", "device-1");
+
+        assert_eq!(id.len(), 32);
+        assert!(id.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(id, machine_id("customer-a", "device-1"));
+        assert_ne!(id, machine_id("customer-b", "device-1"));
+        assert_ne!(id, machine_id("customer-a", "device-2"));
+    }
+
+    #[test]
+    fn connection_string_config_ok() {
+        let connection_string: ConnectionString =
+            "HostName=my-hub.azure-devices.net;DeviceId=my-device;SharedAccessKey=a2V5Cg=="
+                .parse()
+                .unwrap();
+        let config = connection_string_config(&connection_string, "my-device").unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let config_file = dir.path().join("config.toml");
+
+        fs::write(&config_file, &config).unwrap();
+
+        assert!(
+            validate_identity(IdentityType::Standalone, &config_file, &None)
+                .unwrap()
+                .is_empty()
+        );
+        assert!(config.contains(
+            r#"connection_string = "HostName=my-hub.azure-devices.net;DeviceId=my-device;SharedAccessKey=a2V5Cg==""#
+        ));
+
+        let connection_string: ConnectionString =
+            "HostName=my-hub.azure-devices.net;DeviceId=my-device;x509=true"
+                .parse()
+                .unwrap();
+
+        assert!(connection_string_config(&connection_string, "my-device").is_err());
+    }
+}
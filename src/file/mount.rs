@@ -0,0 +1,209 @@
+use super::get_file_path;
+use crate::error::ErrorKind;
+use crate::file::functions::{extract_partition, write_back_partition, Partition};
+use crate::runtime;
+use crate::validators::image::{filesystem, Filesystem};
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use std::fs::{self, File};
+use std::io::BufRead;
+use std::path::Path;
+use std::process::{Child, Command};
+use std::sync::mpsc;
+use std::time::Duration;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// FUSE driver serving the filesystem of `partition_file` at `mountpoint` in
+/// the foreground.
+fn fuse_command(
+    partition_file: &Path,
+    mountpoint: &Path,
+    filesystem: Filesystem,
+    rw: bool,
+) -> Command {
+    match filesystem {
+        Filesystem::Ext => {
+            let mut fuse2fs = Command::new("fuse2fs");
+            fuse2fs
+                .arg("-f")
+                .arg("-o")
+                // files of root in the image are accessible by the user
+                .arg(if rw { "fakeroot" } else { "ro,fakeroot" })
+                .arg(partition_file)
+                .arg(mountpoint);
+            fuse2fs
+        }
+        Filesystem::Vfat => {
+            let mut fusefat = Command::new("fusefat");
+            fusefat.arg("-f");
+
+            if rw {
+                fusefat.arg("-o").arg("rw+");
+            }
+
+            fusefat.arg(partition_file).arg(mountpoint);
+            fusefat
+        }
+    }
+}
+
+fn unmount(mountpoint: &Path) -> Result<()> {
+    for fusermount in ["fusermount", "fusermount3"] {
+        let mut command = Command::new(fusermount);
+        command.arg("-u").arg(mountpoint);
+
+        debug!("unmount: {command:?}");
+
+        match command.status() {
+            Ok(status) if status.success() => return Ok(()),
+            Ok(_) => anyhow::bail!("unmount: cmd failed: {command:?}"),
+            Err(e) => debug!("unmount: cannot run {fusermount}: {e}"),
+        }
+    }
+
+    Err(
+        anyhow::anyhow!("unmount: neither fusermount nor fusermount3 found")
+            .context(ErrorKind::Environment),
+    )
+}
+
+/// Waits until the user presses Enter, the filesystem is unmounted
+/// externally, e.g. by `fusermount -u`, or the command is cancelled.
+fn wait_for_unmount(driver: &mut Child, mountpoint: &Path) -> Result<()> {
+    let (enter, entered) = mpsc::channel();
+
+    std::thread::spawn(move || {
+        // a closed stdin, e.g. in scripts, waits for an external unmount
+        if let Some(Ok(_)) = std::io::stdin().lock().lines().next() {
+            let _ = enter.send(());
+        }
+    });
+
+    loop {
+        if let Some(status) = driver.try_wait()? {
+            anyhow::ensure!(status.success(), "mount: fuse driver failed: {status}");
+            return Ok(());
+        }
+
+        if entered.try_recv().is_ok() || runtime::is_cancelled() {
+            unmount(mountpoint)?;
+            driver.wait()?;
+
+            // changes are discarded on cancellation
+            return runtime::check_cancelled();
+        }
+
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Mounts the filesystem of `partition` of the uncompressed image
+/// `image_file` at `mountpoint` by FUSE until the user unmounts it. The
+/// partition is served from a copy, which is written back to the image if
+/// mounted `rw`.
+pub fn mount(image_file: &Path, partition: &Partition, mountpoint: &Path, rw: bool) -> Result<()> {
+    let is_empty_dir = fs::read_dir(mountpoint)
+        .map(|mut entries| entries.next().is_none())
+        .unwrap_or(false);
+
+    if !is_empty_dir {
+        return Err(anyhow::anyhow!(
+            "mount: {} is no empty directory",
+            mountpoint.to_string_lossy()
+        )
+        .context(ErrorKind::User));
+    }
+
+    let partition_file = get_file_path(image_file, &format!("mount-{partition}.img"))?;
+
+    extract_partition(partition, image_file, &partition_file)?;
+
+    let partition_filesystem = filesystem(&mut File::open(&partition_file)?, 0)?
+        .context(ErrorKind::User)
+        .context(format!(
+            "mount: {partition} contains no ext or vfat filesystem"
+        ))?;
+    let mut command = fuse_command(&partition_file, mountpoint, partition_filesystem, rw);
+
+    debug!("mount: {command:?}");
+
+    let mut driver = command
+        .spawn()
+        .context(ErrorKind::Environment)
+        .context(format!("mount: cannot run {command:?}"))?;
+
+    eprintln!(
+        "Mounted {partition} {} at {}, press Enter to unmount",
+        if rw { "read-write" } else { "read-only" },
+        mountpoint.to_string_lossy()
+    );
+
+    if let Err(e) = wait_for_unmount(&mut driver, mountpoint) {
+        // don't leave a stale mount behind
+        if driver.try_wait()?.is_none() {
+            warn!(
+                "mount: unmounting {} after error",
+                mountpoint.to_string_lossy()
+            );
+            let _ = unmount(mountpoint);
+            let _ = driver.wait();
+        }
+
+        return Err(e);
+    }
+
+    if rw {
+        write_back_partition(partition, image_file, &partition_file)?;
+    }
+
+    fs::remove_file(&partition_file)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fuse_driver_by_filesystem() {
+        let args = |command: &Command| {
+            command
+                .get_args()
+                .map(|arg| arg.to_string_lossy().to_string())
+                .collect::<Vec<_>>()
+        };
+        let partition_file = Path::new("mount-factory.img");
+        let mountpoint = Path::new("/mnt");
+
+        let command = fuse_command(partition_file, mountpoint, Filesystem::Ext, false);
+        assert_eq!(command.get_program(), "fuse2fs");
+        assert_eq!(
+            args(&command),
+            ["-f", "-o", "ro,fakeroot", "mount-factory.img", "/mnt"]
+        );
+
+        let command = fuse_command(partition_file, mountpoint, Filesystem::Vfat, true);
+        assert_eq!(command.get_program(), "fusefat");
+        assert_eq!(
+            args(&command),
+            ["-f", "-o", "rw+", "mount-factory.img", "/mnt"]
+        );
+    }
+
+    #[test]
+    fn mountpoint_must_be_empty() {
+        let dir = tempfile::tempdir().unwrap();
+
+        fs::write(dir.path().join("file"), "").unwrap();
+
+        assert!(mount(
+            Path::new("image.wic"),
+            &Partition::factory,
+            dir.path(),
+            false
+        )
+        .is_err());
+    }
+}
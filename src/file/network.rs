@@ -0,0 +1,374 @@
+use super::{copy_to_image, get_file_path};
+use crate::file::functions::{create_symlink, FileCopyToParams, Partition};
+use anyhow::{Context, Result};
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const WPA_SUPPLICANT_DIR: &str = "/etc/wpa_supplicant";
+const NETWORK_MANAGER_DIR: &str = "/etc/NetworkManager/system-connections";
+const NETWORKD_DIR: &str = "/etc/systemd/network";
+const WIREGUARD_DIR: &str = "/etc/wireguard";
+const SYSTEMD_UNIT_DIR: &str = "/lib/systemd/system";
+const MULTI_USER_WANTS_DIR: &str = "/etc/systemd/system/multi-user.target.wants";
+const SECRET_MODE: u32 = 0o600;
+
+fn validate_ssid(ssid: &str) -> Result<()> {
+    anyhow::ensure!(
+        (1..=32).contains(&ssid.len()),
+        "ssid must have 1 to 32 bytes"
+    );
+    anyhow::ensure!(
+        !ssid.chars().any(char::is_control),
+        "ssid must not contain control characters"
+    );
+
+    Ok(())
+}
+
+/// Returns the psk as expected by wpa_supplicant: either a quoted passphrase
+/// or a raw 256-bit key in hex.
+fn wpa_psk(psk: &str) -> Result<String> {
+    if psk.len() == 64 && psk.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Ok(psk.to_string());
+    }
+
+    anyhow::ensure!(
+        (8..=63).contains(&psk.len()) && psk.chars().all(|c| c.is_ascii() && !c.is_control()),
+        "psk must be a passphrase of 8 to 63 printable ascii characters or 64 hex digits"
+    );
+
+    Ok(format!("\"{psk}\""))
+}
+
+fn wpa_supplicant_config(ssid: &str, psk: &str) -> Result<String> {
+    validate_ssid(ssid)?;
+
+    Ok(format!(
+        "ctrl_interface=/var/run/wpa_supplicant\n\
+         update_config=1\n\
+         \n\
+         network={{\n\
+         \tssid=\"{ssid}\"\n\
+         \tpsk={}\n\
+         }}\n",
+        wpa_psk(psk)?
+    ))
+}
+
+/// Destination of a wifi profile in the factory partition, which depends on
+/// whether it is a NetworkManager profile or a wpa_supplicant configuration.
+fn wifi_profile_destination(profile: &Path, interface: &str) -> Result<PathBuf> {
+    let content = fs::read_to_string(profile).context(format!(
+        "wifi_profile_destination: cannot read {}",
+        profile.to_string_lossy()
+    ))?;
+
+    if profile.extension().is_some_and(|e| e == "nmconnection") {
+        anyhow::ensure!(
+            content.lines().any(|l| l.trim() == "[connection]"),
+            "NetworkManager profile misses [connection] section"
+        );
+
+        return Ok(Path::new(NETWORK_MANAGER_DIR).join(
+            profile
+                .file_name()
+                .context("wifi_profile_destination: cannot get profile file name")?,
+        ));
+    }
+
+    anyhow::ensure!(
+        content
+            .lines()
+            .any(|l| l.trim().replace(' ', "").starts_with("network={")),
+        "wpa_supplicant configuration misses network block"
+    );
+
+    Ok(wpa_supplicant_destination(interface))
+}
+
+fn wpa_supplicant_destination(interface: &str) -> PathBuf {
+    Path::new(WPA_SUPPLICANT_DIR).join(format!("wpa_supplicant-{interface}.conf"))
+}
+
+pub fn set_wifi_credentials(
+    ssid: &str,
+    psk: &str,
+    interface: &str,
+    image_file: &Path,
+) -> Result<()> {
+    let config_file = get_file_path(image_file, "wpa_supplicant.conf")?;
+
+    fs::write(&config_file, wpa_supplicant_config(ssid, psk)?)
+        .context("set_wifi_credentials: cannot write wpa_supplicant configuration")?;
+
+    copy_to_image(
+        &[FileCopyToParams::new(
+            &config_file,
+            Partition::factory,
+            &wpa_supplicant_destination(interface),
+        )
+        .with_mode(SECRET_MODE)],
+        image_file,
+    )
+}
+
+pub fn set_wifi_profile(profile: &Path, interface: &str, image_file: &Path) -> Result<()> {
+    let destination = wifi_profile_destination(profile, interface)?;
+
+    copy_to_image(
+        &[FileCopyToParams::new(profile, Partition::factory, &destination).with_mode(SECRET_MODE)],
+        image_file,
+    )
+}
+
+fn validate_interface(interface: &str) -> Result<()> {
+    anyhow::ensure!(
+        (1..=15).contains(&interface.len())
+            && interface
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c)),
+        "invalid interface name {interface:?}"
+    );
+
+    Ok(())
+}
+
+/// Parses an address in CIDR notation, e.g. 192.168.0.10/24.
+fn parse_address(address: &str) -> Result<(IpAddr, u8)> {
+    let (ip, prefix) = address
+        .split_once('/')
+        .context(format!("address {address} misses prefix length, e.g. /24"))?;
+    let ip = IpAddr::from_str(ip).context(format!("invalid ip address {ip}"))?;
+    let prefix = u8::from_str(prefix).context(format!("invalid prefix length {prefix}"))?;
+    let max_prefix = if ip.is_ipv4() { 32 } else { 128 };
+
+    anyhow::ensure!(
+        prefix <= max_prefix,
+        "prefix length of {address} exceeds {max_prefix}"
+    );
+
+    Ok((ip, prefix))
+}
+
+fn systemd_networkd_config(
+    interface: &str,
+    addresses: &[String],
+    gateway: Option<IpAddr>,
+    dns: &[IpAddr],
+) -> Result<String> {
+    validate_interface(interface)?;
+
+    anyhow::ensure!(!addresses.is_empty(), "at least one address is required");
+
+    let addresses = addresses
+        .iter()
+        .map(|address| parse_address(address))
+        .collect::<Result<Vec<_>>>()?;
+
+    if let Some(gateway) = gateway {
+        anyhow::ensure!(
+            addresses
+                .iter()
+                .any(|(ip, _)| ip.is_ipv4() == gateway.is_ipv4()),
+            "gateway {gateway} has no address of the same ip version"
+        );
+    }
+
+    let mut config = format!("[Match]\nName={interface}\n\n[Network]\n");
+
+    for (ip, prefix) in addresses {
+        config.push_str(&format!("Address={ip}/{prefix}\n"));
+    }
+
+    if let Some(gateway) = gateway {
+        config.push_str(&format!("Gateway={gateway}\n"));
+    }
+
+    for dns in dns {
+        config.push_str(&format!("DNS={dns}\n"));
+    }
+
+    Ok(config)
+}
+
+/// Configures static addresses for `interface` by a systemd-networkd
+/// configuration, which takes precedence over the DHCP configuration of the
+/// image due to its lower number.
+pub fn set_static_network(
+    interface: &str,
+    addresses: &[String],
+    gateway: Option<IpAddr>,
+    dns: &[IpAddr],
+    image_file: &Path,
+) -> Result<()> {
+    let config_file = get_file_path(image_file, "static.network")?;
+
+    fs::write(
+        &config_file,
+        systemd_networkd_config(interface, addresses, gateway, dns)?,
+    )
+    .context("set_static_network: cannot write systemd-networkd configuration")?;
+
+    copy_to_image(
+        &[FileCopyToParams::new(
+            &config_file,
+            Partition::factory,
+            &Path::new(NETWORKD_DIR).join(format!("10-{interface}.network")),
+        )
+        .with_mode(0o644)],
+        image_file,
+    )
+}
+
+/// Checks that `config` is a wg-quick configuration with an interface and
+/// at least one peer.
+fn validate_wireguard_config(config: &str) -> Result<()> {
+    let mut section = "";
+    let mut private_key = false;
+    let mut peers = 0;
+
+    for line in config.lines().map(str::trim) {
+        if line.starts_with('[') {
+            section = line;
+
+            if section == "[Peer]" {
+                peers += 1;
+            }
+
+            continue;
+        }
+
+        if let Some((key, value)) = line.split_once('=') {
+            if section == "[Interface]" && key.trim() == "PrivateKey" && !value.trim().is_empty() {
+                private_key = true;
+            }
+        }
+    }
+
+    anyhow::ensure!(
+        private_key,
+        "wireguard config misses [Interface] PrivateKey"
+    );
+    anyhow::ensure!(peers > 0, "wireguard config misses [Peer] section");
+
+    Ok(())
+}
+
+/// Installs a wg-quick configuration named after the config file, e.g.
+/// wg0.conf for interface wg0, and enables wg-quick@wg0.service.
+pub fn set_wireguard_config(config_file: &Path, image_file: &Path) -> Result<()> {
+    let interface = config_file
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .context("set_wireguard_config: cannot get interface name from config file name")?;
+
+    validate_interface(interface)?;
+    validate_wireguard_config(&fs::read_to_string(config_file).context(format!(
+        "set_wireguard_config: cannot read {}",
+        config_file.to_string_lossy()
+    ))?)?;
+
+    copy_to_image(
+        &[FileCopyToParams::new(
+            config_file,
+            Partition::factory,
+            &Path::new(WIREGUARD_DIR).join(format!("{interface}.conf")),
+        )
+        .with_mode(SECRET_MODE)
+        .with_owner(0, 0)],
+        image_file,
+    )?;
+
+    create_symlink(
+        Partition::factory,
+        &Path::new(MULTI_USER_WANTS_DIR).join(format!("wg-quick@{interface}.service")),
+        &Path::new(SYSTEMD_UNIT_DIR).join("wg-quick@.service"),
+        image_file,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wpa_supplicant_config_with_passphrase_and_raw_key() {
+        assert_eq!(
+            wpa_supplicant_config("factory wifi", "secret123").unwrap(),
+            "ctrl_interface=/var/run/wpa_supplicant\nupdate_config=1\n\nnetwork={\n\tssid=\"factory wifi\"\n\tpsk=\"secret123\"\n}\n"
+        );
+
+        let raw_key = "a".repeat(64);
+
+        assert!(wpa_supplicant_config("factory", &raw_key)
+            .unwrap()
+            .contains(&format!("\tpsk={raw_key}\n")));
+        assert!(wpa_supplicant_config("factory", "short").is_err());
+        assert!(wpa_supplicant_config("", "secret123").is_err());
+        assert!(wpa_supplicant_config("factory\n", "secret123").is_err());
+    }
+
+    #[test]
+    fn wifi_profile_destinations() {
+        let dir = tempfile::tempdir().unwrap();
+        let nm_profile = dir.path().join("factory.nmconnection");
+        let wpa_profile = dir.path().join("wpa.conf");
+
+        fs::write(&nm_profile, "[connection]\nid=factory\ntype=wifi\n").unwrap();
+        fs::write(&wpa_profile, "network={\n\tssid=\"factory\"\n}\n").unwrap();
+
+        assert_eq!(
+            wifi_profile_destination(&nm_profile, "wlan0").unwrap(),
+            Path::new("/etc/NetworkManager/system-connections/factory.nmconnection")
+        );
+        assert_eq!(
+            wifi_profile_destination(&wpa_profile, "wlan1").unwrap(),
+            Path::new("/etc/wpa_supplicant/wpa_supplicant-wlan1.conf")
+        );
+
+        fs::write(&wpa_profile, "update_config=1\n").unwrap();
+
+        assert!(wifi_profile_destination(&wpa_profile, "wlan0").is_err());
+    }
+
+    #[test]
+    fn systemd_networkd_config_validation() {
+        assert_eq!(
+            systemd_networkd_config(
+                "eth0",
+                &["192.168.0.10/24".to_string(), "fd00::10/64".to_string()],
+                Some(IpAddr::from_str("192.168.0.1").unwrap()),
+                &[IpAddr::from_str("192.168.0.2").unwrap()],
+            )
+            .unwrap(),
+            "[Match]\nName=eth0\n\n[Network]\nAddress=192.168.0.10/24\nAddress=fd00::10/64\nGateway=192.168.0.1\nDNS=192.168.0.2\n"
+        );
+
+        assert!(systemd_networkd_config("eth0", &[], None, &[]).is_err());
+        assert!(systemd_networkd_config("eth0", &["192.168.0.10".to_string()], None, &[]).is_err());
+        assert!(
+            systemd_networkd_config("eth0", &["192.168.0.10/33".to_string()], None, &[]).is_err()
+        );
+        assert!(
+            systemd_networkd_config("eth 0", &["192.168.0.10/24".to_string()], None, &[]).is_err()
+        );
+        assert!(systemd_networkd_config(
+            "eth0",
+            &["192.168.0.10/24".to_string()],
+            Some(IpAddr::from_str("fd00::1").unwrap()),
+            &[]
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn wireguard_config_validation() {
+        let config = "[Interface]\nPrivateKey = aGVsbG8=\nAddress = 10.0.0.2/24\n\n[Peer]\nPublicKey = d29ybGQ=\nEndpoint = vpn.example.com:51820\nAllowedIPs = 10.0.0.0/24\n";
+
+        assert!(validate_wireguard_config(config).is_ok());
+        assert!(validate_wireguard_config("[Interface]\nAddress = 10.0.0.2/24\n[Peer]\n").is_err());
+        assert!(validate_wireguard_config("[Interface]\nPrivateKey = aGVsbG8=\n").is_err());
+    }
+}
@@ -0,0 +1,97 @@
+use crate::error::ErrorKind;
+use anyhow::{Context, Result};
+use log::debug;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const MAGIC: &[u8; 4] = b"QFI\xfb";
+
+/// Detects qcow2 images, e.g. used by QEMU, by their magic.
+pub fn is_qcow2(image_file: &Path) -> Result<bool> {
+    let mut magic = [0u8; 4];
+    let mut file = File::open(image_file).context(format!(
+        "is_qcow2: cannot open {}",
+        image_file.to_string_lossy()
+    ))?;
+
+    match file.read_exact(&mut magic) {
+        Ok(()) => Ok(&magic == MAGIC),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e).context("is_qcow2: cannot read magic"),
+    }
+}
+
+/// Converts `from` to `to` by qemu-img, e.g. from qcow2 to raw.
+pub(crate) fn convert(from_format: &str, from: &Path, to_format: &str, to: &Path) -> Result<()> {
+    let mut qemu_img = Command::new("qemu-img");
+    qemu_img
+        .arg("convert")
+        .arg("-f")
+        .arg(from_format)
+        .arg("-O")
+        .arg(to_format)
+        .arg(from)
+        .arg(to);
+
+    debug!("convert: {qemu_img:?}");
+
+    anyhow::ensure!(
+        qemu_img
+            .status()
+            .context(ErrorKind::Environment)
+            .context("convert: cannot run qemu-img")?
+            .success(),
+        "convert: cmd failed: {qemu_img:?}"
+    );
+
+    Ok(())
+}
+
+/// Converts the qcow2 image `image_file` to a raw image next to it, which
+/// replaces `image_file`. Returns the path of the raw image.
+pub fn to_raw(image_file: &Path) -> Result<PathBuf> {
+    let raw_file = image_file.with_extension("raw");
+
+    convert("qcow2", image_file, "raw", &raw_file)?;
+
+    fs::remove_file(image_file).context(format!(
+        "to_raw: cannot remove {}",
+        image_file.to_string_lossy()
+    ))?;
+
+    Ok(raw_file)
+}
+
+/// Converts the raw image `raw_file` to the qcow2 image `image_file`, which
+/// replaces `raw_file`.
+pub fn from_raw(raw_file: &Path, image_file: &Path) -> Result<()> {
+    convert("raw", raw_file, "qcow2", image_file)?;
+
+    fs::remove_file(raw_file).context(format!(
+        "from_raw: cannot remove {}",
+        raw_file.to_string_lossy()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_qcow2() {
+        let dir = tempfile::tempdir().unwrap();
+        let qcow2 = dir.path().join("image.qcow2");
+        let raw = dir.path().join("image.wic");
+        let empty = dir.path().join("empty");
+
+        fs::write(&qcow2, b"QFI\xfb\x00\x00\x00\x03").unwrap();
+        fs::write(&raw, [0u8; 512]).unwrap();
+        fs::write(&empty, b"").unwrap();
+
+        assert!(is_qcow2(&qcow2).unwrap());
+        assert!(!is_qcow2(&raw).unwrap());
+        assert!(!is_qcow2(&empty).unwrap());
+    }
+}
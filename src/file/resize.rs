@@ -0,0 +1,1480 @@
+use crate::error::ErrorKind;
+use crate::validators::image::{ext_identity, ext_size, filesystem, vfat_volume_id, Filesystem};
+use anyhow::{Context, Result};
+use log::debug;
+use serde::Serialize;
+use std::fmt::{self, Display};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+
+const SECTOR_SIZE: u64 = 512;
+// partitions are shifted by multiples of 1 MiB to keep them aligned
+const ALIGNMENT: u64 = 2048;
+const MOVE_BUFFER_SIZE: u64 = 4 * 1024 * 1024;
+const DOS_EXTENDED_TYPES: [&str; 3] = ["5", "f", "85"];
+// the backup gpt (header and 128 entries) at the end of the image
+const GPT_BACKUP_SECTORS: u64 = 33;
+const GPT_TYPE_LINUX: &str = "0FC63DAF-8483-4772-8E79-3D69D8477DE4";
+const GPT_TYPE_BASIC_DATA: &str = "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7";
+const GPT_NAME_MAX_LEN: usize = 36;
+const VFAT_LABEL_MAX_LEN: usize = 11;
+const EXT_LABEL_MAX_LEN: usize = 16;
+
+/// Partitions of the omnect layout, including those which can't be accessed
+/// by the file commands.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+#[clap(rename_all = "verbatim")]
+#[allow(non_camel_case_types)]
+pub enum PartitionName {
+    boot,
+    rootA,
+    rootB,
+    factory,
+    cert,
+    etc,
+    data,
+}
+
+impl Display for PartitionName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+impl PartitionName {
+    /// Number of the partition, in the dos layout partition 4 is the extended
+    /// partition.
+    fn number(&self, label: Label) -> usize {
+        let logical = match label {
+            Label::gpt => 0,
+            Label::dos => 1,
+        };
+
+        match self {
+            PartitionName::boot => 1,
+            PartitionName::rootA => 2,
+            PartitionName::rootB => 3,
+            PartitionName::factory => 4 + logical,
+            PartitionName::cert => 5 + logical,
+            PartitionName::etc => 6 + logical,
+            PartitionName::data => 7 + logical,
+        }
+    }
+}
+
+/// Size in bytes, given as number with an optional suffix K, M, G or T (powers
+/// of 1024), e.g. `4G`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PartitionSize(pub u64);
+
+impl FromStr for PartitionSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let err_msg = format!("invalid size {s}: use e.g. 4G, 512M or a number of bytes");
+        let (number, factor) = match s.char_indices().last() {
+            Some((i, unit)) if unit.is_ascii_alphabetic() => {
+                let exponent = match unit.to_ascii_uppercase() {
+                    'K' => 1,
+                    'M' => 2,
+                    'G' => 3,
+                    'T' => 4,
+                    _ => anyhow::bail!(err_msg),
+                };
+
+                (&s[..i], 1024u64.pow(exponent))
+            }
+            _ => (s, 1),
+        };
+        let bytes = number
+            .parse::<u64>()
+            .ok()
+            .and_then(|number| number.checked_mul(factor))
+            .context(err_msg)?;
+
+        anyhow::ensure!(
+            bytes > 0 && bytes % SECTOR_SIZE == 0,
+            "invalid size {s}: must be a multiple of {SECTOR_SIZE} bytes"
+        );
+
+        Ok(PartitionSize(bytes))
+    }
+}
+
+/// Filesystem of a partition added by `image add-partition`.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+#[allow(non_camel_case_types)]
+pub enum NewFilesystem {
+    ext4,
+    vfat,
+}
+
+impl Display for NewFilesystem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[allow(non_camel_case_types)]
+enum Label {
+    dos,
+    gpt,
+}
+
+/// Partition as dumped by sfdisk, `attributes` contains the remaining fields,
+/// e.g. `type=83, bootable`.
+#[derive(Clone, Debug)]
+struct TableEntry {
+    node: String,
+    num: usize,
+    start: u64,
+    size: u64,
+    attributes: Vec<String>,
+}
+
+impl TableEntry {
+    fn end(&self) -> u64 {
+        self.start + self.size
+    }
+
+    /// Name of a gpt partition, e.g. `name="rootA"`.
+    fn name(&self) -> Option<&str> {
+        self.attributes.iter().find_map(|attribute| {
+            attribute
+                .strip_prefix("name=")
+                .map(|name| name.trim_matches('"'))
+        })
+    }
+
+    fn is_extended(&self) -> bool {
+        self.attributes.iter().any(|attribute| {
+            attribute
+                .strip_prefix("type=")
+                .is_some_and(|t| DOS_EXTENDED_TYPES.contains(&t.to_lowercase().as_str()))
+        })
+    }
+}
+
+/// Identifier replaced by [`regenerate_uuids`], e.g. the UUID of the
+/// filesystem of partition 2, in the notation of blkid.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct UuidChange {
+    pub object: String,
+    pub old: String,
+    pub new: String,
+}
+
+#[derive(Debug)]
+struct PartitionTable {
+    label: Label,
+    /// header lines of the dump without `device` and `last-lba`, which are
+    /// recalculated by sfdisk for a grown image
+    header: Vec<String>,
+    last_lba: Option<u64>,
+    entries: Vec<TableEntry>,
+}
+
+impl FromStr for PartitionTable {
+    type Err = anyhow::Error;
+
+    fn from_str(dump: &str) -> Result<Self> {
+        let mut label = None;
+        let mut header = vec![];
+        let mut last_lba = None;
+        let mut entries = vec![];
+
+        for line in dump.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            if let Some((node, fields)) = line.split_once(" : ") {
+                let num = node
+                    .trim()
+                    .rsplit(|c: char| !c.is_ascii_digit())
+                    .next()
+                    .and_then(|num| num.parse().ok())
+                    .context(format!("invalid partition node {node}"))?;
+                let mut start = None;
+                let mut size = None;
+                let mut attributes = vec![];
+
+                for field in fields.split(',').map(str::trim) {
+                    match field.split_once('=').map(|(k, v)| (k.trim(), v.trim())) {
+                        Some(("start", value)) => start = Some(value.parse()?),
+                        Some(("size", value)) => size = Some(value.parse()?),
+                        _ => attributes.push(field.to_string()),
+                    }
+                }
+
+                entries.push(TableEntry {
+                    node: node.trim().to_string(),
+                    num,
+                    start: start.context(format!("partition {num} has no start"))?,
+                    size: size.context(format!("partition {num} has no size"))?,
+                    attributes,
+                });
+            } else if let Some((key, value)) = line.split_once(':') {
+                match key.trim() {
+                    "device" => {}
+                    "last-lba" => last_lba = Some(value.trim().parse()?),
+                    key => {
+                        if key == "label" {
+                            label = match value.trim() {
+                                "dos" => Some(Label::dos),
+                                "gpt" => Some(Label::gpt),
+                                other => anyhow::bail!("unsupported partition table {other}"),
+                            };
+                        }
+
+                        header.push(line.to_string());
+                    }
+                }
+            }
+        }
+
+        Ok(PartitionTable {
+            label: label.context("partition table has no label")?,
+            header,
+            last_lba,
+            entries,
+        })
+    }
+}
+
+impl Display for PartitionTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for line in &self.header {
+            writeln!(f, "{line}")?;
+        }
+
+        writeln!(f)?;
+
+        for entry in &self.entries {
+            write!(
+                f,
+                "{} : start={}, size={}",
+                entry.node, entry.start, entry.size
+            )?;
+
+            for attribute in &entry.attributes {
+                write!(f, ", {attribute}")?;
+            }
+
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl PartitionTable {
+    /// Replaces the disk identifier and the partition UUIDs of gpt tables by
+    /// ones of `new_uuid`. Partitions of dos tables are identified by the
+    /// disk identifier and their number, e.g. `4bd5ce6a-02`.
+    fn regenerate_ids(&mut self, mut new_uuid: impl FnMut() -> uuid::Uuid) -> Vec<UuidChange> {
+        let mut changes = vec![];
+        let Some(i) = self
+            .header
+            .iter()
+            .position(|line| line.starts_with("label-id:"))
+        else {
+            return changes;
+        };
+        let old = self.header[i]["label-id:".len()..].trim().to_lowercase();
+
+        match self.label {
+            Label::dos => {
+                let old = old.trim_start_matches("0x").to_string();
+                let new = new_uuid().to_simple().to_string()[..8].to_string();
+
+                self.header[i] = format!("label-id: 0x{new}");
+                changes.extend(self.entries.iter().map(|entry| UuidChange {
+                    object: format!("partition {}", entry.num),
+                    old: format!("{old}-{:02x}", entry.num),
+                    new: format!("{new}-{:02x}", entry.num),
+                }));
+                changes.insert(
+                    0,
+                    UuidChange {
+                        object: "disk".to_string(),
+                        old,
+                        new,
+                    },
+                );
+            }
+            Label::gpt => {
+                let new = new_uuid().to_string();
+
+                self.header[i] = format!("label-id: {}", new.to_uppercase());
+                changes.push(UuidChange {
+                    object: "disk".to_string(),
+                    old,
+                    new,
+                });
+
+                for entry in self.entries.iter_mut() {
+                    for attribute in entry.attributes.iter_mut() {
+                        if let Some(old) = attribute.strip_prefix("uuid=") {
+                            let new = new_uuid().to_string();
+
+                            changes.push(UuidChange {
+                                object: format!("partition {}", entry.num),
+                                old: old.to_lowercase(),
+                                new: new.clone(),
+                            });
+                            *attribute = format!("uuid={}", new.to_uppercase());
+                        }
+                    }
+                }
+            }
+        }
+
+        changes
+    }
+
+    /// First sector after `target` which isn't free, i.e. the start of the
+    /// next partition, the end of an extended partition containing `target` or
+    /// the end of the usable space of the image.
+    fn limit(&self, target: &TableEntry, image_sectors: u64) -> u64 {
+        let next = self
+            .entries
+            .iter()
+            .filter(|e| e.start >= target.end())
+            .map(|e| match (self.label, e.num > 4) {
+                // logical partitions need space for their extended boot record
+                (Label::dos, true) => e.start.saturating_sub(ALIGNMENT),
+                _ => e.start,
+            });
+        let container = self
+            .entries
+            .iter()
+            .filter(|e| e.is_extended() && e.start < target.start && e.end() >= target.end())
+            .map(TableEntry::end);
+        let usable = match self.last_lba {
+            Some(last_lba) => last_lba + 1,
+            None => image_sectors,
+        };
+
+        next.chain(container)
+            .chain(std::iter::once(usable))
+            .min()
+            .unwrap_or(usable)
+    }
+
+    /// Appends a partition of `size` sectors behind the last partition and
+    /// returns it. Its device node follows the nodes of the other partitions.
+    fn append(&mut self, size: u64, attributes: Vec<String>) -> Result<TableEntry> {
+        let last = self
+            .entries
+            .iter()
+            .max_by_key(|e| e.end())
+            .context("partition table has no partitions")?;
+        let num = self.entries.iter().map(|e| e.num).max().unwrap_or(0) + 1;
+        let entry = TableEntry {
+            node: format!(
+                "{}{num}",
+                last.node.trim_end_matches(|c: char| c.is_ascii_digit())
+            ),
+            num,
+            start: last.end().next_multiple_of(ALIGNMENT),
+            size,
+            attributes,
+        };
+
+        self.entries.push(entry.clone());
+
+        Ok(entry)
+    }
+
+    /// Shrinks partition `num` to `size` sectors, an extended partition
+    /// ending with it shrinks as well.
+    fn shrink(&mut self, num: usize, size: u64) {
+        let Some(target) = self.entries.iter().find(|e| e.num == num).cloned() else {
+            return;
+        };
+
+        for entry in self.entries.iter_mut() {
+            if entry.num == num {
+                entry.size = size;
+            } else if entry.is_extended() && entry.end() == target.end() {
+                entry.size = target.start + size - entry.start;
+            }
+        }
+    }
+
+    /// Grows partition `num` to `size` sectors, partitions behind it are
+    /// shifted by `shift` sectors.
+    fn grow(&mut self, num: usize, size: u64, shift: u64) {
+        let Some(target) = self.entries.iter().find(|e| e.num == num).cloned() else {
+            return;
+        };
+
+        for entry in self.entries.iter_mut() {
+            if entry.num == num {
+                entry.size = size;
+            } else if entry.start >= target.end() {
+                entry.start += shift;
+            } else if entry.is_extended() && entry.end() >= target.end() {
+                // extended partition containing the target
+                entry.size += shift;
+            }
+        }
+    }
+}
+
+fn run(command: &mut Command) -> Result<std::process::ExitStatus> {
+    debug!("resize_partition: {command:?}");
+
+    command
+        .status()
+        .context(ErrorKind::Environment)
+        .context(format!("resize_partition: cannot run {command:?}"))
+}
+
+fn read_table(image_file: &Path) -> Result<PartitionTable> {
+    let mut sfdisk = Command::new("sfdisk");
+    sfdisk.arg("--dump").arg(image_file);
+
+    debug!("resize_partition: {sfdisk:?}");
+
+    let output = sfdisk
+        .output()
+        .context(ErrorKind::Environment)
+        .context("resize_partition: cannot run sfdisk")?;
+
+    anyhow::ensure!(
+        output.status.success(),
+        "resize_partition: cannot read partition table: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    String::from_utf8_lossy(&output.stdout).parse()
+}
+
+fn write_table(image_file: &Path, table: &PartitionTable) -> Result<()> {
+    let mut sfdisk = Command::new("sfdisk");
+    sfdisk
+        .arg("--no-reread")
+        .arg("--no-tell-kernel")
+        .arg("--quiet")
+        .arg(image_file)
+        .stdin(Stdio::piped());
+
+    debug!("resize_partition: {sfdisk:?} with\n{table}");
+
+    let mut child = sfdisk
+        .spawn()
+        .context(ErrorKind::Environment)
+        .context("resize_partition: cannot run sfdisk")?;
+
+    child
+        .stdin
+        .take()
+        .context("resize_partition: cannot get stdin of sfdisk")?
+        .write_all(table.to_string().as_bytes())
+        .context("resize_partition: cannot write partition table to sfdisk")?;
+
+    anyhow::ensure!(
+        child
+            .wait()
+            .context("resize_partition: sfdisk failed")?
+            .success(),
+        "resize_partition: cannot write partition table"
+    );
+
+    Ok(())
+}
+
+/// Moves the bytes from `offset` to the end of `file` by `shift` bytes towards
+/// the end, starting with the last chunk since source and destination
+/// overlap.
+fn move_tail(file: &File, offset: u64, shift: u64) -> Result<()> {
+    let len = file.metadata()?.len();
+    let mut buf = vec![0u8; MOVE_BUFFER_SIZE as usize];
+    let mut end = len;
+
+    file.set_len(len + shift)?;
+
+    while end > offset {
+        let start = end.saturating_sub(MOVE_BUFFER_SIZE).max(offset);
+        let chunk = &mut buf[..(end - start) as usize];
+
+        file.read_exact_at(chunk, start)?;
+        file.write_all_at(chunk, start + shift)?;
+        end = start;
+    }
+
+    Ok(())
+}
+
+/// Copies the partition `entry` of the image to a file next to it.
+fn read_partition_file(image_file: &Path, entry: &TableEntry) -> Result<PathBuf> {
+    let partition_file = image_file.with_file_name(format!("partition-{}.img", entry.num));
+
+    run(Command::new("dd")
+        .arg(format!("if={}", image_file.to_string_lossy()))
+        .arg(format!("of={}", partition_file.to_string_lossy()))
+        .arg(format!("bs={SECTOR_SIZE}"))
+        .arg(format!("skip={}", entry.start))
+        .arg(format!("count={}", entry.size))
+        .arg("conv=sparse")
+        .arg("status=none"))?
+    .success()
+    .then_some(())
+    .context("resize_partition: cannot read partition")?;
+
+    Ok(partition_file)
+}
+
+/// Checks the ext filesystem of `partition_file`, which resize2fs requires.
+/// `discard` punches holes for the free blocks.
+fn check_filesystem(partition_file: &Path, discard: bool) -> Result<()> {
+    let mut e2fsck = Command::new("e2fsck");
+    e2fsck.arg("-f").arg("-y");
+
+    if discard {
+        e2fsck.arg("-E").arg("discard");
+    }
+
+    // e2fsck exits with 1 if it corrected errors
+    let status = run(e2fsck.arg(partition_file))?;
+
+    anyhow::ensure!(
+        matches!(status.code(), Some(0 | 1)),
+        "resize_partition: filesystem check failed: {status}"
+    );
+
+    Ok(())
+}
+
+fn resize_filesystem(image_file: &Path, entry: &TableEntry) -> Result<()> {
+    let partition_file = read_partition_file(image_file, entry)?;
+
+    check_filesystem(&partition_file, false)?;
+
+    run(Command::new("resize2fs").arg(&partition_file))?
+        .success()
+        .then_some(())
+        .context("resize_partition: cannot resize filesystem")?;
+
+    write_partition_file(image_file, &partition_file, entry)
+}
+
+/// Writes `partition_file` to the partition `entry` of the image and removes
+/// it. The partition is deallocated first, so that blocks which are zero in
+/// `partition_file`, e.g. discarded ones, don't keep their former content.
+fn write_partition_file(
+    image_file: &Path,
+    partition_file: &Path,
+    entry: &TableEntry,
+) -> Result<()> {
+    let len = fs::metadata(partition_file)
+        .context("resize_partition: cannot get size of partition file")?
+        .len();
+
+    run(Command::new("fallocate")
+        .arg("--punch-hole")
+        .arg("--offset")
+        .arg((entry.start * SECTOR_SIZE).to_string())
+        .arg("--length")
+        .arg(len.to_string())
+        .arg(image_file))?
+    .success()
+    .then_some(())
+    .context("resize_partition: cannot deallocate partition")?;
+
+    run(Command::new("dd")
+        .arg(format!("if={}", partition_file.to_string_lossy()))
+        .arg(format!("of={}", image_file.to_string_lossy()))
+        .arg(format!("bs={SECTOR_SIZE}"))
+        .arg(format!("seek={}", entry.start))
+        .arg("conv=notrunc,sparse")
+        .arg("status=none"))?
+    .success()
+    .then_some(())
+    .context("resize_partition: cannot write partition")?;
+
+    fs::remove_file(partition_file).context("resize_partition: cannot remove partition file")
+}
+
+/// Grows `partition` of `image_file` and its ext4 filesystem to `size`. Free
+/// space behind the partition is used, if there isn't enough the following
+/// partitions are shifted towards the end of the image, which grows
+/// accordingly.
+pub fn resize_partition(
+    image_file: &Path,
+    partition: PartitionName,
+    size: PartitionSize,
+) -> Result<()> {
+    let mut table = read_table(image_file)?;
+    let num = partition.number(table.label);
+    let target = table
+        .entries
+        .iter()
+        .find(|e| e.num == num)
+        .cloned()
+        .context(ErrorKind::User)
+        .context(format!(
+            "resize_partition: image has no {partition} partition"
+        ))?;
+    let new_size = size.0 / SECTOR_SIZE;
+
+    if new_size <= target.size {
+        return Err(anyhow::anyhow!(
+            "resize_partition: {partition} partition has already {} bytes, shrinking isn't supported",
+            target.size * SECTOR_SIZE
+        )
+        .context(ErrorKind::User));
+    }
+
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(image_file)
+        .context("resize_partition: cannot open image")?;
+    let partition_filesystem = filesystem(&mut file.try_clone()?, target.start)?;
+
+    if partition_filesystem == Some(Filesystem::Vfat) {
+        return Err(anyhow::anyhow!(
+            "resize_partition: resizing the vfat filesystem of {partition} isn't supported"
+        )
+        .context(ErrorKind::User));
+    }
+
+    let image_sectors = file.metadata()?.len() / SECTOR_SIZE;
+    let new_end = target.start + new_size;
+    let limit = table.limit(&target, image_sectors);
+    let shift = new_end.saturating_sub(limit).next_multiple_of(ALIGNMENT);
+
+    debug!("resize_partition: grow {partition} to {new_size} sectors, shift by {shift} sectors");
+
+    if shift > 0 {
+        move_tail(&file, target.end() * SECTOR_SIZE, shift * SECTOR_SIZE)
+            .context("resize_partition: cannot shift partitions")?;
+    }
+
+    drop(file);
+
+    table.grow(num, new_size, shift);
+    write_table(image_file, &table)?;
+
+    if partition_filesystem == Some(Filesystem::Ext) {
+        let entry = table
+            .entries
+            .iter()
+            .find(|e| e.num == num)
+            .context("resize_partition: partition vanished")?;
+
+        resize_filesystem(image_file, entry)?;
+    }
+
+    // keep the image sparse
+    run(Command::new("fallocate").arg("-d").arg(image_file))?;
+
+    Ok(())
+}
+
+fn validate_partition_name(table: &PartitionTable, name: &str) -> Result<()> {
+    anyhow::ensure!(
+        !name.is_empty()
+            && name.chars().count() <= GPT_NAME_MAX_LEN
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')),
+        "invalid partition name {name:?}: use up to {GPT_NAME_MAX_LEN} letters, digits, '-', '_' or '.'"
+    );
+    anyhow::ensure!(
+        table.entries.iter().all(|e| e.name() != Some(name)),
+        "image has already a partition named {name}"
+    );
+
+    Ok(())
+}
+
+/// Appends a partition named `name` of `size` with an empty `filesystem`
+/// labeled by `name` behind the last partition of the gpt image
+/// `image_file`, which grows accordingly. Returns the partition number.
+pub fn add_partition(
+    image_file: &Path,
+    name: &str,
+    size: PartitionSize,
+    filesystem: NewFilesystem,
+) -> Result<usize> {
+    let mut table = read_table(image_file)?;
+
+    if table.label != Label::gpt {
+        return Err(anyhow::anyhow!(
+            "add_partition: only supported for gpt images, dos images are limited to the existing partitions"
+        )
+        .context(ErrorKind::User));
+    }
+
+    validate_partition_name(&table, name).context(ErrorKind::User)?;
+
+    let partition_type = match filesystem {
+        NewFilesystem::ext4 => GPT_TYPE_LINUX,
+        NewFilesystem::vfat => GPT_TYPE_BASIC_DATA,
+    };
+    let entry = table.append(
+        size.0 / SECTOR_SIZE,
+        vec![format!("type={partition_type}"), format!("name=\"{name}\"")],
+    )?;
+
+    debug!(
+        "add_partition: add {name} as partition {} at sector {} with {} sectors",
+        entry.num, entry.start, entry.size
+    );
+
+    // room for the backup gpt, sfdisk recalculates last-lba
+    OpenOptions::new()
+        .write(true)
+        .open(image_file)
+        .and_then(|file| {
+            let len = file.metadata()?.len();
+            let required = (entry.end() + GPT_BACKUP_SECTORS).next_multiple_of(ALIGNMENT);
+
+            file.set_len(len.max(required * SECTOR_SIZE))
+        })
+        .context("add_partition: cannot grow image")?;
+
+    write_table(image_file, &table)?;
+
+    let partition_file = image_file.with_file_name(format!("add-{}.img", entry.num));
+
+    File::create(&partition_file)
+        .and_then(|file| file.set_len(entry.size * SECTOR_SIZE))
+        .context("add_partition: cannot create partition file")?;
+
+    let mut mkfs = match filesystem {
+        NewFilesystem::ext4 => {
+            let mut mkfs = Command::new("mkfs.ext4");
+            mkfs.arg("-F").arg("-q").arg("-L").arg(name);
+            mkfs
+        }
+        NewFilesystem::vfat => {
+            let mut mkfs = Command::new("mkfs.vfat");
+            mkfs.arg("-n").arg(
+                name.to_uppercase()
+                    .chars()
+                    .take(VFAT_LABEL_MAX_LEN)
+                    .collect::<String>(),
+            );
+            mkfs
+        }
+    };
+
+    run(mkfs.arg(&partition_file))?
+        .success()
+        .then_some(())
+        .context(format!(
+            "add_partition: cannot create {filesystem} filesystem"
+        ))?;
+
+    write_partition_file(image_file, &partition_file, &entry)?;
+
+    // keep the image sparse
+    run(Command::new("fallocate").arg("-d").arg(image_file))?;
+
+    Ok(entry.num)
+}
+
+/// Recreates the ext4 filesystem of `partition`, which has to be the etc or
+/// data partition, empty with the same UUID, label and size, e.g. to return
+/// a used image to its pristine state.
+pub fn reset_partition(image_file: &Path, partition: PartitionName) -> Result<()> {
+    if !matches!(partition, PartitionName::etc | PartitionName::data) {
+        return Err(anyhow::anyhow!(
+            "reset_partition: only the etc and data partitions can be reset"
+        )
+        .context(ErrorKind::User));
+    }
+
+    let table = read_table(image_file)?;
+    let num = partition.number(table.label);
+    let entry = table
+        .entries
+        .iter()
+        .find(|e| e.num == num)
+        .context(ErrorKind::User)
+        .context(format!(
+            "reset_partition: image has no {partition} partition"
+        ))?;
+    let mut file = File::open(image_file).context("reset_partition: cannot open image")?;
+
+    if filesystem(&mut file, entry.start)? != Some(Filesystem::Ext) {
+        return Err(anyhow::anyhow!(
+            "reset_partition: {partition} partition has no ext4 filesystem"
+        )
+        .context(ErrorKind::User));
+    }
+
+    let (block_size, blocks) = ext_size(&mut file, entry.start)?;
+    let (uuid, label) = ext_identity(&mut file, entry.start)?;
+
+    drop(file);
+
+    debug!("reset_partition: recreate {partition} with uuid {uuid} and label {label:?}");
+
+    let partition_file = image_file.with_file_name(format!("partition-{num}.img"));
+
+    File::create(&partition_file)
+        .and_then(|file| file.set_len(entry.size * SECTOR_SIZE))
+        .context("reset_partition: cannot create partition file")?;
+
+    let mut mkfs = Command::new("mkfs.ext4");
+    mkfs.arg("-F")
+        .arg("-q")
+        .arg("-b")
+        .arg(block_size.to_string())
+        .arg("-U")
+        .arg(uuid.to_string());
+
+    if !label.is_empty() {
+        mkfs.arg("-L").arg(&label);
+    }
+
+    run(mkfs.arg(&partition_file).arg(blocks.to_string()))?
+        .success()
+        .then_some(())
+        .context("reset_partition: cannot create filesystem")?;
+
+    write_partition_file(image_file, &partition_file, entry)?;
+
+    // keep the image sparse
+    run(Command::new("fallocate").arg("-d").arg(image_file))?;
+
+    Ok(())
+}
+
+fn parse_min_blocks(output: &str) -> Result<u64> {
+    output
+        .lines()
+        .find_map(|line| line.strip_prefix("Estimated minimum size of the filesystem:"))
+        .and_then(|blocks| blocks.trim().parse().ok())
+        .context("shrink_image: resize2fs printed no minimum size")
+}
+
+/// Estimated minimum number of blocks of the ext filesystem in
+/// `partition_file`.
+fn min_blocks(partition_file: &Path) -> Result<u64> {
+    let mut resize2fs = Command::new("resize2fs");
+    resize2fs.arg("-P").arg(partition_file);
+
+    debug!("shrink_image: {resize2fs:?}");
+
+    let output = resize2fs
+        .output()
+        .context(ErrorKind::Environment)
+        .context("shrink_image: cannot run resize2fs")?;
+
+    anyhow::ensure!(
+        output.status.success(),
+        "shrink_image: cannot get minimum size: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    parse_min_blocks(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Shrinks the ext filesystem in `partition_file` to its content plus
+/// `margin` bytes and truncates the file. Returns the new size in sectors,
+/// which is aligned to 1 MiB and doesn't exceed `size` sectors.
+fn shrink_filesystem(partition_file: &Path, size: u64, margin: PartitionSize) -> Result<u64> {
+    let (block_size, _) = ext_size(&mut File::open(partition_file)?, 0)?;
+    let new_size = (min_blocks(partition_file)? * block_size + margin.0)
+        .div_ceil(SECTOR_SIZE)
+        .next_multiple_of(ALIGNMENT);
+
+    if new_size >= size {
+        return Ok(size);
+    }
+
+    run(Command::new("resize2fs")
+        .arg(partition_file)
+        .arg(format!("{}K", new_size * SECTOR_SIZE / 1024)))?
+    .success()
+    .then_some(())
+    .context("shrink_image: cannot shrink filesystem")?;
+
+    OpenOptions::new()
+        .write(true)
+        .open(partition_file)
+        .and_then(|file| file.set_len(new_size * SECTOR_SIZE))
+        .context("shrink_image: cannot truncate partition file")?;
+
+    Ok(new_size)
+}
+
+/// Volume id of a vfat filesystem given as `1234-ABCD`, as listed by blkid,
+/// in the format of `fatlabel -i`.
+fn parse_vfat_volume_id(uuid: &str) -> Result<String> {
+    match uuid.split_once('-') {
+        Some((high, low))
+            if high.len() == 4
+                && low.len() == 4
+                && high
+                    .chars()
+                    .chain(low.chars())
+                    .all(|c| c.is_ascii_hexdigit()) =>
+        {
+            Ok(format!("{high}{low}").to_uppercase())
+        }
+        _ => Err(anyhow::anyhow!(
+            "set_filesystem: invalid vfat volume id {uuid}: use e.g. 1234-ABCD"
+        )
+        .context(ErrorKind::User)),
+    }
+}
+
+/// Commands setting `label` and `uuid` of `partition_file`: tune2fs for ext
+/// filesystems, fatlabel for vfat filesystems.
+fn set_filesystem_commands(
+    partition_file: &Path,
+    partition_filesystem: Filesystem,
+    label: Option<&str>,
+    uuid: Option<&str>,
+) -> Result<Vec<Command>> {
+    let invalid = |msg: String| anyhow::anyhow!(msg).context(ErrorKind::User);
+    let mut commands = vec![];
+
+    match partition_filesystem {
+        Filesystem::Ext => {
+            let mut tune2fs = Command::new("tune2fs");
+
+            if let Some(label) = label {
+                if label.len() > EXT_LABEL_MAX_LEN {
+                    return Err(invalid(format!(
+                        "set_filesystem: label {label} exceeds {EXT_LABEL_MAX_LEN} characters"
+                    )));
+                }
+
+                tune2fs.arg("-L").arg(label);
+            }
+
+            if let Some(uuid) = uuid {
+                let uuid = uuid::Uuid::parse_str(uuid)
+                    .map_err(|_| invalid(format!("set_filesystem: invalid uuid {uuid}")))?;
+
+                tune2fs.arg("-U").arg(uuid.to_string());
+            }
+
+            tune2fs.arg(partition_file);
+            commands.push(tune2fs);
+        }
+        Filesystem::Vfat => {
+            if let Some(label) = label {
+                if label.len() > VFAT_LABEL_MAX_LEN {
+                    return Err(invalid(format!(
+                        "set_filesystem: label {label} exceeds {VFAT_LABEL_MAX_LEN} characters"
+                    )));
+                }
+
+                let mut fatlabel = Command::new("fatlabel");
+                fatlabel.arg(partition_file).arg(label.to_uppercase());
+                commands.push(fatlabel);
+            }
+
+            if let Some(uuid) = uuid {
+                let mut fatlabel = Command::new("fatlabel");
+                fatlabel
+                    .arg("-i")
+                    .arg(partition_file)
+                    .arg(parse_vfat_volume_id(uuid)?);
+                commands.push(fatlabel);
+            }
+        }
+    }
+
+    Ok(commands)
+}
+
+/// Sets the label and/or UUID of the filesystem of `partition`, e.g. to
+/// distinguish customer specific images by mount-by-label logic. vfat
+/// filesystems take a volume id like `1234-ABCD` as UUID and upper case
+/// labels. References to the filesystem in the image, e.g. in fstab, aren't
+/// adjusted.
+pub fn set_filesystem(
+    image_file: &Path,
+    partition: PartitionName,
+    label: Option<&str>,
+    uuid: Option<&str>,
+) -> Result<()> {
+    let table = read_table(image_file)?;
+    let num = partition.number(table.label);
+    let entry = table
+        .entries
+        .iter()
+        .find(|e| e.num == num)
+        .context(ErrorKind::User)
+        .context(format!(
+            "set_filesystem: image has no {partition} partition"
+        ))?;
+    let mut file = File::open(image_file).context("set_filesystem: cannot open image")?;
+    let partition_filesystem = filesystem(&mut file, entry.start)?
+        .context(ErrorKind::User)
+        .context(format!(
+            "set_filesystem: {partition} partition has no ext4 or vfat filesystem"
+        ))?;
+
+    drop(file);
+
+    set_entry_filesystem(image_file, entry, partition_filesystem, label, uuid)
+}
+
+fn set_entry_filesystem(
+    image_file: &Path,
+    entry: &TableEntry,
+    partition_filesystem: Filesystem,
+    label: Option<&str>,
+    uuid: Option<&str>,
+) -> Result<()> {
+    let partition_file = image_file.with_file_name(format!("partition-{}.img", entry.num));
+    let commands = set_filesystem_commands(&partition_file, partition_filesystem, label, uuid)?;
+
+    read_partition_file(image_file, entry)?;
+
+    // tune2fs refuses to change the uuid of a filesystem with metadata
+    // checksums unless it was checked recently
+    if partition_filesystem == Filesystem::Ext {
+        check_filesystem(&partition_file, false)?;
+    }
+
+    for mut command in commands {
+        run(&mut command)?
+            .success()
+            .then_some(())
+            .context(format!("set_filesystem: cmd failed: {command:?}"))?;
+    }
+
+    write_partition_file(image_file, &partition_file, entry)
+}
+
+/// Replaces the disk identifier, the partition UUIDs of gpt images and the
+/// UUIDs of all ext4 and vfat filesystems of `image_file` by random ones,
+/// e.g. for cloned images. Returns the replaced identifiers in order to
+/// adjust references to them.
+pub fn regenerate_uuids(image_file: &Path) -> Result<Vec<UuidChange>> {
+    let mut table = read_table(image_file)?;
+    let mut changes = table.regenerate_ids(uuid::Uuid::new_v4);
+    let mut file = File::open(image_file).context("regenerate_uuids: cannot open image")?;
+    let mut filesystems = vec![];
+
+    for entry in table.entries.iter().filter(|e| !e.is_extended()) {
+        let (partition_filesystem, old, new) = match filesystem(&mut file, entry.start)? {
+            Some(Filesystem::Ext) => (
+                Filesystem::Ext,
+                ext_identity(&mut file, entry.start)?.0.to_string(),
+                uuid::Uuid::new_v4().to_string(),
+            ),
+            Some(Filesystem::Vfat) => {
+                let id = uuid::Uuid::new_v4().to_simple().to_string().to_uppercase();
+
+                (
+                    Filesystem::Vfat,
+                    vfat_volume_id(&mut file, entry.start)?,
+                    format!("{}-{}", &id[..4], &id[4..8]),
+                )
+            }
+            None => continue,
+        };
+
+        filesystems.push((
+            entry.clone(),
+            partition_filesystem,
+            UuidChange {
+                object: format!("filesystem of partition {}", entry.num),
+                old,
+                new,
+            },
+        ));
+    }
+
+    drop(file);
+
+    write_table(image_file, &table)?;
+
+    for (entry, partition_filesystem, change) in filesystems {
+        set_entry_filesystem(
+            image_file,
+            &entry,
+            partition_filesystem,
+            None,
+            Some(&change.new),
+        )?;
+        changes.push(change);
+    }
+
+    Ok(changes)
+}
+
+/// Reduces `image_file` to a minimal size, e.g. for faster downloads: free
+/// blocks of all ext filesystems are discarded, so that they become holes
+/// skipped by bmap files, the last partition (the data partition of the
+/// omnect layout) is shrunk to its content plus `margin` and the image is
+/// truncated behind it. The other partitions keep their size, since updates
+/// depend on it. Returns the new size of the image in bytes.
+pub fn shrink_image(image_file: &Path, margin: PartitionSize) -> Result<u64> {
+    let mut table = read_table(image_file)?;
+    let last = table
+        .entries
+        .iter()
+        .filter(|e| !e.is_extended())
+        .max_by_key(|e| e.end())
+        .map(|e| e.num)
+        .context("shrink_image: image has no partitions")?;
+    let mut file = File::open(image_file).context("shrink_image: cannot open image")?;
+
+    for entry in table.entries.clone() {
+        if entry.is_extended() || filesystem(&mut file, entry.start)? != Some(Filesystem::Ext) {
+            continue;
+        }
+
+        let partition_file = read_partition_file(image_file, &entry)?;
+
+        check_filesystem(&partition_file, true)?;
+
+        if entry.num == last {
+            let size = shrink_filesystem(&partition_file, entry.size, margin)?;
+
+            debug!(
+                "shrink_image: shrink partition {} from {} to {size} sectors",
+                entry.num, entry.size
+            );
+
+            table.shrink(entry.num, size);
+        }
+
+        write_partition_file(image_file, &partition_file, &entry)?;
+    }
+
+    drop(file);
+
+    let end = table
+        .entries
+        .iter()
+        .map(TableEntry::end)
+        .max()
+        .context("shrink_image: image has no partitions")?;
+    let sectors = match table.label {
+        // room for the backup gpt, sfdisk recalculates last-lba
+        Label::gpt => (end + GPT_BACKUP_SECTORS).next_multiple_of(ALIGNMENT),
+        Label::dos => end,
+    };
+    let image = OpenOptions::new()
+        .write(true)
+        .open(image_file)
+        .context("shrink_image: cannot open image")?;
+
+    if sectors * SECTOR_SIZE < image.metadata()?.len() {
+        image
+            .set_len(sectors * SECTOR_SIZE)
+            .context("shrink_image: cannot truncate image")?;
+    }
+
+    let len = image.metadata()?.len();
+
+    drop(image);
+
+    write_table(image_file, &table)?;
+
+    // keep the image sparse
+    run(Command::new("fallocate").arg("-d").arg(image_file))?;
+
+    Ok(len)
+}
+
+/// Zeroes the free blocks of all ext filesystems of `image_file` by
+/// discarding them, so that leftovers of deleted files neither bloat the
+/// compressed image nor delta updates. Sizes of partitions and image are
+/// kept. vfat filesystems are left untouched.
+pub fn zero_free_space(image_file: &Path) -> Result<()> {
+    let table = read_table(image_file)?;
+    let mut file = File::open(image_file).context("zero_free_space: cannot open image")?;
+
+    for entry in &table.entries {
+        if entry.is_extended() || filesystem(&mut file, entry.start)? != Some(Filesystem::Ext) {
+            continue;
+        }
+
+        debug!(
+            "zero_free_space: discard free blocks of partition {}",
+            entry.num
+        );
+
+        let partition_file = read_partition_file(image_file, entry)?;
+
+        check_filesystem(&partition_file, true)?;
+        write_partition_file(image_file, &partition_file, entry)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOS_DUMP: &str = "label: dos
+label-id: 0x4bd5ce6a
+device: image.wic
+unit: sectors
+sector-size: 512
+
+image.wic1 : start=        8192, size=        2048, type=c, bootable
+image.wic2 : start=       16384, size=        2048, type=83
+image.wic3 : start=       24576, size=        2048, type=83
+image.wic4 : start=       32766, size=       26626, type=f
+image.wic5 : start=       32768, size=        2048, type=83
+image.wic6 : start=       40960, size=        2048, type=83
+image.wic7 : start=       49152, size=        2048, type=83
+image.wic8 : start=       57344, size=        2048, type=83
+";
+
+    #[test]
+    fn parse_size() {
+        assert_eq!(
+            "4G".parse::<PartitionSize>().unwrap().0,
+            4 * 1024 * 1024 * 1024
+        );
+        assert_eq!(
+            "512m".parse::<PartitionSize>().unwrap().0,
+            512 * 1024 * 1024
+        );
+        assert_eq!("1024".parse::<PartitionSize>().unwrap().0, 1024);
+        assert!("1000".parse::<PartitionSize>().is_err());
+        assert!("4X".parse::<PartitionSize>().is_err());
+        assert!("G".parse::<PartitionSize>().is_err());
+    }
+
+    #[test]
+    fn grow_last_logical_partition() {
+        let mut table: PartitionTable = DOS_DUMP.parse().unwrap();
+        let target = table.entries.iter().find(|e| e.num == 8).cloned().unwrap();
+
+        assert_eq!(table.label, Label::dos);
+        assert_eq!(PartitionName::data.number(table.label), 8);
+
+        // the extended partition ends with the image
+        let limit = table.limit(&target, 59392);
+        assert_eq!(limit, 59392);
+
+        table.grow(
+            8,
+            4096,
+            (target.start + 4096 - limit).next_multiple_of(ALIGNMENT),
+        );
+
+        let dump = table.to_string();
+        assert!(dump.contains("image.wic4 : start=32766, size=28674, type=f"));
+        assert!(dump.contains("image.wic8 : start=57344, size=4096, type=83"));
+        assert!(!dump.contains("device:"));
+    }
+
+    #[test]
+    fn grow_primary_partition_shifts_following() {
+        let mut table: PartitionTable = DOS_DUMP.parse().unwrap();
+        let target = table.entries.iter().find(|e| e.num == 2).cloned().unwrap();
+        let limit = table.limit(&target, 59392);
+
+        assert_eq!(limit, 24576);
+
+        // rootA ends 2048 sectors behind the start of rootB
+        table.grow(
+            2,
+            10240,
+            (target.start + 10240 - limit).next_multiple_of(ALIGNMENT),
+        );
+
+        let dump = table.to_string();
+        assert!(dump.contains("image.wic1 : start=8192, size=2048, type=c, bootable"));
+        assert!(dump.contains("image.wic2 : start=16384, size=10240, type=83"));
+        assert!(dump.contains("image.wic3 : start=26624, size=2048, type=83"));
+        assert!(dump.contains("image.wic4 : start=34814, size=26626, type=f"));
+        assert!(dump.contains("image.wic8 : start=59392, size=2048, type=83"));
+    }
+
+    #[test]
+    fn append_gpt_partition() {
+        let mut table: PartitionTable = "label: gpt
+label-id: 6C3A1F4E-1D8B-4E0C-9A51-3B5E9A0D2F11
+device: image.wic
+unit: sectors
+first-lba: 34
+last-lba: 59358
+sector-size: 512
+
+image.wic1 : start=8192, size=2048, type=C12A7328-F81F-11D2-BA4B-00A0C93EC93B, name=\"boot\"
+image.wic2 : start=16384, size=2048, type=0FC63DAF-8483-4772-8E79-3D69D8477DE4, name=\"rootA\"
+image.wic7 : start=49152, size=3000, type=0FC63DAF-8483-4772-8E79-3D69D8477DE4, name=\"data\"
+"
+        .parse()
+        .unwrap();
+
+        assert_eq!(table.entries[1].name(), Some("rootA"));
+        assert!(validate_partition_name(&table, "data").is_err());
+        assert!(validate_partition_name(&table, "apps data").is_err());
+        assert!(validate_partition_name(&table, "apps").is_ok());
+
+        let entry = table
+            .append(4096, vec!["name=\"apps\"".to_string()])
+            .unwrap();
+
+        assert_eq!(entry.num, 8);
+        assert_eq!(entry.start, 53248);
+
+        let dump = table.to_string();
+        assert!(dump.contains("image.wic8 : start=53248, size=4096, name=\"apps\""));
+        assert!(!dump.contains("last-lba"));
+        assert!(dump.contains("first-lba: 34"));
+    }
+
+    #[test]
+    fn shrink_last_logical_partition() {
+        let mut table: PartitionTable = DOS_DUMP.parse().unwrap();
+
+        table.shrink(8, 1024);
+
+        let dump = table.to_string();
+        assert!(dump.contains("image.wic4 : start=32766, size=25602, type=f"));
+        assert!(dump.contains("image.wic8 : start=57344, size=1024, type=83"));
+        assert!(dump.contains("image.wic7 : start=49152, size=2048, type=83"));
+    }
+
+    #[test]
+    fn regenerate_dos_ids() {
+        let mut table: PartitionTable = DOS_DUMP.parse().unwrap();
+        let changes = table.regenerate_ids(|| {
+            uuid::Uuid::parse_str("0a1b2c3d-0000-4000-8000-000000000000").unwrap()
+        });
+
+        assert_eq!(
+            changes[0],
+            UuidChange {
+                object: "disk".to_string(),
+                old: "4bd5ce6a".to_string(),
+                new: "0a1b2c3d".to_string()
+            }
+        );
+        assert_eq!(changes[2].old, "4bd5ce6a-02");
+        assert_eq!(changes[2].new, "0a1b2c3d-02");
+        assert!(table.to_string().contains("label-id: 0x0a1b2c3d\n"));
+    }
+
+    #[test]
+    fn regenerate_gpt_ids() {
+        let mut table: PartitionTable = "label: gpt
+label-id: 6C3A1F4E-1D8B-4E0C-9A51-3B5E9A0D2F11
+device: image.wic
+unit: sectors
+
+image.wic1 : start=8192, size=2048, type=C12A7328-F81F-11D2-BA4B-00A0C93EC93B, uuid=1F2E3D4C-0000-4000-8000-000000000001, name=\"boot\"
+image.wic2 : start=16384, size=2048, type=0FC63DAF-8483-4772-8E79-3D69D8477DE4, uuid=1F2E3D4C-0000-4000-8000-000000000002, name=\"rootA\"
+"
+        .parse()
+        .unwrap();
+        let mut n = 0u128;
+        let changes = table.regenerate_ids(|| {
+            n += 1;
+            uuid::Uuid::from_u128(n)
+        });
+
+        assert_eq!(changes.len(), 3);
+        assert_eq!(changes[0].old, "6c3a1f4e-1d8b-4e0c-9a51-3b5e9a0d2f11");
+        assert_eq!(changes[0].new, "00000000-0000-0000-0000-000000000001");
+        assert_eq!(changes[2].object, "partition 2");
+        assert_eq!(changes[2].old, "1f2e3d4c-0000-4000-8000-000000000002");
+
+        let dump = table.to_string();
+        assert!(dump.contains("label-id: 00000000-0000-0000-0000-000000000001"));
+        assert!(dump.contains("uuid=00000000-0000-0000-0000-000000000003, name=\"rootA\""));
+    }
+
+    #[test]
+    fn minimum_size_of_resize2fs_output() {
+        assert_eq!(
+            parse_min_blocks("Estimated minimum size of the filesystem: 13694\n").unwrap(),
+            13694
+        );
+        assert!(parse_min_blocks("resize2fs 1.47.0 (5-Feb-2023)\n").is_err());
+    }
+
+    #[test]
+    fn move_tail_overlapping() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("image.wic");
+        let data: Vec<u8> = (0..=255u8).cycle().take(10000).collect();
+
+        fs::write(&path, &data).unwrap();
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        move_tail(&file, 1000, 512).unwrap();
+
+        let moved = fs::read(&path).unwrap();
+        assert_eq!(moved.len(), 10512);
+        assert_eq!(&moved[..1000], &data[..1000]);
+        assert_eq!(&moved[1512..], &data[1000..]);
+    }
+
+    #[test]
+    fn set_filesystem_arguments() {
+        let args = |command: &Command| {
+            command
+                .get_args()
+                .map(|arg| arg.to_string_lossy().to_string())
+                .collect::<Vec<_>>()
+        };
+        let partition_file = Path::new("partition-1.img");
+
+        let commands = set_filesystem_commands(
+            partition_file,
+            Filesystem::Ext,
+            Some("CUSTDATA"),
+            Some("5f0d8ab6-7c1e-4c4f-9e2a-0b6f4d3c2a11"),
+        )
+        .unwrap();
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0].get_program(), "tune2fs");
+        assert_eq!(
+            args(&commands[0]),
+            [
+                "-L",
+                "CUSTDATA",
+                "-U",
+                "5f0d8ab6-7c1e-4c4f-9e2a-0b6f4d3c2a11",
+                "partition-1.img"
+            ]
+        );
+
+        let commands = set_filesystem_commands(
+            partition_file,
+            Filesystem::Vfat,
+            Some("boot"),
+            Some("1a2b-3C4D"),
+        )
+        .unwrap();
+        assert_eq!(args(&commands[0]), ["partition-1.img", "BOOT"]);
+        assert_eq!(args(&commands[1]), ["-i", "partition-1.img", "1A2B3C4D"]);
+
+        assert!(
+            set_filesystem_commands(partition_file, Filesystem::Ext, None, Some("1234-ABCD"))
+                .is_err()
+        );
+        assert!(
+            set_filesystem_commands(partition_file, Filesystem::Vfat, None, Some("12345678"))
+                .is_err()
+        );
+        assert!(set_filesystem_commands(
+            partition_file,
+            Filesystem::Ext,
+            Some("a-label-longer-than-16"),
+            None
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,131 @@
+use super::boot::{edit_uboot_env_values, UBOOT_ENV_FILE};
+use super::get_file_path;
+use crate::file::functions::{
+    copy_from_image, copy_to_image, FileCopyFromParams, FileCopyToParams, Partition,
+};
+use crate::file::resize::{regenerate_uuids, UuidChange};
+use anyhow::{Context, Result};
+use log::debug;
+use std::fs;
+use std::path::Path;
+
+/// Files which may reference partitions or filesystems by their UUID.
+const REFERENCES: [(Partition, &str); 9] = [
+    (Partition::boot, "/cmdline.txt"),
+    (Partition::boot, "/extlinux/extlinux.conf"),
+    (Partition::boot, "/uEnv.txt"),
+    (Partition::boot, UBOOT_ENV_FILE),
+    (Partition::boot, "/EFI/BOOT/grub.cfg"),
+    (Partition::boot, "/EFI/BOOT/grubenv"),
+    (Partition::boot, "/grubenv"),
+    (Partition::rootA, "/etc/fstab"),
+    (Partition::rootB, "/etc/fstab"),
+];
+
+/// Replaces the old identifiers of `changes` in `content`, both in lower and
+/// upper case. Since identifiers keep their length, e.g. the size of a grub
+/// environment block is kept.
+fn replace_ids(content: &str, changes: &[UuidChange]) -> String {
+    changes.iter().fold(content.to_string(), |content, change| {
+        content
+            .replace(&change.old.to_lowercase(), &change.new.to_lowercase())
+            .replace(&change.old.to_uppercase(), &change.new.to_uppercase())
+    })
+}
+
+/// Replaces the identifiers of `changes` in all files of [`REFERENCES`]
+/// present in `image_file`. Returns the patched files.
+fn patch_references(image_file: &Path, changes: &[UuidChange]) -> Result<Vec<String>> {
+    let local = get_file_path(image_file, "reuuid-reference")?;
+    let mut patched = vec![];
+
+    for (partition, path) in REFERENCES {
+        if let Err(e) = copy_from_image(
+            &[FileCopyFromParams::new(
+                Path::new(path),
+                partition.clone(),
+                &local,
+            )],
+            image_file,
+        ) {
+            debug!("patch_references: no {partition}:{path}: {e:#}");
+            continue;
+        }
+
+        let content = fs::read(&local).context("patch_references: cannot read reference")?;
+        let edited = if path == UBOOT_ENV_FILE {
+            edit_uboot_env_values(&content, |value| replace_ids(value, changes))?
+        } else {
+            match std::str::from_utf8(&content) {
+                Ok(text) => replace_ids(text, changes).into_bytes(),
+                Err(_) => {
+                    debug!("patch_references: skip binary {partition}:{path}");
+                    content.clone()
+                }
+            }
+        };
+
+        if edited != content {
+            debug!("patch_references: patch {partition}:{path}");
+
+            fs::write(&local, edited).context("patch_references: cannot write reference")?;
+
+            let params = FileCopyToParams::new(&local, partition.clone(), Path::new(path));
+            let params = match partition {
+                Partition::boot => params,
+                _ => params.with_mode(0o644).with_owner(0, 0),
+            };
+
+            copy_to_image(&[params], image_file)?;
+            patched.push(format!("{partition}:{path}"));
+        }
+
+        fs::remove_file(&local).context("patch_references: cannot remove reference")?;
+    }
+
+    Ok(patched)
+}
+
+/// Regenerates the disk identifier, the partition UUIDs and the filesystem
+/// UUIDs of `image_file`, e.g. for images cloned for many devices, and
+/// replaces references to them in fstab and the boot configurations. Returns
+/// the replaced identifiers and the patched files.
+pub fn reuuid(image_file: &Path) -> Result<(Vec<UuidChange>, Vec<String>)> {
+    let changes = regenerate_uuids(image_file)?;
+    let patched = patch_references(image_file, &changes)?;
+
+    Ok((changes, patched))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replace_ids_in_both_cases() {
+        let changes = [
+            UuidChange {
+                object: "partition 2".to_string(),
+                old: "4bd5ce6a-02".to_string(),
+                new: "0a1b2c3d-02".to_string(),
+            },
+            UuidChange {
+                object: "filesystem of partition 1".to_string(),
+                old: "1234-ABCD".to_string(),
+                new: "BEEF-0042".to_string(),
+            },
+        ];
+
+        assert_eq!(
+            replace_ids(
+                "root=PARTUUID=4BD5CE6A-02 rootwait\nUUID=1234-abcd /boot vfat defaults 0 2\n",
+                &changes
+            ),
+            "root=PARTUUID=0A1B2C3D-02 rootwait\nUUID=beef-0042 /boot vfat defaults 0 2\n"
+        );
+        assert_eq!(
+            replace_ids("root=/dev/mmcblk0p2", &changes),
+            "root=/dev/mmcblk0p2"
+        );
+    }
+}
@@ -0,0 +1,214 @@
+use crate::error::ErrorKind;
+use crate::{keyvault, vault};
+use anyhow::{Context, Result};
+use log::debug;
+use regex::Regex;
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::Write;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+// larger files, e.g. docker images, aren't checked for sops encryption
+const SOPS_MAX_SIZE: u64 = 1024 * 1024;
+
+lazy_static::lazy_static! {
+    static ref RE_SOPS_YAML: Regex = Regex::new(r"(?m)^sops:\s*$").unwrap();
+}
+
+/// Source file of an injection, which is decrypted in memory if it is
+/// encrypted with age (`.age`), gpg (`.gpg`) or sops, respectively fetched into
+/// memory if it's an Azure Key Vault reference (`keyvault://<vault>/<name>`)
+/// or a HashiCorp Vault reference (`vault://<path>#<field>`).
+pub struct Secret {
+    path: PathBuf,
+    _memfd: Option<File>,
+}
+
+impl Secret {
+    /// Path of the plaintext, which is `/proc/self/fd/<fd>` of an anonymous
+    /// in memory file for decrypted and fetched files. The file descriptor
+    /// is inherited by child processes, so that the path is valid for e2cp
+    /// and mcopy too.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn in_memory(memfd: File) -> Secret {
+        Secret {
+            path: PathBuf::from(format!("/proc/self/fd/{}", memfd.as_raw_fd())),
+            _memfd: Some(memfd),
+        }
+    }
+}
+
+fn memfd() -> Result<File> {
+    let name = CString::new("omnect-cli-secret")?;
+    let fd = unsafe { libc::memfd_create(name.as_ptr(), 0) };
+
+    if fd == -1 {
+        return Err(std::io::Error::last_os_error()).context("memfd: cannot create memfd");
+    }
+
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Whether `file` is encrypted by sops, i.e. a json or yaml document with a
+/// top-level `sops` entry. Files sops doesn't know the format of, e.g. toml
+/// files, are stored as json.
+fn is_sops(file: &Path) -> bool {
+    if !fs::metadata(file).is_ok_and(|metadata| metadata.len() <= SOPS_MAX_SIZE) {
+        return false;
+    }
+
+    let Ok(content) = fs::read_to_string(file) else {
+        return false;
+    };
+
+    serde_json::from_str::<serde_json::Value>(&content)
+        .is_ok_and(|document| document.get("sops").is_some())
+        || RE_SOPS_YAML.is_match(&content)
+}
+
+/// Keeps `content`, e.g. a generated configuration containing credentials,
+/// in an anonymous in memory file instead of writing it to disk.
+pub fn in_memory(content: &[u8]) -> Result<Secret> {
+    let mut memfd = memfd()?;
+
+    memfd.write_all(content)?;
+
+    Ok(Secret::in_memory(memfd))
+}
+
+/// Whether `file` references a secret in a vault instead of a file.
+pub fn is_reference(file: &str) -> bool {
+    file.starts_with("keyvault://") || file.starts_with("vault://")
+}
+
+/// Decrypts `file` if it's encrypted, respectively fetches it if it's a
+/// vault reference, otherwise returns it as is. age files are decrypted by
+/// `age_identity`, gpg files by the keys of the gpg agent and sops files by
+/// the keys configured for sops.
+pub fn open(file: &Path, age_identity: Option<&Path>) -> Result<Secret> {
+    let content = if let Some(secret) = keyvault::SecretRef::from_path(file)? {
+        Some(secret.fetch()?)
+    } else if let Some(secret) = vault::SecretRef::from_path(file)? {
+        Some(secret.fetch()?)
+    } else {
+        None
+    };
+
+    if let Some(content) = content {
+        return in_memory(&content);
+    }
+
+    let mut decrypt = match file.extension().and_then(|e| e.to_str()) {
+        Some("age") => {
+            let identity = age_identity.context(ErrorKind::User).context(format!(
+                "open: no age identity to decrypt {}, set age_identity or OMNECT_CLI_AGE_IDENTITY",
+                file.to_string_lossy()
+            ))?;
+            let mut age = Command::new("age");
+            age.arg("--decrypt").arg("--identity").arg(identity);
+            age
+        }
+        Some("gpg") => {
+            let mut gpg = Command::new("gpg");
+            gpg.args(["--quiet", "--decrypt"]);
+            gpg
+        }
+        _ if is_sops(file) => {
+            let mut sops = Command::new("sops");
+            sops.arg("--decrypt");
+            sops
+        }
+        _ => {
+            return Ok(Secret {
+                path: file.to_path_buf(),
+                _memfd: None,
+            })
+        }
+    };
+
+    let memfd = memfd()?;
+
+    decrypt
+        .arg(file)
+        .stdin(Stdio::null())
+        .stdout(memfd.try_clone()?)
+        .stderr(Stdio::piped());
+
+    debug!("open: {decrypt:?}");
+
+    let output = decrypt
+        .output()
+        .context(ErrorKind::Environment)
+        .context(format!(
+            "open: cannot run {:?}, is it installed?",
+            decrypt.get_program()
+        ))?;
+
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "cannot decrypt {}: {}",
+            file.to_string_lossy(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .context(ErrorKind::User));
+    }
+
+    Ok(Secret::in_memory(memfd))
+}
+
+/// Reads the plaintext of `file`, which may be encrypted or a vault
+/// reference, e.g. of a configuration.
+pub fn read_to_string(file: &Path) -> Result<String> {
+    fs::read_to_string(open(file, None)?.path()).context(format!(
+        "read_to_string: cannot read {}",
+        file.to_string_lossy()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn open_plain_and_encrypted_files() {
+        let plain = open(Path::new("config.toml"), None).unwrap();
+
+        assert_eq!(plain.path(), Path::new("config.toml"));
+
+        let e = open(Path::new("config.toml.age"), None).err().unwrap();
+
+        assert_eq!(ErrorKind::classify(&e), ErrorKind::User);
+        assert!(e.to_string().contains("no age identity"));
+    }
+
+    #[test]
+    fn detect_sops_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("config.toml");
+        let json = dir.path().join("config.json");
+        let yaml = dir.path().join("config.yaml");
+
+        fs::write(&plain, "[provisioning]\nsource = \"dps\"\n").unwrap();
+        fs::write(
+            &json,
+            r#"{"data": "ENC[AES256_GCM,data:abc]", "sops": {"version": "3.8.1"}}"#,
+        )
+        .unwrap();
+        fs::write(
+            &yaml,
+            "psk: ENC[AES256_GCM,data:abc]\nsops:\n    version: 3.8.1\n",
+        )
+        .unwrap();
+
+        assert!(!is_sops(&plain));
+        assert!(!is_sops(&dir.path().join("missing.toml")));
+        assert!(is_sops(&json));
+        assert!(is_sops(&yaml));
+        assert_eq!(open(&plain, None).unwrap().path(), plain);
+    }
+}
@@ -0,0 +1,166 @@
+use super::{copy_to_image, get_file_path};
+use crate::file::functions::{read_file_from_image, FileCopyToParams, Partition};
+use anyhow::{Context, Result};
+use log::debug;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const LOADER_CONF: &str = "/loader/loader.conf";
+const KEYS_DIR: &str = "/loader/keys";
+
+// WIN_CERTIFICATE_UEFI_GUID header following the EFI_TIME of an
+// EFI_VARIABLE_AUTHENTICATION_2 descriptor
+const WIN_CERT_REVISION: u16 = 0x0200;
+const WIN_CERT_TYPE_EFI_GUID: u16 = 0x0ef1;
+
+/// When systemd-boot enrolls the keys, see loader.conf(5).
+#[derive(clap::ValueEnum, Clone, Debug, PartialEq)]
+#[clap(rename_all = "kebab-case")]
+#[allow(non_camel_case_types)]
+pub enum EnrollMode {
+    /// only on virtual machines
+    if_safe,
+    /// the user has to select the keys in the boot menu
+    manual,
+    /// on every device in setup mode
+    force,
+}
+
+impl EnrollMode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            EnrollMode::if_safe => "if-safe",
+            EnrollMode::manual => "manual",
+            EnrollMode::force => "force",
+        }
+    }
+}
+
+/// Signed secure boot key lists (*.auth) as created by sign-efi-sig-list.
+pub struct SecureBootKeys {
+    pub pk: PathBuf,
+    pub kek: PathBuf,
+    pub db: PathBuf,
+    pub dbx: Option<PathBuf>,
+}
+
+fn validate_auth_file(file: &Path) -> Result<()> {
+    let content = fs::read(file).context(format!(
+        "validate_auth_file: cannot read {}",
+        file.to_string_lossy()
+    ))?;
+
+    // EFI_TIME (16 bytes), dwLength (4 bytes), wRevision, wCertificateType
+    let header = content.get(20..24).map(|h| {
+        (
+            u16::from_le_bytes([h[0], h[1]]),
+            u16::from_le_bytes([h[2], h[3]]),
+        )
+    });
+
+    anyhow::ensure!(
+        header == Some((WIN_CERT_REVISION, WIN_CERT_TYPE_EFI_GUID)),
+        "{} is not a signed efi signature list (*.auth), e.g. created by sign-efi-sig-list",
+        file.to_string_lossy()
+    );
+
+    Ok(())
+}
+
+fn set_enroll_mode(loader_conf: &str, mode: &EnrollMode) -> String {
+    let mut lines = loader_conf
+        .lines()
+        .filter(|line| line.split_whitespace().next() != Some("secure-boot-enroll"))
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+
+    lines.push(format!("secure-boot-enroll {}", mode.as_str()));
+    lines.join("\n") + "\n"
+}
+
+/// Places the keys in the EFI system partition for automatic enrollment by
+/// systemd-boot, which enrolls them if the firmware is in setup mode.
+pub fn enroll_keys(
+    keys: &SecureBootKeys,
+    name: &str,
+    mode: &EnrollMode,
+    image_file: &Path,
+) -> Result<()> {
+    anyhow::ensure!(
+        !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_".contains(c)),
+        "invalid key set name {name:?}"
+    );
+
+    let mut keys_files = vec![("PK", &keys.pk), ("KEK", &keys.kek), ("db", &keys.db)];
+
+    if let Some(dbx) = &keys.dbx {
+        keys_files.push(("dbx", dbx));
+    }
+
+    let mut file_copies = vec![];
+
+    for (var, file) in keys_files {
+        validate_auth_file(file)?;
+
+        file_copies.push(FileCopyToParams::new(
+            file,
+            Partition::boot,
+            &Path::new(KEYS_DIR).join(name).join(format!("{var}.auth")),
+        ));
+    }
+
+    let loader_conf = read_file_from_image(LOADER_CONF, Partition::boot, image_file)
+        .unwrap_or_else(|e| {
+            debug!("enroll_keys: create {LOADER_CONF}: {e:#}");
+            String::new()
+        });
+    let local = get_file_path(image_file, "loader.conf")?;
+
+    fs::write(&local, set_enroll_mode(&loader_conf, mode))
+        .context("enroll_keys: cannot write loader.conf")?;
+
+    file_copies.push(FileCopyToParams::new(
+        &local,
+        Partition::boot,
+        Path::new(LOADER_CONF),
+    ));
+
+    copy_to_image(&file_copies, image_file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auth_file_validation() {
+        let dir = tempfile::tempdir().unwrap();
+        let auth = dir.path().join("PK.auth");
+        let mut content = vec![0u8; 20];
+
+        content.extend([0x00, 0x02, 0xf1, 0x0e]);
+        content.extend([0u8; 32]);
+        fs::write(&auth, &content).unwrap();
+
+        assert!(validate_auth_file(&auth).is_ok());
+
+        fs::write(&auth, "-----BEGIN CERTIFICATE-----\n").unwrap();
+
+        assert!(validate_auth_file(&auth).is_err());
+    }
+
+    #[test]
+    fn loader_conf_enroll_mode() {
+        assert_eq!(
+            set_enroll_mode("timeout 3\nsecure-boot-enroll off\n", &EnrollMode::force),
+            "timeout 3\nsecure-boot-enroll force\n"
+        );
+        assert_eq!(
+            set_enroll_mode("", &EnrollMode::if_safe),
+            "secure-boot-enroll if-safe\n"
+        );
+    }
+}
@@ -0,0 +1,267 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+const BLOCK_SIZE: u64 = 4096;
+
+/// Writer that skips all-zero blocks instead of writing them, so that they
+/// become holes of the destination file. Only suitable for files that are
+/// newly created, since skipped blocks keep their previous content.
+pub struct SparseWriter<'a> {
+    file: &'a mut File,
+    pos: u64,
+    len: u64,
+}
+
+impl<'a> SparseWriter<'a> {
+    pub fn new(file: &'a mut File) -> SparseWriter<'a> {
+        SparseWriter {
+            file,
+            pos: 0,
+            len: 0,
+        }
+    }
+}
+
+impl Write for SparseWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut rest = buf;
+
+        while !rest.is_empty() {
+            // split at block boundaries of the destination file
+            let block_len = (BLOCK_SIZE - self.pos % BLOCK_SIZE).min(rest.len() as u64) as usize;
+            let (block, tail) = rest.split_at(block_len);
+
+            if block.iter().any(|b| *b != 0) {
+                self.file.seek(SeekFrom::Start(self.pos))?;
+                self.file.write_all(block)?;
+                self.len = self.len.max(self.pos + block_len as u64);
+            }
+
+            self.pos += block_len as u64;
+            rest = tail;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        // trailing holes don't extend the file by themselves
+        if self.len < self.pos {
+            self.file.set_len(self.pos)?;
+            self.len = self.pos;
+        }
+
+        self.file.flush()
+    }
+}
+
+/// Reader that returns zeros for holes of a regular file without reading
+/// them, which saves reading e.g. the unused space of partitions.
+pub struct SparseReader {
+    file: File,
+    pos: u64,
+    len: u64,
+    hole_end: u64,
+    data_end: u64,
+}
+
+impl SparseReader {
+    pub fn new(file: File) -> std::io::Result<SparseReader> {
+        let metadata = file.metadata()?;
+
+        // pipes etc. are read as they are
+        let (len, data_end) = if metadata.is_file() {
+            (metadata.len(), 0)
+        } else {
+            (u64::MAX, u64::MAX)
+        };
+
+        Ok(SparseReader {
+            file,
+            pos: 0,
+            len,
+            hole_end: 0,
+            data_end,
+        })
+    }
+
+    /// Locates the next data extent starting at the current position.
+    fn next_extent(&mut self) -> std::io::Result<()> {
+        match seek_data(&self.file, self.pos, SeekTarget::Data) {
+            Ok(Some(data)) => {
+                self.hole_end = data;
+                self.data_end = seek_data(&self.file, data, SeekTarget::Hole)?
+                    .unwrap_or(self.len)
+                    .min(self.len);
+            }
+            // no more data: hole up to the end of file
+            Ok(None) => {
+                self.hole_end = self.len;
+                self.data_end = self.len;
+            }
+            // file system without hole detection
+            Err(_) => {
+                self.hole_end = self.pos;
+                self.data_end = self.len;
+            }
+        }
+
+        self.file.seek(SeekFrom::Start(self.hole_end))?;
+        Ok(())
+    }
+}
+
+impl Read for SparseReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+
+        if self.pos >= self.data_end {
+            self.next_extent()?;
+        }
+
+        let bytes = if self.pos < self.hole_end {
+            let bytes = (self.hole_end - self.pos).min(buf.len() as u64) as usize;
+            buf[..bytes].fill(0);
+            bytes
+        } else {
+            let max = (self.data_end - self.pos).min(buf.len() as u64) as usize;
+            self.file.read(&mut buf[..max])?
+        };
+
+        // a file that shrunk while reading ends early
+        if bytes == 0 {
+            self.len = self.pos;
+        }
+
+        self.pos += bytes as u64;
+        Ok(bytes)
+    }
+}
+
+/// Byte ranges `(start, end)` of the data extents of a regular file, i.e. all
+/// but its holes. File systems without hole detection return the whole file.
+pub fn data_extents(file: &File) -> std::io::Result<Vec<(u64, u64)>> {
+    let len = file.metadata()?.len();
+    let mut extents = vec![];
+    let mut pos = 0;
+
+    while pos < len {
+        let start = match seek_data(file, pos, SeekTarget::Data) {
+            Ok(Some(start)) => start,
+            Ok(None) => break,
+            Err(_) if extents.is_empty() => return Ok(vec![(0, len)]),
+            Err(e) => return Err(e),
+        };
+        let end = seek_data(file, start, SeekTarget::Hole)?
+            .unwrap_or(len)
+            .min(len);
+
+        extents.push((start, end));
+        pos = end;
+    }
+
+    Ok(extents)
+}
+
+enum SeekTarget {
+    Data,
+    Hole,
+}
+
+/// Returns the offset of the next data or hole at or after `offset`, or
+/// `None` if there is no more data.
+#[cfg(target_os = "linux")]
+fn seek_data(file: &File, offset: u64, target: SeekTarget) -> std::io::Result<Option<u64>> {
+    use std::os::fd::AsRawFd;
+
+    let whence = match target {
+        SeekTarget::Data => libc::SEEK_DATA,
+        SeekTarget::Hole => libc::SEEK_HOLE,
+    };
+
+    // SAFETY: the file descriptor is valid for the duration of the call
+    match unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence) } {
+        -1 => {
+            let e = std::io::Error::last_os_error();
+
+            if e.raw_os_error() == Some(libc::ENXIO) {
+                Ok(None)
+            } else {
+                Err(e)
+            }
+        }
+        offset => Ok(Some(offset as u64)),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn seek_data(_file: &File, _offset: u64, _target: SeekTarget) -> std::io::Result<Option<u64>> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn sparse_writer_creates_holes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("image.wic");
+        let mut file = File::create(&path).unwrap();
+        let mut content = vec![0u8; 64 * 1024 * 1024];
+
+        content[..3].copy_from_slice(b"MBR");
+        content[32 * 1024 * 1024] = 1;
+
+        let mut writer = SparseWriter::new(&mut file);
+        writer.write_all(&content).unwrap();
+        writer.flush().unwrap();
+
+        let metadata = std::fs::metadata(&path).unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), content);
+        assert_eq!(metadata.len(), 64 * 1024 * 1024);
+        assert!(metadata.blocks() * 512 < 1024 * 1024);
+    }
+
+    #[test]
+    fn data_extents_skip_holes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("image.wic");
+        let mut file = File::create(&path).unwrap();
+
+        file.set_len(16 * 1024 * 1024).unwrap();
+        file.seek(SeekFrom::Start(8 * 1024 * 1024)).unwrap();
+        file.write_all(b"rootfs").unwrap();
+
+        let extents = data_extents(&file).unwrap();
+
+        assert!(extents
+            .iter()
+            .any(|(start, end)| *start <= 8 * 1024 * 1024 && *end >= 8 * 1024 * 1024 + 6));
+        assert!(extents.iter().map(|(start, end)| end - start).sum::<u64>() < 16 * 1024 * 1024);
+    }
+
+    #[test]
+    fn sparse_reader_reads_holes_as_zeros() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("image.wic");
+        let mut file = File::create(&path).unwrap();
+
+        file.set_len(16 * 1024 * 1024).unwrap();
+        file.seek(SeekFrom::Start(8 * 1024 * 1024)).unwrap();
+        file.write_all(b"rootfs").unwrap();
+
+        let mut content = vec![];
+
+        SparseReader::new(File::open(&path).unwrap())
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+
+        assert_eq!(content, std::fs::read(&path).unwrap());
+    }
+}
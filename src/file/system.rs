@@ -0,0 +1,335 @@
+use super::{copy_from_image, copy_to_image, get_file_path};
+use crate::file::functions::{create_symlink, FileCopyFromParams, FileCopyToParams, Partition};
+use anyhow::{Context, Result};
+use regex::Regex;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+const ZONEINFO_DIR: &str = "/usr/share/zoneinfo";
+const LOCALTIME_FILE: &str = "/etc/localtime";
+const TIMEZONE_FILE: &str = "/etc/timezone";
+const LOCALE_FILE: &str = "/etc/locale.conf";
+const TIMESYNCD_CONFIG_FILE: &str = "/etc/systemd/timesyncd.conf.d/10-omnect-cli.conf";
+const TIMESYNCD_UNIT_FILE: &str = "/lib/systemd/system/systemd-timesyncd.service";
+const TIMESYNCD_WANTS_LINK: &str =
+    "/etc/systemd/system/sysinit.target.wants/systemd-timesyncd.service";
+const SYSTEMD_PROXY_FILE: &str = "/etc/systemd/system.conf.d/10-omnect-cli-proxy.conf";
+const PROFILE_PROXY_FILE: &str = "/etc/profile.d/proxy.sh";
+
+lazy_static::lazy_static! {
+    static ref RE_TIMEZONE: Regex = Regex::new(r"^[A-Za-z0-9_+-]+(/[A-Za-z0-9_+-]+)*$").unwrap();
+    static ref RE_LOCALE: Regex =
+        Regex::new(r"^([a-z]{2,3}(_[A-Z]{2})?(\.[A-Za-z0-9-]+)?(@[a-z]+)?|C(\.UTF-8)?|POSIX)$")
+            .unwrap();
+    // rfc1123 allows labels starting with a digit, e.g. 0.pool.ntp.org
+    static ref RE_NO_PROXY: Regex = Regex::new(r"^[a-zA-Z0-9.*:/_\[\]-]+$").unwrap();
+    static ref RE_NTP_SERVER: Regex = Regex::new(
+        r"^[a-zA-Z0-9]([a-zA-Z0-9-]*[a-zA-Z0-9])?(\.[a-zA-Z0-9]([a-zA-Z0-9-]*[a-zA-Z0-9])?)*$"
+    )
+    .unwrap();
+}
+
+/// Time related settings of the image, at least one of them has to be set.
+#[derive(Debug)]
+pub struct TimeConfig {
+    pub timezone: Option<String>,
+    pub ntp_servers: Vec<String>,
+    pub fallback_ntp_servers: Vec<String>,
+    pub locale: Option<String>,
+}
+
+fn validate_timezone(timezone: &str) -> Result<()> {
+    anyhow::ensure!(
+        RE_TIMEZONE.is_match(timezone),
+        "invalid timezone {timezone:?}, expected e.g. Europe/Berlin"
+    );
+
+    Ok(())
+}
+
+fn validate_locale(locale: &str) -> Result<()> {
+    anyhow::ensure!(
+        RE_LOCALE.is_match(locale),
+        "invalid locale {locale:?}, expected e.g. de_DE.UTF-8"
+    );
+
+    Ok(())
+}
+
+fn validate_ntp_server(server: &str) -> Result<()> {
+    anyhow::ensure!(
+        IpAddr::from_str(server).is_ok() || (server.len() <= 253 && RE_NTP_SERVER.is_match(server)),
+        "invalid ntp server {server:?}"
+    );
+
+    Ok(())
+}
+
+fn timesyncd_config(ntp_servers: &[String], fallback_ntp_servers: &[String]) -> Result<String> {
+    for server in ntp_servers.iter().chain(fallback_ntp_servers) {
+        validate_ntp_server(server)?;
+    }
+
+    let mut config = String::from("[Time]\n");
+
+    if !ntp_servers.is_empty() {
+        config.push_str(&format!("NTP={}\n", ntp_servers.join(" ")));
+    }
+
+    if !fallback_ntp_servers.is_empty() {
+        config.push_str(&format!("FallbackNTP={}\n", fallback_ntp_servers.join(" ")));
+    }
+
+    Ok(config)
+}
+
+/// Sets timezone, locale and the ntp servers of systemd-timesyncd, which is
+/// enabled if ntp servers are given.
+pub fn set_time_config(config: &TimeConfig, image_file: &Path) -> Result<()> {
+    anyhow::ensure!(
+        config.timezone.is_some()
+            || config.locale.is_some()
+            || !config.ntp_servers.is_empty()
+            || !config.fallback_ntp_servers.is_empty(),
+        "set_time_config: neither timezone, locale nor ntp servers given"
+    );
+
+    let mut files = vec![];
+
+    if let Some(locale) = &config.locale {
+        validate_locale(locale)?;
+
+        let locale_file = get_file_path(image_file, "locale.conf")?;
+
+        fs::write(&locale_file, format!("LANG={locale}\n"))
+            .context("set_time_config: cannot write locale.conf")?;
+        files.push(
+            FileCopyToParams::new(&locale_file, Partition::factory, Path::new(LOCALE_FILE))
+                .with_mode(0o644),
+        );
+    }
+
+    let ntp = !config.ntp_servers.is_empty() || !config.fallback_ntp_servers.is_empty();
+
+    if ntp {
+        let timesyncd_file = get_file_path(image_file, "timesyncd.conf")?;
+
+        fs::write(
+            &timesyncd_file,
+            timesyncd_config(&config.ntp_servers, &config.fallback_ntp_servers)?,
+        )
+        .context("set_time_config: cannot write timesyncd configuration")?;
+        files.push(
+            FileCopyToParams::new(
+                &timesyncd_file,
+                Partition::factory,
+                Path::new(TIMESYNCD_CONFIG_FILE),
+            )
+            .with_mode(0o644),
+        );
+    }
+
+    let zoneinfo = config
+        .timezone
+        .as_ref()
+        .map(|timezone| Path::new(ZONEINFO_DIR).join(timezone));
+
+    if let (Some(timezone), Some(zoneinfo)) = (&config.timezone, &zoneinfo) {
+        validate_timezone(timezone)?;
+
+        // the link target has to exist, otherwise the device falls back to UTC silently
+        copy_from_image(
+            &[FileCopyFromParams::new(
+                zoneinfo,
+                Partition::rootA,
+                &get_file_path(image_file, "zoneinfo")?,
+            )],
+            image_file,
+        )
+        .context(format!(
+            "set_time_config: timezone {timezone} is not available in the image"
+        ))?;
+
+        let timezone_file = get_file_path(image_file, "timezone")?;
+
+        fs::write(&timezone_file, format!("{timezone}\n"))
+            .context("set_time_config: cannot write timezone")?;
+        files.push(
+            FileCopyToParams::new(&timezone_file, Partition::factory, Path::new(TIMEZONE_FILE))
+                .with_mode(0o644),
+        );
+    }
+
+    copy_to_image(&files, image_file)?;
+
+    if let Some(zoneinfo) = &zoneinfo {
+        create_symlink(
+            Partition::factory,
+            Path::new(LOCALTIME_FILE),
+            zoneinfo,
+            image_file,
+        )?;
+    }
+
+    if ntp {
+        create_symlink(
+            Partition::factory,
+            Path::new(TIMESYNCD_WANTS_LINK),
+            Path::new(TIMESYNCD_UNIT_FILE),
+            image_file,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Proxy environment variables, lower case variants are honored by most
+/// tools, upper case ones by e.g. docker and the azure sdk.
+fn proxy_variables(url: &url::Url, no_proxy: &[String]) -> Result<Vec<(String, String)>> {
+    anyhow::ensure!(
+        ["http", "https"].contains(&url.scheme()) && url.host().is_some(),
+        "proxy {url} is not a http(s) url"
+    );
+    anyhow::ensure!(
+        !url.as_str().contains(['\'', '"', '\\', '`']),
+        "proxy {url} contains invalid characters"
+    );
+
+    for host in no_proxy {
+        anyhow::ensure!(
+            RE_NO_PROXY.is_match(host),
+            "invalid no-proxy entry {host:?}"
+        );
+    }
+
+    let mut variables = vec![];
+
+    for name in ["http_proxy", "https_proxy"] {
+        variables.push((name.to_string(), url.to_string()));
+        variables.push((name.to_uppercase(), url.to_string()));
+    }
+
+    if !no_proxy.is_empty() {
+        variables.push(("no_proxy".to_string(), no_proxy.join(",")));
+        variables.push(("NO_PROXY".to_string(), no_proxy.join(",")));
+    }
+
+    Ok(variables)
+}
+
+/// Configures `url` as proxy of all systemd services, e.g. docker,
+/// containerd, aziot and device update services, as well as of login shells.
+pub fn set_proxy(url: &url::Url, no_proxy: &[String], image_file: &Path) -> Result<()> {
+    let variables = proxy_variables(url, no_proxy)?;
+    let systemd_file = get_file_path(image_file, "proxy.conf")?;
+    let profile_file = get_file_path(image_file, "proxy.sh")?;
+
+    fs::write(
+        &systemd_file,
+        format!(
+            "[Manager]\nDefaultEnvironment={}\n",
+            variables
+                .iter()
+                .map(|(name, value)| format!("\"{name}={value}\""))
+                .collect::<Vec<_>>()
+                .join(" ")
+        ),
+    )
+    .context("set_proxy: cannot write systemd configuration")?;
+
+    fs::write(
+        &profile_file,
+        variables
+            .iter()
+            .map(|(name, value)| format!("export {name}='{value}'\n"))
+            .collect::<String>(),
+    )
+    .context("set_proxy: cannot write profile")?;
+
+    // credentials are only readable by pid 1 in the systemd configuration
+    let systemd_mode = if url.password().is_some() {
+        0o600
+    } else {
+        0o644
+    };
+
+    copy_to_image(
+        &[
+            FileCopyToParams::new(
+                &systemd_file,
+                Partition::factory,
+                Path::new(SYSTEMD_PROXY_FILE),
+            )
+            .with_mode(systemd_mode)
+            .with_owner(0, 0),
+            FileCopyToParams::new(
+                &profile_file,
+                Partition::factory,
+                Path::new(PROFILE_PROXY_FILE),
+            )
+            .with_mode(0o644)
+            .with_owner(0, 0),
+        ],
+        image_file,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timesyncd_config_with_servers() {
+        assert_eq!(
+            timesyncd_config(
+                &["ntp.customer.example".to_string(), "10.0.0.1".to_string()],
+                &["0.pool.ntp.org".to_string()]
+            )
+            .unwrap(),
+            "[Time]\nNTP=ntp.customer.example 10.0.0.1\nFallbackNTP=0.pool.ntp.org\n"
+        );
+        assert!(timesyncd_config(&["ntp server".to_string()], &[]).is_err());
+        assert!(timesyncd_config(&[], &["ntp\n[Time]".to_string()]).is_err());
+    }
+
+    #[test]
+    fn proxy_variables_for_url() {
+        let url = url::Url::parse("http://proxy.example.com:3128").unwrap();
+        let variables =
+            proxy_variables(&url, &["localhost".to_string(), ".local".to_string()]).unwrap();
+
+        assert_eq!(variables.len(), 6);
+        assert_eq!(
+            variables[0],
+            (
+                "http_proxy".to_string(),
+                "http://proxy.example.com:3128/".to_string()
+            )
+        );
+        assert_eq!(
+            variables[5],
+            ("NO_PROXY".to_string(), "localhost,.local".to_string())
+        );
+        assert_eq!(proxy_variables(&url, &[]).unwrap().len(), 4);
+        assert!(proxy_variables(&url, &["local host".to_string()]).is_err());
+        assert!(
+            proxy_variables(&url::Url::parse("socks5://proxy.example.com").unwrap(), &[]).is_err()
+        );
+    }
+
+    #[test]
+    fn timezone_and_locale_validation() {
+        assert!(validate_timezone("Europe/Berlin").is_ok());
+        assert!(validate_timezone("Etc/GMT+1").is_ok());
+        assert!(validate_timezone("UTC").is_ok());
+        assert!(validate_timezone("../../etc/shadow").is_err());
+        assert!(validate_timezone("/Europe/Berlin").is_err());
+
+        assert!(validate_locale("de_DE.UTF-8").is_ok());
+        assert!(validate_locale("C.UTF-8").is_ok());
+        assert!(validate_locale("en_US").is_ok());
+        assert!(validate_locale("de_DE.UTF-8\nLC_ALL=C").is_err());
+    }
+}
@@ -0,0 +1,230 @@
+use super::get_file_path;
+use crate::file::functions::FileCopyToParams;
+use anyhow::{Context, Result};
+use regex::{Captures, Regex};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use uuid::Uuid;
+
+lazy_static::lazy_static! {
+    static ref RE_PLACEHOLDER: Regex =
+        Regex::new(r"\{\{\s*([A-Za-z_][A-Za-z0-9_]*)\s*\}\}").unwrap();
+    static ref RE_NAME: Regex = Regex::new(r"^[A-Za-z_][A-Za-z0-9_]*$").unwrap();
+}
+
+/// Template variable as given on the command line: "key=value".
+#[derive(Clone, Debug, PartialEq)]
+pub struct TemplateVariable {
+    key: String,
+    value: String,
+}
+
+impl FromStr for TemplateVariable {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (key, value) = s.split_once('=').context("format not matched: key=value")?;
+
+        anyhow::ensure!(RE_NAME.is_match(key), "invalid variable name {key:?}");
+
+        Ok(TemplateVariable {
+            key: key.to_string(),
+            value: value.to_string(),
+        })
+    }
+}
+
+/// Values of template variables, which replace `{{key}}` placeholders.
+#[derive(Clone, Debug, Default)]
+pub struct TemplateVars(BTreeMap<String, String>);
+
+impl TemplateVars {
+    /// Loads the variables of toml `var_files` in the given order, variables
+    /// given by `vars` take precedence.
+    pub fn load(vars: &[TemplateVariable], var_files: &[PathBuf]) -> Result<TemplateVars> {
+        let mut values = BTreeMap::new();
+
+        for var_file in var_files {
+            let content = super::secret::read_to_string(var_file).context(format!(
+                "TemplateVars: cannot read {}",
+                var_file.to_string_lossy()
+            ))?;
+            let table: toml::Table = toml::from_str(&content).context(format!(
+                "TemplateVars: invalid variable file {}",
+                var_file.to_string_lossy()
+            ))?;
+
+            for (key, value) in table {
+                anyhow::ensure!(
+                    RE_NAME.is_match(&key),
+                    "invalid variable name {key:?} in {}",
+                    var_file.to_string_lossy()
+                );
+
+                let value = match value {
+                    toml::Value::String(value) => value,
+                    toml::Value::Integer(_) | toml::Value::Float(_) | toml::Value::Boolean(_) => {
+                        value.to_string()
+                    }
+                    _ => anyhow::bail!(
+                        "value of {key} in {} must be a string, number or boolean",
+                        var_file.to_string_lossy()
+                    ),
+                };
+
+                values.insert(key, value);
+            }
+        }
+
+        for var in vars {
+            values.insert(var.key.clone(), var.value.clone());
+        }
+
+        Ok(TemplateVars(values))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Replaces all placeholders of `content`. Placeholders without value
+    /// are an error, since they would end up unnoticed on the device.
+    pub fn render(&self, content: &str) -> Result<String> {
+        let mut unknown = vec![];
+
+        let rendered = RE_PLACEHOLDER.replace_all(content, |caps: &Captures| {
+            self.0.get(&caps[1]).cloned().unwrap_or_else(|| {
+                unknown.push(caps[1].to_string());
+                caps[0].to_string()
+            })
+        });
+
+        anyhow::ensure!(
+            unknown.is_empty(),
+            "render: no value for template variables: {}",
+            unknown.join(", ")
+        );
+
+        Ok(rendered.to_string())
+    }
+
+    /// Renders `file` to the directory of `image_file` and returns the path of
+    /// the rendered copy. Without variables, for binary files and for files
+    /// without placeholders `file` is returned as is.
+    pub fn render_file(&self, file: &Path, image_file: &Path) -> Result<PathBuf> {
+        if self.is_empty() {
+            return Ok(file.to_path_buf());
+        }
+
+        let Ok(content) = fs::read_to_string(file) else {
+            return Ok(file.to_path_buf());
+        };
+
+        if !RE_PLACEHOLDER.is_match(&content) {
+            return Ok(file.to_path_buf());
+        }
+
+        let rendered_file = get_file_path(
+            image_file,
+            &format!(
+                "{}-{}",
+                Uuid::new_v4(),
+                file.file_name()
+                    .context("render_file: cannot get file name")?
+                    .to_string_lossy()
+            ),
+        )?;
+
+        fs::write(
+            &rendered_file,
+            self.render(&content)
+                .context(format!("render_file: {}", file.to_string_lossy()))?,
+        )
+        .context("render_file: cannot write rendered file")?;
+        fs::set_permissions(&rendered_file, fs::metadata(file)?.permissions())
+            .context("render_file: cannot set permissions of rendered file")?;
+
+        Ok(rendered_file)
+    }
+
+    /// Renders the input files of `file_copy_params`.
+    pub fn render_copy_params(
+        &self,
+        file_copy_params: &[FileCopyToParams],
+        image_file: &Path,
+    ) -> Result<Vec<FileCopyToParams>> {
+        file_copy_params
+            .iter()
+            .map(|params| {
+                let in_file = self.render_file(params.in_file(), image_file)?;
+
+                Ok(params.clone().with_in_file(&in_file))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vars_from_files_and_command_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let var_file = dir.path().join("vars.toml");
+
+        fs::write(
+            &var_file,
+            "hostname = 'device-1'\nport = 8883\ntenant = 'a'\n",
+        )
+        .unwrap();
+
+        let vars = TemplateVars::load(
+            &[TemplateVariable::from_str("tenant=b").unwrap()],
+            &[var_file],
+        )
+        .unwrap();
+
+        assert_eq!(
+            vars.render("hostname = \"{{hostname}}\"\nport = {{ port }}\ntenant = \"{{tenant}}\"")
+                .unwrap(),
+            "hostname = \"device-1\"\nport = 8883\ntenant = \"b\""
+        );
+
+        let err = vars.render("{{hostname}} {{unknown}}").unwrap_err();
+
+        assert!(err.to_string().contains("unknown"));
+        assert!(TemplateVariable::from_str("invalid-name=1").is_err());
+        assert!(TemplateVariable::from_str("novalue").is_err());
+    }
+
+    #[test]
+    fn render_file_keeps_files_without_placeholders() {
+        let dir = tempfile::tempdir().unwrap();
+        let image_file = dir.path().join("image.wic");
+        let plain = dir.path().join("plain.conf");
+        let template = dir.path().join("config.toml");
+
+        fs::write(&plain, "hostname = \"fixed\"\n").unwrap();
+        fs::write(&template, "hostname = \"{{hostname}}\"\n").unwrap();
+
+        let vars = TemplateVars::load(
+            &[TemplateVariable::from_str("hostname=device-1").unwrap()],
+            &[],
+        )
+        .unwrap();
+
+        assert_eq!(vars.render_file(&plain, &image_file).unwrap(), plain);
+
+        let rendered = vars.render_file(&template, &image_file).unwrap();
+
+        assert_ne!(rendered, template);
+        assert!(rendered.to_string_lossy().ends_with("-config.toml"));
+        assert_eq!(
+            fs::read_to_string(rendered).unwrap(),
+            "hostname = \"device-1\"\n"
+        );
+    }
+}
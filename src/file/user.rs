@@ -0,0 +1,342 @@
+use super::{copy_to_image, get_file_path};
+use crate::file::functions::{read_file_from_image, FileCopyToParams, Partition};
+use anyhow::{Context, Result};
+use log::debug;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const FIRST_UID: u32 = 1000;
+const LAST_UID: u32 = 59999;
+const AUTHORIZED_KEYS_DIR: &str = "/etc/ssh/authorized_keys";
+
+/// Local user account to create or modify in the image.
+pub struct User {
+    pub name: String,
+    /// crypt(3) hash, e.g. created by "mkpasswd -m sha-512"; without a hash
+    /// the password is locked and only key based logins are possible
+    pub password_hash: Option<String>,
+    pub groups: Vec<String>,
+    pub authorized_keys: Option<PathBuf>,
+}
+
+fn validate_name(name: &str) -> Result<()> {
+    anyhow::ensure!(
+        (1..=32).contains(&name.len())
+            && name.starts_with(|c: char| c.is_ascii_lowercase() || c == '_')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "_-".contains(c)),
+        "invalid user or group name {name:?}"
+    );
+
+    Ok(())
+}
+
+fn validate_password_hash(hash: &str) -> Result<()> {
+    anyhow::ensure!(
+        hash.starts_with('$') && !hash.contains([':', '\n']),
+        "password hash must be in crypt(3) format, e.g. created by \"mkpasswd -m sha-512\""
+    );
+
+    Ok(())
+}
+
+fn validate_authorized_keys(authorized_keys: &Path) -> Result<()> {
+    let content = fs::read_to_string(authorized_keys).context(format!(
+        "validate_authorized_keys: cannot read {}",
+        authorized_keys.to_string_lossy()
+    ))?;
+
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        // keys might be preceded by options, e.g. 'from="10.0.0.0/8"'
+        anyhow::ensure!(
+            line.split_whitespace().any(|field| {
+                ["ssh-", "ecdsa-", "sk-"]
+                    .iter()
+                    .any(|prefix| field.starts_with(prefix))
+            }),
+            "line {}: not a ssh public key",
+            i + 1
+        );
+    }
+
+    Ok(())
+}
+
+/// Returns the `index`th field of the colon separated line starting with
+/// `name:`.
+fn field<'a>(content: &'a str, name: &str, index: usize) -> Option<&'a str> {
+    content
+        .lines()
+        .find(|line| line.split(':').next() == Some(name))
+        .and_then(|line| line.split(':').nth(index))
+}
+
+/// Lowest id >= FIRST_UID that isn't used in field `index` of `content`.
+fn free_id(content: &str, index: usize) -> Result<u32> {
+    let used = content
+        .lines()
+        .filter_map(|line| line.split(':').nth(index)?.parse::<u32>().ok())
+        .collect::<Vec<_>>();
+
+    (FIRST_UID..=LAST_UID)
+        .find(|id| !used.contains(id))
+        .context("no free id left")
+}
+
+/// Replaces the line of `name` or appends `line` if there is none.
+fn upsert_line(content: &str, name: &str, line: &str) -> String {
+    let mut lines = content
+        .lines()
+        .filter(|l| !l.is_empty())
+        .map(|l| {
+            if l.split(':').next() == Some(name) {
+                line.to_string()
+            } else {
+                l.to_string()
+            }
+        })
+        .collect::<Vec<_>>();
+
+    if field(content, name, 0).is_none() {
+        lines.push(line.to_string());
+    }
+
+    lines.join("\n") + "\n"
+}
+
+struct Account {
+    passwd: String,
+    shadow: String,
+    group: String,
+    uid: u32,
+    gid: u32,
+}
+
+/// Adds or modifies `user` in the given passwd, shadow and group files.
+/// Existing users keep their ids and home directory.
+fn account(user: &User, passwd: &str, shadow: &str, group: &str, days: u64) -> Result<Account> {
+    let name = user.name.as_str();
+
+    let (uid, gid) = match (field(passwd, name, 2), field(passwd, name, 3)) {
+        (Some(uid), Some(gid)) => (
+            uid.parse().context(format!("invalid uid of {name}"))?,
+            gid.parse().context(format!("invalid gid of {name}"))?,
+        ),
+        _ => (free_id(passwd, 2)?, free_id(group, 2)?),
+    };
+
+    let home = field(passwd, name, 5)
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("/home/{name}"));
+    let gecos = field(passwd, name, 4).unwrap_or_default();
+    let shell = field(passwd, name, 6).unwrap_or("/bin/sh");
+    let passwd = upsert_line(
+        passwd,
+        name,
+        &format!("{name}:x:{uid}:{gid}:{gecos}:{home}:{shell}"),
+    );
+
+    let password = user.password_hash.as_deref().unwrap_or("!");
+    let shadow = upsert_line(
+        shadow,
+        name,
+        &format!("{name}:{password}:{days}:0:99999:7:::"),
+    );
+
+    // primary group named after the user
+    let mut group = if field(group, name, 0).is_some() {
+        group.to_string()
+    } else {
+        upsert_line(group, name, &format!("{name}:x:{gid}:"))
+    };
+
+    for supplementary in &user.groups {
+        let members = field(&group, supplementary, 3)
+            .context(format!("group {supplementary} doesn't exist in image"))?;
+        let mut members = members
+            .split(',')
+            .filter(|m| !m.is_empty())
+            .collect::<Vec<_>>();
+
+        if members.contains(&name) {
+            continue;
+        }
+
+        members.push(name);
+
+        let line = format!(
+            "{supplementary}:{}:{}:{}",
+            field(&group, supplementary, 1).unwrap_or("x"),
+            field(&group, supplementary, 2).unwrap_or_default(),
+            members.join(",")
+        );
+
+        group = upsert_line(&group, supplementary, &line);
+    }
+
+    Ok(Account {
+        passwd,
+        shadow,
+        group,
+        uid,
+        gid,
+    })
+}
+
+/// Reads `file` from /etc of the factory partition, which overlays /etc of
+/// rootA, or from rootA if the factory partition doesn't contain it.
+fn read_etc_file(file: &str, image_file: &Path) -> Result<String> {
+    let path = Path::new("/etc").join(file);
+
+    read_file_from_image(&path, Partition::factory, image_file).or_else(|e| {
+        debug!("read_etc_file: {path:?} not in factory partition: {e:#}");
+
+        read_file_from_image(&path, Partition::rootA, image_file)
+            .context(format!("read_etc_file: {path:?} doesn't exist in image"))
+    })
+}
+
+pub fn set_user(user: &User, image_file: &Path) -> Result<()> {
+    validate_name(&user.name)?;
+    user.groups.iter().try_for_each(|g| validate_name(g))?;
+
+    if let Some(hash) = &user.password_hash {
+        validate_password_hash(hash)?;
+    }
+
+    if let Some(authorized_keys) = &user.authorized_keys {
+        validate_authorized_keys(authorized_keys)?;
+    }
+
+    let days = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .context("set_user: invalid system time")?
+        .as_secs()
+        / 86400;
+    let account = account(
+        user,
+        &read_etc_file("passwd", image_file)?,
+        &read_etc_file("shadow", image_file)?,
+        &read_etc_file("group", image_file)?,
+        days,
+    )?;
+
+    let mut file_copies = vec![];
+
+    for (file, content, mode) in [
+        ("passwd", &account.passwd, 0o644),
+        ("shadow", &account.shadow, 0o400),
+        ("group", &account.group, 0o644),
+    ] {
+        let local = get_file_path(image_file, file)?;
+
+        fs::write(&local, content).context(format!("set_user: cannot write {file}"))?;
+
+        file_copies.push(
+            FileCopyToParams::new(&local, Partition::factory, &Path::new("/etc").join(file))
+                .with_mode(mode)
+                .with_owner(0, 0),
+        );
+    }
+
+    // sshd requires authorized_keys to be owned by the user or root
+    if let Some(authorized_keys) = &user.authorized_keys {
+        file_copies.push(
+            FileCopyToParams::new(
+                authorized_keys,
+                Partition::factory,
+                &Path::new(AUTHORIZED_KEYS_DIR).join(&user.name),
+            )
+            .with_mode(0o600)
+            .with_owner(account.uid, account.gid),
+        );
+    }
+
+    copy_to_image(&file_copies, image_file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PASSWD: &str =
+        "root:x:0:0:root:/home/root:/bin/sh\nomnect:x:1000:1000::/home/omnect:/bin/bash\n";
+    const SHADOW: &str = "root:*:19000:0:99999:7:::\nomnect:!:19000:0:99999:7:::\n";
+    const GROUP: &str = "root:x:0:\nsudo:x:27:\ndocker:x:998:omnect\nomnect:x:1000:\n";
+
+    fn user(name: &str, groups: &[&str]) -> User {
+        User {
+            name: name.to_string(),
+            password_hash: Some("$6$salt$hash".to_string()),
+            groups: groups.iter().map(|g| g.to_string()).collect(),
+            authorized_keys: None,
+        }
+    }
+
+    #[test]
+    fn account_creates_user() {
+        let account = account(
+            &user("service", &["docker", "sudo"]),
+            PASSWD,
+            SHADOW,
+            GROUP,
+            20000,
+        )
+        .unwrap();
+
+        assert_eq!((account.uid, account.gid), (1001, 1001));
+        assert!(account
+            .passwd
+            .ends_with("service:x:1001:1001::/home/service:/bin/sh\n"));
+        assert!(account
+            .shadow
+            .ends_with("service:$6$salt$hash:20000:0:99999:7:::\n"));
+        assert!(account.group.contains("sudo:x:27:service\n"));
+        assert!(account.group.contains("docker:x:998:omnect,service\n"));
+        assert!(account.group.ends_with("service:x:1001:\n"));
+    }
+
+    #[test]
+    fn account_modifies_existing_user() {
+        let account = account(&user("omnect", &["docker"]), PASSWD, SHADOW, GROUP, 20000).unwrap();
+
+        assert_eq!((account.uid, account.gid), (1000, 1000));
+        assert_eq!(account.passwd, PASSWD);
+        assert!(account
+            .shadow
+            .contains("omnect:$6$salt$hash:20000:0:99999:7:::\n"));
+        assert_eq!(account.group, GROUP);
+        assert!(account(&user("omnect", &["video"]), PASSWD, SHADOW, GROUP, 20000).is_err());
+    }
+
+    #[test]
+    fn user_validation() {
+        assert!(validate_name("service-1").is_ok());
+        assert!(validate_name("Service").is_err());
+        assert!(validate_name("1service").is_err());
+        assert!(validate_password_hash("$6$salt$hash").is_ok());
+        assert!(validate_password_hash("plaintext").is_err());
+        assert!(validate_password_hash("$6$salt:hash").is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let authorized_keys = dir.path().join("authorized_keys");
+
+        fs::write(
+            &authorized_keys,
+            "# maintenance\nfrom=\"10.0.0.0/8\" ssh-ed25519 AAAAC3Nza user@host\n",
+        )
+        .unwrap();
+        assert!(validate_authorized_keys(&authorized_keys).is_ok());
+
+        fs::write(&authorized_keys, "not a key\n").unwrap();
+        assert!(validate_authorized_keys(&authorized_keys).is_err());
+    }
+}
@@ -0,0 +1,154 @@
+use super::{boot, copy_to_image, get_file_path};
+use crate::error::ErrorKind;
+use crate::file::functions::{
+    extract_partition, write_back_partition, FileCopyToParams, Partition,
+};
+use crate::validators::cert::{read_certs, read_key, validate_key_pair};
+use crate::validators::image::ext_size;
+use anyhow::{Context, Result};
+use log::debug;
+use openssl::pkcs7::{Pkcs7, Pkcs7Flags};
+use openssl::stack::Stack;
+use std::fs::{self, File};
+use std::path::Path;
+use std::process::Command;
+
+const ROOT_HASH_PARAM: &str = "roothash";
+const ROOT_HASH_SIGNATURE: &str = "/roothash.p7s";
+
+fn parse_root_hash(output: &str) -> Result<String> {
+    output
+        .lines()
+        .find_map(|line| line.strip_prefix("Root hash:"))
+        .map(|hash| hash.trim().to_string())
+        .filter(|hash| !hash.is_empty() && hash.chars().all(|c| c.is_ascii_hexdigit()))
+        .context("parse_root_hash: veritysetup printed no root hash")
+}
+
+/// Recomputes the hash tree of `partition_file` behind the filesystem and
+/// returns the new root hash.
+fn format_hash_tree(partition_file: &Path) -> Result<String> {
+    let (block_size, blocks) = ext_size(&mut File::open(partition_file)?, 0)
+        .context("format_hash_tree: rootA contains no ext filesystem")?;
+
+    let mut veritysetup = Command::new("veritysetup");
+    veritysetup
+        .arg("format")
+        .arg(format!("--data-block-size={block_size}"))
+        .arg(format!("--data-blocks={blocks}"))
+        .arg(format!("--hash-offset={}", block_size * blocks))
+        .arg(partition_file)
+        .arg(partition_file);
+
+    debug!("format_hash_tree: {veritysetup:?}");
+
+    let output = veritysetup
+        .output()
+        .context(ErrorKind::Environment)
+        .context("format_hash_tree: cannot run veritysetup")?;
+
+    anyhow::ensure!(
+        output.status.success(),
+        "format_hash_tree: cmd failed: {veritysetup:?}: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    parse_root_hash(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Detached PKCS#7 signature of the root hash as expected by dm-verity
+/// (root_hash_sig_key_desc) and systemd-veritysetup.
+fn sign_root_hash(root_hash: &str, cert_file: &Path, key_file: &Path) -> Result<Vec<u8>> {
+    let certs = read_certs(cert_file)?;
+    let key = read_key(key_file)?;
+    let signature = Pkcs7::sign(
+        &certs[0],
+        &key,
+        &Stack::new()?,
+        root_hash.as_bytes(),
+        Pkcs7Flags::BINARY | Pkcs7Flags::DETACHED | Pkcs7Flags::NOATTR | Pkcs7Flags::NOCERTS,
+    )
+    .context("sign_root_hash: cannot sign root hash")?;
+
+    signature
+        .to_der()
+        .context("sign_root_hash: cannot encode signature")
+}
+
+/// Recomputes the dm-verity hash tree of rootA after files were injected,
+/// sets the new root hash as "roothash=" on the kernel command line and
+/// stores its signature by `key_file` as /roothash.p7s in the boot
+/// partition. Returns the new root hash.
+pub fn update_verity(
+    cert_file: &Path,
+    key_file: &Path,
+    variable: &str,
+    image_file: &Path,
+) -> Result<String> {
+    validate_key_pair(cert_file, key_file).context(ErrorKind::User)?;
+
+    let partition_file = get_file_path(image_file, "verity-rootA.img")?;
+
+    extract_partition(&Partition::rootA, image_file, &partition_file)?;
+
+    let root_hash = format_hash_tree(&partition_file)?;
+
+    // fails if the hash tree grew beyond the partition
+    write_back_partition(&Partition::rootA, image_file, &partition_file)?;
+    fs::remove_file(&partition_file)?;
+
+    let signature_file = get_file_path(image_file, "roothash.p7s")?;
+
+    fs::write(
+        &signature_file,
+        sign_root_hash(&root_hash, cert_file, key_file)?,
+    )
+    .context("update_verity: cannot write signature")?;
+
+    copy_to_image(
+        &[FileCopyToParams::new(
+            &signature_file,
+            Partition::boot,
+            Path::new(ROOT_HASH_SIGNATURE),
+        )],
+        image_file,
+    )?;
+
+    boot::set_cmdline(
+        &[format!("{ROOT_HASH_PARAM}={root_hash}")],
+        &[ROOT_HASH_PARAM.to_string()],
+        variable,
+        image_file,
+    )?;
+
+    Ok(root_hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn root_hash_of_veritysetup_output() {
+        let output = "VERITY header information for rootA.img\n\
+                      UUID:            \t4f0c3d4e-8a0b-4f38-9f7e-2c3f4b8d1a2e\n\
+                      Hash type:       \t1\n\
+                      Salt:            \t5e1f\n\
+                      Root hash:      \t0a1b2c3d\n";
+
+        assert_eq!(parse_root_hash(output).unwrap(), "0a1b2c3d");
+        assert!(parse_root_hash("Hash type: 1\n").is_err());
+    }
+
+    #[test]
+    fn signed_root_hash() {
+        let signature = sign_root_hash(
+            "0a1b2c3d",
+            Path::new("testfiles/test-int-ca.pem"),
+            Path::new("testfiles/test-int-ca.key"),
+        )
+        .unwrap();
+
+        assert!(Pkcs7::from_der(&signature).is_ok());
+    }
+}
@@ -0,0 +1,154 @@
+use crate::file::qcow2;
+use anyhow::{Context, Result};
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+use uuid::Uuid;
+
+// Azure requires the virtual size of fixed VHDs to be aligned to 1 MiB
+const ALIGNMENT: u64 = 1024 * 1024;
+const FOOTER_SIZE: usize = 512;
+// seconds from 1970-01-01 to 2000-01-01, the epoch of VHD timestamps
+const VHD_EPOCH: i64 = 946_684_800;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+#[clap(rename_all = "verbatim")]
+#[allow(non_camel_case_types)]
+pub enum DiskFormat {
+    /// fixed size VHD aligned to 1 MiB, as required by Azure
+    vhd,
+    /// dynamic VHDX (needs qemu-img)
+    vhdx,
+}
+
+/// CHS geometry as calculated by the algorithm of the VHD specification.
+fn geometry(size: u64) -> (u16, u8, u8) {
+    let total_sectors = (size / 512).min(65535 * 16 * 255);
+
+    let (sectors_per_track, heads, cylinder_times_heads) = if total_sectors >= 65535 * 16 * 63 {
+        (255, 16, total_sectors / 255)
+    } else {
+        let mut sectors_per_track = 17;
+        let mut cylinder_times_heads = total_sectors / sectors_per_track;
+        let mut heads = ((cylinder_times_heads + 1023) / 1024).max(4);
+
+        if cylinder_times_heads >= heads * 1024 || heads > 16 {
+            sectors_per_track = 31;
+            heads = 16;
+            cylinder_times_heads = total_sectors / sectors_per_track;
+        }
+
+        if cylinder_times_heads >= heads * 1024 {
+            sectors_per_track = 63;
+            heads = 16;
+            cylinder_times_heads = total_sectors / sectors_per_track;
+        }
+
+        (sectors_per_track, heads, cylinder_times_heads)
+    };
+
+    (
+        (cylinder_times_heads / heads) as u16,
+        heads as u8,
+        sectors_per_track as u8,
+    )
+}
+
+fn footer(size: u64, timestamp: u32, unique_id: &[u8; 16]) -> [u8; FOOTER_SIZE] {
+    let (cylinders, heads, sectors_per_track) = geometry(size);
+    let mut footer = [0u8; FOOTER_SIZE];
+
+    footer[0..8].copy_from_slice(b"conectix");
+    footer[8..12].copy_from_slice(&2u32.to_be_bytes()); // features: reserved
+    footer[12..16].copy_from_slice(&0x0001_0000u32.to_be_bytes()); // format version
+    footer[16..24].copy_from_slice(&u64::MAX.to_be_bytes()); // data offset: none
+    footer[24..28].copy_from_slice(&timestamp.to_be_bytes());
+    footer[28..32].copy_from_slice(b"ocli");
+    footer[32..36].copy_from_slice(&0x0001_0000u32.to_be_bytes()); // creator version
+    footer[36..40].copy_from_slice(b"Wi2k");
+    footer[40..48].copy_from_slice(&size.to_be_bytes()); // original size
+    footer[48..56].copy_from_slice(&size.to_be_bytes()); // current size
+    footer[56..58].copy_from_slice(&cylinders.to_be_bytes());
+    footer[58] = heads;
+    footer[59] = sectors_per_track;
+    footer[60..64].copy_from_slice(&2u32.to_be_bytes()); // disk type: fixed
+    footer[68..84].copy_from_slice(unique_id);
+
+    let checksum = !footer
+        .iter()
+        .fold(0u32, |sum, byte| sum.wrapping_add(*byte as u32));
+    footer[64..68].copy_from_slice(&checksum.to_be_bytes());
+
+    footer
+}
+
+/// Converts the raw image `file` in place to a fixed size VHD: the image is
+/// padded to a multiple of 1 MiB and the VHD footer is appended. Returns the
+/// virtual size of the disk.
+pub fn to_fixed_vhd(file: &Path) -> Result<u64> {
+    let mut vhd = OpenOptions::new().write(true).open(file).context(format!(
+        "to_fixed_vhd: cannot open {}",
+        file.to_string_lossy()
+    ))?;
+    let size = vhd
+        .metadata()
+        .context("to_fixed_vhd: cannot get image size")?
+        .len();
+    let size = size.div_ceil(ALIGNMENT).max(1) * ALIGNMENT;
+    let timestamp = (time::OffsetDateTime::now_utc().unix_timestamp() - VHD_EPOCH) as u32;
+
+    // the padding stays sparse
+    vhd.set_len(size)
+        .context("to_fixed_vhd: cannot pad image")?;
+    vhd.seek(SeekFrom::Start(size))
+        .context("to_fixed_vhd: cannot seek to end of image")?;
+    vhd.write_all(&footer(size, timestamp, Uuid::new_v4().as_bytes()))
+        .context("to_fixed_vhd: cannot write footer")?;
+
+    Ok(size)
+}
+
+/// Converts the raw image `raw_file` to the dynamic VHDX `output`.
+pub fn to_vhdx(raw_file: &Path, output: &Path) -> Result<()> {
+    qcow2::convert("raw", raw_file, "vhdx", output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn footer_checksum() {
+        let footer = footer(ALIGNMENT, 0, &[0u8; 16]);
+        let checksum = u32::from_be_bytes(footer[64..68].try_into().unwrap());
+        let sum = footer
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !(64..68).contains(i))
+            .fold(0u32, |sum, (_, byte)| sum.wrapping_add(*byte as u32));
+
+        assert_eq!(&footer[0..8], b"conectix");
+        assert_eq!(checksum, !sum);
+    }
+
+    #[test]
+    fn geometry_of_spec() {
+        assert_eq!(geometry(ALIGNMENT), (30, 4, 17));
+        assert_eq!(geometry(200 * 1024 * ALIGNMENT), (65535, 16, 255));
+    }
+
+    #[test]
+    fn fixed_vhd_is_aligned() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("image.vhd");
+
+        std::fs::write(&file, [1u8; 1000]).unwrap();
+
+        assert_eq!(to_fixed_vhd(&file).unwrap(), ALIGNMENT);
+
+        let vhd = std::fs::read(&file).unwrap();
+
+        assert_eq!(vhd.len() as u64, ALIGNMENT + FOOTER_SIZE as u64);
+        assert_eq!(&vhd[ALIGNMENT as usize..][..8], b"conectix");
+    }
+}
@@ -0,0 +1,748 @@
+use crate::config::UserConfig;
+use crate::file::{
+    self,
+    compression::{self, Compression},
+    functions::{FileCopyToParams, Partition},
+    template::{TemplateVariable, TemplateVars},
+};
+use crate::{copy_file, move_file, provenance, working_image, TempDirGuard};
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use uuid::Uuid;
+
+const DEVICE_ID_COLUMN: &str = "device_id";
+
+/// Injections applied to the image of every device. Relative paths are
+/// relative to the directory of the manifest.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FleetManifest {
+    /// identity config.toml, rendered with the variables of the device
+    identity_config: Option<PathBuf>,
+    /// dps payload, rendered with the variables of the device
+    dps_payload: Option<PathBuf>,
+    /// hostname, e.g. "{{device_id}}"
+    hostname: Option<String>,
+    /// salt the machine-id is derived from together with the hostname
+    machine_id_salt: Option<String>,
+    /// generates a device certificate and key for x509 based dps provisioning
+    device_certificate: Option<DeviceCertificate>,
+    #[serde(default)]
+    files: Vec<FileInjection>,
+    /// regenerates the partition and filesystem UUIDs of every image
+    #[serde(default)]
+    reuuid: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DeviceCertificate {
+    intermediate_full_chain_cert: PathBuf,
+    intermediate_key: PathBuf,
+    days: u32,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileInjection {
+    source: PathBuf,
+    /// destination in the format partition:path, e.g. factory:/etc/motd
+    destination: String,
+}
+
+impl FileInjection {
+    fn copy_params(&self) -> Result<FileCopyToParams> {
+        let (partition, out_file) = self
+            .destination
+            .split_once(':')
+            .context("format not matched: partition:path")?;
+        let out_file = Path::new(out_file);
+
+        anyhow::ensure!(
+            out_file.is_absolute(),
+            "destination {} isn't an absolute path",
+            self.destination
+        );
+        anyhow::ensure!(
+            self.source.try_exists().is_ok_and(|exists| exists),
+            "source {} doesn't exist",
+            self.source.to_string_lossy()
+        );
+
+        Ok(FileCopyToParams::new(
+            &self.source,
+            Partition::from_str(partition)?,
+            out_file,
+        ))
+    }
+}
+
+impl FleetManifest {
+    pub fn load(manifest: &Path) -> Result<FleetManifest> {
+        let content = file::secret::read_to_string(manifest).context(format!(
+            "FleetManifest: cannot read {}",
+            manifest.to_string_lossy()
+        ))?;
+        let mut fleet_manifest: FleetManifest = toml::from_str(&content).context(format!(
+            "FleetManifest: invalid manifest {}",
+            manifest.to_string_lossy()
+        ))?;
+        let dir = manifest.parent().unwrap_or(Path::new(""));
+        let resolve = |path: &mut PathBuf| {
+            if !path.to_str().is_some_and(file::secret::is_reference) {
+                *path = dir.join(&*path)
+            }
+        };
+
+        if let Some(identity_config) = &mut fleet_manifest.identity_config {
+            resolve(identity_config);
+        }
+
+        if let Some(dps_payload) = &mut fleet_manifest.dps_payload {
+            resolve(dps_payload);
+        }
+
+        if let Some(device_certificate) = &mut fleet_manifest.device_certificate {
+            resolve(&mut device_certificate.intermediate_full_chain_cert);
+            resolve(&mut device_certificate.intermediate_key);
+        }
+
+        for injection in &mut fleet_manifest.files {
+            resolve(&mut injection.source);
+            injection
+                .copy_params()
+                .context(format!("FleetManifest: invalid file {injection:?}"))?;
+        }
+
+        anyhow::ensure!(
+            fleet_manifest.identity_config.is_some() || fleet_manifest.dps_payload.is_none(),
+            "FleetManifest: dps_payload requires identity_config"
+        );
+
+        Ok(fleet_manifest)
+    }
+
+    /// Manifest of `image clone`: fresh UUIDs, the `hostname` template and
+    /// optionally a device certificate issued by the intermediate certificate
+    /// and key of `device_certificate` for every copy.
+    pub fn for_clones(
+        hostname: Option<String>,
+        device_certificate: Option<(PathBuf, PathBuf)>,
+        days: u32,
+    ) -> FleetManifest {
+        FleetManifest {
+            identity_config: None,
+            dps_payload: None,
+            hostname,
+            machine_id_salt: None,
+            device_certificate: device_certificate.map(
+                |(intermediate_full_chain_cert, intermediate_key)| DeviceCertificate {
+                    intermediate_full_chain_cert,
+                    intermediate_key,
+                    days,
+                },
+            ),
+            files: vec![],
+            reuuid: true,
+        }
+    }
+}
+
+/// JSON schema of [`FleetManifest`], e.g. for validation and completion of
+/// manifests in editors. Has to be kept in sync with the manifest types.
+pub fn manifest_schema() -> serde_json::Value {
+    let partitions = Partition::value_variants()
+        .iter()
+        .map(|partition| partition.to_string())
+        .collect::<Vec<_>>()
+        .join("|");
+
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "omnect-cli fleet manifest",
+        "description": "Injections applied to the image of every device by \"omnect-cli fleet provision\". Relative paths are relative to the directory of the manifest.",
+        "type": "object",
+        "additionalProperties": false,
+        "properties": {
+            "identity_config": {
+                "description": "identity config.toml, rendered with the variables of the device",
+                "type": "string"
+            },
+            "dps_payload": {
+                "description": "dps payload, rendered with the variables of the device",
+                "type": "string"
+            },
+            "hostname": {
+                "description": "hostname, e.g. \"{{device_id}}\"",
+                "type": "string"
+            },
+            "machine_id_salt": {
+                "description": "salt the machine-id is derived from together with the hostname",
+                "type": "string"
+            },
+            "device_certificate": {
+                "description": "generates a device certificate and key for x509 based dps provisioning",
+                "type": "object",
+                "additionalProperties": false,
+                "required": ["intermediate_full_chain_cert", "intermediate_key", "days"],
+                "properties": {
+                    "intermediate_full_chain_cert": { "type": "string" },
+                    "intermediate_key": { "type": "string" },
+                    "days": { "type": "integer", "minimum": 1, "maximum": u32::MAX }
+                }
+            },
+            "reuuid": {
+                "description": "regenerates the partition and filesystem UUIDs of every image",
+                "type": "boolean"
+            },
+            "files": {
+                "description": "files copied to the image, rendered with the variables of the device",
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "additionalProperties": false,
+                    "required": ["source", "destination"],
+                    "properties": {
+                        "source": { "type": "string" },
+                        "destination": {
+                            "description": "destination in the format partition:path, e.g. factory:/etc/motd",
+                            "type": "string",
+                            "pattern": format!("^({partitions}):/")
+                        }
+                    }
+                }
+            }
+        },
+        "dependentRequired": {
+            "dps_payload": ["identity_config"]
+        }
+    })
+}
+
+/// Device of the device list with the values of its row as template
+/// variables.
+#[derive(Debug)]
+pub struct FleetDevice {
+    pub device_id: String,
+    vars: Vec<TemplateVariable>,
+}
+
+/// Records of a csv file, fields may be quoted with '"'.
+fn csv_records(content: &str) -> Result<Vec<Vec<String>>> {
+    let mut records = vec![];
+    let mut record = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    // byte order mark of csv files exported by spreadsheets
+    let mut chars = content.trim_start_matches('\u{feff}').chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            ('"', true) => quoted = false,
+            ('"', false) if field.trim().is_empty() => {
+                field.clear();
+                quoted = true;
+            }
+            (',', false) => record.push(std::mem::take(&mut field)),
+            ('\r', false) => {}
+            ('\n', false) => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            (c, _) => field.push(c),
+        }
+    }
+
+    anyhow::ensure!(!quoted, "csv_records: unterminated quoted field");
+
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+
+    Ok(records
+        .into_iter()
+        .map(|record| {
+            record
+                .into_iter()
+                .map(|field| field.trim().to_string())
+                .collect::<Vec<_>>()
+        })
+        .filter(|record| record.iter().any(|field| !field.is_empty()))
+        .collect())
+}
+
+/// The device id is part of the image file name.
+fn is_valid_device_id(device_id: &str) -> bool {
+    !device_id.is_empty() && !device_id.starts_with('.') && !device_id.contains(['/', '\\'])
+}
+
+/// Parses the device list, a csv file with a header row. The column
+/// "device_id" is required, all columns are available as template variables.
+pub fn parse_devices(content: &str) -> Result<Vec<FleetDevice>> {
+    let mut records = csv_records(content)?.into_iter();
+    let header = records.next().context("parse_devices: empty device list")?;
+    let id_column = header
+        .iter()
+        .position(|column| column == DEVICE_ID_COLUMN)
+        .context(format!("parse_devices: no column {DEVICE_ID_COLUMN}"))?;
+    let mut device_ids = HashSet::new();
+    let mut devices = vec![];
+
+    for (row, record) in records.enumerate() {
+        anyhow::ensure!(
+            record.len() == header.len(),
+            "parse_devices: row {} has {} instead of {} columns",
+            row + 1,
+            record.len(),
+            header.len()
+        );
+
+        let device_id = record[id_column].clone();
+
+        anyhow::ensure!(
+            is_valid_device_id(&device_id),
+            "parse_devices: invalid device id {device_id:?} in row {}",
+            row + 1
+        );
+        anyhow::ensure!(
+            device_ids.insert(device_id.clone()),
+            "parse_devices: duplicate device id {device_id}"
+        );
+
+        let vars = header
+            .iter()
+            .zip(record)
+            .map(|(column, value)| TemplateVariable::from_str(&format!("{column}={value}")))
+            .collect::<Result<Vec<_>>>()
+            .context("parse_devices: invalid column name")?;
+
+        devices.push(FleetDevice { device_id, vars });
+    }
+
+    Ok(devices)
+}
+
+/// Devices of `count` copies of `base_image` named by `name_pattern`. The
+/// template variables `n`, the number of the copy zero padded to the width of
+/// `count`, and `image`, the file name of `base_image` without extensions, are
+/// available to the name and, together with `device_id`, to the injections.
+pub fn clone_devices(
+    count: usize,
+    name_pattern: &str,
+    base_image: &Path,
+) -> Result<Vec<FleetDevice>> {
+    let image = base_image
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.split('.').next())
+        .context("clone_devices: invalid image file name")?;
+    anyhow::ensure!(count > 0, "clone_devices: count has to be at least 1");
+
+    let width = count.to_string().len();
+    let mut device_ids = HashSet::new();
+    let mut devices = vec![];
+
+    for n in 1..=count {
+        let mut vars = vec![
+            TemplateVariable::from_str(&format!("n={n:0width$}"))?,
+            TemplateVariable::from_str(&format!("image={image}"))?,
+        ];
+        let device_id = TemplateVars::load(&vars, &[])?.render(name_pattern)?;
+
+        anyhow::ensure!(
+            is_valid_device_id(&device_id),
+            "clone_devices: invalid name {device_id:?} of copy {n}"
+        );
+        anyhow::ensure!(
+            device_ids.insert(device_id.clone()),
+            "clone_devices: duplicate name {device_id}, the name pattern has to contain {{{{n}}}}"
+        );
+
+        vars.push(TemplateVariable::from_str(&format!(
+            "{DEVICE_ID_COLUMN}={device_id}"
+        ))?);
+        devices.push(FleetDevice { device_id, vars });
+    }
+
+    Ok(devices)
+}
+
+/// Provisioned image of a device.
+#[derive(Debug, Serialize)]
+pub struct ProvisionedImage {
+    pub device_id: String,
+    pub image: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_cert: Option<PathBuf>,
+}
+
+pub struct ProvisionOptions {
+    pub jobs: usize,
+    pub generate_bmap: bool,
+    pub compression: Option<Compression>,
+}
+
+/// Applies the injections of `manifest` for `device` to `image_file`.
+/// Returns the path of the generated device certificate, if any.
+fn provision_device(
+    manifest: &FleetManifest,
+    device: &FleetDevice,
+    image_file: &Path,
+    output_dir: &Path,
+) -> Result<Option<PathBuf>> {
+    let vars = TemplateVars::load(&device.vars, &[])?;
+
+    if manifest.reuuid {
+        file::reuuid::reuuid(image_file)?;
+    }
+
+    if let Some(identity_config) = &manifest.identity_config {
+        let identity_config = vars.render_file(identity_config, image_file)?;
+        let payload = manifest
+            .dps_payload
+            .as_ref()
+            .map(|payload| vars.render_file(payload, image_file))
+            .transpose()?;
+
+        file::set_identity_config(&identity_config, image_file, payload.as_deref())?;
+    }
+
+    // after the identity config, which might configure a hostname as well
+    if let Some(hostname) = &manifest.hostname {
+        file::set_hostname(
+            &vars.render(hostname)?,
+            manifest.machine_id_salt.as_deref(),
+            image_file,
+        )?;
+    }
+
+    let mut device_cert = None;
+
+    if let Some(certificate) = &manifest.device_certificate {
+        let intermediate_full_chain_cert =
+            file::secret::open(&certificate.intermediate_full_chain_cert, None)?;
+        let crypto = omnect_crypto::Crypto::new(
+            fs::read_to_string(file::secret::open(&certificate.intermediate_key, None)?.path())
+                .context("couldn't read intermediate key")?
+                .as_bytes(),
+            fs::read_to_string(intermediate_full_chain_cert.path())
+                .context("couldn't read intermediate fullchain cert")?
+                .as_bytes(),
+        )?;
+        let (device_cert_pem, device_key_pem) = crypto
+            .create_cert_and_key(&device.device_id, &None, certificate.days)
+            .context("couldn't create device cert and key")?;
+        let device_cert_path = file::get_file_path(image_file, "device_cert_path.pem")?;
+        let device_key_path = file::get_file_path(image_file, "device_key_path.key.pem")?;
+
+        fs::write(&device_cert_path, &device_cert_pem)
+            .context("provision_device: write device cert")?;
+        fs::write(&device_key_path, device_key_pem)
+            .context("provision_device: write device key")?;
+        file::set_device_cert(
+            Some(intermediate_full_chain_cert.path()),
+            &device_cert_path,
+            &device_key_path,
+            image_file,
+        )?;
+
+        // the certificate is needed to register the device, the key stays in the image
+        let cert_file = output_dir.join(format!("{}.cert.pem", device.device_id));
+
+        fs::write(&cert_file, device_cert_pem).context("provision_device: write device cert")?;
+        device_cert = Some(cert_file);
+    }
+
+    if !manifest.files.is_empty() {
+        let file_copy_params = manifest
+            .files
+            .iter()
+            .map(FileInjection::copy_params)
+            .collect::<Result<Vec<_>>>()?;
+
+        file::copy_to_image(
+            &vars.render_copy_params(&file_copy_params, image_file)?,
+            image_file,
+        )?;
+    }
+
+    Ok(device_cert)
+}
+
+fn provision_image(
+    device: &FleetDevice,
+    base_image: &Path,
+    working_base_image: &Path,
+    manifest: &FleetManifest,
+    output_dir: &Path,
+    options: &ProvisionOptions,
+    user_config: &UserConfig,
+) -> Result<ProvisionedImage> {
+    let tmp_dir = user_config.workdir().join(Uuid::new_v4().to_string());
+
+    fs::create_dir_all(&tmp_dir).context(format!(
+        "provision_image: couldn't create {}",
+        tmp_dir.to_string_lossy()
+    ))?;
+
+    let _guard = TempDirGuard::new(tmp_dir.clone());
+    let mut image_file = tmp_dir.join(format!(
+        "{}.{}",
+        device.device_id,
+        working_base_image
+            .extension()
+            .unwrap_or(std::ffi::OsStr::new("wic"))
+            .to_string_lossy()
+    ));
+
+    copy_file(working_base_image, &image_file)?;
+
+    let device_cert = provision_device(manifest, device, &image_file, output_dir)?;
+
+    provenance::embed(&image_file, base_image, &user_config.invocation)?;
+
+    if options.generate_bmap {
+        file::functions::generate_bmap_file(
+            image_file.to_str().context("cannot get image file path")?,
+        )?;
+
+        let bmap_file = format!("{}.bmap", image_file.to_string_lossy());
+
+        move_file(
+            Path::new(&bmap_file),
+            &output_dir.join(Path::new(&bmap_file).file_name().unwrap_or_default()),
+        )?;
+    }
+
+    if let Some(compression) = &options.compression {
+        image_file = compression::compress(&image_file, compression)?;
+    }
+
+    let dest_image_file = output_dir.join(
+        image_file
+            .file_name()
+            .context("cannot get image file name")?,
+    );
+
+    move_file(&image_file, &dest_image_file)?;
+
+    if user_config.modification_log.unwrap_or(false) {
+        provenance::record_copy(base_image, &dest_image_file, &user_config.invocation)?;
+    }
+
+    info!("provisioned {}", dest_image_file.to_string_lossy());
+
+    Ok(ProvisionedImage {
+        device_id: device.device_id.clone(),
+        image: dest_image_file,
+        device_cert,
+    })
+}
+
+/// Creates an image per device of `devices` in `output_dir` by applying the
+/// injections of `manifest` to a copy of `base_image`. Up to `options.jobs`
+/// images are provisioned in parallel. A failing device doesn't abort the
+/// provisioning of the others.
+pub fn provision(
+    devices: &[FleetDevice],
+    base_image: &Path,
+    manifest: &FleetManifest,
+    output_dir: &Path,
+    options: &ProvisionOptions,
+    user_config: &UserConfig,
+) -> Result<Vec<ProvisionedImage>> {
+    fs::create_dir_all(output_dir).context(format!(
+        "provision: couldn't create {}",
+        output_dir.to_string_lossy()
+    ))?;
+
+    // decompressed once for all devices
+    let working_base_image = working_image(base_image, false, user_config)?;
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(vec![]);
+
+    std::thread::scope(|scope| {
+        for _ in 0..options.jobs.clamp(1, devices.len().max(1)) {
+            scope.spawn(|| {
+                while let Some(device) = devices.get(next.fetch_add(1, Ordering::SeqCst)) {
+                    let result = provision_image(
+                        device,
+                        base_image,
+                        &working_base_image.file,
+                        manifest,
+                        output_dir,
+                        options,
+                        user_config,
+                    );
+
+                    if let Err(e) = &result {
+                        error!("cannot provision {}: {e:#}", device.device_id);
+                    }
+
+                    results
+                        .lock()
+                        .unwrap()
+                        .push((device.device_id.clone(), result));
+                }
+            });
+        }
+    });
+
+    let mut provisioned = vec![];
+    let mut failed = vec![];
+
+    for (device_id, result) in results.into_inner().unwrap() {
+        match result {
+            Ok(image) => provisioned.push(image),
+            Err(_) => failed.push(device_id),
+        }
+    }
+
+    anyhow::ensure!(
+        failed.is_empty(),
+        "provision: {} of {} devices failed: {}",
+        failed.len(),
+        devices.len(),
+        failed.join(", ")
+    );
+
+    provisioned.sort_by(|a, b| a.device_id.cmp(&b.device_id));
+
+    Ok(provisioned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_device_list() {
+        let devices = parse_devices(
+            "device_id,tenant,location\n\
+            device-1,customer-a,\"Hall 1, Gate 2\"\n\
+            \n\
+            device-2,customer-b,\"\"\"Lab\"\"\"\r\n",
+        )
+        .unwrap();
+
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].device_id, "device-1");
+
+        let vars = TemplateVars::load(&devices[0].vars, &[]).unwrap();
+
+        assert_eq!(
+            vars.render("{{device_id}} {{tenant}} {{location}}")
+                .unwrap(),
+            "device-1 customer-a Hall 1, Gate 2"
+        );
+        assert_eq!(
+            TemplateVars::load(&devices[1].vars, &[])
+                .unwrap()
+                .render("{{location}}")
+                .unwrap(),
+            "\"Lab\""
+        );
+
+        assert!(parse_devices("tenant\ncustomer-a\n").is_err());
+        assert!(parse_devices("device_id,tenant\ndevice-1\n").is_err());
+        assert!(parse_devices("device_id\ndevice-1\ndevice-1\n").is_err());
+        assert!(parse_devices("device_id\n../device-1\n").is_err());
+        assert!(parse_devices("device_id,invalid-column\ndevice-1,a\n").is_err());
+    }
+
+    #[test]
+    fn clone_device_names() {
+        let devices =
+            clone_devices(12, "{{image}}-{{n}}", Path::new("/tmp/golden.wic.xz")).unwrap();
+
+        assert_eq!(devices.len(), 12);
+        assert_eq!(devices[0].device_id, "golden-01");
+        assert_eq!(devices[11].device_id, "golden-12");
+        assert_eq!(
+            TemplateVars::load(&devices[1].vars, &[])
+                .unwrap()
+                .render("omnect-{{n}} {{device_id}}")
+                .unwrap(),
+            "omnect-02 golden-02"
+        );
+
+        assert!(clone_devices(0, "{{n}}", Path::new("golden.wic")).is_err());
+        assert!(clone_devices(2, "golden", Path::new("golden.wic")).is_err());
+        assert!(clone_devices(2, "../{{n}}", Path::new("golden.wic")).is_err());
+    }
+
+    #[test]
+    fn load_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = dir.path().join("injections.toml");
+
+        fs::write(dir.path().join("motd"), "{{device_id}}\n").unwrap();
+        fs::write(
+            &manifest,
+            "hostname = \"{{device_id}}\"\n\n\
+            [[files]]\nsource = \"motd\"\ndestination = \"factory:/etc/motd\"\n",
+        )
+        .unwrap();
+
+        let fleet_manifest = FleetManifest::load(&manifest).unwrap();
+
+        assert_eq!(fleet_manifest.files[0].source, dir.path().join("motd"));
+
+        fs::write(
+            &manifest,
+            "[[files]]\nsource = \"motd\"\ndestination = \"/etc/motd\"\n",
+        )
+        .unwrap();
+
+        assert!(FleetManifest::load(&manifest).is_err());
+
+        fs::write(&manifest, "unknown = 1\n").unwrap();
+
+        assert!(FleetManifest::load(&manifest).is_err());
+    }
+
+    #[test]
+    fn schema_covers_manifest() {
+        let schema = manifest_schema();
+        let properties = schema["properties"].as_object().unwrap();
+        let manifest: toml::Table = toml::from_str(
+            "identity_config = \"config.toml\"\n\
+            dps_payload = \"payload.json\"\n\
+            hostname = \"{{device_id}}\"\n\
+            machine_id_salt = \"salt\"\n\
+            reuuid = true\n\
+            [device_certificate]\n\
+            intermediate_full_chain_cert = \"chain.pem\"\n\
+            intermediate_key = \"key.pem\"\n\
+            days = 365\n\
+            [[files]]\nsource = \"motd\"\ndestination = \"factory:/etc/motd\"\n",
+        )
+        .unwrap();
+
+        // the sample has to be a valid manifest, otherwise the check is moot
+        toml::from_str::<FleetManifest>(&toml::to_string(&manifest).unwrap()).unwrap();
+
+        assert_eq!(properties.len(), manifest.len());
+        assert!(manifest.keys().all(|key| properties.contains_key(key)));
+        assert!(
+            schema["properties"]["files"]["items"]["properties"]["destination"]["pattern"]
+                .as_str()
+                .unwrap()
+                .contains("factory")
+        );
+    }
+}
@@ -0,0 +1,115 @@
+use crate::error::ErrorKind;
+use crate::runtime;
+use anyhow::{Context, Result};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// set for hooks, so that omnect-cli invoked by a hook doesn't run the hooks
+// again
+const ENV_HOOK: &str = "OMNECT_CLI_HOOK";
+const ENV_IMAGE: &str = "OMNECT_CLI_IMAGE";
+const ENV_STAGE: &str = "OMNECT_CLI_HOOK_STAGE";
+
+/// Point of an image modification at which a hook runs.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[allow(non_camel_case_types)]
+pub enum HookStage {
+    /// before the modification of omnect-cli
+    pre,
+    /// after the modification of omnect-cli, before the image is compressed
+    #[default]
+    post,
+}
+
+/// External executable, which is invoked with the path of the working copy
+/// of the image as last argument, e.g. for proprietary provisioning steps.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct HookConfig {
+    pub name: String,
+    pub command: PathBuf,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub stage: HookStage,
+}
+
+/// Runs the hooks of `stage` in the configured order on `image_file`. A hook
+/// exiting with an error aborts the modification.
+pub fn run(hooks: &[HookConfig], stage: HookStage, image_file: &Path) -> Result<()> {
+    if std::env::var_os(ENV_HOOK).is_some() {
+        debug!("skip hooks of omnect-cli invoked by a hook");
+        return Ok(());
+    }
+
+    for hook in hooks.iter().filter(|hook| hook.stage == stage) {
+        runtime::check_cancelled()?;
+
+        info!("run {stage:?} hook {}", hook.name);
+
+        let mut command = Command::new(&hook.command);
+        command
+            .args(&hook.args)
+            .arg(image_file)
+            .env(ENV_HOOK, &hook.name)
+            .env(ENV_IMAGE, image_file)
+            .env(ENV_STAGE, format!("{stage:?}"));
+
+        debug!("run {command:?}");
+
+        let status = command.status().context(ErrorKind::User).context(format!(
+            "hook {}: cannot run {}",
+            hook.name,
+            hook.command.to_string_lossy()
+        ))?;
+
+        if !status.success() {
+            return Err(
+                anyhow::anyhow!("hook {} failed: {status}", hook.name).context(ErrorKind::User)
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hooks_run_per_stage() {
+        let dir = tempfile::tempdir().unwrap();
+        let image_file = dir.path().join("image.wic");
+        let hook = |name: &str, script: &str, stage| HookConfig {
+            name: name.to_string(),
+            command: PathBuf::from("sh"),
+            args: vec!["-c".to_string(), script.to_string(), "sh".to_string()],
+            stage,
+        };
+
+        std::fs::write(&image_file, "").unwrap();
+
+        let hooks = [
+            hook("pre", "echo pre >> \"$1\"", HookStage::pre),
+            hook(
+                "post",
+                "echo \"$OMNECT_CLI_HOOK_STAGE\" >> \"$1\"",
+                HookStage::post,
+            ),
+        ];
+
+        run(&hooks, HookStage::pre, &image_file).unwrap();
+        run(&hooks, HookStage::post, &image_file).unwrap();
+
+        assert_eq!(std::fs::read_to_string(&image_file).unwrap(), "pre\npost\n");
+
+        let failing = [hook("failing", "exit 1", HookStage::post)];
+        let err = run(&failing, HookStage::post, &image_file).unwrap_err();
+
+        assert_eq!(ErrorKind::classify(&err), ErrorKind::User);
+        assert!(run(&failing, HookStage::pre, &image_file).is_ok());
+    }
+}
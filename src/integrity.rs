@@ -0,0 +1,99 @@
+use crate::diff::{diff_image_files, image_files, FileDiff};
+use crate::error::ErrorKind;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+/// Files of all partitions with their sha256 (or `-> <target>` for symbolic
+/// links) after the last modification by omnect-cli.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct IntegrityManifest {
+    pub version: String,
+    pub timestamp: String,
+    pub partitions: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+/// Sidecar file of the integrity manifest of an image.
+pub fn manifest_path(image_file: &Path) -> PathBuf {
+    let mut path = image_file.as_os_str().to_owned();
+    path.push(".manifest.json");
+    PathBuf::from(path)
+}
+
+/// Creates the integrity manifest of the uncompressed image `image_file`.
+pub fn create(image_file: &Path) -> Result<IntegrityManifest> {
+    Ok(IntegrityManifest {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        timestamp: OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .context("create: cannot format timestamp")?,
+        partitions: image_files(image_file)?,
+    })
+}
+
+/// Writes `manifest` as sidecar file of `image_file`.
+pub fn write(manifest: &IntegrityManifest, image_file: &Path) -> Result<()> {
+    let path = manifest_path(image_file);
+
+    serde_json::to_writer_pretty(
+        File::create(&path).context(format!("write: cannot create {}", path.to_string_lossy()))?,
+        manifest,
+    )?;
+
+    Ok(())
+}
+
+pub fn read(manifest_file: &Path) -> Result<IntegrityManifest> {
+    serde_json::from_reader(
+        File::open(manifest_file)
+            .context(ErrorKind::User)
+            .context(format!(
+                "read: cannot open integrity manifest {}",
+                manifest_file.to_string_lossy()
+            ))?,
+    )
+    .context(format!(
+        "read: invalid integrity manifest {}",
+        manifest_file.to_string_lossy()
+    ))
+}
+
+/// Compares the files of the uncompressed image `image_file` with
+/// `manifest`. Returns the files altered since the manifest was created.
+pub fn verify(manifest: &IntegrityManifest, image_file: &Path) -> Result<Vec<FileDiff>> {
+    Ok(diff_image_files(
+        &manifest.partitions,
+        &image_files(image_file)?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.wic.xz");
+        let manifest = IntegrityManifest {
+            version: "1.0.0".to_string(),
+            timestamp: "2024-05-02T09:00:00Z".to_string(),
+            partitions: BTreeMap::from([(
+                "factory".to_string(),
+                BTreeMap::from([("/etc/hostname".to_string(), "a".to_string())]),
+            )]),
+        };
+
+        write(&manifest, &image).unwrap();
+
+        assert_eq!(
+            manifest_path(&image),
+            dir.path().join("image.wic.xz.manifest.json")
+        );
+        assert_eq!(read(&manifest_path(&image)).unwrap(), manifest);
+        assert!(read(&dir.path().join("missing.json")).is_err());
+    }
+}
@@ -0,0 +1,648 @@
+use crate::device_update::AzureCredentials;
+use crate::error::ErrorKind;
+use anyhow::{Context, Result};
+use log::debug;
+use sha2::Digest;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use url::Url;
+
+const API_VERSION: &str = "2021-04-12";
+const IOT_HUB_SCOPE: &str = "https://iothubs.azure.net/.default";
+const IOT_HUB_DOMAIN: &str = "azure-devices.net";
+
+/// Authentication of a device registered in iot-hub.
+#[derive(clap::ValueEnum, Clone, Debug, PartialEq)]
+#[clap(rename_all = "kebab-case")]
+#[allow(non_camel_case_types)]
+pub enum DeviceAuthentication {
+    /// self-signed or ca-signed device certificate registered by its thumbprint
+    x509_thumbprint,
+    /// device certificate signed by a ca verified in iot-hub, the device id has to match the certificate's common name
+    x509_ca,
+}
+
+/// Hostname of an iot-hub given either by its hostname or by its name.
+pub fn iot_hub_hostname(hub: &str) -> String {
+    if hub.contains('.') {
+        hub.to_string()
+    } else {
+        format!("{hub}.{IOT_HUB_DOMAIN}")
+    }
+}
+
+/// Connection string of a device, e.g.
+/// `HostName=my-hub.azure-devices.net;DeviceId=my-device;SharedAccessKey=...`.
+#[derive(Clone, PartialEq)]
+pub struct ConnectionString {
+    pub hostname: String,
+    pub device_id: String,
+    pub shared_access_key: Option<String>,
+}
+
+impl fmt::Display for ConnectionString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "HostName={};DeviceId={}", self.hostname, self.device_id)?;
+
+        if let Some(shared_access_key) = &self.shared_access_key {
+            write!(f, ";SharedAccessKey={shared_access_key}")?;
+        }
+
+        Ok(())
+    }
+}
+
+// the shared access key isn't logged
+impl fmt::Debug for ConnectionString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnectionString")
+            .field("hostname", &self.hostname)
+            .field("device_id", &self.device_id)
+            .field(
+                "shared_access_key",
+                &self.shared_access_key.as_ref().map(|_| "***"),
+            )
+            .finish()
+    }
+}
+
+impl FromStr for ConnectionString {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (mut hostname, mut device_id, mut shared_access_key) = (None, None, None);
+
+        for pair in s.trim().split(';').filter(|pair| !pair.is_empty()) {
+            // keys are base64 encoded and may end with '='
+            let (key, value) = pair
+                .split_once('=')
+                .context(format!("connection string: invalid entry {pair:?}"))?;
+
+            match key.trim() {
+                "HostName" => hostname = Some(value.trim().to_string()),
+                "DeviceId" => device_id = Some(value.trim().to_string()),
+                "SharedAccessKey" => shared_access_key = Some(value.trim().to_string()),
+                _ => {}
+            }
+        }
+
+        Ok(ConnectionString {
+            hostname: hostname.context("connection string: HostName is missing")?,
+            device_id: device_id.context("connection string: DeviceId is missing")?,
+            shared_access_key,
+        })
+    }
+}
+
+fn iot_hub_url(iot_hub_hostname: &str, segments: &[&str]) -> Result<Url> {
+    let mut url = Url::parse(&format!("https://{iot_hub_hostname}"))
+        .context(format!("invalid iot-hub hostname: {iot_hub_hostname}"))?;
+
+    url.path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("invalid iot-hub hostname: {iot_hub_hostname}"))?
+        .pop_if_empty()
+        .extend(segments);
+    url.query_pairs_mut()
+        .append_pair("api-version", API_VERSION);
+
+    Ok(url)
+}
+
+fn twin_url(iot_hub_hostname: &str, device_id: &str) -> Result<Url> {
+    iot_hub_url(iot_hub_hostname, &["twins", device_id])
+}
+
+/// DER of the first certificate of a pem file.
+pub(crate) fn certificate_der(pem: &str) -> Result<Vec<u8>> {
+    let base64 = pem
+        .split("-----BEGIN CERTIFICATE-----")
+        .nth(1)
+        .and_then(|cert| cert.split("-----END CERTIFICATE-----").next())
+        .context("thumbprint: no certificate found")?
+        .split_whitespace()
+        .collect::<String>();
+
+    base64::decode(base64).context("thumbprint: invalid certificate")
+}
+
+/// SHA-256 thumbprint of the first certificate of a pem file, as expected by
+/// iot-hub for x509 thumbprint authentication.
+fn thumbprint(pem: &str) -> Result<String> {
+    Ok(format!("{:X}", sha2::Sha256::digest(certificate_der(pem)?)))
+}
+
+/// SHA-1 and SHA-256 thumbprints of a certificate, both accepted by iot-hub
+/// for self-signed x509 devices.
+#[derive(Debug, PartialEq, serde::Serialize)]
+pub struct Thumbprints {
+    pub sha1: String,
+    pub sha256: String,
+}
+
+pub fn thumbprints(pem: &str) -> Result<Thumbprints> {
+    let der = certificate_der(pem)?;
+    let hex = |digest: &[u8]| -> String { digest.iter().map(|b| format!("{b:02X}")).collect() };
+
+    Ok(Thumbprints {
+        sha1: hex(&openssl::sha::sha1(&der)),
+        sha256: hex(&openssl::sha::sha256(&der)),
+    })
+}
+
+fn device_registration(
+    device_id: &str,
+    authentication: &DeviceAuthentication,
+    device_cert: Option<&str>,
+    secondary_device_cert: Option<&str>,
+    iotedge: bool,
+) -> Result<serde_json::Value> {
+    let authentication = match authentication {
+        DeviceAuthentication::x509_thumbprint => {
+            let primary = thumbprint(device_cert.context(
+                "device_registration: x509 thumbprint authentication requires a device certificate",
+            )?)?;
+            // iot-hub requires a secondary thumbprint, the primary one is used if there is none
+            let secondary = secondary_device_cert
+                .map(thumbprint)
+                .transpose()?
+                .unwrap_or_else(|| primary.clone());
+
+            serde_json::json!({
+                "type": "selfSigned",
+                "x509Thumbprint": {
+                    "primaryThumbprint": primary,
+                    "secondaryThumbprint": secondary,
+                },
+            })
+        }
+        DeviceAuthentication::x509_ca => serde_json::json!({ "type": "certificateAuthority" }),
+    };
+
+    Ok(serde_json::json!({
+        "deviceId": device_id,
+        "status": "enabled",
+        "authentication": authentication,
+        "capabilities": { "iotEdge": iotedge },
+    }))
+}
+
+/// Creates the device identity `device_id` in iot-hub. An existing device is
+/// not modified.
+pub async fn register_device(
+    credentials: &AzureCredentials,
+    iot_hub_hostname: &str,
+    device_id: &str,
+    authentication: &DeviceAuthentication,
+    device_cert: Option<&Path>,
+    secondary_device_cert: Option<&Path>,
+    iotedge: bool,
+) -> Result<serde_json::Value> {
+    let read_cert = |cert: Option<&Path>| {
+        cert.map(|cert| {
+            std::fs::read_to_string(cert).context(format!(
+                "register_device: cannot read {}",
+                cert.to_string_lossy()
+            ))
+        })
+        .transpose()
+    };
+    let registration = device_registration(
+        device_id,
+        authentication,
+        read_cert(device_cert)?.as_deref(),
+        read_cert(secondary_device_cert)?.as_deref(),
+        iotedge,
+    )?;
+
+    put_device(credentials, iot_hub_hostname, device_id, &registration).await
+}
+
+/// Creates the device identity `device_id` authenticated by the shared
+/// access key `shared_access_key`, e.g. of a connection string.
+pub async fn register_sas_device(
+    credentials: &AzureCredentials,
+    iot_hub_hostname: &str,
+    device_id: &str,
+    shared_access_key: &str,
+) -> Result<serde_json::Value> {
+    put_device(
+        credentials,
+        iot_hub_hostname,
+        device_id,
+        &sas_registration(device_id, shared_access_key),
+    )
+    .await
+}
+
+fn sas_registration(device_id: &str, shared_access_key: &str) -> serde_json::Value {
+    serde_json::json!({
+        "deviceId": device_id,
+        "status": "enabled",
+        "authentication": {
+            "type": "sas",
+            "symmetricKey": {
+                "primaryKey": shared_access_key,
+                "secondaryKey": shared_access_key,
+            },
+        },
+    })
+}
+
+async fn put_device(
+    credentials: &AzureCredentials,
+    iot_hub_hostname: &str,
+    device_id: &str,
+    registration: &serde_json::Value,
+) -> Result<serde_json::Value> {
+    debug!("register device {device_id}: {registration}");
+
+    let response = reqwest::Client::new()
+        .put(iot_hub_url(iot_hub_hostname, &["devices", device_id])?)
+        .bearer_auth(credentials.access_token(IOT_HUB_SCOPE).await?)
+        .json(registration)
+        .send()
+        .await
+        .context("iot-hub device registration request failed")?;
+
+    let status = response.status();
+
+    if status == reqwest::StatusCode::CONFLICT {
+        return Err(
+            anyhow::anyhow!("device {device_id} already exists in {iot_hub_hostname}")
+                .context(ErrorKind::User),
+        );
+    }
+
+    if !status.is_success() {
+        return Err(anyhow::anyhow!(
+            "cannot register device {device_id}. status: {status}, message: {}",
+            response.text().await.unwrap_or_default()
+        )
+        .context(ErrorKind::from_status(status)));
+    }
+
+    response
+        .json()
+        .await
+        .context("register_device: invalid response")
+}
+
+/// Whether the device identity `device_id` exists in iot-hub.
+pub async fn device_exists(
+    credentials: &AzureCredentials,
+    iot_hub_hostname: &str,
+    device_id: &str,
+) -> Result<bool> {
+    let response = reqwest::Client::new()
+        .get(iot_hub_url(iot_hub_hostname, &["devices", device_id])?)
+        .bearer_auth(credentials.access_token(IOT_HUB_SCOPE).await?)
+        .send()
+        .await
+        .context("iot-hub device request failed")?;
+
+    let status = response.status();
+
+    if status == reqwest::StatusCode::NOT_FOUND {
+        return Ok(false);
+    }
+
+    if !status.is_success() {
+        return Err(anyhow::anyhow!(
+            "cannot get device {device_id}. status: {status}, message: {}",
+            response.text().await.unwrap_or_default()
+        )
+        .context(ErrorKind::from_status(status)));
+    }
+
+    Ok(true)
+}
+
+pub async fn patch_twin_tags(
+    credentials: &AzureCredentials,
+    iot_hub_hostname: &str,
+    device_id: &str,
+    tags: &serde_json::Value,
+) -> Result<()> {
+    patch_twin(
+        credentials,
+        iot_hub_hostname,
+        device_id,
+        &serde_json::json!({ "tags": tags }),
+    )
+    .await
+    .map(|_| ())
+}
+
+async fn patch_twin(
+    credentials: &AzureCredentials,
+    iot_hub_hostname: &str,
+    device_id: &str,
+    patch: &serde_json::Value,
+) -> Result<serde_json::Value> {
+    let url = twin_url(iot_hub_hostname, device_id)?;
+
+    debug!("patch twin of {device_id}: {patch}");
+
+    let response = reqwest::Client::new()
+        .patch(url)
+        .bearer_auth(credentials.access_token(IOT_HUB_SCOPE).await?)
+        .json(patch)
+        .send()
+        .await
+        .context("iot-hub twin request failed")?;
+
+    let status = response.status();
+
+    if !status.is_success() {
+        return Err(anyhow::anyhow!(
+            "cannot patch twin of {device_id}. status: {status}, message: {}",
+            response.text().await.unwrap_or_default()
+        )
+        .context(ErrorKind::from_status(status)));
+    }
+
+    response
+        .json()
+        .await
+        .context("patch_twin: invalid response")
+}
+
+/// Twin patch of `tags` and desired properties, null values remove entries.
+fn twin_patch(
+    tags: Option<&serde_json::Value>,
+    desired: Option<&serde_json::Value>,
+) -> Result<serde_json::Value> {
+    let mut patch = serde_json::Map::new();
+
+    for (key, value) in [("tags", tags), ("desired", desired)] {
+        if let Some(value) = value {
+            anyhow::ensure!(value.is_object(), "twin_patch: {key} must be a json object");
+        }
+    }
+
+    if let Some(tags) = tags {
+        patch.insert("tags".to_string(), tags.clone());
+    }
+
+    if let Some(desired) = desired {
+        patch.insert(
+            "properties".to_string(),
+            serde_json::json!({ "desired": desired }),
+        );
+    }
+
+    anyhow::ensure!(
+        !patch.is_empty(),
+        "twin_patch: neither tags nor desired properties given"
+    );
+
+    Ok(serde_json::Value::Object(patch))
+}
+
+pub async fn get_twin(
+    credentials: &AzureCredentials,
+    iot_hub_hostname: &str,
+    device_id: &str,
+) -> Result<serde_json::Value> {
+    let response = reqwest::Client::new()
+        .get(twin_url(iot_hub_hostname, device_id)?)
+        .bearer_auth(credentials.access_token(IOT_HUB_SCOPE).await?)
+        .send()
+        .await
+        .context("iot-hub twin request failed")?;
+
+    let status = response.status();
+
+    if status == reqwest::StatusCode::NOT_FOUND {
+        return Err(
+            anyhow::anyhow!("device {device_id} not found in {iot_hub_hostname}")
+                .context(ErrorKind::User),
+        );
+    }
+
+    if !status.is_success() {
+        return Err(anyhow::anyhow!(
+            "cannot get twin of {device_id}. status: {status}, message: {}",
+            response.text().await.unwrap_or_default()
+        )
+        .context(ErrorKind::from_status(status)));
+    }
+
+    response.json().await.context("get_twin: invalid response")
+}
+
+/// Patches tags and desired properties of the twin of `device_id` and returns
+/// the updated twin.
+pub async fn update_twin(
+    credentials: &AzureCredentials,
+    iot_hub_hostname: &str,
+    device_id: &str,
+    tags: Option<&serde_json::Value>,
+    desired: Option<&serde_json::Value>,
+) -> Result<serde_json::Value> {
+    patch_twin(
+        credentials,
+        iot_hub_hostname,
+        device_id,
+        &twin_patch(tags, desired)?,
+    )
+    .await
+}
+
+/// Modules content of a deployment manifest, either in the format of the
+/// iotedge dev tools or of an iot-hub deployment ("content" object).
+fn modules_content(manifest: &serde_json::Value) -> Result<serde_json::Value> {
+    let modules_content = manifest
+        .get("modulesContent")
+        .or_else(|| manifest["content"].get("modulesContent"))
+        .context("modules_content: deployment manifest has no modulesContent")?;
+
+    for module in ["$edgeAgent", "$edgeHub"] {
+        anyhow::ensure!(
+            modules_content[module]["properties.desired"].is_object(),
+            "modules_content: deployment manifest has no desired properties of {module}"
+        );
+    }
+
+    Ok(serde_json::json!({ "modulesContent": modules_content }))
+}
+
+/// Applies the module deployment `manifest` to the iotedge device `device_id`.
+pub async fn set_modules(
+    credentials: &AzureCredentials,
+    iot_hub_hostname: &str,
+    device_id: &str,
+    manifest: &Path,
+) -> Result<()> {
+    let manifest: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(manifest).context(format!(
+            "set_modules: cannot read {}",
+            manifest.to_string_lossy()
+        ))?)
+        .context("set_modules: invalid deployment manifest")?;
+    let content = modules_content(&manifest).context(ErrorKind::User)?;
+
+    let response = reqwest::Client::new()
+        .post(iot_hub_url(
+            iot_hub_hostname,
+            &["devices", device_id, "applyConfigurationContent"],
+        )?)
+        .bearer_auth(credentials.access_token(IOT_HUB_SCOPE).await?)
+        .json(&content)
+        .send()
+        .await
+        .context("iot-hub apply configuration request failed")?;
+
+    let status = response.status();
+
+    if !status.is_success() {
+        return Err(anyhow::anyhow!(
+            "cannot apply deployment manifest to {device_id}. status: {status}, message: {}",
+            response.text().await.unwrap_or_default()
+        )
+        .context(ErrorKind::from_status(status)));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn twin_url_ok() {
+        assert_eq!(
+            twin_url("my-hub.azure-devices.net", "my-device")
+                .unwrap()
+                .as_str(),
+            "https://my-hub.azure-devices.net/twins/my-device?api-version=2021-04-12"
+        );
+    }
+
+    #[test]
+    fn modules_content_ok() {
+        let modules_content = serde_json::json!({
+            "$edgeAgent": { "properties.desired": { "schemaVersion": "1.1" } },
+            "$edgeHub": { "properties.desired": { "schemaVersion": "1.1" } },
+        });
+        let expected = serde_json::json!({ "modulesContent": modules_content });
+
+        assert_eq!(
+            modules_content(&serde_json::json!({ "modulesContent": modules_content })).unwrap(),
+            expected
+        );
+        assert_eq!(
+            modules_content(
+                &serde_json::json!({ "content": { "modulesContent": modules_content } })
+            )
+            .unwrap(),
+            expected
+        );
+        assert!(modules_content(&serde_json::json!({
+            "modulesContent": { "$edgeAgent": { "properties.desired": {} } }
+        }))
+        .is_err());
+        assert!(modules_content(&serde_json::json!({})).is_err());
+    }
+
+    #[test]
+    fn twin_patch_ok() {
+        let tags = serde_json::json!({ "ADUGroup": "beta" });
+        let desired = serde_json::json!({ "logLevel": "debug" });
+
+        assert_eq!(
+            twin_patch(Some(&tags), Some(&desired)).unwrap(),
+            serde_json::json!({
+                "tags": { "ADUGroup": "beta" },
+                "properties": { "desired": { "logLevel": "debug" } },
+            })
+        );
+        assert!(twin_patch(None, None).is_err());
+        assert!(twin_patch(Some(&serde_json::json!("beta")), None).is_err());
+    }
+
+    #[test]
+    fn connection_string_ok() {
+        let connection_string: ConnectionString =
+            "HostName=my-hub.azure-devices.net;DeviceId=my-device;SharedAccessKey=a2V5Cg=="
+                .parse()
+                .unwrap();
+
+        assert_eq!(
+            connection_string,
+            ConnectionString {
+                hostname: "my-hub.azure-devices.net".to_string(),
+                device_id: "my-device".to_string(),
+                shared_access_key: Some("a2V5Cg==".to_string()),
+            }
+        );
+        assert_eq!(
+            sas_registration("my-device", "a2V5Cg==")["authentication"]["symmetricKey"]
+                ["primaryKey"],
+            "a2V5Cg=="
+        );
+        assert!("HostName=my-hub.azure-devices.net;x509=true"
+            .parse::<ConnectionString>()
+            .is_err());
+        assert!("my-hub".parse::<ConnectionString>().is_err());
+    }
+
+    #[test]
+    fn device_registration_ok() {
+        // "test" as certificate content
+        let cert = "-----BEGIN CERTIFICATE-----\ndGVz\ndA==\n-----END CERTIFICATE-----\n";
+        let registration = device_registration(
+            "my-device",
+            &DeviceAuthentication::x509_thumbprint,
+            Some(cert),
+            None,
+            true,
+        )
+        .unwrap();
+        let thumbprint = "9F86D081884C7D659A2FEAA0C55AD015A3BF4F1B2B0B822CD15D6C15B0F00A08";
+
+        assert_eq!(registration["deviceId"], "my-device");
+        assert_eq!(registration["capabilities"]["iotEdge"], true);
+        assert_eq!(
+            registration["authentication"]["x509Thumbprint"]["primaryThumbprint"],
+            thumbprint
+        );
+        assert_eq!(
+            registration["authentication"]["x509Thumbprint"]["secondaryThumbprint"],
+            thumbprint
+        );
+        assert!(device_registration(
+            "my-device",
+            &DeviceAuthentication::x509_thumbprint,
+            None,
+            None,
+            false
+        )
+        .is_err());
+        assert_eq!(
+            device_registration(
+                "my-device",
+                &DeviceAuthentication::x509_ca,
+                None,
+                None,
+                false
+            )
+            .unwrap()["authentication"]["type"],
+            "certificateAuthority"
+        );
+        assert_eq!(
+            thumbprints(cert).unwrap(),
+            Thumbprints {
+                sha1: "A94A8FE5CCB19BA61C4C0873D391E987982FBBD3".to_string(),
+                sha256: thumbprint.to_string(),
+            }
+        );
+        assert_eq!(iot_hub_hostname("my-hub"), "my-hub.azure-devices.net");
+        assert_eq!(
+            iot_hub_hostname("my-hub.azure-devices.cn"),
+            "my-hub.azure-devices.cn"
+        );
+    }
+}
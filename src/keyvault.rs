@@ -0,0 +1,209 @@
+use crate::device_update::AzureCredentials;
+use crate::error::ErrorKind;
+use crate::runtime;
+use anyhow::{Context, Result};
+use log::debug;
+use openssl::pkcs12::Pkcs12;
+use serde::Deserialize;
+use std::fmt::{self, Display};
+use std::path::Path;
+
+const KEYVAULT_SCOPE: &str = "https://vault.azure.net/.default";
+const KEYVAULT_API_VERSION: &str = "7.4";
+const PKCS12_CONTENT_TYPE: &str = "application/x-pkcs12";
+
+/// Secret in an Azure Key Vault given as `keyvault://<vault>/<name>` or
+/// `keyvault://<vault>/<name>/<version>`. `vault` is either the name of the
+/// vault or its hostname, e.g. for sovereign clouds.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SecretRef {
+    vault: String,
+    name: String,
+    version: Option<String>,
+}
+
+impl Display for SecretRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "keyvault://{}/{}", self.vault, self.name)?;
+
+        if let Some(version) = &self.version {
+            write!(f, "/{version}")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SecretBundle {
+    value: String,
+    content_type: Option<String>,
+}
+
+impl SecretRef {
+    /// Returns `None` if `reference` isn't a `keyvault://` reference.
+    pub fn parse(reference: &str) -> Result<Option<SecretRef>> {
+        let Some(path) = reference.strip_prefix("keyvault://") else {
+            return Ok(None);
+        };
+        let invalid = || {
+            anyhow::anyhow!(
+                "invalid key vault reference {reference}, expected keyvault://<vault>/<name>[/<version>]"
+            )
+            .context(ErrorKind::User)
+        };
+        let parts = path.split('/').collect::<Vec<_>>();
+
+        if parts.iter().any(|part| part.is_empty()) {
+            return Err(invalid());
+        }
+
+        match parts[..] {
+            [vault, name] => Ok(Some(SecretRef {
+                vault: vault.to_string(),
+                name: name.to_string(),
+                version: None,
+            })),
+            [vault, name, version] => Ok(Some(SecretRef {
+                vault: vault.to_string(),
+                name: name.to_string(),
+                version: Some(version.to_string()),
+            })),
+            _ => Err(invalid()),
+        }
+    }
+
+    pub fn from_path(path: &Path) -> Result<Option<SecretRef>> {
+        match path.to_str() {
+            Some(path) => SecretRef::parse(path),
+            None => Ok(None),
+        }
+    }
+
+    fn url(&self) -> String {
+        let host = if self.vault.contains('.') {
+            self.vault.clone()
+        } else {
+            format!("{}.vault.azure.net", self.vault)
+        };
+
+        format!(
+            "https://{host}/secrets/{}/{}?api-version={KEYVAULT_API_VERSION}",
+            self.name,
+            self.version.as_deref().unwrap_or_default()
+        )
+    }
+
+    /// Fetches the secret authenticated by the azure credential chain.
+    /// Certificates stored as PKCS#12 are converted to PEM: the private key
+    /// followed by the certificate and its chain.
+    pub fn fetch(&self) -> Result<Vec<u8>> {
+        runtime::block_on(async {
+            let token = AzureCredentials::Default
+                .access_token(KEYVAULT_SCOPE)
+                .await?;
+            let response = reqwest::Client::new()
+                .get(self.url())
+                .bearer_auth(token)
+                .send()
+                .await
+                .context(format!("keyvault: cannot access {self}"))
+                .context(ErrorKind::Remote)?;
+            let status = response.status();
+
+            if !status.is_success() {
+                debug!("{self}: {}", response.text().await.unwrap_or_default());
+
+                return Err(
+                    anyhow::anyhow!("keyvault: cannot get {self}. status: {status}")
+                        .context(ErrorKind::from_status(status)),
+                );
+            }
+
+            let secret: SecretBundle = response
+                .json()
+                .await
+                .context(format!("keyvault: invalid response for {self}"))?;
+
+            match secret.content_type.as_deref() {
+                Some(PKCS12_CONTENT_TYPE) => pkcs12_to_pem(&secret.value)
+                    .context(format!("keyvault: invalid certificate {self}")),
+                _ => Ok(secret.value.into_bytes()),
+            }
+        })
+    }
+}
+
+/// Converts a base64 encoded PKCS#12 bundle without password, as returned
+/// by Key Vault for certificates, to PEM.
+fn pkcs12_to_pem(value: &str) -> Result<Vec<u8>> {
+    let pkcs12 = Pkcs12::from_der(&base64::decode(value)?)?.parse2("")?;
+    let mut pem = vec![];
+
+    if let Some(pkey) = pkcs12.pkey {
+        pem.extend(pkey.private_key_to_pem_pkcs8()?);
+    }
+
+    if let Some(cert) = pkcs12.cert {
+        pem.extend(cert.to_pem()?);
+    }
+
+    for cert in pkcs12.ca.into_iter().flatten() {
+        pem.extend(cert.to_pem()?);
+    }
+
+    Ok(pem)
+}
+
+/// Replaces `value` by the secret it references if it's a `keyvault://`
+/// reference, e.g. for client secrets or storage account keys.
+pub fn resolve(value: String) -> Result<String> {
+    match SecretRef::parse(&value)? {
+        Some(secret) => {
+            String::from_utf8(secret.fetch()?).context(format!("keyvault: {secret} isn't utf-8"))
+        }
+        None => Ok(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_secret_ref() {
+        assert_eq!(SecretRef::parse("key.pem").unwrap(), None);
+
+        let secret = SecretRef::parse("keyvault://my-vault/intermediate-key")
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            secret.url(),
+            "https://my-vault.vault.azure.net/secrets/intermediate-key/?api-version=7.4"
+        );
+        assert_eq!(secret.to_string(), "keyvault://my-vault/intermediate-key");
+
+        let secret = SecretRef::parse("keyvault://my-vault.vault.azure.cn/key/0123abcd")
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            secret.url(),
+            "https://my-vault.vault.azure.cn/secrets/key/0123abcd?api-version=7.4"
+        );
+
+        for invalid in [
+            "keyvault://my-vault",
+            "keyvault://my-vault/",
+            "keyvault://a/b/c/d",
+        ] {
+            let e = SecretRef::parse(invalid).unwrap_err();
+
+            assert_eq!(ErrorKind::classify(&e), ErrorKind::User);
+        }
+
+        assert_eq!(resolve("plain".to_string()).unwrap(), "plain");
+    }
+}
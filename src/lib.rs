@@ -1,86 +1,250 @@
 #[macro_use]
 extern crate lazy_static;
+pub mod artifact;
 pub mod auth;
+pub mod batch;
+pub mod bmap;
 pub mod cli;
 pub mod config;
+pub mod daemon;
 pub mod device_update;
+pub mod diagnostics;
+pub mod diff;
 pub mod docker;
+pub mod docs;
+pub mod dps;
+pub mod error;
 pub mod file;
+pub mod fleet;
+pub mod hooks;
 pub mod image;
+pub mod integrity;
+pub mod iot_hub;
+pub mod keyvault;
+mod lock;
+pub mod pkcs12;
+pub mod progress;
+pub mod provenance;
+pub mod runtime;
+pub mod sbom;
+pub mod session;
+pub mod signature;
 pub mod ssh;
+pub mod update_server;
 mod validators;
+pub mod vault;
 use anyhow::{Context, Result};
 use cli::{
+    Boot::{SetCmdline, SetUbootEnv, UpdateVerity},
     Command,
+    Config::{Init as ConfigInit, Validate as ConfigValidate},
+    Device::{CollectLogs, Status as DeviceStatus, Twin},
+    DeviceGroup, DeviceTwin,
     Docker::Inject,
-    File::{CopyFromImage, CopyToImage},
+    Docs,
+    Edge::SetModules,
+    EnrollmentGroup,
+    File::{CopyFromImage, CopyToImage, Hash as FileHash},
+    Fleet::Provision,
     IdentityConfig::{
-        SetConfig, SetDeviceCertificate, SetDeviceCertificateNoEst, SetIotLeafSasConfig,
+        EnrollmentGroup as IdentityEnrollmentGroup, RegisterDevice, SetConfig, SetConnectionString,
+        SetDeviceCertificate, SetDeviceCertificateNoEst, SetHostname, SetIotLeafSasConfig,
         SetIotedgeGatewayConfig,
     },
+    Image::{
+        AddPartition, Check as ImageCheck, Clone as ImageClone, Convert as ImageConvert,
+        CreateMenderArtifact, CreateRaucBundle, CreateSwu, Diff as ImageDiff, Flash as ImageFlash,
+        History as ImageHistory, Mount as ImageMount, ResetData, ResizePartition, Reuuid, Sbom,
+        SetFs, Shrink as ImageShrink, Sign as ImageSign, Transcode, Verify as ImageVerify,
+        VerifyBmap,
+    },
     IotHubDeviceUpdate::{self, SetDeviceConfig as IotHubDeviceUpdateSet},
-    SshConfig::{SetCertificate, SetConnection},
+    Network::{SetStatic, SetWifi, SetWireguard},
+    OutputFormat, Schema,
+    SecureBoot::Enroll,
+    SshConfig::{RemoveConnection, SetCertificate, SetConnection},
+    System::{SetProxy, SetTimeConfig},
+    User::Set as UserSet,
+};
+use error::ErrorKind;
+use file::{compression::Compression, functions::FileCopyToParams, object_storage::ObjectUrl};
+use log::{debug, error, warn};
+use serde_json::json;
+pub use session::ImageSession;
+use std::{
+    cell::RefCell,
+    fs,
+    io::Write,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
 };
-use file::{compression::Compression, functions::FileCopyToParams};
-use log::error;
-use std::{fs, path::PathBuf};
-use tokio::fs::remove_dir_all;
 use uuid::Uuid;
 
 use crate::file::compression;
 
 struct TempDirGuard(PathBuf);
 
+impl TempDirGuard {
+    /// Guards `dir`, which is also removed if the process exits on Ctrl-C.
+    fn new(dir: PathBuf) -> TempDirGuard {
+        runtime::register_temp_dir(&dir);
+        TempDirGuard(dir)
+    }
+}
+
 impl Drop for TempDirGuard {
     fn drop(&mut self) {
-        let Ok(rt) = tokio::runtime::Builder::new_current_thread()
-            .enable_all()
-            .build()
-        else {
-            error!("cannot create tokio runtime");
-            return;
-        };
-
-        rt.block_on(async {
-            if let Err(e) = remove_dir_all(self.0.clone()).await {
-                error!("cannot remove tmp dir: {e}")
-            }
-        })
+        runtime::unregister_temp_dir(&self.0);
+
+        if let Err(e) = fs::remove_dir_all(&self.0) {
+            error!("cannot remove tmp dir: {e}")
+        }
     }
 }
 
-fn run_image_command<F>(
-    image_file: PathBuf,
-    generate_bmap: bool,
-    target_compression: Option<Compression>,
-    command: F,
-) -> Result<()>
-where
-    F: FnOnce(&PathBuf) -> Result<()>,
-{
-    if let Ok("true") | Ok("1") = std::env::var("CONTAINERIZED").as_deref() {
-        anyhow::ensure!(
-            !generate_bmap,
-            "run_image_command: generating bmap file is not supported in containerized environments."
-        );
+/// Copy of an image in a temporary directory, which commands operate on.
+struct WorkingImage {
+    file: PathBuf,
+    /// local image the copy was made of, whose modification log is continued
+    source_file: PathBuf,
+    /// image file without compression extension
+    dest_file: PathBuf,
+    /// image is converted back to qcow2 on finish
+    qcow2: bool,
+    /// object the image is uploaded to on finish
+    upload: Option<ObjectUrl>,
+    _guard: TempDirGuard,
+    _download_guard: Option<TempDirGuard>,
+}
+
+/// Image downloaded from a http(s) or object storage url to the work dir.
+struct Download {
+    file: PathBuf,
+    dest_file: PathBuf,
+    upload: Option<ObjectUrl>,
+    guard: TempDirGuard,
+}
+
+/// Downloads `image_file` if it is a http(s) url, whose modified image is
+/// stored in the current directory, or an object storage url, whose
+/// modified image is uploaded to object storage again.
+fn download_image(image_file: &Path, user_config: &config::UserConfig) -> Result<Option<Download>> {
+    let url = file::download::url(image_file);
+    let object = ObjectUrl::parse(image_file)?;
+
+    if url.is_none() && object.is_none() {
+        return Ok(None);
     }
 
-    anyhow::ensure!(
-        image_file.try_exists().is_ok_and(|exists| exists),
-        "run_image_command: image doesn't exist {}",
-        image_file.to_str().context("cannot get image file path")?
-    );
+    let download_dir = user_config.workdir().join(Uuid::new_v4().to_string());
+    fs::create_dir_all(&download_dir).context(format!(
+        "working_image: couldn't create download path {}",
+        download_dir.to_string_lossy()
+    ))?;
+    let guard = TempDirGuard::new(download_dir.clone());
+    let with_signature = user_config.verify_signature.is_some();
+
+    if let Some(url) = url {
+        let file = file::download::download(
+            &url,
+            &download_dir,
+            with_signature,
+            user_config.cache_dir.as_deref(),
+        )?;
+        let dest_file = std::env::current_dir()
+            .context("working_image: cannot get current dir")?
+            .join(file.file_name().context("cannot get image file name")?);
+
+        return Ok(Some(Download {
+            file,
+            dest_file,
+            upload: None,
+            guard,
+        }));
+    }
+
+    let object = object.context("working_image: no object url")?;
+    let file = object.download(&download_dir)?;
+
+    // continue the modification log of the image
+    object
+        .with_file_name(&format!("{}.modifications.json", object.file_name()))
+        .download_if_exists(&download_dir)?;
+
+    if with_signature {
+        object
+            .with_file_name(&format!("{}.sig", object.file_name()))
+            .download(&download_dir)?;
+        object
+            .with_file_name(&format!("{}.att", object.file_name()))
+            .download_if_exists(&download_dir)?;
+    }
+
+    Ok(Some(Download {
+        dest_file: file.clone(),
+        file,
+        upload: Some(object),
+        guard,
+    }))
+}
+
+/// Copies and if applicable decompresses `image_file` to a temporary
+/// directory. An uncompressed image is copied next to the image if
+/// `next_to_image`, so that it can finally replace the image by a rename.
+/// Remote images are downloaded to the work dir first as described in
+/// [`download_image`].
+fn working_image(
+    image_file: &Path,
+    next_to_image: bool,
+    user_config: &config::UserConfig,
+) -> Result<WorkingImage> {
+    let download = download_image(image_file, user_config)?;
+    let (image_file, mut dest_image_file, next_to_image) = match &download {
+        Some(download) => (download.file.clone(), download.dest_file.clone(), false),
+        None => (
+            image_file.to_path_buf(),
+            image_file.to_path_buf(),
+            next_to_image,
+        ),
+    };
+    let image_file = image_file.as_path();
+
+    if !image_file.try_exists().is_ok_and(|exists| exists) {
+        return Err(anyhow::anyhow!(
+            "working_image: image doesn't exist {}",
+            image_file.to_str().context("cannot get image file path")?
+        )
+        .context(ErrorKind::User));
+    }
+
+    if let Some(public_key) = &user_config.verify_signature {
+        signature::verify(image_file, public_key, None)
+            .context("working_image: refuse to operate on unverified image")?;
+    }
 
-    let mut dest_image_file = image_file.clone();
+    let source_compression = Compression::from_file(&image_file.to_path_buf())?;
 
-    // create /tmp/{uuid}/ and copy image into
-    let tmp_dir = PathBuf::from(format!("/tmp/{}", Uuid::new_v4()));
+    // create {image_dir}/.omnect-cli-{uuid}/ or {work_dir}/{uuid}/ and copy the image into
+    let tmp_dir = match image_file.parent() {
+        Some(image_dir) if source_compression.is_none() && next_to_image => {
+            let tmp_dir = image_dir.join(format!(".omnect-cli-{}", Uuid::new_v4()));
+
+            match fs::create_dir(&tmp_dir) {
+                Ok(()) => tmp_dir,
+                Err(e) => {
+                    debug!("cannot create {tmp_dir:?}, fall back to work dir: {e}");
+                    user_config.workdir().join(Uuid::new_v4().to_string())
+                }
+            }
+        }
+        _ => user_config.workdir().join(Uuid::new_v4().to_string()),
+    };
     fs::create_dir_all(tmp_dir.clone()).context(format!(
-        "run_image_command: couldn't create destination path {}",
+        "working_image: couldn't create destination path {}",
         tmp_dir.to_str().context("cannot get tmp dir name")?
     ))?;
 
-    let _guard = TempDirGuard(tmp_dir.clone());
+    let guard = TempDirGuard::new(tmp_dir.clone());
 
     let mut tmp_image_file = tmp_dir.join(
         image_file
@@ -89,70 +253,530 @@ where
     );
 
     // if applicable decompress image to *.wic
-    if let Some(source_compression) = Compression::from_file(&image_file)? {
-        std::fs::copy(&image_file, &tmp_image_file)?;
-        tmp_image_file = compression::decompress(&tmp_image_file, &source_compression)?;
-        dest_image_file.set_extension("");
+    if let (Some(source_compression), Some(cache_dir)) =
+        (&source_compression, &user_config.cache_dir)
+    {
+        let cached = file::cache::decompressed_image(cache_dir, image_file, source_compression)?;
+        tmp_image_file = compression::without_extension(
+            &tmp_dir.join(
+                image_file
+                    .file_name()
+                    .context("cannot get image file name")?,
+            ),
+            source_compression,
+        );
+        copy_file(&cached, &tmp_image_file)?;
+        dest_image_file = compression::without_extension(&dest_image_file, source_compression);
+    } else if let Some(source_compression) = source_compression {
+        tmp_image_file = compression::decompress(image_file, &tmp_dir, &source_compression)?;
+        dest_image_file = compression::without_extension(&dest_image_file, &source_compression);
     } else {
-        // copy sparse file (std::fs::copy isn't able)
-        libfs::copy_file(&image_file, &tmp_image_file).context(format!(
-            "error: libfs::copy_file({:?}, {:?})",
-            image_file, tmp_image_file
-        ))?;
+        copy_file(image_file, &tmp_image_file)?;
     }
 
-    // run command
-    command(&tmp_image_file)?;
-
-    // create and copy back bmap file if one was created
-    if generate_bmap {
-        let mut target_bmap = image_file
-            .parent()
-            .context("cannot get parent dir of image path")?
-            .to_path_buf();
-        let tmp_bmap = PathBuf::from(format!(
-            "{}.bmap",
-            tmp_image_file
-                .to_str()
-                .context("cannot get image file path")?
-        ));
-        file::functions::generate_bmap_file(
-            tmp_image_file
-                .to_str()
-                .context("cannot get image file path")?,
-        )?;
-        target_bmap.push(tmp_bmap.file_name().context("cannot get bmap file name")?);
-        std::fs::copy(&tmp_bmap, &target_bmap).context(format!(
-            "error: std::fs::copy({:?}, {:?})",
-            tmp_bmap, target_bmap
-        ))?;
+    // qcow2 images are modified as raw images
+    let qcow2 = file::qcow2::is_qcow2(&tmp_image_file)?;
+
+    if qcow2 {
+        tmp_image_file = file::qcow2::to_raw(&tmp_image_file)?;
     }
 
-    // if applicable compress image
-    if let Some(c) = target_compression {
-        tmp_image_file = compression::compress(&tmp_image_file, &c)?;
-        dest_image_file.set_file_name(
-            tmp_image_file
-                .file_name()
-                .context("cannot get image file name")?,
-        );
-        std::fs::copy(&tmp_image_file, &dest_image_file).context(format!(
-            "error: std::fs::copy({:?}, {:?})",
-            tmp_image_file, dest_image_file
-        ))?;
+    validators::image::validate_partition_layout(&tmp_image_file).context(ErrorKind::User)?;
+
+    // the downloaded image isn't needed anymore
+    let (upload, download_guard) = match download {
+        Some(download) => {
+            let _ = fs::remove_file(&download.file);
+            (download.upload, Some(download.guard))
+        }
+        None => (None, None),
+    };
+
+    Ok(WorkingImage {
+        file: tmp_image_file,
+        source_file: image_file.to_path_buf(),
+        dest_file: dest_image_file,
+        qcow2,
+        upload,
+        _guard: guard,
+        _download_guard: download_guard,
+    })
+}
+
+/// Runs `command` on a copy of `image_file`, which replaces the image
+/// afterwards, or which is written to the configured output device.
+fn run_image_command<F>(
+    image_file: PathBuf,
+    generate_bmap: bool,
+    target_compression: Option<Compression>,
+    user_config: &config::UserConfig,
+    command: F,
+) -> Result<()>
+where
+    F: FnOnce(&PathBuf) -> Result<()>,
+{
+    // an uncompressed image that isn't compressed afterwards is modified in a
+    // copy next to the image, which finally replaces the image by a rename.
+    ImageSession::new(&image_file, target_compression.is_none(), user_config)?
+        .generate_bmap(generate_bmap)?
+        .integrity_manifest(user_config.integrity_manifest.unwrap_or(false))
+        .fsck(user_config.fsck.unwrap_or(false))
+        .zero_free_space(user_config.zero_free_space.unwrap_or(false))
+        .history(true)
+        .modification_log(user_config.modification_log.unwrap_or(false))
+        .invocation(user_config.invocation.clone())
+        .backup(
+            user_config.backup.unwrap_or(false),
+            user_config.backup_dir.as_deref(),
+        )
+        .output_device(user_config.output_device.as_deref(), user_config.assume_yes)?
+        .apply(|img| hooks::run(&user_config.hooks, hooks::HookStage::pre, img))?
+        .apply(command)?
+        .apply(|img| hooks::run(&user_config.hooks, hooks::HookStage::post, img))?
+        .finish(target_compression)
+        .map(|_| ())
+}
+
+/// Runs `command` on a copy of `image_file` without modifying the image.
+fn read_image_command<F>(
+    image_file: PathBuf,
+    user_config: &config::UserConfig,
+    command: F,
+) -> Result<()>
+where
+    F: FnOnce(&PathBuf) -> Result<()>,
+{
+    let working_image = working_image(&image_file, true, user_config)?;
+
+    command(&working_image.file)
+}
+
+/// Moves `from` to `to` by a rename, which only succeeds on the same file
+/// system, otherwise by a copy. An existing `to` keeps its permissions and
+/// ownership. A symlink `to` is resolved, so that its target is replaced, and
+/// a hardlinked `to` is overwritten in place, so that all links get the new
+/// content.
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    let to = fs::canonicalize(to).unwrap_or_else(|_| to.to_path_buf());
+
+    match fs::metadata(&to) {
+        Ok(original) if original.nlink() > 1 => return overwrite_file(from, &to),
+        Ok(original) => copy_permissions(&original, from)?,
+        Err(_) => {}
+    }
+
+    if let Err(e) = fs::rename(from, &to) {
+        debug!("cannot rename {from:?} to {to:?}, copy instead: {e}");
+
+        copy_file(from, &to)?;
+    }
+
+    Ok(())
+}
+
+/// Applies permissions and ownership of `original` to `file`.
+fn copy_permissions(original: &fs::Metadata, file: &Path) -> Result<()> {
+    fs::set_permissions(file, original.permissions())
+        .context(format!("cannot set permissions of {file:?}"))?;
+
+    // changing the owner requires privileges, which are only given e.g. by sudo
+    if let Err(e) = std::os::unix::fs::chown(file, Some(original.uid()), Some(original.gid())) {
+        debug!("cannot change owner of {file:?}: {e}");
+    }
+
+    Ok(())
+}
+
+/// Replaces the content of `to` by the content of `from`, keeping the inode
+/// of `to`, and removes `from`.
+fn overwrite_file(from: &Path, to: &Path) -> Result<()> {
+    let mut source = fs::File::open(from).context(format!("cannot open {from:?}"))?;
+    let mut dest = fs::OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .open(to)
+        .context(format!("cannot open {to:?}"))?;
+    let mut writer = file::sparse::SparseWriter::new(&mut dest);
+
+    std::io::copy(&mut source, &mut writer)
+        .and_then(|_| writer.flush())
+        .context(format!("cannot overwrite {to:?}"))?;
+
+    fs::remove_file(from).context(format!("cannot remove {from:?}"))
+}
+
+/// Copies `from` to `to` as reflink, which shares the data blocks on
+/// copy-on-write file systems like btrfs or XFS. Falls back to a sparse copy
+/// on other file systems.
+fn copy_file(from: &Path, to: &Path) -> Result<()> {
+    match reflink(from, to) {
+        Ok(()) => debug!("reflinked {from:?} to {to:?}"),
+        Err(e) => {
+            debug!("cannot reflink {from:?} to {to:?}, copy instead: {e}");
+
+            // copy sparse file (std::fs::copy isn't able)
+            libfs::copy_file(from, to)
+                .context(format!("error: libfs::copy_file({:?}, {:?})", from, to))?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(from: &Path, to: &Path) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    // _IOW(0x94, 9, int) from linux/fs.h
+    const FICLONE: u32 = 0x40049409;
+
+    let source = fs::File::open(from)?;
+    let dest = fs::File::create(to)?;
+
+    // SAFETY: both file descriptors are valid for the duration of the call
+    if unsafe { libc::ioctl(dest.as_raw_fd(), FICLONE as _, source.as_raw_fd()) } == -1 {
+        let e = std::io::Error::last_os_error();
+        drop(dest);
+        let _ = fs::remove_file(to);
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_from: &Path, _to: &Path) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Creates a ssh tunnel to `device` in the environment given by `env` or the
+/// user configuration.
+fn create_ssh_tunnel(
+    device: &str,
+    username: &str,
+    dir: Option<PathBuf>,
+    priv_key_path: Option<PathBuf>,
+    config_path: Option<PathBuf>,
+    env: Option<PathBuf>,
+    user_config: &config::UserConfig,
+) -> Result<ssh::TunnelInfo> {
+    async fn create_tunnel(
+        device: &str,
+        username: &str,
+        dir: Option<PathBuf>,
+        priv_key_path: Option<PathBuf>,
+        config_path: Option<PathBuf>,
+        env_config: config::BackendConfig,
+    ) -> Result<ssh::TunnelInfo> {
+        let access_token = crate::auth::authorize(env_config.auth)
+            .await
+            .context(ErrorKind::Auth)
+            .context("create ssh tunnel")?;
+
+        let config = ssh::Config::new(env_config.backend, dir, priv_key_path, config_path)?;
+
+        ssh::ssh_create_tunnel(device, username, config, access_token).await
+    }
+
+    let env_conf: config::BackendConfig = if let Some(env_path) = env {
+        let config_file = file::secret::read_to_string(&env_path)?;
+
+        toml::from_str(&config_file)?
     } else {
-        // copy sparse file (std::fs::copy isn't able)
-        libfs::copy_file(&tmp_image_file, &dest_image_file).context(format!(
-            "error: libfs::copy_file({:?}, {:?})",
-            tmp_image_file, dest_image_file
-        ))?;
+        user_config.backend_config()?
+    };
+
+    runtime::block_on(create_tunnel(
+        device,
+        username,
+        dir,
+        priv_key_path,
+        config_path,
+        env_conf,
+    ))
+}
+
+/// How `--create` registers a device missing in iot-hub.
+enum NewDevice {
+    /// authenticated by the ca of its device certificate
+    X509Ca,
+    /// authenticated by a shared access key, if it's known
+    Sas(Option<String>),
+}
+
+/// Checks that `device_id` exists in the iot-hub `hub`, respectively has an
+/// individual enrollment in `dps`, before its identity is injected into an
+/// image. A missing device is registered in iot-hub if `create` is given.
+fn verify_cloud_device(
+    credentials: &device_update::AzureCredentials,
+    hub: Option<&str>,
+    dps: Option<&str>,
+    device_id: &str,
+    create: Option<NewDevice>,
+) -> Result<()> {
+    match (hub, dps) {
+        (Some(hub), _) => {
+            let iot_hub_hostname = iot_hub::iot_hub_hostname(hub);
+
+            if runtime::block_on(iot_hub::device_exists(
+                credentials,
+                &iot_hub_hostname,
+                device_id,
+            ))? {
+                debug!("verified device {device_id} in {iot_hub_hostname}");
+                return Ok(());
+            }
+
+            let registration = match create {
+                None => {
+                    return Err(anyhow::anyhow!(
+                        "device {device_id} doesn't exist in {iot_hub_hostname}, register it or pass --create"
+                    )
+                    .context(ErrorKind::User))
+                }
+                Some(NewDevice::X509Ca) => iot_hub::register_device(
+                    credentials,
+                    &iot_hub_hostname,
+                    device_id,
+                    &iot_hub::DeviceAuthentication::x509_ca,
+                    None,
+                    None,
+                    false,
+                ),
+                Some(NewDevice::Sas(Some(shared_access_key))) => iot_hub::register_sas_device(
+                    credentials,
+                    &iot_hub_hostname,
+                    device_id,
+                    &shared_access_key,
+                ),
+                Some(NewDevice::Sas(None)) => {
+                    return Err(anyhow::anyhow!(
+                        "cannot register device {device_id}, its shared access key is only known for connection strings"
+                    )
+                    .context(ErrorKind::User))
+                }
+            };
+
+            runtime::block_on(registration)?;
+            debug!("registered device {device_id} in {iot_hub_hostname}");
+
+            Ok(())
+        }
+        (None, Some(dps)) => {
+            let dps_hostname = dps::dps_hostname(dps);
+
+            if !runtime::block_on(dps::enrollment_exists(
+                credentials,
+                &dps_hostname,
+                device_id,
+            ))? {
+                return Err(anyhow::anyhow!(
+                    "{device_id} has no individual enrollment in {dps_hostname}"
+                )
+                .context(ErrorKind::User));
+            }
+
+            debug!("verified enrollment {device_id} in {dps_hostname}");
+
+            Ok(())
+        }
+        (None, None) => Err(anyhow::anyhow!(
+            "--verify-cloud requires --hub or --dps to verify {device_id} in"
+        )
+        .context(ErrorKind::User)),
+    }
+}
+
+thread_local! {
+    // results of commands run as jobs of the http service are collected
+    // instead of being printed
+    static COLLECTED_RESULTS: RefCell<Option<Vec<serde_json::Value>>> = const { RefCell::new(None) };
+}
+
+/// Prints the result of a command to stdout, either as text or as json.
+fn print_result(
+    output: &OutputFormat,
+    text: impl std::fmt::Display,
+    json: serde_json::Value,
+) -> Result<()> {
+    let json = COLLECTED_RESULTS.with_borrow_mut(|results| match results {
+        Some(results) => {
+            results.push(json);
+            None
+        }
+        None => Some(json),
+    });
+
+    let Some(json) = json else {
+        return Ok(());
+    };
+
+    match output {
+        OutputFormat::text => println!("{text}"),
+        OutputFormat::json => println!("{}", serde_json::to_string_pretty(&json)?),
+    }
+
+    Ok(())
+}
+
+/// Runs `cli` and returns the json results of the command instead of
+/// printing them.
+pub fn run_collecting_results(cli: cli::Cli) -> Result<Vec<serde_json::Value>> {
+    COLLECTED_RESULTS.set(Some(vec![]));
+
+    let result = run(cli);
+    let results = COLLECTED_RESULTS.take().unwrap_or_default();
+
+    result.map(|_| results)
+}
+
+/// Runs a config command, which doesn't depend on the user configuration in
+/// order to be able to diagnose and replace a broken one.
+fn run_config_command(command: cli::Config, output: &OutputFormat) -> Result<()> {
+    match command {
+        ConfigInit { config_path } => {
+            let config_path = match config_path {
+                Some(config_path) => config_path,
+                None => config::UserConfig::default_path()?,
+            };
+            // a broken configuration is replaced instead of preventing its fix
+            let current = config::UserConfig::load_from(&config_path).unwrap_or_else(|e| {
+                warn!("{e:#}, starting from an empty configuration");
+                config::UserConfig::default()
+            });
+            let config = config::query_user_config(
+                &current,
+                std::io::BufReader::new(std::io::stdin()),
+                std::io::stderr(),
+            )?;
+
+            config.store(&config_path)?;
+
+            print_result(
+                output,
+                format!(
+                    "Stored user configuration to {}",
+                    config_path.to_string_lossy()
+                ),
+                json!({ "config_path": config_path }),
+            )?;
+        }
+        ConfigValidate { config_path, env } => {
+            let config_path = match config_path {
+                Some(config_path) => config_path,
+                None => config::UserConfig::default_path()?,
+            };
+
+            let mut valid = vec![];
+
+            if config_path.try_exists().is_ok_and(|exists| exists) {
+                validators::config::validate_user_config(&config_path).context(ErrorKind::User)?;
+                valid.push(config_path);
+            } else if *output == OutputFormat::text {
+                println!("{} does not exist", config_path.to_string_lossy());
+            }
+
+            if let Some(env) = env {
+                validators::config::validate_backend_config(&env).context(ErrorKind::User)?;
+                valid.push(env);
+            }
+
+            print_result(
+                output,
+                valid
+                    .iter()
+                    .map(|path| format!("{} is valid", path.to_string_lossy()))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                json!({ "valid": valid }),
+            )?;
+        }
     }
 
     Ok(())
 }
 
-pub fn run() -> Result<()> {
-    match cli::from_args() {
+/// Exports the proxy of the user configuration, respectively of the `--env`
+/// file of commands creating a ssh tunnel, to the environment. Has to be
+/// called before the shared runtime is started, e.g. by [`run`], since
+/// modifying the environment isn't thread safe.
+pub fn apply_proxy(cli: &cli::Cli) -> Result<()> {
+    // vault references are resolved by the runtime, their proxy isn't applied
+    let env = match &cli.command {
+        Command::Ssh(SetConnection { env, .. })
+        | Command::Device(CollectLogs { env, .. } | DeviceStatus { env, .. }) => env
+            .as_ref()
+            .filter(|env| !file::secret::is_reference(&env.to_string_lossy())),
+        _ => None,
+    };
+    let proxy = match env {
+        Some(env_path) => {
+            toml::from_str::<config::BackendConfig>(&file::secret::read_to_string(env_path)?)?.proxy
+        }
+        None => config::UserConfig::load(cli.env_name.as_deref())?.proxy,
+    };
+
+    config::apply_proxy(proxy.as_ref())
+}
+
+pub fn run(cli: cli::Cli) -> Result<()> {
+    let command = match cli.command {
+        Command::Config(command) => return run_config_command(command, &cli.output),
+        command => command,
+    };
+    let mut user_config = config::UserConfig::load(cli.env_name.as_deref())?;
+
+    if cli.verify_signature.is_some() {
+        user_config.verify_signature = cli.verify_signature;
+    }
+
+    if cli.fsck {
+        user_config.fsck = Some(true);
+    }
+
+    if cli.zero_free_space {
+        user_config.zero_free_space = Some(true);
+    }
+
+    if cli.backup {
+        user_config.backup = Some(true);
+    }
+
+    user_config.output_device = cli.output_device;
+    user_config.assume_yes = cli.yes;
+    user_config.invocation = cli.invocation;
+
+    // decrypts .age and .gpg source files
+    let age_identity = user_config.age_identity.as_deref();
+
+    match command {
+        Command::Completions { shell } => cli::write_completions(
+            shell,
+            user_config.environments.keys().cloned().collect(),
+            &mut std::io::stdout(),
+        ),
+        Command::Serve {
+            listen,
+            token,
+            jobs,
+        } => daemon::serve(listen, token, jobs)?,
+        Command::Docs(Docs::Man { output_dir }) => {
+            docs::write_man_pages(&output_dir)?;
+
+            print_result(
+                &cli.output,
+                format!("Stored man pages to {}", output_dir.to_string_lossy()),
+                json!({ "output_dir": output_dir }),
+            )?;
+        }
+        Command::Docs(Docs::Markdown) => docs::write_markdown(&mut std::io::stdout())?,
+        Command::Schema(Schema::Manifest) => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&fleet::manifest_schema())?
+            )
+        }
+        Command::Config(_) => unreachable!("config commands run before loading the user config"),
         Command::Docker(Inject {
             docker_image,
             image,
@@ -160,97 +784,299 @@ pub fn run() -> Result<()> {
             dest,
             generate_bmap,
             compress_image,
-        }) => run_image_command(image, generate_bmap, compress_image, |img| {
-            anyhow::ensure!(
-                dest.to_string_lossy().ends_with(".tar.gz"),
-                format!(
-                    "invalid destination file path \"{}\". Must end in \".tar.gz\".",
-                    dest.to_string_lossy(),
-                ),
-            );
+        }) => run_image_command(
+            image,
+            user_config.generate_bmap(generate_bmap),
+            user_config.compression(compress_image)?,
+            &user_config,
+            |img| {
+                anyhow::ensure!(
+                    dest.to_string_lossy().ends_with(".tar.gz"),
+                    format!(
+                        "invalid destination file path \"{}\". Must end in \".tar.gz\".",
+                        dest.to_string_lossy(),
+                    ),
+                );
 
-            let arch = image::image_arch(img)?;
+                let arch = image::image_arch(img)?;
 
-            let docker_path = docker::pull_image(&docker_image, arch)?;
+                let docker_path = docker::pull_image(&docker_image, arch)?;
 
-            let result = file::copy_to_image(
-                &[FileCopyToParams::new(
-                    &docker_path,
-                    partition.clone(),
-                    &dest,
-                )],
-                img,
-            );
-            std::fs::remove_file(docker_path)?;
-
-            if result.is_ok() {
-                println!(
-                    "Stored {} to {}:{}",
-                    docker_image,
-                    partition,
-                    dest.to_string_lossy(),
+                let result = file::copy_to_image(
+                    &[FileCopyToParams::new(
+                        &docker_path,
+                        partition.clone(),
+                        &dest,
+                    )],
+                    img,
                 );
-            }
+                std::fs::remove_file(docker_path)?;
 
-            result
-        })?,
+                result?;
+
+                print_result(
+                    &cli.output,
+                    format!(
+                        "Stored {} to {}:{}",
+                        docker_image,
+                        partition,
+                        dest.to_string_lossy(),
+                    ),
+                    json!({
+                        "docker_image": docker_image,
+                        "partition": partition.to_string(),
+                        "dest": dest,
+                    }),
+                )
+            },
+        )?,
         Command::Identity(SetConfig {
             config,
             image,
             payload,
+            vars,
+            var_files,
+            verify_cloud,
+            azure,
+            hub,
+            dps,
+            create,
             generate_bmap,
             compress_image,
-        }) => run_image_command(image, generate_bmap, compress_image, |img| {
-            file::set_identity_config(&config, img, payload.as_deref())
-        })?,
+        }) => {
+            let vars = file::template::TemplateVars::load(&vars, &var_files)?;
+            let credentials = azure.credentials()?;
+
+            run_image_command(
+                image,
+                user_config.generate_bmap(generate_bmap),
+                user_config.compression(compress_image)?,
+                &user_config,
+                |img| {
+                    // encrypted files aren't rendered, so that their plaintext
+                    // isn't written to disk
+                    let config = vars.render_file(&config, img)?;
+                    let config = file::secret::open(&config, age_identity)?;
+
+                    if verify_cloud {
+                        match validators::identity::provisioned_device(config.path())
+                            .context(ErrorKind::User)?
+                        {
+                            validators::identity::ProvisionedDevice::Manual {
+                                iothub_hostname,
+                                device_id,
+                                shared_access_key,
+                            } => verify_cloud_device(
+                                &credentials,
+                                Some(hub.as_deref().unwrap_or(&iothub_hostname)),
+                                None,
+                                &device_id,
+                                create.then_some(NewDevice::Sas(shared_access_key)),
+                            )?,
+                            validators::identity::ProvisionedDevice::Dps { registration_id } => {
+                                verify_cloud_device(
+                                    &credentials,
+                                    hub.as_deref(),
+                                    dps.as_deref(),
+                                    &registration_id,
+                                    create.then_some(NewDevice::X509Ca),
+                                )?
+                            }
+                        }
+                    }
+
+                    let payload = payload
+                        .map(|payload| vars.render_file(&payload, img))
+                        .transpose()?
+                        .map(|payload| file::secret::open(&payload, age_identity))
+                        .transpose()?;
+
+                    file::set_identity_config(
+                        config.path(),
+                        img,
+                        payload.as_ref().map(file::secret::Secret::path),
+                    )
+                },
+            )?
+        }
+        Command::Identity(SetConnectionString {
+            connection_string,
+            hostname,
+            image,
+            generate_bmap,
+            compress_image,
+        }) => {
+            let hostname = hostname.unwrap_or_else(|| connection_string.device_id.clone());
+
+            validators::identity::validate_hostname(&hostname)
+                .context("pass a valid hostname by --hostname")
+                .context(ErrorKind::User)?;
+
+            run_image_command(
+                image,
+                user_config.generate_bmap(generate_bmap),
+                user_config.compression(compress_image)?,
+                &user_config,
+                |img: &PathBuf| file::set_connection_string(&connection_string, &hostname, img),
+            )?
+        }
         Command::Identity(SetDeviceCertificate {
             intermediate_full_chain_cert,
             intermediate_key,
+            vault_pki,
             image,
             device_id,
             days,
+            verify_cloud,
+            azure,
+            hub,
+            dps,
+            create,
+            export_pkcs12,
+            password,
             generate_bmap,
             compress_image,
         }) => {
-            let intermediate_full_chain_cert_str =
-                std::fs::read_to_string(&intermediate_full_chain_cert)
-                    .context("couldn't read intermediate fullchain cert")?;
-            let intermediate_key_str = std::fs::read_to_string(intermediate_key)
-                .context("couldn't read intermediate key")?;
-            let crypto = omnect_crypto::Crypto::new(
-                intermediate_key_str.as_bytes(),
-                intermediate_full_chain_cert_str.as_bytes(),
-            )?;
-            let (device_cert_pem, device_key_pem) = crypto
-                .create_cert_and_key(&device_id, &None, days)
-                .context("couldn't create device cert and key")?;
+            if verify_cloud {
+                verify_cloud_device(
+                    &azure.credentials()?,
+                    hub.as_deref(),
+                    dps.as_deref(),
+                    &device_id,
+                    create.then_some(NewDevice::X509Ca),
+                )?;
+            }
 
             let device_cert_path = file::get_file_path(&image, "device_cert_path.pem")?;
             let device_key_path = file::get_file_path(&image, "device_key_path.key.pem")?;
 
-            fs::write(&device_cert_path, device_cert_pem)
-                .context("set_device_cert: write device_cert_path")?;
-            fs::write(&device_key_path, device_key_pem)
-                .context("set_device_cert: write device_key_path")?;
+            let intermediate_full_chain_cert = match vault_pki {
+                Some(role_path) => {
+                    let issued = vault::issue_certificate(&role_path, &device_id, days)?;
+                    let full_chain_path =
+                        file::get_file_path(&image, "intermediate_full_chain_cert.pem")?;
+
+                    fs::write(&device_cert_path, &issued.certificate)
+                        .context("set_device_cert: write device_cert_path")?;
+                    fs::write(&device_key_path, &issued.private_key)
+                        .context("set_device_cert: write device_key_path")?;
+                    fs::write(&full_chain_path, issued.full_chain())
+                        .context("set_device_cert: write intermediate_full_chain_cert")?;
+
+                    file::secret::open(&full_chain_path, None)?
+                }
+                None => {
+                    let intermediate_full_chain_cert = file::secret::open(
+                        &intermediate_full_chain_cert
+                            .context("intermediate full-chain-certificate must be provided")?,
+                        age_identity,
+                    )?;
+                    let intermediate_full_chain_cert_str =
+                        std::fs::read_to_string(intermediate_full_chain_cert.path())
+                            .context("couldn't read intermediate fullchain cert")?;
+                    let intermediate_key_str = std::fs::read_to_string(
+                        file::secret::open(
+                            &intermediate_key.context("intermediate key must be provided")?,
+                            age_identity,
+                        )?
+                        .path(),
+                    )
+                    .context("couldn't read intermediate key")?;
+                    let crypto = omnect_crypto::Crypto::new(
+                        intermediate_key_str.as_bytes(),
+                        intermediate_full_chain_cert_str.as_bytes(),
+                    )?;
+                    let (device_cert_pem, device_key_pem) = crypto
+                        .create_cert_and_key(&device_id, &None, days)
+                        .context("couldn't create device cert and key")?;
+
+                    fs::write(&device_cert_path, device_cert_pem)
+                        .context("set_device_cert: write device_cert_path")?;
+                    fs::write(&device_key_path, device_key_pem)
+                        .context("set_device_cert: write device_key_path")?;
+
+                    intermediate_full_chain_cert
+                }
+            };
+
+            run_image_command(
+                image,
+                user_config.generate_bmap(generate_bmap),
+                user_config.compression(compress_image)?,
+                &user_config,
+                |img| {
+                    file::set_device_cert(
+                        Some(intermediate_full_chain_cert.path()),
+                        &device_cert_path,
+                        &device_key_path,
+                        img,
+                    )
+                },
+            )?;
 
-            run_image_command(image, generate_bmap, compress_image, |img| {
-                file::set_device_cert(
-                    Some(&intermediate_full_chain_cert),
+            if let Some(export_pkcs12) = &export_pkcs12 {
+                pkcs12::export(
                     &device_cert_path,
                     &device_key_path,
-                    img,
-                )
-            })?
+                    Some(intermediate_full_chain_cert.path()),
+                    password.as_deref().unwrap_or_default(),
+                    export_pkcs12,
+                )?;
+            }
+
+            let thumbprints = iot_hub::thumbprints(
+                &fs::read_to_string(&device_cert_path)
+                    .context("set_device_cert: read device_cert_path")?,
+            )?;
+
+            print_result(
+                &cli.output,
+                format!(
+                    "Injected device certificate of {device_id}\nSHA-1 thumbprint: {}\nSHA-256 thumbprint: {}",
+                    thumbprints.sha1, thumbprints.sha256
+                ),
+                json!({
+                    "device_id": device_id,
+                    "thumbprints": thumbprints,
+                    "pkcs12": export_pkcs12,
+                }),
+            )?
         }
         Command::Identity(SetDeviceCertificateNoEst {
             device_cert: device_cert_pem,
             device_key: device_key_pem,
             image,
+            export_pkcs12,
+            password,
             generate_bmap,
             compress_image,
-        }) => run_image_command(image, generate_bmap, compress_image, |img| {
-            file::set_device_cert(None, &device_cert_pem, &device_key_pem, img)
-        })?,
+        }) => {
+            let device_cert_pem = file::secret::open(&device_cert_pem, age_identity)?;
+            let device_key_pem = file::secret::open(&device_key_pem, age_identity)?;
+
+            validators::cert::validate_key_pair(device_cert_pem.path(), device_key_pem.path())
+                .context(ErrorKind::User)?;
+
+            run_image_command(
+                image,
+                user_config.generate_bmap(generate_bmap),
+                user_config.compression(compress_image)?,
+                &user_config,
+                |img| {
+                    file::set_device_cert(None, device_cert_pem.path(), device_key_pem.path(), img)
+                },
+            )?;
+
+            if let Some(export_pkcs12) = export_pkcs12 {
+                pkcs12::export(
+                    device_cert_pem.path(),
+                    device_key_pem.path(),
+                    None,
+                    password.as_deref().unwrap_or_default(),
+                    &export_pkcs12,
+                )?;
+            }
+        }
         Command::Identity(SetIotedgeGatewayConfig {
             config,
             image,
@@ -259,80 +1085,1182 @@ pub fn run() -> Result<()> {
             device_identity_key,
             generate_bmap,
             compress_image,
-        }) => run_image_command(image, generate_bmap, compress_image, |img: &PathBuf| {
-            file::set_iotedge_gateway_config(
-                &config,
-                img,
-                &root_ca,
-                &device_identity,
-                &device_identity_key,
-            )
-        })?,
+        }) => {
+            let config = file::secret::open(&config, age_identity)?;
+            let root_ca = file::secret::open(&root_ca, age_identity)?;
+            let device_identity = file::secret::open(&device_identity, age_identity)?;
+            let device_identity_key = file::secret::open(&device_identity_key, age_identity)?;
+
+            validators::cert::validate_certificates(root_ca.path()).context(ErrorKind::User)?;
+            validators::cert::validate_chain(device_identity.path(), root_ca.path())
+                .context(ErrorKind::User)?;
+            validators::cert::validate_key_pair(device_identity.path(), device_identity_key.path())
+                .context(ErrorKind::User)?;
+
+            run_image_command(
+                image,
+                user_config.generate_bmap(generate_bmap),
+                user_config.compression(compress_image)?,
+                &user_config,
+                |img: &PathBuf| {
+                    file::set_iotedge_gateway_config(
+                        config.path(),
+                        img,
+                        root_ca.path(),
+                        device_identity.path(),
+                        device_identity_key.path(),
+                    )
+                },
+            )?
+        }
         Command::Identity(SetIotLeafSasConfig {
             config,
             image,
             root_ca,
             generate_bmap,
             compress_image,
-        }) => run_image_command(image, generate_bmap, compress_image, |img: &PathBuf| {
-            file::set_iot_leaf_sas_config(&config, img, &root_ca)
-        })?,
-        Command::Ssh(SetCertificate {
+        }) => {
+            let config = file::secret::open(&config, age_identity)?;
+            let root_ca = file::secret::open(&root_ca, age_identity)?;
+
+            validators::cert::validate_certificates(root_ca.path()).context(ErrorKind::User)?;
+
+            run_image_command(
+                image,
+                user_config.generate_bmap(generate_bmap),
+                user_config.compression(compress_image)?,
+                &user_config,
+                |img: &PathBuf| file::set_iot_leaf_sas_config(config.path(), img, root_ca.path()),
+            )?
+        }
+        Command::Identity(SetHostname {
+            hostname,
+            machine_id_salt,
+            image,
+            generate_bmap,
+            compress_image,
+        }) => run_image_command(
+            image,
+            user_config.generate_bmap(generate_bmap),
+            user_config.compression(compress_image)?,
+            &user_config,
+            |img: &PathBuf| file::set_hostname(&hostname, machine_id_salt.as_deref(), img),
+        )?,
+        Command::Network(SetWifi {
+            image,
+            ssid,
+            psk,
+            profile,
+            interface,
+            generate_bmap,
+            compress_image,
+        }) => run_image_command(
+            image,
+            user_config.generate_bmap(generate_bmap),
+            user_config.compression(compress_image)?,
+            &user_config,
+            |img: &PathBuf| match (&ssid, &psk, &profile) {
+                (Some(ssid), Some(psk), None) => {
+                    file::network::set_wifi_credentials(ssid, psk, &interface, img)
+                }
+                (None, None, Some(profile)) => {
+                    file::network::set_wifi_profile(profile, &interface, img)
+                }
+                _ => Err(
+                    anyhow::anyhow!("either --ssid and --psk or --profile is required")
+                        .context(ErrorKind::User),
+                ),
+            },
+        )?,
+        Command::Boot(SetCmdline {
+            image,
+            append,
+            remove,
+            variable,
+            generate_bmap,
+            compress_image,
+        }) => run_image_command(
+            image,
+            user_config.generate_bmap(generate_bmap),
+            user_config.compression(compress_image)?,
+            &user_config,
+            |img: &PathBuf| file::boot::set_cmdline(&append, &remove, &variable, img),
+        )?,
+        Command::Boot(SetUbootEnv {
+            image,
+            variables,
+            offset,
+            size,
+            generate_bmap,
+            compress_image,
+        }) => run_image_command(
+            image,
+            user_config.generate_bmap(generate_bmap),
+            user_config.compression(compress_image)?,
+            &user_config,
+            |img: &PathBuf| file::boot::set_uboot_env(&variables, offset.zip(size), img),
+        )?,
+        Command::Boot(UpdateVerity {
+            image,
+            cert,
+            key,
+            variable,
+            generate_bmap,
+            compress_image,
+        }) => run_image_command(
+            image,
+            user_config.generate_bmap(generate_bmap),
+            user_config.compression(compress_image)?,
+            &user_config,
+            |img: &PathBuf| {
+                let root_hash = file::verity::update_verity(&cert, &key, &variable, img)?;
+
+                print_result(
+                    &cli.output,
+                    format!("Updated root hash to {root_hash}"),
+                    json!({ "root_hash": root_hash }),
+                )
+            },
+        )?,
+        Command::Secureboot(Enroll {
+            image,
+            pk,
+            kek,
+            db,
+            dbx,
+            name,
+            mode,
+            generate_bmap,
+            compress_image,
+        }) => {
+            let keys = file::secureboot::SecureBootKeys { pk, kek, db, dbx };
+
+            run_image_command(
+                image,
+                user_config.generate_bmap(generate_bmap),
+                user_config.compression(compress_image)?,
+                &user_config,
+                |img: &PathBuf| file::secureboot::enroll_keys(&keys, &name, &mode, img),
+            )?
+        }
+        Command::Image(ImageSign {
+            image,
+            key,
+            signature,
+        }) => {
+            let signed = signature::sign(&image, &key, signature.as_deref())?;
+
+            print_result(
+                &cli.output,
+                format!(
+                    "signature: {}\npredicate: {}\nattestation: {}",
+                    signed.signature.to_string_lossy(),
+                    signed.predicate.to_string_lossy(),
+                    signed.attestation.to_string_lossy()
+                ),
+                json!(signed),
+            )?;
+        }
+        Command::Image(ImageConvert { image, output, to }) => {
+            read_image_command(image, &user_config, |img: &PathBuf| match to {
+                file::vhd::DiskFormat::vhd => {
+                    // the working copy is converted in place
+                    move_file(img, &output)?;
+                    file::vhd::to_fixed_vhd(&output).map(|_| ())
+                }
+                file::vhd::DiskFormat::vhdx => file::vhd::to_vhdx(img, &output),
+            })?;
+
+            print_result(
+                &cli.output,
+                format!("Stored {to:?} image to {}", output.to_string_lossy()),
+                json!({ "image": output, "format": format!("{to:?}") }),
+            )?;
+        }
+        Command::Image(ImageCheck { image }) => {
+            let mut errors = vec![];
+
+            read_image_command(image.clone(), &user_config, |img: &PathBuf| {
+                errors = file::fsck::check_image(img)?;
+                Ok(())
+            })?;
+
+            if !errors.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "{} has inconsistent filesystems:\n{}",
+                    image.to_string_lossy(),
+                    errors
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                )
+                .context(ErrorKind::User));
+            }
+
+            print_result(
+                &cli.output,
+                format!(
+                    "All filesystems of {} are consistent",
+                    image.to_string_lossy()
+                ),
+                json!({ "image": image, "errors": errors }),
+            )?;
+        }
+        Command::Image(ImageMount {
+            image,
+            partition,
+            mountpoint,
+            rw,
+            generate_bmap,
+            compress_image,
+        }) => {
+            if rw {
+                run_image_command(
+                    image,
+                    user_config.generate_bmap(generate_bmap),
+                    user_config.compression(compress_image)?,
+                    &user_config,
+                    |img: &PathBuf| file::mount::mount(img, &partition, &mountpoint, true),
+                )?
+            } else {
+                read_image_command(image, &user_config, |img: &PathBuf| {
+                    file::mount::mount(img, &partition, &mountpoint, false)
+                })?
+            }
+        }
+        Command::Image(Transcode {
+            image,
+            output,
+            compress_image,
+        }) => {
+            if !image.try_exists().is_ok_and(|exists| exists) {
+                return Err(
+                    anyhow::anyhow!("image doesn't exist {}", image.to_string_lossy())
+                        .context(ErrorKind::User),
+                );
+            }
+
+            let output = output.unwrap_or_else(|| {
+                compression::transcoded_file_name(&image, compress_image.as_ref())
+            });
+
+            if output == image {
+                return Err(anyhow::anyhow!(
+                    "output must differ from the image {}",
+                    image.to_string_lossy()
+                )
+                .context(ErrorKind::User));
+            }
+
+            compression::transcode(&image, compress_image.as_ref(), &output)?;
+
+            print_result(
+                &cli.output,
+                format!("Stored image to {}", output.to_string_lossy()),
+                json!({
+                    "image": output,
+                    "compression": compress_image.as_ref().map(Compression::name),
+                }),
+            )?;
+        }
+        Command::Image(ImageDiff { base, image }) => {
+            let mut diffs = vec![];
+
+            read_image_command(base, &user_config, |base_img: &PathBuf| {
+                read_image_command(image, &user_config, |img: &PathBuf| {
+                    diffs = diff::diff_images(base_img, img)?;
+                    Ok(())
+                })
+            })?;
+
+            let count = |change| diffs.iter().filter(|d| d.change == change).count();
+            let mut text: Vec<String> = diffs.iter().map(ToString::to_string).collect();
+
+            text.push(format!(
+                "{} added, {} removed, {} changed",
+                count(diff::Change::added),
+                count(diff::Change::removed),
+                count(diff::Change::changed)
+            ));
+
+            print_result(&cli.output, text.join("\n"), json!(diffs))?;
+        }
+        Command::Image(ResizePartition {
+            image,
+            partition,
+            size,
+            generate_bmap,
+            compress_image,
+        }) => run_image_command(
+            image,
+            user_config.generate_bmap(generate_bmap),
+            user_config.compression(compress_image)?,
+            &user_config,
+            |img: &PathBuf| file::resize::resize_partition(img, partition, size),
+        )?,
+        Command::Image(AddPartition {
+            image,
+            name,
+            size,
+            filesystem,
+            generate_bmap,
+            compress_image,
+        }) => run_image_command(
+            image,
+            user_config.generate_bmap(generate_bmap),
+            user_config.compression(compress_image)?,
+            &user_config,
+            |img: &PathBuf| {
+                let num = file::resize::add_partition(img, &name, size, filesystem)?;
+
+                print_result(
+                    &cli.output,
+                    format!("Added {filesystem} partition {name} as partition {num}"),
+                    json!({ "name": name, "partition": num, "filesystem": filesystem.to_string() }),
+                )
+            },
+        )?,
+        Command::Image(ResetData {
+            image,
+            partition,
+            generate_bmap,
+            compress_image,
+        }) => run_image_command(
+            image,
+            user_config.generate_bmap(generate_bmap),
+            user_config.compression(compress_image)?,
+            &user_config,
+            |img: &PathBuf| file::resize::reset_partition(img, partition),
+        )?,
+        Command::Image(SetFs {
+            image,
+            partition,
+            label,
+            uuid,
+            generate_bmap,
+            compress_image,
+        }) => run_image_command(
+            image,
+            user_config.generate_bmap(generate_bmap),
+            user_config.compression(compress_image)?,
+            &user_config,
+            |img: &PathBuf| {
+                file::resize::set_filesystem(img, partition, label.as_deref(), uuid.as_deref())
+            },
+        )?,
+        Command::Image(Reuuid {
+            image,
+            generate_bmap,
+            compress_image,
+        }) => run_image_command(
+            image,
+            user_config.generate_bmap(generate_bmap),
+            user_config.compression(compress_image)?,
+            &user_config,
+            |img: &PathBuf| {
+                let (changes, patched) = file::reuuid::reuuid(img)?;
+
+                print_result(
+                    &cli.output,
+                    changes
+                        .iter()
+                        .map(|change| {
+                            format!("{}: {} -> {}", change.object, change.old, change.new)
+                        })
+                        .chain(patched.iter().map(|file| format!("patched {file}")))
+                        .collect::<Vec<_>>()
+                        .join("\n"),
+                    json!({ "changes": changes, "patched": patched }),
+                )
+            },
+        )?,
+        Command::Image(ImageClone {
+            image,
+            count,
+            output_dir,
+            name,
+            hostname,
+            intermediate_full_chain_cert,
+            intermediate_key,
+            days,
+            jobs,
+            generate_bmap,
+            compress_image,
+        }) => {
+            let devices = fleet::clone_devices(count, &name, &image).context(ErrorKind::User)?;
+            let manifest = fleet::FleetManifest::for_clones(
+                hostname,
+                intermediate_full_chain_cert.zip(intermediate_key),
+                days,
+            );
+            let images = fleet::provision(
+                &devices,
+                &image,
+                &manifest,
+                &output_dir,
+                &fleet::ProvisionOptions {
+                    jobs,
+                    generate_bmap: user_config.generate_bmap(generate_bmap),
+                    compression: user_config.compression(compress_image)?,
+                },
+                &user_config,
+            )?;
+
+            print_result(
+                &cli.output,
+                images
+                    .iter()
+                    .map(|image| format!("{}: {}", image.device_id, image.image.to_string_lossy()))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                json!({ "images": images }),
+            )?
+        }
+        Command::Image(ImageFlash {
+            image,
+            bmap,
+            device,
+        }) => {
+            let bmap = match bmap {
+                Some(bmap) => bmap,
+                None => bmap::default_bmap_file(&image)?,
+            };
+            let written = bmap::flash(&image, &bmap::Bmap::read(&bmap)?, &device)?;
+
+            print_result(
+                &cli.output,
+                format!(
+                    "Flashed {} to {} ({written} bytes written)",
+                    image.to_string_lossy(),
+                    device.to_string_lossy()
+                ),
+                json!({ "image": image, "device": device, "written": written }),
+            )?;
+        }
+        Command::Image(VerifyBmap { image, bmap }) => {
+            let bmap = match bmap {
+                Some(bmap) => bmap,
+                None => bmap::default_bmap_file(&image)?,
+            };
+            let bmap = bmap::Bmap::read(&bmap)?;
+            let mismatches = bmap::verify(&image, &bmap)?;
+
+            if !mismatches.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "{} doesn't match the bmap, checksum mismatch of blocks {}",
+                    image.to_string_lossy(),
+                    mismatches
+                        .iter()
+                        .map(|range| format!("{}-{}", range.first, range.last))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+                .context(ErrorKind::User));
+            }
+
+            print_result(
+                &cli.output,
+                format!(
+                    "{} matches the bmap ({} bytes verified)",
+                    image.to_string_lossy(),
+                    bmap.mapped_size()
+                ),
+                json!({ "image": image, "verified": bmap.mapped_size() }),
+            )?;
+        }
+        Command::Image(ImageShrink {
+            image,
+            margin,
+            generate_bmap,
+            compress_image,
+        }) => run_image_command(
+            image,
+            generate_bmap.or(user_config.generate_bmap).unwrap_or(true),
+            user_config.compression(compress_image)?,
+            &user_config,
+            |img: &PathBuf| {
+                let size = file::resize::shrink_image(img, margin)?;
+
+                print_result(
+                    &cli.output,
+                    format!("Shrunk image to {size} bytes"),
+                    json!({ "size": size }),
+                )
+            },
+        )?,
+        Command::Image(CreateMenderArtifact {
+            image,
+            output,
+            name,
+            device_types,
+            software_version,
+            key,
+        }) => {
+            read_image_command(image, &user_config, |img: &PathBuf| {
+                artifact::mender::create_artifact(
+                    img,
+                    &artifact::mender::MenderArtifact {
+                        name: &name,
+                        device_types: &device_types,
+                        software_version: software_version.as_deref(),
+                    },
+                    key.as_deref(),
+                    &output,
+                )
+            })?;
+
+            print_result(
+                &cli.output,
+                format!("Stored mender artifact to {}", output.to_string_lossy()),
+                json!({ "artifact": output }),
+            )?;
+        }
+        Command::Image(CreateRaucBundle {
+            image,
+            output,
+            compatible,
+            version,
+            cert,
+            key,
+            keyring,
+            partition,
+            slot_class,
+            format,
+        }) => {
+            read_image_command(image, &user_config, |img: &PathBuf| {
+                artifact::rauc::create_bundle(
+                    img,
+                    &partition,
+                    &artifact::rauc::RaucManifest {
+                        compatible: &compatible,
+                        version: version.as_deref(),
+                        slot_class: &slot_class,
+                        format: &format,
+                    },
+                    &artifact::rauc::RaucSigning {
+                        cert: &cert,
+                        key: &key,
+                        keyring: keyring.as_deref(),
+                    },
+                    &output,
+                )
+            })?;
+
+            print_result(
+                &cli.output,
+                format!("Stored rauc bundle to {}", output.to_string_lossy()),
+                json!({ "bundle": output }),
+            )?;
+        }
+        Command::Image(CreateSwu {
+            image,
+            sw_description,
+            output,
+            partition,
+            name,
+            files,
+            sign_key,
+            sign_cert,
+        }) => {
+            read_image_command(image, &user_config, |img: &PathBuf| {
+                artifact::swupdate::create_swu(
+                    img,
+                    &partition,
+                    &name,
+                    &sw_description,
+                    &files,
+                    sign_key
+                        .as_deref()
+                        .map(|key| artifact::swupdate::SwuSigning {
+                            key,
+                            cert: sign_cert.as_deref(),
+                        }),
+                    &output,
+                )
+            })?;
+
+            print_result(
+                &cli.output,
+                format!("Stored swu to {}", output.to_string_lossy()),
+                json!({ "swu": output }),
+            )?;
+        }
+        Command::Image(ImageHistory { image }) => {
+            let mut history = vec![];
+
+            read_image_command(image, &user_config, |img: &PathBuf| {
+                history = provenance::history(img)?;
+                Ok(())
+            })?;
+
+            let verified = provenance::verify_history(&history);
+            let text = if history.is_empty() {
+                "image wasn't modified by omnect-cli".to_string()
+            } else {
+                history
+                    .iter()
+                    .enumerate()
+                    .map(|(i, entry)| {
+                        let mut text = format!(
+                            "{}. {} omnect-cli {}: {}",
+                            i + 1,
+                            entry.timestamp,
+                            entry.version,
+                            entry.command
+                        );
+
+                        for input in &entry.inputs {
+                            text.push_str(&format!(
+                                "\n     {} sha256:{}",
+                                input.file, input.sha256
+                            ));
+                        }
+
+                        text
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            };
+
+            print_result(
+                &cli.output,
+                text,
+                json!({ "history": history, "verified": verified.is_ok() }),
+            )?;
+
+            verified?;
+        }
+        Command::Image(Sbom {
+            image,
+            output,
+            format,
+            containers,
+        }) => {
+            let name = image
+                .file_name()
+                .context("cannot get image file name")?
+                .to_string_lossy()
+                .to_string();
+            let mut packages = 0;
+
+            read_image_command(image, &user_config, |img: &PathBuf| {
+                packages = sbom::write_sbom(img, &name, &containers, &format, &output)?;
+                Ok(())
+            })?;
+
+            print_result(
+                &cli.output,
+                format!(
+                    "Stored sbom of {packages} packages to {}",
+                    output.to_string_lossy()
+                ),
+                json!({ "sbom": output, "packages": packages }),
+            )?;
+        }
+        Command::Image(ImageVerify {
+            image,
+            key,
+            signature,
+            manifest,
+        }) => {
+            if let Some(key) = &key {
+                signature::verify(&image, key, signature.as_deref())?;
+            }
+
+            let manifest = manifest.or_else(|| {
+                let sidecar = integrity::manifest_path(&image);
+                sidecar
+                    .try_exists()
+                    .is_ok_and(|exists| exists)
+                    .then_some(sidecar)
+            });
+
+            if let Some(manifest) = manifest {
+                let manifest = integrity::read(&manifest)?;
+
+                read_image_command(image.clone(), &user_config, |img: &PathBuf| {
+                    let altered = integrity::verify(&manifest, img)?;
+
+                    if altered.is_empty() {
+                        return Ok(());
+                    }
+
+                    Err(anyhow::anyhow!(
+                        "{} files were altered since the integrity manifest was written:\n{}",
+                        altered.len(),
+                        altered
+                            .iter()
+                            .map(|diff| format!("  {diff}"))
+                            .collect::<Vec<_>>()
+                            .join("\n")
+                    )
+                    .context(ErrorKind::User))
+                })?;
+            }
+
+            print_result(
+                &cli.output,
+                format!("{} verified", image.to_string_lossy()),
+                json!({ "image": image, "verified": true }),
+            )?;
+        }
+        Command::Network(SetWireguard {
+            image,
+            config,
+            generate_bmap,
+            compress_image,
+        }) => run_image_command(
+            image,
+            user_config.generate_bmap(generate_bmap),
+            user_config.compression(compress_image)?,
+            &user_config,
+            |img: &PathBuf| file::network::set_wireguard_config(&config, img),
+        )?,
+        Command::Network(SetStatic {
+            image,
+            interface,
+            addresses,
+            gateway,
+            dns,
+            generate_bmap,
+            compress_image,
+        }) => run_image_command(
+            image,
+            user_config.generate_bmap(generate_bmap),
+            user_config.compression(compress_image)?,
+            &user_config,
+            |img: &PathBuf| {
+                file::network::set_static_network(&interface, &addresses, gateway, &dns, img)
+            },
+        )?,
+        Command::System(SetProxy {
+            image,
+            url,
+            no_proxy,
+            generate_bmap,
+            compress_image,
+        }) => run_image_command(
+            image,
+            user_config.generate_bmap(generate_bmap),
+            user_config.compression(compress_image)?,
+            &user_config,
+            |img: &PathBuf| file::system::set_proxy(&url, &no_proxy, img),
+        )?,
+        Command::System(SetTimeConfig {
+            image,
+            timezone,
+            ntp_servers,
+            fallback_ntp_servers,
+            locale,
+            generate_bmap,
+            compress_image,
+        }) => {
+            let config = file::system::TimeConfig {
+                timezone,
+                ntp_servers,
+                fallback_ntp_servers,
+                locale,
+            };
+
+            run_image_command(
+                image,
+                user_config.generate_bmap(generate_bmap),
+                user_config.compression(compress_image)?,
+                &user_config,
+                |img: &PathBuf| file::system::set_time_config(&config, img),
+            )?
+        }
+        Command::User(UserSet {
+            image,
+            name,
+            password_hash,
+            groups,
+            authorized_keys,
+            generate_bmap,
+            compress_image,
+        }) => {
+            let user = file::user::User {
+                name,
+                password_hash,
+                groups,
+                authorized_keys,
+            };
+
+            run_image_command(
+                image,
+                user_config.generate_bmap(generate_bmap),
+                user_config.compression(compress_image)?,
+                &user_config,
+                |img: &PathBuf| file::user::set_user(&user, img),
+            )?
+        }
+        Command::Ssh(SetCertificate {
             image,
             root_ca,
             generate_bmap,
             compress_image,
-        }) => run_image_command(image, generate_bmap, compress_image, |img: &PathBuf| {
-            file::set_ssh_tunnel_certificate(img, &root_ca)
-        })?,
+        }) => run_image_command(
+            image,
+            user_config.generate_bmap(generate_bmap),
+            user_config.compression(compress_image)?,
+            &user_config,
+            |img: &PathBuf| file::set_ssh_tunnel_certificate(img, &root_ca),
+        )?,
         Command::IotHubDeviceUpdate(IotHubDeviceUpdateSet {
             iot_hub_device_update_config,
             image,
             generate_bmap,
             compress_image,
-        }) => run_image_command(image, generate_bmap, compress_image, |img: &PathBuf| {
-            file::set_iot_hub_device_update_config(&iot_hub_device_update_config, img)
-        })?,
+        }) => run_image_command(
+            image,
+            user_config.generate_bmap(generate_bmap),
+            user_config.compression(compress_image)?,
+            &user_config,
+            |img: &PathBuf| {
+                file::set_iot_hub_device_update_config(&iot_hub_device_update_config, img)
+            },
+        )?,
         Command::IotHubDeviceUpdate(IotHubDeviceUpdate::ImportUpdate {
             import_manifest: import_manifest_path,
+            import_manifest_url,
             storage_container_name,
-            tenant_id,
-            client_id,
-            client_secret,
-            instance_id,
-            device_update_endpoint_url,
+            azure,
+            instance,
             blob_storage_account,
             blob_storage_key,
-        }) => device_update::import_update(
-            &import_manifest_path,
-            &storage_container_name,
-            &tenant_id,
-            &client_id,
-            &client_secret,
-            &instance_id,
-            &device_update_endpoint_url,
-            &blob_storage_account,
-            &blob_storage_key,
-        )?,
+            blob_storage_sas_url,
+        }) => {
+            let credentials = azure.credentials()?;
+            let (instance_id, device_update_endpoint_url) = user_config.device_update_instance(
+                instance.instance_id,
+                instance.device_update_endpoint_url,
+            )?;
+
+            if let Some(import_manifest_url) = import_manifest_url {
+                runtime::block_on(device_update::import_update_from_url(
+                    &import_manifest_url,
+                    &credentials,
+                    &instance_id,
+                    &device_update_endpoint_url,
+                ))?;
+
+                print_result(
+                    &cli.output,
+                    format!("Imported update from {import_manifest_url}"),
+                    json!({ "imported": import_manifest_url }),
+                )?
+            } else {
+                let import_manifest_path =
+                    import_manifest_path.context("import manifest path must be provided")?;
+
+                let blob_storage = device_update::BlobStorage::new(
+                    blob_storage_sas_url,
+                    blob_storage_account,
+                    blob_storage_key,
+                    storage_container_name,
+                )?;
+
+                runtime::block_on(device_update::import_update(
+                    &import_manifest_path,
+                    &credentials,
+                    &instance_id,
+                    &device_update_endpoint_url,
+                    &blob_storage,
+                ))?;
+
+                print_result(
+                    &cli.output,
+                    format!(
+                        "Imported update from {}",
+                        import_manifest_path.to_string_lossy()
+                    ),
+                    json!({ "imported": import_manifest_path }),
+                )?
+            }
+        }
+        Command::IotHubDeviceUpdate(IotHubDeviceUpdate::ExportUpdate {
+            azure,
+            instance,
+            provider,
+            distro_name,
+            version,
+            storage_container_name,
+            blob_storage_account,
+            blob_storage_key,
+            blob_storage_sas_url,
+            output_dir,
+        }) => {
+            let (instance_id, device_update_endpoint_url) = user_config.device_update_instance(
+                instance.instance_id,
+                instance.device_update_endpoint_url,
+            )?;
+
+            runtime::block_on(device_update::export_update(
+                &azure.credentials()?,
+                &instance_id,
+                &device_update_endpoint_url,
+                &provider,
+                &distro_name,
+                &version,
+                &device_update::BlobStorage::new(
+                    blob_storage_sas_url,
+                    blob_storage_account,
+                    blob_storage_key,
+                    storage_container_name,
+                )?,
+                &output_dir,
+            ))?;
+
+            print_result(
+                &cli.output,
+                format!(
+                    "Exported {provider}/{distro_name}/{version} to {}",
+                    output_dir.to_string_lossy()
+                ),
+                json!({
+                    "provider": provider,
+                    "name": distro_name,
+                    "version": version,
+                    "output_dir": output_dir,
+                }),
+            )?
+        }
         Command::IotHubDeviceUpdate(IotHubDeviceUpdate::RemoveUpdate {
-            tenant_id,
-            client_id,
-            client_secret,
-            instance_id,
-            device_update_endpoint_url,
+            azure,
+            instance,
             provider,
             distro_name,
             version,
-        }) => device_update::remove_update(
-            &tenant_id,
-            &client_id,
-            &client_secret,
-            &instance_id,
-            &device_update_endpoint_url,
-            &provider,
-            &distro_name,
-            &version,
-        )?,
+        }) => {
+            let (instance_id, device_update_endpoint_url) = user_config.device_update_instance(
+                instance.instance_id,
+                instance.device_update_endpoint_url,
+            )?;
+
+            runtime::block_on(device_update::remove_update(
+                &azure.credentials()?,
+                &instance_id,
+                &device_update_endpoint_url,
+                &provider,
+                &distro_name,
+                &version,
+            ))?;
+
+            print_result(
+                &cli.output,
+                format!("Removed {provider}/{distro_name}/{version}"),
+                json!({ "provider": provider, "name": distro_name, "version": version }),
+            )?
+        }
+        Command::IotHubDeviceUpdate(IotHubDeviceUpdate::CancelDeployment {
+            azure,
+            instance,
+            group_id,
+            device_class_id,
+            deployment_id,
+        }) => {
+            let (instance_id, device_update_endpoint_url) = user_config.device_update_instance(
+                instance.instance_id,
+                instance.device_update_endpoint_url,
+            )?;
+
+            runtime::block_on(device_update::cancel_deployment(
+                &azure.credentials()?,
+                &instance_id,
+                &device_update_endpoint_url,
+                &group_id,
+                &device_class_id,
+                &deployment_id,
+            ))?;
+
+            print_result(
+                &cli.output,
+                format!("Canceled deployment {deployment_id}"),
+                json!({
+                    "group_id": group_id,
+                    "device_class_id": device_class_id,
+                    "deployment_id": deployment_id,
+                    "result": "canceled",
+                }),
+            )?
+        }
+        Command::IotHubDeviceUpdate(IotHubDeviceUpdate::RetryDeployment {
+            azure,
+            instance,
+            group_id,
+            device_class_id,
+            deployment_id,
+        }) => {
+            let (instance_id, device_update_endpoint_url) = user_config.device_update_instance(
+                instance.instance_id,
+                instance.device_update_endpoint_url,
+            )?;
+
+            runtime::block_on(device_update::retry_deployment(
+                &azure.credentials()?,
+                &instance_id,
+                &device_update_endpoint_url,
+                &group_id,
+                &device_class_id,
+                &deployment_id,
+            ))?;
+
+            print_result(
+                &cli.output,
+                format!("Retried deployment {deployment_id}"),
+                json!({
+                    "group_id": group_id,
+                    "device_class_id": device_class_id,
+                    "deployment_id": deployment_id,
+                    "result": "retried",
+                }),
+            )?
+        }
+        Command::IotHubDeviceUpdate(IotHubDeviceUpdate::ComplianceReport {
+            azure,
+            instance,
+            group_id,
+            format,
+        }) => {
+            let (instance_id, device_update_endpoint_url) = user_config.device_update_instance(
+                instance.instance_id,
+                instance.device_update_endpoint_url,
+            )?;
+
+            runtime::block_on(device_update::compliance_report(
+                &azure.credentials()?,
+                &instance_id,
+                &device_update_endpoint_url,
+                group_id.as_deref(),
+                &cli.output.report_format(format),
+            ))?
+        }
+        Command::IotHubDeviceUpdate(IotHubDeviceUpdate::DeviceClasses {
+            azure,
+            instance,
+            format,
+        }) => {
+            let (instance_id, device_update_endpoint_url) = user_config.device_update_instance(
+                instance.instance_id,
+                instance.device_update_endpoint_url,
+            )?;
+
+            runtime::block_on(device_update::list_device_classes(
+                &azure.credentials()?,
+                &instance_id,
+                &device_update_endpoint_url,
+                &cli.output.report_format(format),
+            ))?
+        }
+        Command::IotHubDeviceUpdate(IotHubDeviceUpdate::Group(DeviceGroup::List {
+            azure,
+            instance,
+            format,
+        })) => {
+            let (instance_id, device_update_endpoint_url) = user_config.device_update_instance(
+                instance.instance_id,
+                instance.device_update_endpoint_url,
+            )?;
+
+            runtime::block_on(device_update::list_groups(
+                &azure.credentials()?,
+                &instance_id,
+                &device_update_endpoint_url,
+                &cli.output.report_format(format),
+            ))?
+        }
+        Command::IotHubDeviceUpdate(IotHubDeviceUpdate::Group(DeviceGroup::Devices {
+            azure,
+            instance,
+            group_id,
+            format,
+        })) => {
+            let (instance_id, device_update_endpoint_url) = user_config.device_update_instance(
+                instance.instance_id,
+                instance.device_update_endpoint_url,
+            )?;
+
+            runtime::block_on(device_update::list_group_devices(
+                &azure.credentials()?,
+                &instance_id,
+                &device_update_endpoint_url,
+                &group_id,
+                &cli.output.report_format(format),
+            ))?
+        }
+        Command::IotHubDeviceUpdate(IotHubDeviceUpdate::Group(DeviceGroup::Create {
+            azure,
+            iot_hub_hostname,
+            group_id,
+            devices,
+        })) => {
+            runtime::block_on(device_update::create_group(
+                &azure.credentials()?,
+                &iot_hub_hostname,
+                &group_id,
+                &devices,
+            ))?;
+
+            print_result(
+                &cli.output,
+                format!("Added {} devices to group {group_id}", devices.len()),
+                json!({ "group_id": group_id, "devices": devices }),
+            )?
+        }
+        Command::IotHubDeviceUpdate(IotHubDeviceUpdate::Group(DeviceGroup::Delete {
+            azure,
+            instance,
+            group_id,
+        })) => {
+            let (instance_id, device_update_endpoint_url) = user_config.device_update_instance(
+                instance.instance_id,
+                instance.device_update_endpoint_url,
+            )?;
+
+            runtime::block_on(device_update::delete_group(
+                &azure.credentials()?,
+                &instance_id,
+                &device_update_endpoint_url,
+                &group_id,
+            ))?;
+
+            print_result(
+                &cli.output,
+                format!("Deleted group {group_id}"),
+                json!({ "deleted": group_id }),
+            )?
+        }
+        Command::IotHubDeviceUpdate(IotHubDeviceUpdate::ValidateManifest { import_manifest }) => {
+            device_update::validate_import_manifest(&import_manifest).context(ErrorKind::User)?;
+
+            print_result(
+                &cli.output,
+                format!("{} is valid", import_manifest.to_string_lossy()),
+                json!({ "valid": [import_manifest] }),
+            )?;
+        }
+        Command::IotHubDeviceUpdate(IotHubDeviceUpdate::Serve {
+            artifact,
+            import_manifest,
+            port,
+            bind,
+        }) => {
+            let files = update_server::served_files(&artifact, import_manifest.as_deref())?;
+            let address = std::net::SocketAddr::new(bind, port);
+
+            print_result(
+                &cli.output,
+                files
+                    .iter()
+                    .map(|file| format!("serving http://{address}/{}", file.name))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                json!({ "address": address, "files": files }),
+            )?;
+
+            update_server::serve(files, address)?
+        }
         Command::IotHubDeviceUpdate(IotHubDeviceUpdate::CreateImportManifest {
             image,
             script,
@@ -340,22 +2268,56 @@ pub fn run() -> Result<()> {
             model,
             compatibilityid,
             provider,
+            wic_image,
             consent_handler,
             swupdate_handler,
             distro_name,
             version,
-        }) => device_update::create_import_manifest(
-            &image,
-            &script,
-            &manufacturer,
-            &model,
-            &compatibilityid,
-            &provider,
-            &consent_handler,
-            &swupdate_handler,
-            &distro_name,
-            &version,
-        )?,
+        }) => {
+            let mut derived = device_update::DuConfigCompatibility::default();
+
+            if let Some(wic_image) = wic_image {
+                read_image_command(wic_image, &user_config, |img: &PathBuf| {
+                    derived = device_update::du_config_compatibility(img)?;
+                    Ok(())
+                })?;
+            }
+
+            let manufacturer = device_update::resolve_compatibility_property(
+                "manufacturer",
+                manufacturer,
+                derived.manufacturer,
+            )?
+            .unwrap_or_else(|| "conplement-ag".to_string());
+            let model =
+                device_update::resolve_compatibility_property("model", model, derived.model)?
+                    .context("model must be provided")?;
+            let compatibilityid = device_update::resolve_compatibility_property(
+                "compatibilityid",
+                compatibilityid,
+                derived.compatibilityid,
+            )?
+            .context("compatibilityid must be provided")?;
+            let provider = device_update::resolve_compatibility_property(
+                "provider",
+                provider,
+                derived.provider,
+            )?
+            .unwrap_or_else(|| "conplement-AG".to_string());
+
+            runtime::block_on(device_update::create_import_manifest(
+                &image,
+                &script,
+                &manufacturer,
+                &model,
+                &compatibilityid,
+                &provider,
+                &consent_handler,
+                &swupdate_handler,
+                &distro_name,
+                &version,
+            ))?
+        }
         Command::Ssh(SetConnection {
             device,
             username,
@@ -363,60 +2325,394 @@ pub fn run() -> Result<()> {
             priv_key_path,
             config_path,
             env,
+            install_ssh_config,
         }) => {
-            #[tokio::main]
-            async fn create_ssh_tunnel(
-                device: &str,
-                username: &str,
-                dir: Option<PathBuf>,
-                priv_key_path: Option<PathBuf>,
-                config_path: Option<PathBuf>,
-                env_config: config::BackendConfig,
-            ) -> Result<()> {
-                let access_token = crate::auth::authorize(env_config.auth)
-                    .await
-                    .context("create ssh tunnel")?;
-
-                let config = ssh::Config::new(env_config.backend, dir, priv_key_path, config_path)?;
-
-                ssh::ssh_create_tunnel(device, username, config, access_token).await
+            let mut tunnel_info = create_ssh_tunnel(
+                &device,
+                &user_config.ssh_username(username),
+                dir,
+                priv_key_path,
+                config_path,
+                env,
+                &user_config,
+            )?;
+
+            if install_ssh_config {
+                ssh::install_ssh_config(&mut tunnel_info)?;
             }
 
-            let env_conf: config::BackendConfig = if let Some(env_path) = env {
-                let config_file = std::fs::read_to_string(env_path)?;
+            match cli.output {
+                OutputFormat::text => tunnel_info.print(),
+                OutputFormat::json => println!("{}", serde_json::to_string_pretty(&tunnel_info)?),
+            }
+        }
+        Command::Ssh(RemoveConnection { device }) => {
+            let snippet = ssh::remove_ssh_config(&device)?;
 
-                toml::from_str(&config_file)?
-            } else {
-                config::BackendConfig {
-                    backend: url::Url::parse("https://cp.omnect.conplement.cloud")?,
-                    auth: config::AUTH_INFO_PROD.clone(),
-                }
-            };
+            print_result(
+                &cli.output,
+                match &snippet {
+                    Some(snippet) => format!("Removed {}", snippet.to_string_lossy()),
+                    None => format!("No ssh config snippet of {device} installed"),
+                },
+                json!({ "device": device, "removed": snippet }),
+            )?
+        }
+        Command::Identity(RegisterDevice {
+            azure,
+            hub,
+            device_id,
+            authentication,
+            device_cert,
+            secondary_device_cert,
+            iotedge,
+        }) => {
+            let iot_hub_hostname = iot_hub::iot_hub_hostname(&hub);
+            let device = runtime::block_on(iot_hub::register_device(
+                &azure.credentials()?,
+                &iot_hub_hostname,
+                &device_id,
+                &authentication,
+                device_cert.as_deref(),
+                secondary_device_cert.as_deref(),
+                iotedge,
+            ))?;
+
+            print_result(
+                &cli.output,
+                format!("Registered device {device_id} in {iot_hub_hostname}"),
+                device,
+            )?
+        }
+        Command::Identity(IdentityEnrollmentGroup(EnrollmentGroup::Set {
+            azure,
+            dps,
+            group_id,
+            intermediate_full_chain_cert,
+            tags,
+            iotedge,
+        })) => {
+            let dps_hostname = dps::dps_hostname(&dps);
+            let intermediate_full_chain_cert =
+                file::secret::open(&intermediate_full_chain_cert, age_identity)?;
+            let group = runtime::block_on(dps::set_enrollment_group(
+                &azure.credentials()?,
+                &dps_hostname,
+                &group_id,
+                intermediate_full_chain_cert.path(),
+                tags.as_ref(),
+                iotedge,
+            ))?;
+
+            print_result(
+                &cli.output,
+                format!("Stored enrollment group {group_id} in {dps_hostname}"),
+                group,
+            )?
+        }
+        Command::Identity(IdentityEnrollmentGroup(EnrollmentGroup::Get {
+            azure,
+            dps,
+            group_id,
+        })) => {
+            let dps_hostname = dps::dps_hostname(&dps);
+            let group = runtime::block_on(dps::get_enrollment_group(
+                &azure.credentials()?,
+                &dps_hostname,
+                &group_id,
+            ))?
+            .context(format!(
+                "enrollment group {group_id} not found in {dps_hostname}"
+            ))
+            .context(ErrorKind::User)?;
+
+            print_result(&cli.output, serde_json::to_string_pretty(&group)?, group)?
+        }
+        Command::Identity(IdentityEnrollmentGroup(EnrollmentGroup::Delete {
+            azure,
+            dps,
+            group_id,
+        })) => {
+            let dps_hostname = dps::dps_hostname(&dps);
+
+            runtime::block_on(dps::delete_enrollment_group(
+                &azure.credentials()?,
+                &dps_hostname,
+                &group_id,
+            ))?;
+
+            print_result(
+                &cli.output,
+                format!("Deleted enrollment group {group_id} from {dps_hostname}"),
+                json!({ "dps": dps_hostname, "group_id": group_id }),
+            )?
+        }
+        Command::Device(Twin(DeviceTwin::Get {
+            azure,
+            hub,
+            device_id,
+        })) => {
+            let twin = runtime::block_on(iot_hub::get_twin(
+                &azure.credentials()?,
+                &iot_hub::iot_hub_hostname(&hub),
+                &device_id,
+            ))?;
+
+            print_result(&cli.output, serde_json::to_string_pretty(&twin)?, twin)?
+        }
+        Command::Device(Twin(DeviceTwin::Patch {
+            azure,
+            hub,
+            device_id,
+            tags,
+            desired,
+        })) => {
+            let twin = runtime::block_on(iot_hub::update_twin(
+                &azure.credentials()?,
+                &iot_hub::iot_hub_hostname(&hub),
+                &device_id,
+                tags.as_ref(),
+                desired.as_ref(),
+            ))?;
+
+            print_result(&cli.output, format!("Patched twin of {device_id}"), twin)?
+        }
+        Command::Fleet(Provision {
+            csv,
+            base_image,
+            manifest,
+            output_dir,
+            jobs,
+            generate_bmap,
+            compress_image,
+        }) => {
+            let devices = fleet::parse_devices(&file::secret::read_to_string(&csv).context(
+                format!("fleet provision: cannot read {}", csv.to_string_lossy()),
+            )?)
+            .context(ErrorKind::User)?;
+            let manifest = fleet::FleetManifest::load(&manifest).context(ErrorKind::User)?;
+            let images = fleet::provision(
+                &devices,
+                &base_image,
+                &manifest,
+                &output_dir,
+                &fleet::ProvisionOptions {
+                    jobs,
+                    generate_bmap: user_config.generate_bmap(generate_bmap),
+                    compression: user_config.compression(compress_image)?,
+                },
+                &user_config,
+            )?;
+
+            print_result(
+                &cli.output,
+                images
+                    .iter()
+                    .map(|image| format!("{}: {}", image.device_id, image.image.to_string_lossy()))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                json!({ "images": images }),
+            )?
+        }
+        Command::Edge(SetModules {
+            azure,
+            hub,
+            device_id,
+            manifest,
+        }) => {
+            runtime::block_on(iot_hub::set_modules(
+                &azure.credentials()?,
+                &iot_hub::iot_hub_hostname(&hub),
+                &device_id,
+                &manifest,
+            ))?;
 
-            create_ssh_tunnel(
+            print_result(
+                &cli.output,
+                format!("Applied {} to {device_id}", manifest.to_string_lossy()),
+                json!({ "device_id": device_id, "manifest": manifest }),
+            )?
+        }
+        Command::Device(CollectLogs {
+            device,
+            output,
+            since,
+            username,
+            priv_key_path,
+            env,
+        }) => {
+            let tunnel_info = create_ssh_tunnel(
                 &device,
-                &username,
-                dir,
+                &user_config.ssh_username(username),
+                None,
                 priv_key_path,
-                config_path,
-                env_conf,
+                None,
+                env,
+                &user_config,
+            )?;
+            let collected = diagnostics::collect_logs(
+                &tunnel_info.config_path,
+                &tunnel_info.destination,
+                since.as_deref(),
+                &output,
+            )?;
+
+            print_result(
+                &cli.output,
+                format!("Support bundle written to {}", output.to_string_lossy()),
+                json!({ "bundle": output, "files": collected }),
             )?;
         }
+        Command::Device(DeviceStatus {
+            device,
+            hub,
+            ssh,
+            azure,
+            username,
+            priv_key_path,
+            env,
+        }) => {
+            let mut status = diagnostics::DeviceStatus::new(&device);
+
+            if let Some(hub) = hub {
+                let twin = runtime::block_on(iot_hub::get_twin(
+                    &azure.credentials()?,
+                    &iot_hub::iot_hub_hostname(&hub),
+                    &device,
+                ))?;
+
+                status = status.with_twin(&twin);
+            }
+
+            if ssh {
+                let tunnel_info = create_ssh_tunnel(
+                    &device,
+                    &user_config.ssh_username(username),
+                    None,
+                    priv_key_path,
+                    None,
+                    env,
+                    &user_config,
+                )?;
+
+                status = status.with_ssh(&tunnel_info.config_path, &tunnel_info.destination)?;
+            }
+
+            print_result(&cli.output, &status, serde_json::to_value(&status)?)?;
+        }
         Command::File(CopyToImage {
             file_copy_params,
             image,
+            vars,
+            var_files,
+            all_slots,
             generate_bmap,
             compress_image,
-        }) => run_image_command(image, generate_bmap, compress_image, |img: &PathBuf| {
-            file::copy_to_image(&file_copy_params, img)
-        })?,
+        }) => {
+            let vars = file::template::TemplateVars::load(&vars, &var_files)?;
+
+            run_image_command(
+                image,
+                user_config.generate_bmap(generate_bmap),
+                user_config.compression(compress_image)?,
+                &user_config,
+                |img: &PathBuf| {
+                    let mut file_copy_params = vars.render_copy_params(&file_copy_params, img)?;
+                    let secrets = file_copy_params
+                        .iter()
+                        .map(|params| file::secret::open(params.in_file(), age_identity))
+                        .collect::<Result<Vec<_>>>()?;
+
+                    file_copy_params = file_copy_params
+                        .into_iter()
+                        .zip(&secrets)
+                        .map(|(params, secret)| params.with_in_file(secret.path()))
+                        .collect();
+
+                    if all_slots {
+                        file_copy_params = file::functions::with_all_slots(&file_copy_params, img)?;
+                    }
+
+                    file::copy_to_image(&file_copy_params, img)
+                },
+            )?
+        }
         Command::File(CopyFromImage {
             file_copy_params,
             image,
-        }) => run_image_command(image, false, None, |img: &PathBuf| {
+        }) => read_image_command(image, &user_config, |img: &PathBuf| {
             file::copy_from_image(&file_copy_params, img)
         })?,
+        Command::File(FileHash {
+            files,
+            image,
+            algorithm,
+        }) => {
+            let mut digests = vec![];
+
+            read_image_command(image, &user_config, |img: &PathBuf| {
+                digests = file::hash::hash_files(&files, algorithm, img)?;
+                Ok(())
+            })?;
+
+            print_result(
+                &cli.output,
+                files
+                    .iter()
+                    .zip(&digests)
+                    .map(|(file, digest)| format!("{digest}  {file}"))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                json!(files
+                    .iter()
+                    .zip(&digests)
+                    .map(|(file, digest)| json!({
+                        "partition": file.partition.to_string(),
+                        "path": file.path,
+                        "algorithm": algorithm.to_string(),
+                        "digest": digest,
+                    }))
+                    .collect::<Vec<_>>()),
+            )?;
+        }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn move_file_keeps_permissions_and_links() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.wic");
+        let symlink = dir.path().join("current.wic");
+        let hardlink = dir.path().join("hardlink.wic");
+        let new_image = dir.path().join("new.wic");
+
+        fs::write(&image, "old").unwrap();
+        fs::set_permissions(&image, fs::Permissions::from_mode(0o640)).unwrap();
+        std::os::unix::fs::symlink(&image, &symlink).unwrap();
+        fs::write(&new_image, "new").unwrap();
+
+        move_file(&new_image, &symlink).unwrap();
+
+        assert!(fs::symlink_metadata(&symlink).unwrap().is_symlink());
+        assert_eq!(fs::read_to_string(&image).unwrap(), "new");
+        assert_eq!(
+            fs::metadata(&image).unwrap().permissions().mode() & 0o777,
+            0o640
+        );
+
+        fs::hard_link(&image, &hardlink).unwrap();
+        fs::write(&new_image, "newer").unwrap();
+
+        move_file(&new_image, &image).unwrap();
+
+        assert_eq!(fs::read_to_string(&hardlink).unwrap(), "newer");
+        assert!(!new_image.exists());
+    }
+}
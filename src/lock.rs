@@ -0,0 +1,110 @@
+use crate::error::ErrorKind;
+use anyhow::{Context, Result};
+use log::debug;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// Advisory lock of an image, which prevents concurrent modifications of the
+/// image by several omnect-cli processes. The lock is held by a
+/// `.<image>.lock` file next to the image, which is removed on drop.
+pub struct ImageLock {
+    path: PathBuf,
+    _file: File,
+}
+
+impl ImageLock {
+    /// Locks `image_file`, which doesn't have to exist yet. Fails immediately
+    /// if the image is locked by another process.
+    pub fn acquire(image_file: &Path) -> Result<ImageLock> {
+        let path = image_file.with_file_name(format!(
+            ".{}.lock",
+            image_file
+                .file_name()
+                .context("lock: cannot get image file name")?
+                .to_string_lossy()
+        ));
+
+        loop {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)
+                .context(format!("lock: cannot open {}", path.to_string_lossy()))?;
+
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == -1 {
+                let e = std::io::Error::last_os_error();
+
+                if e.raw_os_error() != Some(libc::EWOULDBLOCK) {
+                    return Err(e).context(format!("lock: cannot lock {}", path.to_string_lossy()));
+                }
+
+                let holder = fs::read_to_string(&path).unwrap_or_default();
+
+                return Err(anyhow::anyhow!(
+                    "{} is being modified by another omnect-cli process{}, retry after it finished",
+                    image_file.to_string_lossy(),
+                    match holder.trim() {
+                        "" => String::new(),
+                        pid => format!(" (pid {pid})"),
+                    }
+                )
+                .context(ErrorKind::User));
+            }
+
+            // the previous holder may have removed the lock file meanwhile,
+            // then the lock has to be taken on a new file
+            match fs::metadata(&path) {
+                Ok(metadata) if metadata.ino() == file.metadata()?.ino() => {
+                    file.set_len(0)?;
+                    write!(file, "{}", std::process::id())?;
+                    debug!("locked {}", image_file.to_string_lossy());
+
+                    return Ok(ImageLock { path, _file: file });
+                }
+                _ => continue,
+            }
+        }
+    }
+}
+
+impl Drop for ImageLock {
+    fn drop(&mut self) {
+        // removed while still locked, the lock is released by closing the file
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lock_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.wic");
+        let lock_file = dir.path().join(".image.wic.lock");
+
+        let lock = ImageLock::acquire(&image).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&lock_file).unwrap(),
+            std::process::id().to_string()
+        );
+
+        // flock locks are per open file, so that a second acquire fails
+        // within the same process as well
+        let e = ImageLock::acquire(&image).unwrap_err();
+
+        assert_eq!(ErrorKind::classify(&e), ErrorKind::User);
+        assert!(format!("{e:#}").contains("another omnect-cli process"));
+
+        drop(lock);
+
+        assert!(!lock_file.exists());
+        assert!(ImageLock::acquire(&image).is_ok());
+    }
+}
@@ -1,40 +1,113 @@
 use env_logger::{Builder, Env};
-use log::{error, info};
+use log::{debug, error, info};
+use omnect_cli::batch::Batch;
+use omnect_cli::cli::{LogFormat, OutputFormat};
+use omnect_cli::error::ErrorKind;
+use std::io::Write;
 use std::process;
+use std::time::Instant;
 
-fn main() {
-    // storage_account_client logs cleartext credentials, the others are just unnecessarily verbose.
-    if cfg!(debug_assertions) {
-        Builder::from_env(Env::default().default_filter_or(concat!(
-            "debug",
-            ",azure_core::http_client::reqwest=debug",
-            ",azure_core::policies::transport=debug",
-            ",azure_iot_deviceupdate::device_update=debug",
-            ",azure_storage::core::clients::storage_account_client=info",
-            ",azure_storage_blobs=info",
-            ",device_update_importer::blob_uploader=info",
-            ",reqwest::async_impl::client=debug"
-        )))
-        .init();
+// storage_account_client logs cleartext credentials, the others are just unnecessarily verbose.
+const MODULE_FILTERS: &str = concat!(
+    ",azure_core::http_client::reqwest=debug",
+    ",azure_core::policies::transport=debug",
+    ",azure_iot_deviceupdate::device_update=debug",
+    ",azure_storage::core::clients::storage_account_client=info",
+    ",azure_storage_blobs=info",
+    ",device_update_importer::blob_uploader=info",
+    ",reqwest::async_impl::client=debug"
+);
+
+fn init_logger(verbose: u8, log_format: &LogFormat) {
+    let level = match verbose {
+        0 if cfg!(debug_assertions) => "debug",
+        0 => "info",
+        1 => "debug",
+        _ => "trace",
+    };
+    let filters = format!("{level}{MODULE_FILTERS}");
+
+    // an explicit verbosity takes precedence over RUST_LOG
+    let mut builder = if verbose == 0 {
+        Builder::from_env(Env::default().default_filter_or(filters))
+    } else {
+        let mut builder = Builder::new();
+        builder.parse_filters(&filters);
+        builder
+    };
+
+    if let LogFormat::json = log_format {
+        builder.format(|buf, record| {
+            writeln!(
+                buf,
+                "{}",
+                serde_json::json!({
+                    "timestamp": buf.timestamp_millis().to_string(),
+                    "level": record.level().to_string(),
+                    "target": record.target(),
+                    "message": record.args().to_string(),
+                })
+            )
+        });
     } else {
-        Builder::from_env(Env::default().default_filter_or(concat!(
-            "info",
-            ",azure_core::http_client::reqwest=debug",
-            ",azure_core::policies::transport=debug",
-            ",azure_iot_deviceupdate::device_update=debug",
-            ",azure_storage::core::clients::storage_account_client=info",
-            ",azure_storage_blobs=info",
-            ",device_update_importer::blob_uploader=info",
-            ",reqwest::async_impl::client=debug"
-        )))
-        .init();
+        builder.format_timestamp_millis();
     }
 
+    builder.init();
+}
+
+fn main() {
+    let args = std::env::args().collect::<Vec<_>>();
+    let batch = Batch::from_args(&args).unwrap_or_else(|e| {
+        eprintln!("{e:#}");
+        process::exit(ErrorKind::classify(&e).exit_code());
+    });
+    let cli = batch.is_none().then(omnect_cli::cli::from_args);
+    // log and output settings are the same for all images of a batch
+    let settings = match (&cli, &batch) {
+        (Some(cli), _) => cli,
+        (None, Some(batch)) => batch.first(),
+        (None, None) => unreachable!(),
+    };
+
+    init_logger(settings.verbose, &settings.log_format);
+
     info!("version: {}", env!("CARGO_PKG_VERSION"));
 
-    if let Err(e) = omnect_cli::run() {
+    let output = settings.output.clone();
+    // the proxy is exported to the environment before the runtime starts its
+    // threads
+    let proxy = omnect_cli::apply_proxy(settings);
+
+    omnect_cli::runtime::handle_ctrl_c();
+
+    let started = Instant::now();
+    let result = proxy.and_then(|()| match (cli, batch) {
+        (Some(cli), _) => omnect_cli::run(cli),
+        (None, Some(batch)) => batch.run(),
+        (None, None) => unreachable!(),
+    });
+
+    if let Err(e) = result {
+        let kind = ErrorKind::classify(&e);
+
         error!("Application error: {e:#?}");
 
-        process::exit(1);
+        if let OutputFormat::json = output {
+            println!(
+                "{}",
+                serde_json::json!({
+                    "error": {
+                        "code": kind.code(),
+                        "transient": kind.is_transient(),
+                        "message": format!("{e:#}"),
+                    }
+                })
+            );
+        }
+
+        process::exit(kind.exit_code());
     }
+
+    debug!("finished in {:.1}s", started.elapsed().as_secs_f64());
 }
@@ -0,0 +1,102 @@
+use crate::validators::cert::{read_certs, read_key};
+use anyhow::{Context, Result};
+use openssl::nid::Nid;
+use openssl::pkcs12::Pkcs12;
+use openssl::stack::Stack;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+/// Exports the first certificate of `cert_file` and its key as PKCS#12
+/// bundle protected by `password`, e.g. for HSM import or TPM provisioning
+/// rigs. Further certificates of `cert_file` and the certificates of
+/// `chain_file` are added as chain. The friendly name of the bundle is the
+/// common name of the certificate.
+pub fn export(
+    cert_file: &Path,
+    key_file: &Path,
+    chain_file: Option<&Path>,
+    password: &str,
+    out_file: &Path,
+) -> Result<()> {
+    let mut certs = read_certs(cert_file)?;
+    let cert = certs.remove(0);
+    let key = read_key(key_file)?;
+    let mut ca = Stack::new()?;
+
+    if let Some(chain_file) = chain_file {
+        certs.extend(read_certs(chain_file)?);
+    }
+
+    for chain_cert in certs {
+        ca.push(chain_cert)?;
+    }
+
+    let name = cert
+        .subject_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .next()
+        .and_then(|entry| entry.data().as_utf8().ok())
+        .map(|name| name.to_string())
+        .unwrap_or_default();
+    let der = Pkcs12::builder()
+        .name(&name)
+        .pkey(&key)
+        .cert(&cert)
+        .ca(ca)
+        .build2(password)
+        .context("pkcs12: cannot create bundle")?
+        .to_der()?;
+
+    // the bundle contains the private key
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(out_file)
+        .and_then(|mut file| file.write_all(&der))
+        .context(format!(
+            "pkcs12: cannot write {}",
+            out_file.to_string_lossy()
+        ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn export_bundle() {
+        let dir = tempfile::tempdir().unwrap();
+        let out_file = dir.path().join("device.p12");
+
+        export(
+            Path::new("testfiles/test-int-ca.pem"),
+            Path::new("testfiles/test-int-ca.key"),
+            Some(Path::new("testfiles/test-ca.pem")),
+            "secret",
+            &out_file,
+        )
+        .unwrap();
+
+        let pkcs12 = Pkcs12::from_der(&std::fs::read(&out_file).unwrap()).unwrap();
+
+        assert!(pkcs12.parse2("wrong").is_err());
+
+        let parsed = pkcs12.parse2("secret").unwrap();
+
+        assert!(parsed.pkey.is_some());
+        assert!(parsed.cert.is_some());
+        assert_eq!(parsed.ca.map(|ca| ca.len()), Some(1));
+        assert!(export(
+            Path::new("testfiles/test-int-ca.pem"),
+            Path::new("testfiles/test-int-ca.pem"),
+            None,
+            "",
+            &out_file,
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,205 @@
+use std::future::Future;
+use std::io::{IsTerminal, Read, Write};
+use std::process::{Child, ExitStatus};
+use std::time::{Duration, Instant};
+
+const TTY_INTERVAL: Duration = Duration::from_millis(200);
+const NON_TTY_INTERVAL: Duration = Duration::from_secs(10);
+const MIB: f64 = 1024.0 * 1024.0;
+
+/// Reports the progress of a long running operation on stderr. On a terminal
+/// a single status line is redrawn, otherwise a json line is written
+/// periodically, so that the progress can be consumed by other tools.
+pub struct Progress {
+    label: String,
+    total: Option<u64>,
+    current: u64,
+    tty: bool,
+    started: Instant,
+    last_report: Instant,
+}
+
+impl Progress {
+    pub fn new(label: impl Into<String>, total: Option<u64>) -> Progress {
+        let now = Instant::now();
+        let progress = Progress {
+            label: label.into(),
+            total,
+            current: 0,
+            tty: std::io::stderr().is_terminal(),
+            started: now,
+            last_report: now,
+        };
+
+        progress.report();
+        progress
+    }
+
+    pub fn inc(&mut self, bytes: u64) {
+        self.current += bytes;
+
+        let interval = if self.tty {
+            TTY_INTERVAL
+        } else {
+            NON_TTY_INTERVAL
+        };
+
+        if self.last_report.elapsed() >= interval {
+            self.last_report = Instant::now();
+            self.report();
+        }
+    }
+
+    /// Reports the unchanged progress, so that consumers of the json lines
+    /// see that an operation without intermediate results is still running.
+    pub fn tick(&mut self) {
+        self.inc(0);
+    }
+
+    /// Awaits `future` while reporting the progress periodically, e.g. for a
+    /// remote operation like an update import.
+    pub async fn wait<F: Future>(&mut self, future: F) -> F::Output {
+        let mut interval = tokio::time::interval(TTY_INTERVAL);
+
+        tokio::pin!(future);
+
+        loop {
+            tokio::select! {
+                output = &mut future => return output,
+                _ = interval.tick() => self.tick(),
+            }
+        }
+    }
+
+    /// Waits for `child` to exit while reporting the progress periodically,
+    /// e.g. for commands which don't report progress without a terminal.
+    pub fn wait_child(&mut self, child: &mut Child) -> std::io::Result<ExitStatus> {
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return Ok(status);
+            }
+
+            self.tick();
+            std::thread::sleep(TTY_INTERVAL);
+        }
+    }
+
+    pub fn finish(self) {
+        if self.tty {
+            eprintln!(
+                "\r{} done in {:.1}s\x1b[K",
+                self.status_line(),
+                self.started.elapsed().as_secs_f64()
+            );
+        } else {
+            eprintln!("{}", self.json_line(true));
+        }
+    }
+
+    fn report(&self) {
+        if self.tty {
+            eprint!("\r{}\x1b[K", self.status_line());
+            let _ = std::io::stderr().flush();
+        } else {
+            eprintln!("{}", self.json_line(false));
+        }
+    }
+
+    fn status_line(&self) -> String {
+        let current = self.current as f64 / MIB;
+
+        match self.total {
+            Some(total) if total > 0 => format!(
+                "{}: {:3}% ({current:.1}/{:.1} MiB)",
+                self.label,
+                self.current * 100 / total,
+                total as f64 / MIB
+            ),
+            _ if self.current > 0 => format!("{}: {current:.1} MiB", self.label),
+            _ => format!("{}:", self.label),
+        }
+    }
+
+    fn json_line(&self, done: bool) -> String {
+        serde_json::json!({
+            "progress": self.label,
+            "bytes": self.current,
+            "total": self.total,
+            "elapsed_ms": self.started.elapsed().as_millis() as u64,
+            "done": done,
+        })
+        .to_string()
+    }
+}
+
+/// Reader that reports the number of bytes read.
+pub struct ProgressReader<R> {
+    inner: R,
+    progress: Progress,
+}
+
+impl<R: Read> ProgressReader<R> {
+    pub fn new(inner: R, progress: Progress) -> ProgressReader<R> {
+        ProgressReader { inner, progress }
+    }
+
+    pub fn finish(self) {
+        self.progress.finish()
+    }
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let bytes = self.inner.read(buf)?;
+        self.progress.inc(bytes as u64);
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress_reader_counts_bytes() {
+        let mut reader = ProgressReader::new(
+            std::io::Cursor::new(vec![0u8; 3 * 1024 * 1024]),
+            Progress::new("test", Some(6 * 1024 * 1024)),
+        );
+
+        std::io::copy(&mut reader, &mut std::io::sink()).unwrap();
+
+        assert_eq!(reader.progress.current, 3 * 1024 * 1024);
+        assert_eq!(reader.progress.status_line(), "test:  50% (3.0/6.0 MiB)");
+
+        let json: serde_json::Value =
+            serde_json::from_str(&reader.progress.json_line(true)).unwrap();
+
+        assert_eq!(json["progress"], "test");
+        assert_eq!(json["bytes"], 3 * 1024 * 1024);
+        assert_eq!(json["done"], true);
+    }
+
+    #[test]
+    fn progress_without_total() {
+        let mut progress = Progress::new("pull", None);
+
+        assert_eq!(progress.status_line(), "pull:");
+
+        progress.inc(1024 * 1024);
+
+        assert_eq!(progress.status_line(), "pull: 1.0 MiB");
+    }
+
+    #[test]
+    fn progress_wait_child() {
+        let mut progress = Progress::new("sleep", None);
+        let mut child = std::process::Command::new("sleep")
+            .arg("0.5")
+            .spawn()
+            .unwrap();
+
+        assert!(progress.wait_child(&mut child).unwrap().success());
+        assert_eq!(progress.current, 0);
+    }
+}
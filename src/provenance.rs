@@ -0,0 +1,325 @@
+use crate::error::ErrorKind;
+use crate::file::functions::{copy_to_image, read_file_from_image, FileCopyToParams, Partition};
+use anyhow::{Context, Result};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+pub const PREDICATE_TYPE: &str = "https://github.com/omnect/omnect-cli/modifications/v1";
+
+/// Modification history embedded in /etc of the factory partition, so that
+/// it is part of the image and readable on the device.
+pub const HISTORY_PATH: &str = "/etc/omnect/omnect-cli-history.json";
+
+/// omnect-cli command modifying an image, which is recorded in the
+/// modification history and log.
+#[derive(Clone, Debug, Default)]
+pub struct Invocation {
+    /// names of the subcommands, e.g. "identity set-config". Arguments
+    /// aren't recorded since they might contain secrets.
+    pub command: String,
+    /// files passed to the command, e.g. identity configs
+    pub inputs: Vec<PathBuf>,
+}
+
+/// Modification of an image by an omnect-cli command.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Modification {
+    pub command: String,
+    pub version: String,
+    pub timestamp: String,
+}
+
+/// File passed to an omnect-cli command, e.g. an identity config.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Input {
+    pub file: String,
+    pub sha256: String,
+}
+
+/// Entry of the modification history embedded in an image. Each entry
+/// contains the sha256 of its predecessor, so that the history forms a hash
+/// chain.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct HistoryEntry {
+    pub timestamp: String,
+    pub version: String,
+    pub command: String,
+    pub inputs: Vec<Input>,
+    pub previous: Option<String>,
+}
+
+impl HistoryEntry {
+    pub fn sha256(&self) -> Result<String> {
+        Ok(format!(
+            "{:x}",
+            sha2::Sha256::digest(serde_json::to_vec(self)?)
+        ))
+    }
+}
+
+/// Log of all modifications of an image, which is stored next to the image.
+pub(crate) fn log_path(image_file: &Path) -> PathBuf {
+    let mut path = image_file.as_os_str().to_owned();
+    path.push(".modifications.json");
+    PathBuf::from(path)
+}
+
+pub fn modifications(image_file: &Path) -> Result<Vec<Modification>> {
+    let path = log_path(image_file);
+
+    if !path.try_exists().is_ok_and(|exists| exists) {
+        return Ok(vec![]);
+    }
+
+    serde_json::from_reader(File::open(&path).context(format!(
+        "modifications: cannot open {}",
+        path.to_string_lossy()
+    ))?)
+    .context(format!(
+        "modifications: invalid modification log {}",
+        path.to_string_lossy()
+    ))
+}
+
+/// Appends `invocation` to the modification log of `source` and stores it
+/// for `destination`, which differs from `source` if the image was
+/// (de)compressed.
+pub fn record(source: &Path, destination: &Path, invocation: &Invocation) -> Result<()> {
+    record_copy(source, destination, invocation)?;
+
+    if source != destination {
+        let _ = std::fs::remove_file(log_path(source));
+    }
+
+    Ok(())
+}
+
+/// Stores the modification log of `source` including `invocation` for
+/// `destination`, which is a modified copy of `source`.
+pub fn record_copy(source: &Path, destination: &Path, invocation: &Invocation) -> Result<()> {
+    let mut log = modifications(source)?;
+
+    log.push(Modification {
+        command: invocation.command.clone(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        timestamp: OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .context("record: cannot format timestamp")?,
+    });
+
+    let path = log_path(destination);
+
+    serde_json::to_writer_pretty(
+        File::create(&path).context(format!("record: cannot create {}", path.to_string_lossy()))?,
+        &log,
+    )?;
+
+    Ok(())
+}
+
+/// Files passed to `invocation`, except the image `source` itself. Only file
+/// names are recorded, since paths might reveal details of the build
+/// environment.
+fn inputs(source: &Path, invocation: &Invocation) -> Result<Vec<Input>> {
+    let source = source.canonicalize().ok();
+    let mut files: Vec<PathBuf> = vec![];
+
+    for input in &invocation.inputs {
+        if !input.is_file() {
+            continue;
+        }
+
+        let path = input.canonicalize()?;
+
+        if Some(&path) != source.as_ref() && !files.contains(&path) {
+            files.push(path);
+        }
+    }
+
+    files
+        .iter()
+        .map(|file| {
+            Ok(Input {
+                file: file
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .to_string(),
+                sha256: sha256(file)?,
+            })
+        })
+        .collect()
+}
+
+/// Modification history embedded in `image_file`, which is empty for images
+/// never modified by omnect-cli.
+pub fn history(image_file: &Path) -> Result<Vec<HistoryEntry>> {
+    match read_file_from_image(HISTORY_PATH, Partition::factory, image_file) {
+        Ok(content) => serde_json::from_str(&content).context(format!(
+            "history: invalid modification history {HISTORY_PATH}"
+        )),
+        Err(e) => {
+            debug!("history: no {HISTORY_PATH}: {e:#}");
+            Ok(vec![])
+        }
+    }
+}
+
+/// Checks that each entry of `history` contains the sha256 of its
+/// predecessor, i.e. that no entry was changed or removed. Entries appended
+/// by a third party with correct hashes can't be detected.
+pub fn verify_history(history: &[HistoryEntry]) -> Result<()> {
+    let mut previous = None;
+
+    for (i, entry) in history.iter().enumerate() {
+        if entry.previous != previous {
+            return Err(anyhow::anyhow!(
+                "history: entry {} isn't chained to its predecessor, the history was modified",
+                i + 1
+            )
+            .context(ErrorKind::User));
+        }
+
+        previous = Some(entry.sha256()?);
+    }
+
+    Ok(())
+}
+
+/// Appends `invocation` and the hashes of its input files to the
+/// modification history embedded in `image_file`, which is a modified copy
+/// of `source`.
+pub fn embed(image_file: &Path, source: &Path, invocation: &Invocation) -> Result<()> {
+    let mut history = history(image_file)?;
+    let previous = history.last().map(HistoryEntry::sha256).transpose()?;
+
+    history.push(HistoryEntry {
+        timestamp: OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .context("embed: cannot format timestamp")?,
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        command: invocation.command.clone(),
+        inputs: inputs(source, invocation)?,
+        previous,
+    });
+
+    let tmp_file =
+        tempfile::NamedTempFile::new().context("embed: could not create temporary file path")?;
+
+    serde_json::to_writer_pretty(tmp_file.as_file(), &history)?;
+
+    copy_to_image(
+        &[FileCopyToParams::new(
+            tmp_file.path(),
+            Partition::factory,
+            Path::new(HISTORY_PATH),
+        )],
+        image_file,
+    )
+}
+
+pub fn sha256(file: &Path) -> Result<String> {
+    let mut hasher = sha2::Sha256::new();
+
+    std::io::copy(
+        &mut File::open(file).context(format!("sha256: cannot open {}", file.to_string_lossy()))?,
+        &mut hasher,
+    )?;
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// In-toto predicate describing how omnect-cli modified the image.
+pub fn predicate(image_file: &Path) -> Result<serde_json::Value> {
+    Ok(serde_json::json!({
+        "builder": {
+            "id": env!("CARGO_PKG_NAME"),
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "image": {
+            "name": image_file.file_name().map(|name| name.to_string_lossy()),
+            "digest": { "sha256": sha256(image_file)? },
+        },
+        "modifications": modifications(image_file)?,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modifications_follow_renamed_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.wic");
+        let compressed = dir.path().join("image.wic.xz");
+
+        let invocation = Invocation {
+            command: "identity set-hostname".to_string(),
+            ..Default::default()
+        };
+
+        std::fs::write(&compressed, "image").unwrap();
+
+        assert!(modifications(&image).unwrap().is_empty());
+
+        record(&image, &image, &invocation).unwrap();
+        record(&image, &compressed, &invocation).unwrap();
+
+        assert!(modifications(&image).unwrap().is_empty());
+        assert_eq!(modifications(&compressed).unwrap().len(), 2);
+
+        let predicate = predicate(&compressed).unwrap();
+
+        assert_eq!(predicate["image"]["name"], "image.wic.xz");
+        assert_eq!(
+            predicate["image"]["digest"]["sha256"],
+            "6105d6cc76af400325e94d588ce511be5bfdbb73b437dc51eca43917d7a43e3d"
+        );
+        assert_eq!(
+            predicate["modifications"][0]["version"],
+            env!("CARGO_PKG_VERSION")
+        );
+        assert_eq!(
+            predicate["modifications"][1]["command"],
+            "identity set-hostname"
+        );
+    }
+
+    #[test]
+    fn history_entries_are_chained() {
+        let entry = HistoryEntry {
+            timestamp: "2024-01-01T00:00:00Z".to_string(),
+            version: "0.1.0".to_string(),
+            command: "identity set-config".to_string(),
+            inputs: vec![Input {
+                file: "config.toml".to_string(),
+                sha256: "00".to_string(),
+            }],
+            previous: None,
+        };
+        let mut next = entry.clone();
+
+        next.previous = Some(entry.sha256().unwrap());
+
+        assert_eq!(entry.sha256().unwrap().len(), 64);
+        assert_ne!(entry.sha256().unwrap(), next.sha256().unwrap());
+
+        assert!(verify_history(&[]).is_ok());
+        assert!(verify_history(&[entry.clone(), next.clone()]).is_ok());
+
+        // a removed first entry
+        let e = verify_history(&[next.clone()]).unwrap_err();
+        assert_eq!(ErrorKind::classify(&e), ErrorKind::User);
+
+        // a modified first entry
+        let mut modified = entry.clone();
+        modified.inputs.clear();
+        let e = verify_history(&[modified, next]).unwrap_err();
+        assert!(format!("{e:#}").contains("entry 2"));
+    }
+}
@@ -0,0 +1,126 @@
+use crate::error::ErrorKind;
+use anyhow::Result;
+use log::{error, warn};
+use std::collections::HashSet;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::sync::watch;
+
+// time for cancelled commands to clean up, synchronous image operations
+// only notice a cancellation between their steps
+const CANCEL_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+lazy_static::lazy_static! {
+    static ref RUNTIME: Runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("cannot create tokio runtime");
+    static ref CANCELLED: watch::Sender<bool> = watch::channel(false).0;
+    static ref TEMP_DIRS: Mutex<HashSet<PathBuf>> = Mutex::new(HashSet::new());
+}
+
+/// Runtime shared by all async operations of omnect-cli.
+pub fn runtime() -> &'static Runtime {
+    &RUNTIME
+}
+
+/// Runs `future` on the shared runtime until it completes or [`cancel`] is
+/// called. A cancelled future is dropped, which aborts e.g. pending uploads.
+pub fn block_on<F, T>(future: F) -> Result<T>
+where
+    F: Future<Output = Result<T>>,
+{
+    RUNTIME.block_on(async {
+        tokio::select! {
+            result = future => result,
+            _ = cancelled() => Err(anyhow::anyhow!("operation cancelled").context(ErrorKind::Cancelled)),
+        }
+    })
+}
+
+/// Cancels all running and subsequent operations.
+pub fn cancel() {
+    CANCELLED.send_replace(true);
+}
+
+pub fn is_cancelled() -> bool {
+    *CANCELLED.borrow()
+}
+
+/// Completes once [`cancel`] is called, e.g. to abort futures by
+/// `tokio::select!` on the caller's runtime.
+pub async fn cancelled() {
+    // the sender is static, so waiting only ends on cancellation
+    let _ = CANCELLED.subscribe().wait_for(|cancelled| *cancelled).await;
+}
+
+/// Fails if the operation was cancelled, used between the steps of
+/// synchronous operations.
+pub fn check_cancelled() -> Result<()> {
+    if is_cancelled() {
+        return Err(anyhow::anyhow!("operation cancelled").context(ErrorKind::Cancelled));
+    }
+
+    Ok(())
+}
+
+/// Cancels running operations on Ctrl-C. If the process doesn't exit within
+/// a grace period or on a second Ctrl-C, temporary directories are removed
+/// and the process exits.
+pub fn handle_ctrl_c() {
+    RUNTIME.spawn(async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("cannot listen for Ctrl-C: {e}");
+            return;
+        }
+
+        warn!("cancelling, press Ctrl-C again to abort immediately");
+        cancel();
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = tokio::time::sleep(CANCEL_GRACE_PERIOD) => {},
+        }
+
+        remove_temp_dirs();
+        std::process::exit(ErrorKind::Cancelled.exit_code());
+    });
+}
+
+pub(crate) fn register_temp_dir(dir: &Path) {
+    if let Ok(mut dirs) = TEMP_DIRS.lock() {
+        dirs.insert(dir.to_path_buf());
+    }
+}
+
+pub(crate) fn unregister_temp_dir(dir: &Path) {
+    if let Ok(mut dirs) = TEMP_DIRS.lock() {
+        dirs.remove(dir);
+    }
+}
+
+fn remove_temp_dirs() {
+    let Ok(mut dirs) = TEMP_DIRS.lock() else {
+        return;
+    };
+
+    for dir in dirs.drain() {
+        if let Err(e) = std::fs::remove_dir_all(&dir) {
+            error!("cannot remove tmp dir {}: {e}", dir.to_string_lossy());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_on_result() {
+        assert_eq!(block_on(async { Ok(42) }).unwrap(), 42);
+        assert!(block_on(async { Err::<(), _>(anyhow::anyhow!("failed")) }).is_err());
+    }
+}
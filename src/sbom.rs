@@ -0,0 +1,512 @@
+use crate::error::ErrorKind;
+use crate::file::functions::{
+    copy_from_image, read_file_from_image, FileCopyFromParams, Partition,
+};
+use anyhow::{Context, Result};
+use flate2::read::GzDecoder;
+use log::debug;
+use serde::Deserialize;
+use serde_json::json;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use uuid::Uuid;
+
+const DPKG_STATUS: &str = "/var/lib/dpkg/status";
+const OPKG_STATUS: [&str; 2] = ["/var/lib/opkg/status", "/usr/lib/opkg/status"];
+const RPM_DB: [&str; 2] = ["/usr/lib/sysimage/rpm", "/var/lib/rpm"];
+const RPM_DB_FILE: &str = "rpmdb.sqlite";
+
+#[derive(clap::ValueEnum, Clone, Debug, PartialEq)]
+#[clap(rename_all = "verbatim")]
+#[allow(non_camel_case_types)]
+pub enum SbomFormat {
+    spdx,
+    cyclonedx,
+}
+
+/// Container archive injected by `docker inject`, given as
+/// `<partition>:<path>`.
+#[derive(Clone, Debug)]
+pub struct ContainerArchive {
+    partition: Partition,
+    path: PathBuf,
+}
+
+impl FromStr for ContainerArchive {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (partition, path) = s
+            .split_once(':')
+            .context("format not matched: partition:file-path")?;
+        let path = PathBuf::from(path);
+
+        anyhow::ensure!(path.is_absolute(), "file-path isn't an absolute path");
+
+        Ok(ContainerArchive {
+            partition: Partition::from_str(partition)?,
+            path,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum PackageType {
+    Deb,
+    Opkg,
+    Rpm,
+    Container,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Package {
+    name: String,
+    version: String,
+    arch: Option<String>,
+    license: Option<String>,
+    package_type: PackageType,
+}
+
+impl Package {
+    fn purl(&self) -> String {
+        let (purl_type, name) = match self.package_type {
+            PackageType::Deb => ("deb", self.name.as_str()),
+            PackageType::Opkg => ("generic", self.name.as_str()),
+            PackageType::Rpm => ("rpm", self.name.as_str()),
+            // docker purls don't contain the registry
+            PackageType::Container => ("docker", self.name.rsplit('/').next().unwrap()),
+        };
+        let mut purl = format!(
+            "pkg:{purl_type}/{}@{}",
+            purl_encode(name),
+            purl_encode(&self.version)
+        );
+
+        if let Some(arch) = &self.arch {
+            purl.push_str(&format!("?arch={}", purl_encode(arch)));
+        }
+
+        purl
+    }
+
+    /// License as SPDX expression, yocto uses "&" and "|" as operators.
+    fn license_expression(&self) -> Option<String> {
+        self.license
+            .as_ref()
+            .map(|license| license.replace(" & ", " AND ").replace(" | ", " OR "))
+    }
+}
+
+fn purl_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'.' | b'-' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+/// Parses installed packages of a dpkg or opkg status file.
+fn parse_status(content: &str, package_type: PackageType) -> Vec<Package> {
+    let mut packages = vec![];
+
+    for paragraph in content.split("\n\n") {
+        let field = |name: &str| {
+            paragraph.lines().find_map(|line| {
+                line.strip_prefix(name)
+                    .and_then(|rest| rest.strip_prefix(':'))
+                    .map(|value| value.trim().to_string())
+            })
+        };
+
+        let installed = field("Status").is_some_and(|status| status.ends_with(" installed"));
+
+        if let (true, Some(name), Some(version)) = (installed, field("Package"), field("Version")) {
+            packages.push(Package {
+                name,
+                version,
+                arch: field("Architecture"),
+                license: field("License"),
+                package_type: package_type.clone(),
+            });
+        }
+    }
+
+    packages
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ContainerManifest {
+    repo_tags: Option<Vec<String>>,
+}
+
+/// Parses the images of a (gzip compressed) archive created by `docker save`.
+fn parse_container_archive<R: Read>(archive: R) -> Result<Vec<Package>> {
+    let mut archive = tar::Archive::new(GzDecoder::new(archive));
+
+    for entry in archive
+        .entries()
+        .context("parse_container_archive: invalid archive")?
+    {
+        let entry = entry.context("parse_container_archive: invalid archive entry")?;
+
+        if entry.path()?.as_os_str() != "manifest.json" {
+            continue;
+        }
+
+        let manifests: Vec<ContainerManifest> = serde_json::from_reader(entry)
+            .context("parse_container_archive: invalid manifest.json")?;
+
+        return Ok(manifests
+            .into_iter()
+            .flat_map(|manifest| manifest.repo_tags.unwrap_or_default())
+            .filter_map(|repo_tag| {
+                // the tag is separated by the last colon, which isn't part of a registry port
+                let (name, tag) = repo_tag.rsplit_once(':')?;
+
+                (!tag.contains('/')).then(|| Package {
+                    name: name.to_string(),
+                    version: tag.to_string(),
+                    arch: None,
+                    license: None,
+                    package_type: PackageType::Container,
+                })
+            })
+            .collect());
+    }
+
+    anyhow::bail!("parse_container_archive: archive doesn't contain a manifest.json")
+}
+
+fn rpm_packages(image_file: &Path) -> Result<Vec<Package>> {
+    let db_dir = tempfile::tempdir().context("rpm_packages: cannot create temporary dir")?;
+
+    let Some(db_path) = RPM_DB.iter().find(|db_path| {
+        let params = FileCopyFromParams::new(
+            &Path::new(db_path).join(RPM_DB_FILE),
+            Partition::rootA,
+            &db_dir.path().join(RPM_DB_FILE),
+        );
+
+        copy_from_image(&[params], image_file).is_ok()
+    }) else {
+        return Ok(vec![]);
+    };
+
+    debug!("found rpm database in {db_path}");
+
+    let output = Command::new("rpm")
+        .arg("--dbpath")
+        .arg(db_dir.path())
+        .args([
+            "-qa",
+            "--qf",
+            "%{NAME}\t%{VERSION}-%{RELEASE}\t%{ARCH}\t%{LICENSE}\n",
+        ])
+        .output()
+        .context(ErrorKind::Environment)
+        .context("rpm_packages: cannot run rpm, is it installed?")?;
+
+    anyhow::ensure!(
+        output.status.success(),
+        "rpm_packages: cannot query rpm database: {}",
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+
+            Some(Package {
+                name: fields.next()?.to_string(),
+                version: fields.next()?.to_string(),
+                arch: fields.next().map(str::to_string),
+                license: fields.next().map(str::to_string),
+                package_type: PackageType::Rpm,
+            })
+        })
+        .collect())
+}
+
+/// Collects the packages installed in the rootfs and the images of the
+/// given container archives.
+fn packages(image_file: &Path, containers: &[ContainerArchive]) -> Result<Vec<Package>> {
+    let mut packages = vec![];
+
+    if let Ok(status) = read_file_from_image(DPKG_STATUS, Partition::rootA, image_file) {
+        packages.append(&mut parse_status(&status, PackageType::Deb));
+    }
+
+    if let Some(status) = OPKG_STATUS
+        .iter()
+        .find_map(|path| read_file_from_image(path, Partition::rootA, image_file).ok())
+    {
+        packages.append(&mut parse_status(&status, PackageType::Opkg));
+    }
+
+    packages.append(&mut rpm_packages(image_file)?);
+
+    for container in containers {
+        let archive =
+            tempfile::NamedTempFile::new().context("packages: cannot create temporary file")?;
+
+        copy_from_image(
+            &[FileCopyFromParams::new(
+                &container.path,
+                container.partition.clone(),
+                archive.path(),
+            )],
+            image_file,
+        )
+        .context(format!(
+            "packages: cannot read container archive {}",
+            container.path.to_string_lossy()
+        ))?;
+
+        packages.append(
+            &mut parse_container_archive(archive.reopen()?).context(format!(
+                "packages: invalid container archive {}",
+                container.path.to_string_lossy()
+            ))?,
+        );
+    }
+
+    Ok(packages)
+}
+
+fn timestamp() -> Result<String> {
+    Ok(OffsetDateTime::now_utc().format(&Rfc3339)?)
+}
+
+fn spdx(name: &str, packages: &[Package]) -> Result<serde_json::Value> {
+    let spdx_packages = packages
+        .iter()
+        .enumerate()
+        .map(|(i, package)| {
+            let license = package
+                .license_expression()
+                .unwrap_or_else(|| "NOASSERTION".to_string());
+
+            json!({
+                "name": package.name,
+                "SPDXID": format!("SPDXRef-Package-{i}"),
+                "versionInfo": package.version,
+                "downloadLocation": "NOASSERTION",
+                "filesAnalyzed": false,
+                "licenseConcluded": "NOASSERTION",
+                "licenseDeclared": license,
+                "primaryPackagePurpose": match package.package_type {
+                    PackageType::Container => "CONTAINER",
+                    _ => "LIBRARY",
+                },
+                "externalRefs": [{
+                    "referenceCategory": "PACKAGE-MANAGER",
+                    "referenceType": "purl",
+                    "referenceLocator": package.purl(),
+                }],
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let relationships = (0..packages.len())
+        .map(|i| {
+            json!({
+                "spdxElementId": "SPDXRef-DOCUMENT",
+                "relationshipType": "DESCRIBES",
+                "relatedSpdxElement": format!("SPDXRef-Package-{i}"),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    Ok(json!({
+        "spdxVersion": "SPDX-2.3",
+        "dataLicense": "CC0-1.0",
+        "SPDXID": "SPDXRef-DOCUMENT",
+        "name": name,
+        "documentNamespace": format!("https://github.com/omnect/omnect-cli/spdx/{name}-{}", Uuid::new_v4()),
+        "creationInfo": {
+            "created": timestamp()?,
+            "creators": [format!("Tool: omnect-cli-{}", env!("CARGO_PKG_VERSION"))],
+        },
+        "packages": spdx_packages,
+        "relationships": relationships,
+    }))
+}
+
+fn cyclonedx(name: &str, packages: &[Package]) -> Result<serde_json::Value> {
+    let components = packages
+        .iter()
+        .map(|package| {
+            let mut component = json!({
+                "type": match package.package_type {
+                    PackageType::Container => "container",
+                    _ => "library",
+                },
+                "name": package.name,
+                "version": package.version,
+                "purl": package.purl(),
+            });
+
+            if let Some(license) = package.license_expression() {
+                component["licenses"] = json!([{ "expression": license }]);
+            }
+
+            component
+        })
+        .collect::<Vec<_>>();
+
+    Ok(json!({
+        "bomFormat": "CycloneDX",
+        "specVersion": "1.5",
+        "serialNumber": format!("urn:uuid:{}", Uuid::new_v4()),
+        "version": 1,
+        "metadata": {
+            "timestamp": timestamp()?,
+            "tools": {
+                "components": [{
+                    "type": "application",
+                    "name": env!("CARGO_PKG_NAME"),
+                    "version": env!("CARGO_PKG_VERSION"),
+                }],
+            },
+            "component": {
+                "type": "firmware",
+                "name": name,
+            },
+        },
+        "components": components,
+    }))
+}
+
+/// Writes a software bill of materials of the packages installed in the
+/// rootfs of `image_file` and of the given container archives to `output`.
+/// Returns the number of packages.
+pub fn write_sbom(
+    image_file: &Path,
+    name: &str,
+    containers: &[ContainerArchive],
+    format: &SbomFormat,
+    output: &Path,
+) -> Result<usize> {
+    let packages = packages(image_file, containers)?;
+
+    let sbom = match format {
+        SbomFormat::spdx => spdx(name, &packages)?,
+        SbomFormat::cyclonedx => cyclonedx(name, &packages)?,
+    };
+
+    std::fs::write(output, serde_json::to_string_pretty(&sbom)?).context(format!(
+        "write_sbom: cannot write {}",
+        output.to_string_lossy()
+    ))?;
+
+    Ok(packages.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATUS: &str = "Package: busybox
+Version: 1.36.1-r0
+Status: install ok installed
+Architecture: cortexa53
+License: GPL-2.0-only & bzip2-1.0.4
+
+Package: removed
+Version: 1.0
+Status: deinstall ok config-files
+Architecture: all
+
+Package: libc6
+Version: 2:2.39-r0
+Status: install ok installed
+Architecture: cortexa53
+";
+
+    #[test]
+    fn status_contains_installed_packages() {
+        let packages = parse_status(STATUS, PackageType::Opkg);
+
+        assert_eq!(packages.len(), 2);
+        assert_eq!(packages[0].name, "busybox");
+        assert_eq!(
+            packages[0].license_expression().unwrap(),
+            "GPL-2.0-only AND bzip2-1.0.4"
+        );
+        assert_eq!(packages[1].name, "libc6");
+        assert_eq!(packages[1].license, None);
+        assert_eq!(
+            packages[1].purl(),
+            "pkg:generic/libc6@2%3A2.39-r0?arch=cortexa53"
+        );
+    }
+
+    #[test]
+    fn container_archive_contains_repo_tags() {
+        let manifest = br#"[{"Config":"abc.json","RepoTags":["registry.example.com:5000/omnect/app:1.2.3"],"Layers":[]}]"#;
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+            vec![],
+            flate2::Compression::default(),
+        ));
+        let mut header = tar::Header::new_gnu();
+
+        header.set_size(manifest.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "manifest.json", &manifest[..])
+            .unwrap();
+
+        let archive = builder.into_inner().unwrap().finish().unwrap();
+        let packages = parse_container_archive(&archive[..]).unwrap();
+
+        assert_eq!(packages.len(), 1);
+        assert_eq!(packages[0].name, "registry.example.com:5000/omnect/app");
+        assert_eq!(packages[0].version, "1.2.3");
+        assert_eq!(packages[0].purl(), "pkg:docker/app@1.2.3");
+    }
+
+    #[test]
+    fn sbom_formats() {
+        let packages = parse_status(STATUS, PackageType::Deb);
+
+        let spdx = spdx("image.wic", &packages).unwrap();
+
+        assert_eq!(spdx["spdxVersion"], "SPDX-2.3");
+        assert_eq!(spdx["packages"][0]["name"], "busybox");
+        assert_eq!(spdx["packages"][1]["licenseDeclared"], "NOASSERTION");
+        assert_eq!(
+            spdx["packages"][0]["externalRefs"][0]["referenceLocator"],
+            "pkg:deb/busybox@1.36.1-r0?arch=cortexa53"
+        );
+        assert_eq!(spdx["relationships"].as_array().unwrap().len(), 2);
+
+        let cyclonedx = cyclonedx("image.wic", &packages).unwrap();
+
+        assert_eq!(cyclonedx["bomFormat"], "CycloneDX");
+        assert_eq!(cyclonedx["metadata"]["component"]["name"], "image.wic");
+        assert_eq!(
+            cyclonedx["components"][0]["licenses"][0]["expression"],
+            "GPL-2.0-only AND bzip2-1.0.4"
+        );
+        assert!(cyclonedx["components"][1].get("licenses").is_none());
+    }
+
+    #[test]
+    fn container_archive_param() {
+        let archive = ContainerArchive::from_str("factory:/oci/app.tar.gz").unwrap();
+
+        assert_eq!(archive.partition, Partition::factory);
+        assert_eq!(archive.path, PathBuf::from("/oci/app.tar.gz"));
+        assert!(ContainerArchive::from_str("factory:oci/app.tar.gz").is_err());
+        assert!(ContainerArchive::from_str("/oci/app.tar.gz").is_err());
+    }
+}
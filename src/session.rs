@@ -0,0 +1,500 @@
+use crate::config::UserConfig;
+use crate::error::ErrorKind;
+use crate::file::{
+    self,
+    compression::{self, Compression},
+    functions::{FileCopyFromParams, FileCopyToParams},
+    object_storage::ObjectUrl,
+};
+use crate::lock::ImageLock;
+use crate::{
+    bmap, copy_file, integrity, move_file, provenance, runtime, working_image, WorkingImage,
+};
+use anyhow::{Context, Result};
+use log::{info, warn};
+use std::fs;
+use std::io::{BufRead, IsTerminal, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Modification of an image for embedding omnect-cli in other tools. All
+/// operations work on a copy of the image, which replaces the image on
+/// [`ImageSession::finish`]. Dropping a session discards the modifications.
+/// Operations fail after [`crate::runtime::cancel`].
+///
+/// ```no_run
+/// use omnect_cli::file::functions::{FileCopyToParams, Partition};
+/// use omnect_cli::ImageSession;
+/// use std::path::Path;
+///
+/// let image = ImageSession::open(Path::new("image.wic.xz"))?
+///     .copy_to(&[FileCopyToParams::new(
+///         Path::new("motd"),
+///         Partition::factory,
+///         Path::new("/etc/motd"),
+///     )])?
+///     .set_identity(Path::new("config.toml"), None)?
+///     .finish(None)?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub struct ImageSession {
+    working_image: WorkingImage,
+    generate_bmap: bool,
+    integrity_manifest: bool,
+    fsck: bool,
+    zero_free_space: bool,
+    history: bool,
+    modification_log: bool,
+    invocation: provenance::Invocation,
+    backup: Option<Option<PathBuf>>,
+    output_device: Option<PathBuf>,
+    _locks: Vec<ImageLock>,
+}
+
+impl ImageSession {
+    /// Opens `image_file` (optionally compressed with xz, bzip2, gzip or zstd, raw
+    /// or qcow2) without a user configuration.
+    pub fn open(image_file: &Path) -> Result<ImageSession> {
+        Self::open_with_config(image_file, &UserConfig::default())
+    }
+
+    /// Opens `image_file` with the work and cache directories and the
+    /// signature verification of `user_config`.
+    pub fn open_with_config(image_file: &Path, user_config: &UserConfig) -> Result<ImageSession> {
+        Self::new(image_file, true, user_config)
+    }
+
+    pub(crate) fn new(
+        image_file: &Path,
+        next_to_image: bool,
+        user_config: &UserConfig,
+    ) -> Result<ImageSession> {
+        let mut locks = vec![];
+
+        // remote images are downloaded to a unique temporary directory first
+        if file::download::url(image_file).is_none() && ObjectUrl::parse(image_file)?.is_none() {
+            locks.push(ImageLock::acquire(image_file)?);
+        }
+
+        let working_image = working_image(image_file, next_to_image, user_config)?;
+
+        if working_image.upload.is_none() && working_image.dest_file != image_file {
+            locks.push(ImageLock::acquire(&working_image.dest_file)?);
+        }
+
+        Ok(ImageSession {
+            working_image,
+            generate_bmap: false,
+            integrity_manifest: false,
+            fsck: false,
+            zero_free_space: false,
+            history: false,
+            modification_log: false,
+            invocation: provenance::Invocation::default(),
+            backup: None,
+            output_device: None,
+            _locks: locks,
+        })
+    }
+
+    /// Path of the working copy, e.g. for [`ImageSession::apply`] closures.
+    pub fn working_file(&self) -> &Path {
+        &self.working_image.file
+    }
+
+    /// Generates a bmap file next to the image on [`ImageSession::finish`].
+    pub fn generate_bmap(mut self, generate_bmap: bool) -> Result<Self> {
+        if generate_bmap && self.working_image.qcow2 {
+            return Err(
+                anyhow::anyhow!("generating bmap file is not supported for qcow2 images.")
+                    .context(ErrorKind::User),
+            );
+        }
+
+        self.generate_bmap = generate_bmap;
+
+        Ok(self)
+    }
+
+    /// Writes an integrity manifest of all files next to the image on
+    /// [`ImageSession::finish`], which is checked by `image verify`.
+    pub fn integrity_manifest(mut self, integrity_manifest: bool) -> Self {
+        self.integrity_manifest = integrity_manifest;
+        self
+    }
+
+    /// Checks the filesystems of all partitions on [`ImageSession::finish`],
+    /// so that an inconsistent image isn't written.
+    pub fn fsck(mut self, fsck: bool) -> Self {
+        self.fsck = fsck;
+        self
+    }
+
+    /// Zeroes the free blocks of all ext filesystems on
+    /// [`ImageSession::finish`], so that the image compresses better.
+    pub fn zero_free_space(mut self, zero_free_space: bool) -> Self {
+        self.zero_free_space = zero_free_space;
+        self
+    }
+
+    /// Appends the invocation to the modification history embedded in the
+    /// image on [`ImageSession::finish`].
+    pub fn history(mut self, history: bool) -> Self {
+        self.history = history;
+        self
+    }
+
+    /// Appends the invocation to the modification log `<image>.modifications.json`
+    /// next to the image on [`ImageSession::finish`], which is part of the
+    /// predicate of `image sign`.
+    pub fn modification_log(mut self, modification_log: bool) -> Self {
+        self.modification_log = modification_log;
+        self
+    }
+
+    /// Command modifying the image, which is recorded in the modification
+    /// history and log.
+    pub fn invocation(mut self, invocation: provenance::Invocation) -> Self {
+        self.invocation = invocation;
+        self
+    }
+
+    /// Keeps the image replaced on [`ImageSession::finish`] as `<image>.bak`
+    /// next to the image or in `backup_dir`, so that the original survives an
+    /// interrupted or failed finish.
+    pub fn backup(mut self, backup: bool, backup_dir: Option<&Path>) -> Self {
+        self.backup = backup.then(|| backup_dir.map(Path::to_path_buf));
+        self
+    }
+
+    /// Writes the image to the block device `device` on
+    /// [`ImageSession::finish`] instead of replacing the image. The device
+    /// is checked to be unmounted and large enough before the image is
+    /// modified, overwriting it has to be confirmed interactively unless
+    /// `confirmed`.
+    pub fn output_device(mut self, device: Option<&Path>, confirmed: bool) -> Result<Self> {
+        let Some(device) = device else {
+            return Ok(self);
+        };
+        let image_size = std::fs::metadata(&self.working_image.file)
+            .context("output_device: cannot get image size")?
+            .len();
+
+        if let Some(device_size) = bmap::check_target(device, image_size)? {
+            if !confirmed {
+                confirm_overwrite(device, device_size)?;
+            }
+        }
+
+        self.output_device = Some(device.to_path_buf());
+
+        Ok(self)
+    }
+
+    /// Applies `command` to the working copy of the image.
+    pub fn apply<F>(self, command: F) -> Result<Self>
+    where
+        F: FnOnce(&PathBuf) -> Result<()>,
+    {
+        runtime::check_cancelled()?;
+        command(&self.working_image.file)?;
+
+        Ok(self)
+    }
+
+    pub fn copy_to(self, file_copy_params: &[FileCopyToParams]) -> Result<Self> {
+        self.apply(|img| file::copy_to_image(file_copy_params, img))
+    }
+
+    pub fn copy_from(self, file_copy_params: &[FileCopyFromParams]) -> Result<Self> {
+        self.apply(|img| file::copy_from_image(file_copy_params, img))
+    }
+
+    pub fn set_identity(self, config_file: &Path, payload: Option<&Path>) -> Result<Self> {
+        self.apply(|img| file::set_identity_config(config_file, img, payload))
+    }
+
+    pub fn set_device_cert(
+        self,
+        intermediate_full_chain_cert: Option<&Path>,
+        device_cert: &Path,
+        device_key: &Path,
+    ) -> Result<Self> {
+        self.apply(|img| {
+            file::set_device_cert(intermediate_full_chain_cert, device_cert, device_key, img)
+        })
+    }
+
+    pub fn set_hostname(self, hostname: &str, machine_id_salt: Option<&str>) -> Result<Self> {
+        self.apply(|img| file::set_hostname(hostname, machine_id_salt, img))
+    }
+
+    pub fn set_device_update_config(self, du_config_file: &Path) -> Result<Self> {
+        self.apply(|img| file::set_iot_hub_device_update_config(du_config_file, img))
+    }
+
+    pub fn set_ssh_tunnel_certificate(self, root_ca_file: &Path) -> Result<Self> {
+        self.apply(|img| file::set_ssh_tunnel_certificate(img, root_ca_file))
+    }
+
+    /// Replaces the image by the modified copy, compressed with
+    /// `compression` if given. Returns the path of the resulting image, whose
+    /// extension changes with the compression. With an output device the
+    /// uncompressed image is written to the device instead.
+    pub fn finish(self, compression: Option<Compression>) -> Result<PathBuf> {
+        runtime::check_cancelled()?;
+
+        let mut tmp_image_file = self.working_image.file.clone();
+        let mut dest_image_file = self.working_image.dest_file.clone();
+
+        // before the filesystem check, which then covers the history as well
+        if self.history {
+            provenance::embed(
+                &tmp_image_file,
+                &self.working_image.source_file,
+                &self.invocation,
+            )?;
+        }
+
+        if self.fsck {
+            file::fsck::ensure_consistent(&tmp_image_file)?;
+        }
+
+        // before the bmap file, which skips the discarded blocks
+        if self.zero_free_space {
+            file::resize::zero_free_space(&tmp_image_file)?;
+        }
+
+        // files next to the image, which are uploaded with it
+        let mut sidecars = vec![];
+
+        // create and copy back bmap file if one was created
+        if self.generate_bmap {
+            let mut target_bmap = dest_image_file
+                .parent()
+                .context("cannot get parent dir of image path")?
+                .to_path_buf();
+            let tmp_bmap = PathBuf::from(format!(
+                "{}.bmap",
+                tmp_image_file
+                    .to_str()
+                    .context("cannot get image file path")?
+            ));
+            file::functions::generate_bmap_file(
+                tmp_image_file
+                    .to_str()
+                    .context("cannot get image file path")?,
+            )?;
+            target_bmap.push(tmp_bmap.file_name().context("cannot get bmap file name")?);
+            std::fs::copy(&tmp_bmap, &target_bmap).context(format!(
+                "error: std::fs::copy({:?}, {:?})",
+                tmp_bmap, target_bmap
+            ))?;
+            sidecars.push(target_bmap);
+        }
+
+        // the raw image is written as is, neither converted nor compressed
+        if let Some(device) = &self.output_device {
+            if self.integrity_manifest {
+                warn!("integrity manifest isn't written for an output device");
+            }
+
+            let written = bmap::flash(&tmp_image_file, &bmap::create(&tmp_image_file)?, device)?;
+
+            info!("wrote {written} bytes to {}", device.to_string_lossy());
+
+            return Ok(device.clone());
+        }
+
+        let manifest = if self.integrity_manifest {
+            Some(integrity::create(&tmp_image_file)?)
+        } else {
+            None
+        };
+
+        // if applicable convert back to qcow2
+        if self.working_image.qcow2 {
+            let qcow2_file = tmp_image_file.with_file_name(
+                dest_image_file
+                    .file_name()
+                    .context("cannot get image file name")?,
+            );
+            file::qcow2::from_raw(&tmp_image_file, &qcow2_file)?;
+            tmp_image_file = qcow2_file;
+        }
+
+        // if applicable compress image
+        if let Some(c) = compression {
+            tmp_image_file = compression::compress(&tmp_image_file, &c)?;
+            dest_image_file.set_file_name(
+                tmp_image_file
+                    .file_name()
+                    .context("cannot get image file name")?,
+            );
+        }
+
+        if let Some(backup_dir) = &self.backup {
+            backup(&dest_image_file, backup_dir.as_deref())?;
+        }
+
+        move_file(&tmp_image_file, &dest_image_file)?;
+
+        if self.modification_log {
+            provenance::record(
+                &self.working_image.source_file,
+                &dest_image_file,
+                &self.invocation,
+            )?;
+            sidecars.push(provenance::log_path(&dest_image_file));
+        }
+
+        if let Some(manifest) = manifest {
+            integrity::write(&manifest, &dest_image_file)?;
+            sidecars.push(integrity::manifest_path(&dest_image_file));
+        }
+
+        if let Some(object) = &self.working_image.upload {
+            return upload(object, &dest_image_file, &sidecars);
+        }
+
+        Ok(dest_image_file)
+    }
+}
+
+/// Moves an existing `image_file` aside as `<image>.bak`, in `backup_dir`
+/// if given. A symlinked or hardlinked image is copied instead, so that the
+/// links still refer to the image written back in place.
+fn backup(image_file: &Path, backup_dir: Option<&Path>) -> Result<()> {
+    if !image_file.try_exists().is_ok_and(|exists| exists) {
+        return Ok(());
+    }
+
+    let backup_name = format!(
+        "{}.bak",
+        image_file
+            .file_name()
+            .context("backup: cannot get image file name")?
+            .to_string_lossy()
+    );
+    let backup_file = match backup_dir {
+        Some(backup_dir) => {
+            std::fs::create_dir_all(backup_dir).context(format!(
+                "backup: cannot create {}",
+                backup_dir.to_string_lossy()
+            ))?;
+            backup_dir.join(backup_name)
+        }
+        None => image_file.with_file_name(backup_name),
+    };
+
+    let is_linked = fs::symlink_metadata(image_file)
+        .is_ok_and(|metadata| metadata.file_type().is_symlink() || metadata.nlink() > 1);
+    let backed_up = if is_linked {
+        let _ = fs::remove_file(&backup_file);
+
+        fs::canonicalize(image_file)
+            .map_err(anyhow::Error::from)
+            .and_then(|target| copy_file(&target, &backup_file))
+    } else {
+        move_file(image_file, &backup_file)
+    };
+
+    backed_up.context(format!(
+        "backup: cannot back up {}",
+        image_file.to_string_lossy()
+    ))?;
+
+    info!(
+        "backed up {} to {}",
+        image_file.to_string_lossy(),
+        backup_file.to_string_lossy()
+    );
+
+    Ok(())
+}
+
+/// Uploads `image_file` and its `sidecars` next to `object` and returns the
+/// url of the uploaded image. The image is uploaded last, so that its
+/// sidecars are up to date once it appears.
+fn upload(object: &ObjectUrl, image_file: &Path, sidecars: &[PathBuf]) -> Result<PathBuf> {
+    for file in sidecars.iter().chain([&image_file.to_path_buf()]) {
+        let file_name = file
+            .file_name()
+            .context("upload: cannot get file name")?
+            .to_string_lossy();
+
+        object.with_file_name(&file_name).upload(file)?;
+    }
+
+    let image_object = object.with_file_name(
+        &image_file
+            .file_name()
+            .context("upload: cannot get image file name")?
+            .to_string_lossy(),
+    );
+
+    info!("uploaded image to {image_object}");
+
+    Ok(PathBuf::from(image_object.to_string()))
+}
+
+/// Asks the user on the terminal to confirm overwriting `device`.
+fn confirm_overwrite(device: &Path, device_size: u64) -> Result<()> {
+    let device = device.to_string_lossy();
+
+    if !std::io::stdin().is_terminal() {
+        return Err(anyhow::anyhow!(
+            "output_device: overwriting {device} has to be confirmed by --yes"
+        )
+        .context(ErrorKind::User));
+    }
+
+    eprint!(
+        "All data on {device} ({:.1} GiB) will be overwritten. Continue? [y/N] ",
+        device_size as f64 / (1024.0 * 1024.0 * 1024.0)
+    );
+    std::io::stderr().flush()?;
+
+    let mut answer = String::new();
+    std::io::stdin()
+        .lock()
+        .read_line(&mut answer)
+        .context("output_device: cannot read confirmation")?;
+
+    match answer.trim() {
+        "y" | "yes" => Ok(()),
+        _ => {
+            Err(anyhow::anyhow!("output_device: {device} not overwritten").context(ErrorKind::User))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backup_keeps_links() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.wic");
+        let symlink = dir.path().join("current.wic");
+        let new_image = dir.path().join("new.wic");
+
+        fs::write(&image, "old").unwrap();
+        std::os::unix::fs::symlink(&image, &symlink).unwrap();
+        fs::write(&new_image, "new").unwrap();
+
+        backup(&symlink, None).unwrap();
+        move_file(&new_image, &symlink).unwrap();
+
+        assert!(fs::symlink_metadata(&symlink)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(fs::read_to_string(&image).unwrap(), "new");
+        assert_eq!(
+            fs::read_to_string(dir.path().join("current.wic.bak")).unwrap(),
+            "old"
+        );
+    }
+}
@@ -0,0 +1,117 @@
+use crate::error::ErrorKind;
+use crate::provenance;
+use anyhow::{Context, Result};
+use log::debug;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Files created by signing an image.
+#[derive(Debug, Serialize)]
+pub struct SignedImage {
+    pub signature: PathBuf,
+    pub predicate: PathBuf,
+    pub attestation: PathBuf,
+}
+
+fn with_suffix(file: &Path, suffix: &str) -> PathBuf {
+    let mut path = file.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+fn run_cosign(mut cosign: Command, error_kind: ErrorKind) -> Result<()> {
+    debug!("run {cosign:?}");
+
+    let output = cosign
+        .output()
+        .context(ErrorKind::Environment)
+        .context("run_cosign: cannot run cosign, is it installed?")?;
+
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "cosign failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .context(error_kind));
+    }
+
+    Ok(())
+}
+
+/// Creates a detached signature of `image_file` and an attestation of the
+/// predicate describing its modifications, both signed by `key`. A password
+/// protected key is unlocked by COSIGN_PASSWORD.
+pub fn sign(image_file: &Path, key: &Path, signature: Option<&Path>) -> Result<SignedImage> {
+    let signed = SignedImage {
+        signature: signature
+            .map(Path::to_path_buf)
+            .unwrap_or_else(|| with_suffix(image_file, ".sig")),
+        predicate: with_suffix(image_file, ".predicate.json"),
+        attestation: with_suffix(image_file, ".att"),
+    };
+
+    std::fs::write(
+        &signed.predicate,
+        serde_json::to_string_pretty(&provenance::predicate(image_file)?)?,
+    )
+    .context("sign: cannot write predicate")?;
+
+    let mut sign_blob = Command::new("cosign");
+    sign_blob
+        .args(["sign-blob", "--yes", "--key"])
+        .arg(key)
+        .arg("--output-signature")
+        .arg(&signed.signature)
+        .arg(image_file);
+    run_cosign(sign_blob, ErrorKind::User)?;
+
+    let mut attest_blob = Command::new("cosign");
+    attest_blob
+        .args(["attest-blob", "--yes", "--key"])
+        .arg(key)
+        .arg("--predicate")
+        .arg(&signed.predicate)
+        .args(["--type", provenance::PREDICATE_TYPE])
+        .arg("--output-attestation")
+        .arg(&signed.attestation)
+        .arg(image_file);
+    run_cosign(attest_blob, ErrorKind::User)?;
+
+    Ok(signed)
+}
+
+/// Verifies the detached signature of `image_file` and, if present, the
+/// attestation of its modifications.
+pub fn verify(image_file: &Path, public_key: &Path, signature: Option<&Path>) -> Result<()> {
+    let signature = signature
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| with_suffix(image_file, ".sig"));
+
+    let mut verify_blob = Command::new("cosign");
+    verify_blob
+        .args(["verify-blob", "--key"])
+        .arg(public_key)
+        .arg("--signature")
+        .arg(&signature)
+        .arg(image_file);
+    run_cosign(verify_blob, ErrorKind::User)
+        .context(format!("verify: invalid signature of {image_file:?}"))?;
+
+    let attestation = with_suffix(image_file, ".att");
+
+    if attestation.try_exists().is_ok_and(|exists| exists) {
+        let mut verify_attestation = Command::new("cosign");
+        verify_attestation
+            .args(["verify-blob-attestation", "--key"])
+            .arg(public_key)
+            .arg("--signature")
+            .arg(&attestation)
+            .args(["--type", provenance::PREDICATE_TYPE])
+            .arg(image_file);
+        run_cosign(verify_attestation, ErrorKind::User)
+            .context(format!("verify: invalid attestation of {image_file:?}"))?;
+    }
+
+    Ok(())
+}
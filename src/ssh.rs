@@ -7,17 +7,20 @@ use std::process::{Command, Stdio};
 use std::str;
 
 use anyhow::{Context, Result};
-use directories::ProjectDirs;
+use directories::{BaseDirs, ProjectDirs};
 use oauth2::AccessToken;
 use serde::{Deserialize, Serialize};
 use url::Url;
 
+use crate::error::ErrorKind;
+
 static BACKEND_API_ENDPOINT: &str = "/api/devices/prepareSSHConnection";
 static SSH_KEY_FORMAT: &str = "ed25519";
 
 static BASTION_CERT_NAME: &str = "bastion-cert.pub";
 static DEVICE_CERT_NAME: &str = "device-cert.pub";
 static SSH_CONFIG_NAME: &str = "config";
+static SSH_CONFIG_SNIPPET_DIR: &str = ".ssh/config.d/omnect";
 
 pub struct Config {
     backend: Url,
@@ -228,7 +231,11 @@ async fn request_ssh_tunnel(
 
     if !status.is_success() {
         let error_msg = into_error_message(response).await;
-        anyhow::bail!("Something went wrong while creating the ssh tunnel. status: {status}, message: {error_msg}");
+
+        return Err(anyhow::anyhow!(
+            "Something went wrong while creating the ssh tunnel. status: {status}, message: {error_msg}"
+        )
+        .context(ErrorKind::from_status(status)));
     }
 
     Ok(response.json().await?)
@@ -380,22 +387,41 @@ Host {}
     Ok(())
 }
 
-fn print_ssh_tunnel_info(cert_dir: &Path, config_path: &Path, destination: &str) {
-    println!("Successfully established ssh tunnel!");
-    if let Ok("windows") = std::env::var("CONTAINER_HOST").as_deref() {
-        println!(
-            "You can ssh now to your device via its device name, e.g.:\nssh {}",
-            destination
-        );
-    } else {
-        println!("Certificate dir: {}", cert_dir.to_str().unwrap());
-        println!("Configuration path: {}", config_path.to_str().unwrap());
-        println!(
-            "Use the configuration in \"{}\" to use the tunnel, e.g.:\nssh -F {} {}",
-            config_path.to_str().unwrap(), // safe
-            config_path.to_str().unwrap(), // safe
-            destination
-        );
+#[derive(Serialize)]
+pub struct TunnelInfo {
+    pub cert_dir: PathBuf,
+    pub config_path: PathBuf,
+    pub destination: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ssh_config_snippet: Option<PathBuf>,
+}
+
+impl TunnelInfo {
+    pub fn print(&self) {
+        println!("Successfully established ssh tunnel!");
+        if let Ok("windows") = std::env::var("CONTAINER_HOST").as_deref() {
+            println!(
+                "You can ssh now to your device via its device name, e.g.:\nssh {}",
+                self.destination
+            );
+        } else if let Some(snippet) = &self.ssh_config_snippet {
+            println!("Certificate dir: {}", self.cert_dir.to_str().unwrap());
+            println!("Configuration path: {}", self.config_path.to_str().unwrap());
+            println!("ssh config snippet: {}", snippet.to_str().unwrap());
+            println!(
+                "You can ssh now to your device via its device name, e.g.:\nssh {}",
+                self.destination
+            );
+        } else {
+            println!("Certificate dir: {}", self.cert_dir.to_str().unwrap());
+            println!("Configuration path: {}", self.config_path.to_str().unwrap());
+            println!(
+                "Use the configuration in \"{}\" to use the tunnel, e.g.:\nssh -F {} {}",
+                self.config_path.to_str().unwrap(), // safe
+                self.config_path.to_str().unwrap(), // safe
+                self.destination
+            );
+        }
     }
 }
 
@@ -404,7 +430,7 @@ pub async fn ssh_create_tunnel(
     username: &str,
     config: Config,
     access_token: oauth2::AccessToken,
-) -> Result<()> {
+) -> Result<TunnelInfo> {
     // setup place to store the certificates and configuration
     fs::create_dir_all(&config.dir)?;
     fs::create_dir_all(
@@ -472,15 +498,159 @@ pub async fn ssh_create_tunnel(
 
     create_ssh_config(&config.config_path, bastion_details, device_details)?;
 
-    print_ssh_tunnel_info(&config.dir, &config.config_path, device);
+    Ok(TunnelInfo {
+        cert_dir: config.dir,
+        config_path: config.config_path,
+        destination: device.to_string(),
+        ssh_config_snippet: None,
+    })
+}
+
+fn ssh_config_snippet_dir() -> Result<PathBuf> {
+    Ok(BaseDirs::new()
+        .context("home directory not accessible")?
+        .home_dir()
+        .join(SSH_CONFIG_SNIPPET_DIR))
+}
+
+fn ssh_config_snippet_path(snippet_dir: &Path, device: &str) -> Result<PathBuf> {
+    anyhow::ensure!(
+        !device.is_empty() && !device.contains(['/', '\\']) && device != "." && device != "..",
+        "invalid device name for an ssh config snippet: \"{device}\""
+    );
+
+    Ok(snippet_dir.join(device))
+}
+
+/// ssh config file a snippet includes, respectively `None` if it wasn't
+/// written by omnect-cli.
+fn included_config(snippet: &Path) -> Option<PathBuf> {
+    fs::read_to_string(snippet)
+        .ok()?
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("Include "))
+        .map(|config| PathBuf::from(config.trim().trim_matches('"')))
+}
+
+fn install_ssh_config_in(snippet_dir: &Path, tunnel: &TunnelInfo) -> Result<PathBuf> {
+    let snippet = ssh_config_snippet_path(snippet_dir, &tunnel.destination)?;
+
+    fs::create_dir_all(snippet_dir)
+        .context(format!("cannot create {}", snippet_dir.to_string_lossy()))?;
+
+    // the tunnel configurations are kept in the runtime directory, so that
+    // snippets of earlier sessions may include configurations which are gone
+    for entry in fs::read_dir(snippet_dir)?.flatten() {
+        if included_config(&entry.path()).is_some_and(|config| !config.exists()) {
+            log::info!(
+                "removing stale ssh config snippet {}",
+                entry.path().to_string_lossy()
+            );
+            fs::remove_file(entry.path())?;
+        }
+    }
+
+    // the device block of the tunnel configuration applies, since it is
+    // included for the device only
+    fs::write(
+        &snippet,
+        format!(
+            "# written by \"omnect-cli ssh set-connection --install-ssh-config\", removed by \"omnect-cli ssh remove-connection {}\"\nHost {}\n\tInclude \"{}\"\n",
+            tunnel.destination,
+            tunnel.destination,
+            tunnel.config_path.to_string_lossy()
+        ),
+    )
+    .context(format!("cannot write {}", snippet.to_string_lossy()))?;
+
+    Ok(snippet)
+}
+
+fn remove_ssh_config_in(snippet_dir: &Path, device: &str) -> Result<Option<PathBuf>> {
+    let snippet = ssh_config_snippet_path(snippet_dir, device)?;
+
+    match fs::remove_file(&snippet) {
+        Ok(()) => Ok(Some(snippet)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).context(format!("cannot remove {}", snippet.to_string_lossy())),
+    }
+}
+
+/// Writes a snippet for the tunnel to `~/.ssh/config.d/omnect/<device>`, so
+/// that `ssh <device>` uses the tunnel if the snippets are included by
+/// `Include config.d/omnect/*` in `~/.ssh/config`. Snippets of tunnel
+/// configurations which don't exist anymore are removed.
+pub fn install_ssh_config(tunnel: &mut TunnelInfo) -> Result<()> {
+    let snippet = install_ssh_config_in(&ssh_config_snippet_dir()?, tunnel)?;
+
+    let included = BaseDirs::new()
+        .map(|dirs| dirs.home_dir().join(".ssh/config"))
+        .and_then(|config| fs::read_to_string(config).ok())
+        .is_some_and(|config| config.contains("config.d/omnect/"));
+
+    if !included {
+        log::warn!(
+            "add \"Include config.d/omnect/*\" at the top of ~/.ssh/config to use {}",
+            snippet.to_string_lossy()
+        );
+    }
+
+    tunnel.ssh_config_snippet = Some(snippet);
 
     Ok(())
 }
 
+/// Removes the ssh config snippet of `device`, returns `None` if there is none.
+pub fn remove_ssh_config(device: &str) -> Result<Option<PathBuf>> {
+    remove_ssh_config_in(&ssh_config_snippet_dir()?, device)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn test_install_and_remove_ssh_config_snippet() {
+        let dir = tempfile::tempdir().unwrap();
+        let snippet_dir = dir.path().join("config.d/omnect");
+        let config_path = dir.path().join("config");
+        let stale = snippet_dir.join("stale-device");
+
+        fs::create_dir_all(&snippet_dir).unwrap();
+        fs::write(&config_path, "Host my-device\n").unwrap();
+        fs::write(
+            &stale,
+            "Host stale-device\n\tInclude \"/nonexistent/config\"\n",
+        )
+        .unwrap();
+
+        let tunnel = TunnelInfo {
+            cert_dir: dir.path().to_path_buf(),
+            config_path: config_path.clone(),
+            destination: "my-device".to_string(),
+            ssh_config_snippet: None,
+        };
+        let snippet = install_ssh_config_in(&snippet_dir, &tunnel).unwrap();
+
+        assert_eq!(snippet, snippet_dir.join("my-device"));
+        assert_eq!(included_config(&snippet), Some(config_path));
+        assert!(fs::read_to_string(&snippet)
+            .unwrap()
+            .contains("Host my-device\n"));
+        assert!(!stale.exists());
+
+        assert_eq!(
+            remove_ssh_config_in(&snippet_dir, "my-device").unwrap(),
+            Some(snippet.clone())
+        );
+        assert!(!snippet.exists());
+        assert_eq!(
+            remove_ssh_config_in(&snippet_dir, "my-device").unwrap(),
+            None
+        );
+        assert!(remove_ssh_config_in(&snippet_dir, "../config").is_err());
+    }
+
     fn test_query_yes_no_for_result(
         input: &str,
         expected_result: bool,
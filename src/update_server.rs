@@ -0,0 +1,410 @@
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use serde::Serialize;
+use sha2::Digest;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+const MAX_REQUEST_HEADERS: usize = 100;
+const MAX_LINE_LENGTH: u64 = 8192;
+const MAX_CONNECTIONS: usize = 16;
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// File served by the update server.
+#[derive(Clone, Debug, Serialize)]
+pub struct ServedFile {
+    pub name: String,
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(rename = "sizeInBytes")]
+    pub size_in_bytes: u64,
+    pub sha256: String,
+    #[serde(skip)]
+    content_type: &'static str,
+}
+
+impl ServedFile {
+    fn new(path: &Path, content_type: &'static str) -> Result<ServedFile> {
+        let name = path
+            .file_name()
+            .context("ServedFile: cannot get file name")?
+            .to_string_lossy()
+            .to_string();
+        let mut hasher = sha2::Sha256::new();
+        let size_in_bytes = std::io::copy(
+            &mut File::open(path).context(format!(
+                "ServedFile: cannot open {}",
+                path.to_string_lossy()
+            ))?,
+            &mut hasher,
+        )?;
+
+        Ok(ServedFile {
+            name,
+            path: path.to_path_buf(),
+            size_in_bytes,
+            sha256: base64::encode_config(hasher.finalize(), base64::STANDARD),
+            content_type,
+        })
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    range: Option<String>,
+}
+
+enum Response<'a> {
+    File(&'a ServedFile, Option<(u64, u64)>),
+    Index(Vec<u8>),
+    Error(u16, &'static str),
+}
+
+/// Checks that the import manifest describes `artifact`, otherwise the device
+/// update agent rejects the update after downloading it.
+fn check_manifest(manifest: &Path, artifact: &ServedFile) -> Result<()> {
+    let manifest: serde_json::Value = serde_json::from_reader(File::open(manifest).context(
+        format!("check_manifest: cannot open {}", manifest.to_string_lossy()),
+    )?)
+    .context("check_manifest: invalid import manifest")?;
+
+    let Some(file) = manifest["files"]
+        .as_array()
+        .and_then(|files| files.iter().find(|file| file["filename"] == artifact.name))
+    else {
+        warn!("import manifest doesn't list {}", artifact.name);
+        return Ok(());
+    };
+
+    anyhow::ensure!(
+        file["sizeInBytes"] == artifact.size_in_bytes,
+        "check_manifest: size of {} doesn't match the import manifest",
+        artifact.name
+    );
+    anyhow::ensure!(
+        file["hashes"]["sha256"] == artifact.sha256.as_str(),
+        "check_manifest: sha256 of {} doesn't match the import manifest",
+        artifact.name
+    );
+
+    Ok(())
+}
+
+/// Reads a line of at most [`MAX_LINE_LENGTH`] bytes into `line`, so that
+/// a client can't exhaust the memory by an endless line.
+fn read_line<R: BufRead>(reader: &mut R, line: &mut String) -> Result<usize> {
+    let read = reader.take(MAX_LINE_LENGTH).read_line(line)?;
+
+    anyhow::ensure!(
+        read < MAX_LINE_LENGTH as usize || line.ends_with('\n'),
+        "read_request: line exceeds {MAX_LINE_LENGTH} bytes"
+    );
+
+    Ok(read)
+}
+
+fn read_request<R: BufRead>(reader: &mut R) -> Result<Request> {
+    let mut line = String::new();
+
+    read_line(reader, &mut line)?;
+
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        anyhow::bail!("read_request: invalid request line {line:?}");
+    };
+    let mut request = Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        range: None,
+    };
+
+    for _ in 0..MAX_REQUEST_HEADERS {
+        line.clear();
+
+        if read_line(reader, &mut line)? == 0 || line.trim().is_empty() {
+            return Ok(request);
+        }
+
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("range") {
+                request.range = Some(value.trim().to_string());
+            }
+        }
+    }
+
+    anyhow::bail!("read_request: too many headers")
+}
+
+/// Parses a single "bytes=start-end" range, as used by download agents to
+/// resume interrupted downloads. Returns the inclusive range or None if the
+/// range is not satisfiable.
+fn parse_range(range: &str, size: u64) -> Option<(u64, u64)> {
+    let (start, end) = range.strip_prefix("bytes=")?.split_once('-')?;
+    let last = size.checked_sub(1)?;
+
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix = suffix.parse::<u64>().ok().filter(|suffix| *suffix > 0)?;
+            (size.saturating_sub(suffix), last)
+        }
+        (start, "") => (start.parse().ok()?, last),
+        (start, end) => (start.parse().ok()?, end.parse::<u64>().ok()?.min(last)),
+    };
+
+    (start <= end && start <= last).then_some((start, end))
+}
+
+fn route<'a>(request: &Request, files: &'a [ServedFile]) -> Result<Response<'a>> {
+    if request.method != "GET" && request.method != "HEAD" {
+        return Ok(Response::Error(405, "Method Not Allowed"));
+    }
+
+    // query parameters, e.g. of SAS like urls, are ignored
+    let path = request.path.split('?').next().unwrap_or_default();
+
+    if path == "/" {
+        return Ok(Response::Index(serde_json::to_vec_pretty(files)?));
+    }
+
+    let Some(file) = files
+        .iter()
+        .find(|file| path.strip_prefix('/') == Some(file.name.as_str()))
+    else {
+        return Ok(Response::Error(404, "Not Found"));
+    };
+
+    match &request.range {
+        Some(range) => match parse_range(range, file.size_in_bytes) {
+            Some(range) => Ok(Response::File(file, Some(range))),
+            None => Ok(Response::Error(416, "Range Not Satisfiable")),
+        },
+        None => Ok(Response::File(file, None)),
+    }
+}
+
+fn write_response<W: Write>(writer: &mut W, response: Response, head: bool) -> Result<()> {
+    match response {
+        Response::File(file, range) => {
+            let (start, end) = range.unwrap_or((0, file.size_in_bytes.saturating_sub(1)));
+            let length = if file.size_in_bytes == 0 {
+                0
+            } else {
+                end - start + 1
+            };
+
+            match range {
+                Some(_) => write!(
+                    writer,
+                    "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {start}-{end}/{}\r\n",
+                    file.size_in_bytes
+                )?,
+                None => write!(writer, "HTTP/1.1 200 OK\r\n")?,
+            }
+
+            write!(
+                writer,
+                "Content-Type: {}\r\nContent-Length: {length}\r\nAccept-Ranges: bytes\r\n\
+                ETag: \"{}\"\r\nContent-Disposition: attachment; filename=\"{}\"\r\n\
+                Connection: close\r\n\r\n",
+                file.content_type, file.sha256, file.name
+            )?;
+
+            if !head {
+                let mut content = File::open(&file.path)?;
+
+                content.seek(SeekFrom::Start(start))?;
+                std::io::copy(&mut content.take(length), writer)?;
+            }
+        }
+        Response::Index(index) => {
+            write!(
+                writer,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+                Connection: close\r\n\r\n",
+                index.len()
+            )?;
+
+            if !head {
+                writer.write_all(&index)?;
+            }
+        }
+        Response::Error(status, reason) => write!(
+            writer,
+            "HTTP/1.1 {status} {reason}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        )?,
+    }
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn handle_connection(stream: TcpStream, files: &[ServedFile]) -> Result<()> {
+    let peer = stream.peer_addr()?;
+
+    // a stalled client would otherwise occupy a connection forever
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let request = read_request(&mut reader)?;
+    let response = route(&request, files)?;
+
+    info!("{peer} {} {}", request.method, request.path);
+
+    let mut writer = std::io::BufWriter::new(stream);
+
+    write_response(&mut writer, response, request.method == "HEAD")
+}
+
+/// Prepares the files served by [`serve`]: `artifact` and optionally the
+/// import `manifest`, which is checked to match the artifact.
+pub fn served_files(artifact: &Path, manifest: Option<&Path>) -> Result<Vec<ServedFile>> {
+    let artifact = ServedFile::new(artifact, "application/octet-stream")?;
+    let mut files = vec![artifact.clone()];
+
+    if let Some(manifest) = manifest {
+        check_manifest(manifest, &artifact)?;
+        files.push(ServedFile::new(manifest, "application/json")?);
+    }
+
+    Ok(files)
+}
+
+/// Serves `files` via http on `address` until the process is terminated.
+/// `GET /` returns the list of files with their sizes and sha256 hashes.
+/// At most [`MAX_CONNECTIONS`] connections are handled in parallel, further
+/// connections are rejected with 503.
+pub fn serve(files: Vec<ServedFile>, address: SocketAddr) -> Result<()> {
+    let listener = TcpListener::bind(address).context(format!("serve: cannot bind {address}"))?;
+    let files = Arc::new(files);
+    let connections = Arc::new(AtomicUsize::new(0));
+
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                debug!("serve: cannot accept connection: {e}");
+                continue;
+            }
+        };
+
+        if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+            connections.fetch_sub(1, Ordering::SeqCst);
+            warn!("serve: all {MAX_CONNECTIONS} connections are busy");
+
+            let _ = stream.set_write_timeout(Some(TIMEOUT));
+            let _ = write_response(
+                &mut stream,
+                Response::Error(503, "Service Unavailable"),
+                false,
+            );
+            continue;
+        }
+
+        let files = files.clone();
+        let connections = connections.clone();
+
+        std::thread::spawn(move || {
+            if let Err(e) = handle_connection(stream, &files) {
+                debug!("serve: {e:#}");
+            }
+
+            connections.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_range_variants() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some((0, 99)));
+        assert_eq!(parse_range("bytes=900-", 1000), Some((900, 999)));
+        assert_eq!(parse_range("bytes=-100", 1000), Some((900, 999)));
+        assert_eq!(parse_range("bytes=900-2000", 1000), Some((900, 999)));
+        assert_eq!(parse_range("bytes=1000-", 1000), None);
+        assert_eq!(parse_range("bytes=99-0", 1000), None);
+        assert_eq!(parse_range("items=0-99", 1000), None);
+        assert_eq!(parse_range("bytes=0-0", 0), None);
+    }
+
+    #[test]
+    fn serve_file_range() {
+        let dir = tempfile::tempdir().unwrap();
+        let artifact = dir.path().join("update.swu");
+
+        std::fs::write(&artifact, b"0123456789").unwrap();
+
+        let files = served_files(&artifact, None).unwrap();
+        let request = read_request(
+            &mut &b"GET /update.swu HTTP/1.1\r\nHost: device\r\nRange: bytes=2-5\r\n\r\n"[..],
+        )
+        .unwrap();
+        let mut response = vec![];
+
+        write_response(&mut response, route(&request, &files).unwrap(), false).unwrap();
+
+        let response = String::from_utf8(response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        assert!(response.contains("Content-Range: bytes 2-5/10\r\n"));
+        assert!(response.contains("Content-Length: 4\r\n"));
+        assert!(response.ends_with("\r\n\r\n2345"));
+
+        let request = read_request(&mut &b"GET /other.swu HTTP/1.1\r\n\r\n"[..]).unwrap();
+
+        assert!(matches!(
+            route(&request, &files).unwrap(),
+            Response::Error(404, _)
+        ));
+    }
+
+    #[test]
+    fn read_request_limits_line_length() {
+        let mut request = b"GET /update.swu HTTP/1.1\r\nX-Long: ".to_vec();
+
+        request.extend(vec![b'a'; MAX_LINE_LENGTH as usize]);
+        request.extend(b"\r\n\r\n");
+
+        let e = read_request(&mut &request[..]).unwrap_err();
+
+        assert!(e.to_string().contains("exceeds"));
+    }
+
+    #[test]
+    fn manifest_has_to_match_artifact() {
+        let dir = tempfile::tempdir().unwrap();
+        let artifact = dir.path().join("update.swu");
+        let manifest = dir.path().join("update.importmanifest.json");
+
+        std::fs::write(&artifact, b"0123456789").unwrap();
+
+        let sha256 = ServedFile::new(&artifact, "application/octet-stream")
+            .unwrap()
+            .sha256;
+
+        std::fs::write(
+            &manifest,
+            serde_json::json!({"files": [{"filename": "update.swu", "sizeInBytes": 10, "hashes": {"sha256": sha256}}]})
+                .to_string(),
+        )
+        .unwrap();
+
+        assert_eq!(served_files(&artifact, Some(&manifest)).unwrap().len(), 2);
+
+        std::fs::write(&artifact, b"9876543210").unwrap();
+
+        assert!(served_files(&artifact, Some(&manifest)).is_err());
+    }
+}
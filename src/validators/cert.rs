@@ -0,0 +1,157 @@
+use anyhow::{Context, Result};
+use openssl::pkey::{PKey, Private};
+use openssl::stack::Stack;
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::{X509StoreContext, X509};
+use std::path::Path;
+
+pub(crate) fn read_certs(file: &Path) -> Result<Vec<X509>> {
+    let pem = std::fs::read(file).context(format!("cannot read {}", file.to_string_lossy()))?;
+    let certs = X509::stack_from_pem(&pem).map_err(|e| {
+        anyhow::anyhow!(
+            "{} is no valid pem certificate: {e}",
+            file.to_string_lossy()
+        )
+    })?;
+
+    anyhow::ensure!(
+        !certs.is_empty(),
+        "{} contains no certificate",
+        file.to_string_lossy()
+    );
+
+    Ok(certs)
+}
+
+pub(crate) fn read_key(file: &Path) -> Result<PKey<Private>> {
+    let pem = std::fs::read(file).context(format!("cannot read {}", file.to_string_lossy()))?;
+
+    PKey::private_key_from_pem(&pem).map_err(|e| {
+        anyhow::anyhow!(
+            "{} is no valid pem private key: {e}",
+            file.to_string_lossy()
+        )
+    })
+}
+
+/// Checks that every certificate of a chain is issued by its successor.
+fn check_chain_order(file: &Path, certs: &[X509]) -> Result<()> {
+    for (i, pair) in certs.windows(2).enumerate() {
+        anyhow::ensure!(
+            pair[0].verify(&pair[1].public_key()?)?,
+            "certificate {} of {} is not issued by the following certificate {}",
+            i + 1,
+            file.to_string_lossy(),
+            i + 2
+        );
+    }
+
+    Ok(())
+}
+
+/// Checks that `file` contains pem certificates, e.g. a root ca.
+pub fn validate_certificates(file: &Path) -> Result<()> {
+    read_certs(file).map(|_| ())
+}
+
+/// Checks that the private key of `key_file` belongs to the (first)
+/// certificate of `cert_file` and that a chain in `cert_file` is ordered
+/// from leaf to root.
+pub fn validate_key_pair(cert_file: &Path, key_file: &Path) -> Result<()> {
+    let certs = read_certs(cert_file)?;
+    let key = read_key(key_file)?;
+
+    anyhow::ensure!(
+        certs[0].public_key()?.public_eq(&key),
+        "{} doesn't match the certificate of {}",
+        key_file.to_string_lossy(),
+        cert_file.to_string_lossy()
+    );
+
+    check_chain_order(cert_file, &certs)
+}
+
+/// Checks that the certificate chain `chain_file` verifies against the
+/// certificates of `root_ca_file`.
+pub fn validate_chain(chain_file: &Path, root_ca_file: &Path) -> Result<()> {
+    let chain = read_certs(chain_file)?;
+    let roots = read_certs(root_ca_file)?;
+
+    check_chain_order(chain_file, &chain)?;
+
+    let mut store = X509StoreBuilder::new()?;
+
+    for root in roots {
+        store.add_cert(root)?;
+    }
+
+    let store = store.build();
+    let mut untrusted = Stack::new()?;
+
+    for cert in chain.iter().skip(1) {
+        untrusted.push(cert.clone())?;
+    }
+
+    let mut context = X509StoreContext::new()?;
+    let (valid, error) = context.init(&store, &chain[0], &untrusted, |context| {
+        Ok((context.verify_cert()?, context.error()))
+    })?;
+
+    anyhow::ensure!(
+        valid,
+        "{} doesn't verify against {}: {}",
+        chain_file.to_string_lossy(),
+        root_ca_file.to_string_lossy(),
+        error.error_string()
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_pair() {
+        assert!(validate_key_pair(
+            Path::new("testfiles/test-int-ca_fullchain.pem"),
+            Path::new("testfiles/test-int-ca.key")
+        )
+        .is_ok());
+
+        let err = validate_key_pair(
+            Path::new("testfiles/test-int-ca_fullchain.pem"),
+            Path::new("testfiles/test-ca.key"),
+        )
+        .unwrap_err();
+
+        assert!(err
+            .to_string()
+            .contains("testfiles/test-ca.key doesn't match"));
+
+        let err = validate_key_pair(
+            Path::new("testfiles/root.ca.cert.pem"),
+            Path::new("testfiles/test-ca.key"),
+        )
+        .unwrap_err();
+
+        assert!(err.to_string().contains("testfiles/root.ca.cert.pem"));
+    }
+
+    #[test]
+    fn chain() {
+        assert!(validate_chain(
+            Path::new("testfiles/test-int-ca_fullchain.pem"),
+            Path::new("testfiles/test-ca.pem")
+        )
+        .is_ok());
+        assert!(validate_chain(
+            Path::new("testfiles/test-int-ca_fullchain.pem"),
+            Path::new("testfiles/rootCA.crt")
+        )
+        .is_err());
+        assert!(validate_certificates(Path::new("testfiles/rootCA.crt")).is_ok());
+        assert!(validate_certificates(Path::new("testfiles/device-ca.key.pem")).is_err());
+    }
+}
@@ -0,0 +1,306 @@
+use crate::config::{AuthProvider, BackendConfig, DeviceUpdateInstance, ProxyConfig, UserConfig};
+use crate::file::compression::Compression;
+use anyhow::{Context, Result};
+use log::warn;
+use serde::de::DeserializeOwned;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Collects errors of a configuration file, prefixed with the line of the
+/// offending key where it can be located.
+struct Report<'a> {
+    content: &'a str,
+    errors: Vec<String>,
+}
+
+impl Report<'_> {
+    fn locate(&self, table: &str, key: &str, message: impl std::fmt::Display) -> String {
+        let name = if table.is_empty() {
+            key.to_string()
+        } else {
+            format!("{table}.{key}")
+        };
+
+        match line_of(self.content, table, key) {
+            Some(line) => format!("line {line}: {name}: {message}"),
+            None => format!("{name}: {message}"),
+        }
+    }
+
+    fn push(&mut self, table: &str, key: &str, message: impl std::fmt::Display) {
+        let error = self.locate(table, key, message);
+
+        self.errors.push(error);
+    }
+
+    fn warn(&self, table: &str, key: &str, message: impl std::fmt::Display) {
+        warn!("{}", self.locate(table, key, message));
+    }
+
+    fn check_url(&mut self, table: &str, key: &str, url: &url::Url) {
+        if !["http", "https"].contains(&url.scheme()) || url.host().is_none() {
+            self.push(table, key, format!("{url} is not a http(s) url"));
+        }
+    }
+
+    fn check_backend_config(&mut self, prefix: &str, config: &BackendConfig) {
+        self.check_url(prefix.trim_end_matches('.'), "backend", &config.backend);
+        self.check_auth(&format!("{prefix}auth.Keycloak"), &config.auth);
+
+        if let Some(device_update) = &config.device_update {
+            self.check_device_update(&format!("{prefix}device_update"), device_update);
+        }
+
+        if let Some(proxy) = &config.proxy {
+            self.check_proxy(&format!("{prefix}proxy"), proxy);
+        }
+    }
+
+    fn check_auth(&mut self, table: &str, auth: &AuthProvider) {
+        let AuthProvider::Keycloak(keycloak) = auth;
+
+        match url::Url::parse(&keycloak.provider) {
+            Ok(provider) => self.check_url(table, "provider", &provider),
+            Err(e) => self.push(table, "provider", format!("invalid url: {e}")),
+        }
+
+        if keycloak.realm.is_empty() {
+            self.push(table, "realm", "must not be empty");
+        }
+
+        if keycloak.client_id.is_empty() {
+            self.push(table, "client_id", "must not be empty");
+        }
+
+        if keycloak.bind_addrs.is_empty() {
+            self.push(table, "bind_addrs", "must contain at least one address");
+        }
+
+        for addr in &keycloak.bind_addrs {
+            if SocketAddr::from_str(addr).is_err() {
+                self.push(
+                    table,
+                    "bind_addrs",
+                    format!("{addr} is not a socket address"),
+                );
+            }
+        }
+
+        self.check_url(table, "redirect", &keycloak.redirect);
+    }
+
+    fn check_device_update(&mut self, table: &str, device_update: &DeviceUpdateInstance) {
+        if let Some(endpoint) = &device_update.endpoint {
+            self.check_url(table, "endpoint", endpoint);
+        }
+
+        // the missing setting may be passed by "--instance-id", respectively
+        // "--device-update-endpoint"
+        match (&device_update.instance_id, &device_update.endpoint) {
+            (Some(_), None) => self.warn(table, "instance_id", "has no endpoint configured"),
+            (None, Some(_)) => self.warn(table, "endpoint", "has no instance_id configured"),
+            _ => {}
+        }
+    }
+
+    fn check_proxy(&mut self, table: &str, proxy: &ProxyConfig) {
+        self.check_url(table, "url", &proxy.url);
+
+        if proxy.password.is_some() && proxy.username.is_none() {
+            self.push(table, "password", "requires username");
+        }
+    }
+
+    fn finish(self, path: &Path) -> Result<()> {
+        anyhow::ensure!(
+            self.errors.is_empty(),
+            "configuration {} is invalid:\n{}",
+            path.to_string_lossy(),
+            self.errors.join("\n")
+        );
+
+        Ok(())
+    }
+}
+
+/// Returns the 1-based line number of `key` in table `table`, which is the
+/// top-level table if empty.
+fn line_of(content: &str, table: &str, key: &str) -> Option<usize> {
+    let mut current_table = "";
+
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+
+        if let Some(header) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            current_table = header.trim();
+            continue;
+        }
+
+        if current_table == table
+            && line
+                .strip_prefix(key)
+                .is_some_and(|rest| rest.trim_start().starts_with('='))
+        {
+            return Some(i + 1);
+        }
+    }
+
+    None
+}
+
+fn parse<T: DeserializeOwned>(path: &Path, content: &str) -> Result<T> {
+    // toml errors already contain line and column of the offending value
+    toml::from_str(content).context(format!(
+        "configuration {} is invalid",
+        path.to_string_lossy()
+    ))
+}
+
+pub fn validate_user_config(path: &Path) -> Result<()> {
+    let content = std::fs::read_to_string(path)
+        .context(format!("validate_user_config: cannot read {path:?}"))?;
+    let config: UserConfig = parse(path, &content)?;
+    let mut report = Report {
+        content: &content,
+        errors: vec![],
+    };
+
+    if let Some(backend) = &config.backend {
+        report.check_url("", "backend", backend);
+    }
+
+    if let Some(auth) = &config.auth {
+        report.check_auth("auth.Keycloak", auth);
+    }
+
+    if let Some(device_update) = &config.device_update {
+        report.check_device_update("device_update", device_update);
+    }
+
+    if let Some(compression) = &config.compression {
+        if let Err(e) = Compression::from_str(compression) {
+            report.push("", "compression", e);
+        }
+    }
+
+    if let Some(workdir) = &config.workdir {
+        if !workdir.is_dir() {
+            report.push("", "workdir", format!("{workdir:?} is not a directory"));
+        }
+    }
+
+    if let Some(public_key) = &config.verify_signature {
+        if !public_key.is_file() {
+            report.push(
+                "",
+                "verify_signature",
+                format!("{public_key:?} is not a file"),
+            );
+        }
+    }
+
+    if let Some(proxy) = &config.proxy {
+        report.check_proxy("proxy", proxy);
+    }
+
+    for hook in &config.hooks {
+        if hook.command.is_absolute() && !hook.command.is_file() {
+            report.push(
+                "hooks",
+                "command",
+                format!("{}: {:?} is not a file", hook.name, hook.command),
+            );
+        }
+    }
+
+    for (name, environment) in &config.environments {
+        report.check_backend_config(&format!("environments.{name}."), environment);
+    }
+
+    report.finish(path)
+}
+
+pub fn validate_backend_config(path: &Path) -> Result<()> {
+    let content = crate::file::secret::read_to_string(path)
+        .context(format!("validate_backend_config: cannot read {path:?}"))?;
+    let config: BackendConfig = parse(path, &content)?;
+    let mut report = Report {
+        content: &content,
+        errors: vec![],
+    };
+
+    report.check_backend_config("", &config);
+    report.finish(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate_user_config_str(content: &str) -> Result<()> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+
+        std::fs::write(&path, content).unwrap();
+        validate_user_config(&path)
+    }
+
+    #[test]
+    fn user_config_valid() {
+        assert!(validate_user_config_str(
+            r#"
+backend = 'https://cp.omnect.conplement.cloud'
+compression = 'xz'
+workdir = '/tmp'
+
+[device_update]
+instance_id = 'my-instance'
+endpoint = 'https://my-adu.api.adu.microsoft.com'
+"#
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn user_config_errors_with_line_numbers() {
+        let err = validate_user_config_str(
+            r#"
+backend = 'ftp://cp.omnect.conplement.cloud'
+compression = 'zip'
+workdir = '/nonexistent'
+
+[device_update]
+instance_id = 'my-instance'
+
+[proxy]
+url = 'http://proxy.example.com:3128'
+password = 'secret'
+"#,
+        )
+        .unwrap_err()
+        .to_string();
+
+        assert!(
+            err.contains("line 2: backend: ftp://cp.omnect.conplement.cloud/ is not a http(s) url")
+        );
+        assert!(err.contains("line 3: compression: unknown compression"));
+        assert!(err.contains("line 4: workdir: \"/nonexistent\" is not a directory"));
+        // a partial device update instance may be completed on the command line
+        assert!(!err.contains("device_update.instance_id"));
+        assert!(err.contains("line 11: proxy.password: requires username"));
+    }
+
+    #[test]
+    fn user_config_syntax_error() {
+        let err = format!(
+            "{:#}",
+            validate_user_config_str(
+                "backend = 'https://cp.omnect.conplement.cloud'\nunknown = 1\n"
+            )
+            .unwrap_err()
+        );
+
+        assert!(err.contains("line 2"));
+    }
+}
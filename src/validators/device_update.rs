@@ -1,8 +1,12 @@
 use anyhow::{Context, Result};
+use sha2::Digest;
+use std::collections::HashSet;
 use std::fs::File;
 use std::io::BufReader;
 use std::path::Path;
 
+const SUPPORTED_MANIFEST_VERSIONS: [&str; 2] = ["4.0", "5.0"];
+
 pub fn validate_config(device_update_conf_file: &Path) -> Result<()> {
     let file = File::open(device_update_conf_file).context(format!(
         "validate_du_config: failed to open {device_update_conf_file:?}"
@@ -14,3 +18,167 @@ pub fn validate_config(device_update_conf_file: &Path) -> Result<()> {
 
     Ok(())
 }
+
+pub fn validate_import_manifest(import_manifest_file: &Path) -> Result<()> {
+    let file = File::open(import_manifest_file).context(format!(
+        "validate_import_manifest: failed to open {import_manifest_file:?}"
+    ))?;
+    let manifest: serde_json::Value = serde_json::from_reader(BufReader::new(file))
+        .context("validate_import_manifest: read import manifest")?;
+    let manifest_dir = import_manifest_file
+        .parent()
+        .context("validate_import_manifest: cannot get directory of import manifest")?;
+    let mut errors = Vec::<String>::new();
+
+    match manifest["manifestVersion"].as_str() {
+        Some(version) if SUPPORTED_MANIFEST_VERSIONS.contains(&version) => {}
+        Some(version) => errors.push(format!(
+            "manifestVersion \"{version}\" is not supported, use one of {SUPPORTED_MANIFEST_VERSIONS:?}"
+        )),
+        None => errors.push("manifestVersion is missing".to_string()),
+    }
+
+    for key in ["provider", "name", "version"] {
+        if manifest["updateId"][key]
+            .as_str()
+            .map_or(true, |value| value.is_empty())
+        {
+            errors.push(format!("updateId.{key} is missing or empty"));
+        }
+    }
+
+    if !manifest["compatibility"]
+        .as_array()
+        .is_some_and(|compatibility| !compatibility.is_empty())
+    {
+        errors.push("compatibility must contain at least one set of device properties".to_string());
+    }
+
+    let mut filenames = HashSet::new();
+
+    for (i, file) in manifest["files"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .enumerate()
+    {
+        let Some(filename) = file["filename"].as_str() else {
+            errors.push(format!("files[{i}].filename is missing"));
+            continue;
+        };
+
+        filenames.insert(filename);
+
+        let path = manifest_dir.join(filename);
+
+        // update images may be several GB, so hash while reading
+        let mut hasher = sha2::Sha256::new();
+        let Ok(len) = File::open(&path).and_then(|mut f| std::io::copy(&mut f, &mut hasher)) else {
+            errors.push(format!(
+                "{filename} is referenced by import manifest but cannot be read from {path:?}"
+            ));
+            continue;
+        };
+
+        match file["sizeInBytes"].as_u64() {
+            Some(size) if size == len => {}
+            Some(size) => errors.push(format!(
+                "files[{i}].sizeInBytes of {filename} is {size} but file has {len} bytes, recreate the import manifest"
+            )),
+            None => errors.push(format!("files[{i}].sizeInBytes of {filename} is missing")),
+        }
+
+        let sha256 = base64::encode_config(hasher.finalize(), base64::STANDARD);
+
+        match file["hashes"]["sha256"].as_str() {
+            Some(hash) if hash == sha256 => {}
+            Some(hash) => errors.push(format!(
+                "files[{i}].hashes.sha256 of {filename} is {hash} but file hash is {sha256}, recreate the import manifest"
+            )),
+            None => errors.push(format!("files[{i}].hashes.sha256 of {filename} is missing")),
+        }
+    }
+
+    match manifest["instructions"]["steps"].as_array() {
+        Some(steps) if !steps.is_empty() => {
+            for (i, step) in steps.iter().enumerate() {
+                for filename in step["files"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|f| f.as_str())
+                {
+                    if !filenames.contains(filename) {
+                        errors.push(format!(
+                            "instructions.steps[{i}] references {filename} which is not listed in files"
+                        ));
+                    }
+                }
+            }
+        }
+        _ => errors.push("instructions.steps must contain at least one step".to_string()),
+    }
+
+    anyhow::ensure!(
+        errors.is_empty(),
+        "import manifest {} is invalid:\n{}",
+        import_manifest_file.to_string_lossy(),
+        errors.join("\n")
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn import_manifest_valid() {
+        assert!(validate_import_manifest(Path::new(
+            "testfiles/image.swu.importManifest.json.orig"
+        ))
+        .is_ok());
+    }
+
+    #[test]
+    fn import_manifest_missing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest_path = dir.path().join("image.swu.importManifest.json");
+
+        std::fs::copy(
+            "testfiles/image.swu.importManifest.json.orig",
+            &manifest_path,
+        )
+        .unwrap();
+
+        let err = validate_import_manifest(&manifest_path)
+            .unwrap_err()
+            .to_string();
+
+        assert!(err.contains("image.swu is referenced by import manifest but cannot be read"));
+        assert!(err.contains("image.swu.sh is referenced by import manifest but cannot be read"));
+    }
+
+    #[test]
+    fn import_manifest_hash_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest_path = dir.path().join("image.swu.importManifest.json");
+
+        std::fs::copy(
+            "testfiles/image.swu.importManifest.json.orig",
+            &manifest_path,
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("image.swu"), "modified").unwrap();
+        std::fs::copy("testfiles/image.swu.sh", dir.path().join("image.swu.sh")).unwrap();
+
+        let err = validate_import_manifest(&manifest_path)
+            .unwrap_err()
+            .to_string();
+
+        assert!(err.contains("files[0].sizeInBytes of image.swu is 0 but file has 8 bytes"));
+        assert!(err.contains("files[0].hashes.sha256 of image.swu"));
+        assert!(!err.contains("image.swu.sh"));
+    }
+}
@@ -1,3 +1,4 @@
+use crate::iot_hub::ConnectionString;
 use anyhow::{anyhow, Context, Result};
 use log::debug;
 use regex::Regex;
@@ -197,29 +198,104 @@ const WARN_UNEXPECTED_PATH: &str = "Unexpected path found.";
 const WARN_UNEQUAL_COMMON_NAME_AND_REGISTRATION_ID: &str =
     "provisioning.attestation.registration_id is not equal to provisioning.attestation.identity_cert.common_name";
 const WARN_PAYLOAD_FILEPATH_MISSING: &str = "Payload file is configred but file is missing.";
+pub fn validate_hostname(hostname: &str) -> Result<()> {
+    anyhow::ensure!(
+        hostname.len() <= 253
+            && hostname.split('.').all(|label| label.len() <= 63)
+            && RE_HOSTNAME.is_match(hostname),
+        "hostname {hostname:?} is not compliant with rfc1035"
+    );
+
+    Ok(())
+}
+
 const WARN_PAYLOAD_CONFIG_MISSING: &str = "Payload file is passed but not configred.";
 
-pub fn validate_identity(
-    _id_type: IdentityType,
-    config_file_name: &Path,
-    payload: &Option<&Path>,
-) -> Result<Vec<&'static str>> {
-    let mut out = Vec::<&'static str>::new();
+fn parse_identity(config_file_name: &Path) -> Result<IdentityConfig> {
     let file_content = std::fs::read_to_string(config_file_name)
         .context("validate_identity: cannot read identity file")?;
     debug!("validate identity for:\n{}", file_content);
     let des = toml::Deserializer::new(&file_content);
     let body: Result<IdentityConfig, _> = serde_path_to_error::deserialize(des);
-    let body = match body {
-        Err(e) => {
-            return Err(anyhow!(
-                "{} parsing failed with error {}",
-                config_file_name.to_string_lossy(),
-                e
-            ));
-        }
-        Ok(body) => body,
+
+    body.map_err(|e| {
+        anyhow!(
+            "{} parsing failed with error {}",
+            config_file_name.to_string_lossy(),
+            e
+        )
+    })
+}
+
+/// Device a config.toml provisions.
+#[derive(Debug, PartialEq)]
+pub enum ProvisionedDevice {
+    /// manual provisioning, the shared access key is known for connection strings only
+    Manual {
+        iothub_hostname: String,
+        device_id: String,
+        shared_access_key: Option<String>,
+    },
+    /// provisioning by DPS
+    Dps { registration_id: String },
+}
+
+pub fn provisioned_device(config_file_name: &Path) -> Result<ProvisionedDevice> {
+    let unknown = |what: &str| {
+        anyhow!(
+            "cannot determine the device of {}: {what}",
+            config_file_name.to_string_lossy()
+        )
     };
+    let provisioning = parse_identity(config_file_name)?
+        .provisioning
+        .ok_or_else(|| unknown("no provisioning section"))?;
+
+    match provisioning.source.as_str() {
+        "manual" => {
+            if let Some(connection_string) = provisioning.connection_string {
+                let connection_string: ConnectionString = connection_string.parse()?;
+
+                return Ok(ProvisionedDevice::Manual {
+                    iothub_hostname: connection_string.hostname,
+                    device_id: connection_string.device_id,
+                    shared_access_key: connection_string.shared_access_key,
+                });
+            }
+
+            match (provisioning.iothub_hostname, provisioning.device_id) {
+                (Some(iothub_hostname), Some(device_id)) => Ok(ProvisionedDevice::Manual {
+                    iothub_hostname,
+                    device_id,
+                    shared_access_key: None,
+                }),
+                _ => Err(unknown(WARN_MISSING_MANUAL_PARAMS)),
+            }
+        }
+        "dps" => {
+            let registration_id = match provisioning.attestation {
+                Some(Attestation::Est(a)) => a
+                    .registration_id
+                    .or(a.identity_cert.map(|ic| ic.common_name)),
+                Some(Attestation::NoEst(a)) => a.registration_id,
+                None => None,
+            };
+
+            registration_id
+                .map(|registration_id| ProvisionedDevice::Dps { registration_id })
+                .ok_or_else(|| unknown("provisioning.attestation.registration_id is missing"))
+        }
+        _ => Err(unknown(WARN_INVALID_SOURCE)),
+    }
+}
+
+pub fn validate_identity(
+    _id_type: IdentityType,
+    config_file_name: &Path,
+    payload: &Option<&Path>,
+) -> Result<Vec<&'static str>> {
+    let mut out = Vec::<&'static str>::new();
+    let body = parse_identity(config_file_name)?;
     body.validate()?;
     match body.provisioning {
         None => {
@@ -349,6 +425,15 @@ mod tests {
         );
     }
 
+    #[test]
+    fn hostname_validation() {
+        assert!(validate_hostname("omnect-device.local").is_ok());
+        assert!(validate_hostname("").is_err());
+        assert!(validate_hostname("1device").is_err());
+        assert!(validate_hostname("device-").is_err());
+        assert!(validate_hostname(&"a".repeat(64)).is_err());
+    }
+
     #[test]
     fn identity_config_hostname_invalid() {
         lazy_static::initialize(&LOG);
@@ -509,6 +594,20 @@ mod tests {
         assert_eq!(0, result.len());
     }
 
+    #[test]
+    fn identity_config_provisioned_device() {
+        lazy_static::initialize(&LOG);
+
+        assert_eq!(
+            provisioned_device(Path::new("testfiles/identity_config_dps_x509_est.toml")).unwrap(),
+            ProvisionedDevice::Dps {
+                registration_id: "test-omnect-est".to_string()
+            }
+        );
+        assert!(provisioned_device(Path::new("testfiles/identity_config_dps_tpm.toml")).is_err());
+        assert!(provisioned_device(Path::new("testfiles/identity_config_manual.toml")).is_err());
+    }
+
     #[test]
     fn identity_config_dps_payload() {
         lazy_static::initialize(&LOG);
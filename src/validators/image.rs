@@ -0,0 +1,507 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+const SECTOR_SIZE: u64 = 512;
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_PARTITION_TYPE_GPT: u8 = 0xee;
+const MBR_PARTITION_TYPES_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+// bounds the number of logical partitions and gpt entries of malformed images
+const MAX_PARTITIONS: usize = 128;
+const EXT_MAGIC_OFFSET: u64 = 1080;
+const EXT_MAGIC: [u8; 2] = [0x53, 0xef];
+const EXT_SUPERBLOCK_OFFSET: u64 = 1024;
+const EXT_INCOMPAT_64BIT: u64 = 0x80;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PartitionTable {
+    Dos,
+    Gpt,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Filesystem {
+    Vfat,
+    Ext,
+}
+
+/// Partition of the image by its number as listed by fdisk, e.g. 1 for
+/// `/dev/sda1`.
+#[derive(Debug)]
+struct PartitionEntry {
+    num: usize,
+    start: u64,
+}
+
+/// Partition of the omnect layout and the filesystem it has to contain, if
+/// any.
+struct ExpectedPartition {
+    name: &'static str,
+    num: usize,
+    filesystem: Option<Filesystem>,
+}
+
+fn expected_layout(table: PartitionTable) -> Vec<ExpectedPartition> {
+    // in the dos layout partition 4 is the extended partition
+    let (factory, cert) = match table {
+        PartitionTable::Gpt => (4, 5),
+        PartitionTable::Dos => (5, 6),
+    };
+
+    vec![
+        ExpectedPartition {
+            name: "boot",
+            num: 1,
+            filesystem: Some(Filesystem::Vfat),
+        },
+        ExpectedPartition {
+            name: "rootA",
+            num: 2,
+            filesystem: Some(Filesystem::Ext),
+        },
+        // rootB may be empty until the first update
+        ExpectedPartition {
+            name: "rootB",
+            num: 3,
+            filesystem: None,
+        },
+        ExpectedPartition {
+            name: "factory",
+            num: factory,
+            filesystem: Some(Filesystem::Ext),
+        },
+        ExpectedPartition {
+            name: "cert",
+            num: cert,
+            filesystem: Some(Filesystem::Ext),
+        },
+    ]
+}
+
+fn read_at<const N: usize>(file: &mut File, offset: u64) -> Result<[u8; N]> {
+    let mut buf = [0u8; N];
+
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut buf)?;
+
+    Ok(buf)
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u64 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap()) as u64
+}
+
+fn u64_at(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+/// Primary partitions of a mbr as (type, start sector).
+fn mbr_entries(mbr: &[u8; 512]) -> Vec<(u8, u64)> {
+    (0..4)
+        .map(|i| {
+            let entry = &mbr[446 + i * 16..462 + i * 16];
+
+            (entry[4], u32_at(entry, 8))
+        })
+        .collect()
+}
+
+fn dos_partitions(file: &mut File, mbr: &[u8; 512]) -> Result<Vec<PartitionEntry>> {
+    let mut partitions = vec![];
+
+    for (i, (partition_type, start)) in mbr_entries(mbr).into_iter().enumerate() {
+        if partition_type == 0 {
+            continue;
+        }
+
+        partitions.push(PartitionEntry { num: i + 1, start });
+
+        if !MBR_PARTITION_TYPES_EXTENDED.contains(&partition_type) {
+            continue;
+        }
+
+        // logical partitions are chained by extended boot records, whose
+        // second entry points to the next record relative to the extended
+        // partition
+        let mut ebr_start = start;
+
+        for num in 5..5 + MAX_PARTITIONS {
+            let ebr = read_at::<512>(file, ebr_start * SECTOR_SIZE)
+                .context("cannot read extended boot record")?;
+
+            anyhow::ensure!(ebr[510..] == MBR_SIGNATURE, "invalid extended boot record");
+
+            let entries = mbr_entries(&ebr);
+
+            if entries[0].0 != 0 {
+                partitions.push(PartitionEntry {
+                    num,
+                    start: ebr_start + entries[0].1,
+                });
+            }
+
+            if entries[1].0 == 0 {
+                break;
+            }
+
+            ebr_start = start + entries[1].1;
+        }
+    }
+
+    Ok(partitions)
+}
+
+fn gpt_partitions(file: &mut File) -> Result<Vec<PartitionEntry>> {
+    let header = read_at::<92>(file, SECTOR_SIZE).context("cannot read gpt header")?;
+
+    anyhow::ensure!(&header[..8] == GPT_SIGNATURE, "invalid gpt header");
+
+    let entries_start = u64_at(&header, 72);
+    let entries = (u32_at(&header, 80) as usize).min(MAX_PARTITIONS);
+    let entry_size = u32_at(&header, 84);
+
+    anyhow::ensure!(entry_size >= 128, "invalid gpt entry size {entry_size}");
+
+    let mut partitions = vec![];
+
+    for i in 0..entries {
+        let entry = read_at::<48>(file, entries_start * SECTOR_SIZE + i as u64 * entry_size)
+            .context("cannot read gpt entry")?;
+
+        // unused entries have an all zero type guid
+        if entry[..16].iter().any(|b| *b != 0) {
+            partitions.push(PartitionEntry {
+                num: i + 1,
+                start: u64_at(&entry, 32),
+            });
+        }
+    }
+
+    Ok(partitions)
+}
+
+/// Detects the filesystem of the partition starting at sector `start`.
+pub(crate) fn filesystem(file: &mut File, start: u64) -> Result<Option<Filesystem>> {
+    let offset = start * SECTOR_SIZE;
+
+    if read_at::<2>(file, offset + EXT_MAGIC_OFFSET)? == EXT_MAGIC {
+        return Ok(Some(Filesystem::Ext));
+    }
+
+    let boot_sector = read_at::<512>(file, offset)?;
+
+    // FAT12/16 and FAT32 have their type at different offsets
+    if boot_sector[510..] == MBR_SIGNATURE
+        && (&boot_sector[54..57] == b"FAT" || &boot_sector[82..85] == b"FAT")
+    {
+        return Ok(Some(Filesystem::Vfat));
+    }
+
+    Ok(None)
+}
+
+/// Block size and block count of the ext filesystem of the partition starting
+/// at sector `start`.
+pub(crate) fn ext_size(file: &mut File, start: u64) -> Result<(u64, u64)> {
+    let superblock = read_at::<1024>(file, start * SECTOR_SIZE + EXT_SUPERBLOCK_OFFSET)
+        .context("ext_size: cannot read superblock")?;
+
+    anyhow::ensure!(
+        superblock[56..58] == EXT_MAGIC,
+        "ext_size: no ext filesystem"
+    );
+
+    let block_size = 1024 << u32_at(&superblock, 24);
+    let mut blocks = u32_at(&superblock, 4);
+
+    if u32_at(&superblock, 96) & EXT_INCOMPAT_64BIT != 0 {
+        blocks |= u32_at(&superblock, 0x150) << 32;
+    }
+
+    Ok((block_size, blocks))
+}
+
+/// UUID and volume label of the ext filesystem of the partition starting at
+/// sector `start`.
+pub(crate) fn ext_identity(file: &mut File, start: u64) -> Result<(uuid::Uuid, String)> {
+    let superblock = read_at::<1024>(file, start * SECTOR_SIZE + EXT_SUPERBLOCK_OFFSET)
+        .context("ext_identity: cannot read superblock")?;
+
+    anyhow::ensure!(
+        superblock[56..58] == EXT_MAGIC,
+        "ext_identity: no ext filesystem"
+    );
+
+    let uuid = uuid::Uuid::from_slice(&superblock[0x68..0x78])?;
+    let label = String::from_utf8_lossy(&superblock[0x78..0x88])
+        .trim_end_matches('\0')
+        .to_string();
+
+    Ok((uuid, label))
+}
+
+/// Volume id of the vfat filesystem of the partition starting at sector
+/// `start`, as listed by blkid, e.g. `1234-ABCD`.
+pub(crate) fn vfat_volume_id(file: &mut File, start: u64) -> Result<String> {
+    let boot_sector = read_at::<512>(file, start * SECTOR_SIZE)
+        .context("vfat_volume_id: cannot read boot sector")?;
+
+    // FAT32 has no 16 bit sectors per fat and a longer bios parameter block
+    let offset = if boot_sector[22..24] == [0, 0] {
+        67
+    } else {
+        39
+    };
+    let id = u32::from_le_bytes(boot_sector[offset..offset + 4].try_into()?);
+
+    Ok(format!("{:04X}-{:04X}", id >> 16, id & 0xffff))
+}
+
+/// Checks that `image_file` has the partitions and filesystems of the omnect
+/// os, so that commands fail early with a clear error for other images.
+pub fn validate_partition_layout(image_file: &Path) -> Result<()> {
+    let not_omnect = |reason: String| {
+        anyhow::anyhow!(
+            "{} doesn't look like an omnect image ({reason})",
+            image_file.to_string_lossy()
+        )
+    };
+    let mut file = File::open(image_file).context(format!(
+        "validate_partition_layout: cannot open {}",
+        image_file.to_string_lossy()
+    ))?;
+    let mbr =
+        read_at::<512>(&mut file, 0).map_err(|_| not_omnect("image is too small".to_string()))?;
+
+    if mbr[510..] != MBR_SIGNATURE {
+        return Err(not_omnect("no partition table".to_string()));
+    }
+
+    let (table, partitions) = if mbr_entries(&mbr)
+        .iter()
+        .any(|(partition_type, _)| *partition_type == MBR_PARTITION_TYPE_GPT)
+    {
+        (PartitionTable::Gpt, gpt_partitions(&mut file))
+    } else {
+        (PartitionTable::Dos, dos_partitions(&mut file, &mbr))
+    };
+    let partitions = partitions.map_err(|e| not_omnect(format!("{e:#}")))?;
+
+    for expected in expected_layout(table) {
+        let Some(partition) = partitions.iter().find(|p| p.num == expected.num) else {
+            return Err(not_omnect(format!(
+                "missing partition {} ({})",
+                expected.num, expected.name
+            )));
+        };
+
+        let Some(expected_filesystem) = expected.filesystem else {
+            continue;
+        };
+
+        if filesystem(&mut file, partition.start).unwrap_or(None) != Some(expected_filesystem) {
+            return Err(not_omnect(format!(
+                "partition {} ({}) has no {} filesystem",
+                expected.num,
+                expected.name,
+                match expected_filesystem {
+                    Filesystem::Vfat => "vfat",
+                    Filesystem::Ext => "ext4",
+                }
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const PARTITION_SECTORS: u64 = 8;
+
+    fn mbr_entry(sector: &mut [u8], i: usize, partition_type: u8, start: u64, sectors: u64) {
+        let entry = &mut sector[446 + i * 16..462 + i * 16];
+
+        entry[4] = partition_type;
+        entry[8..12].copy_from_slice(&(start as u32).to_le_bytes());
+        entry[12..16].copy_from_slice(&(sectors as u32).to_le_bytes());
+    }
+
+    fn write_at(file: &mut File, offset: u64, data: &[u8]) {
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(data).unwrap();
+    }
+
+    fn write_filesystem(file: &mut File, start: u64, filesystem: Filesystem) {
+        let offset = start * SECTOR_SIZE;
+
+        match filesystem {
+            Filesystem::Ext => write_at(file, offset + EXT_MAGIC_OFFSET, &EXT_MAGIC),
+            Filesystem::Vfat => {
+                write_at(file, offset + 54, b"FAT16");
+                write_at(file, offset + 510, &MBR_SIGNATURE);
+            }
+        }
+    }
+
+    #[test]
+    fn size_of_ext_filesystem() {
+        let mut superblock = [0u8; 1024];
+
+        superblock[4..8].copy_from_slice(&1000u32.to_le_bytes());
+        superblock[24..28].copy_from_slice(&2u32.to_le_bytes());
+        superblock[56..58].copy_from_slice(&EXT_MAGIC);
+
+        let mut file = tempfile::tempfile().unwrap();
+
+        write_at(&mut file, SECTOR_SIZE + EXT_SUPERBLOCK_OFFSET, &superblock);
+        assert_eq!(ext_size(&mut file, 1).unwrap(), (4096, 1000));
+
+        superblock[96..100].copy_from_slice(&(EXT_INCOMPAT_64BIT as u32).to_le_bytes());
+        superblock[0x150..0x154].copy_from_slice(&1u32.to_le_bytes());
+        write_at(&mut file, SECTOR_SIZE + EXT_SUPERBLOCK_OFFSET, &superblock);
+        assert_eq!(ext_size(&mut file, 1).unwrap(), (4096, (1 << 32) + 1000));
+
+        assert!(ext_size(&mut file, 0).is_err());
+
+        superblock[0x68..0x78].copy_from_slice(&[0x11; 16]);
+        superblock[0x78..0x7c].copy_from_slice(b"data");
+        write_at(&mut file, SECTOR_SIZE + EXT_SUPERBLOCK_OFFSET, &superblock);
+
+        let (uuid, label) = ext_identity(&mut file, 1).unwrap();
+        assert_eq!(uuid.to_string(), "11111111-1111-1111-1111-111111111111");
+        assert_eq!(label, "data");
+    }
+
+    #[test]
+    fn volume_id_of_vfat_filesystem() {
+        let mut boot_sector = [0u8; 512];
+        let mut file = tempfile::tempfile().unwrap();
+
+        // FAT16 with 32 sectors per fat
+        boot_sector[22] = 32;
+        boot_sector[39..43].copy_from_slice(&0x1234abcdu32.to_le_bytes());
+        write_at(&mut file, SECTOR_SIZE, &boot_sector);
+        assert_eq!(vfat_volume_id(&mut file, 1).unwrap(), "1234-ABCD");
+
+        // FAT32
+        boot_sector[22] = 0;
+        boot_sector[67..71].copy_from_slice(&0xbeef0042u32.to_le_bytes());
+        write_at(&mut file, SECTOR_SIZE, &boot_sector);
+        assert_eq!(vfat_volume_id(&mut file, 1).unwrap(), "BEEF-0042");
+    }
+
+    /// Creates a dos image with boot, rootA, rootB and an extended partition
+    /// with factory and cert, each partition 8 sectors.
+    fn dos_image(path: &Path, cert_filesystem: Filesystem) {
+        let mut file = File::create(path).unwrap();
+        let mut mbr = [0u8; 512];
+        let extended_start = 4 * PARTITION_SECTORS;
+
+        mbr_entry(&mut mbr, 0, 0x0c, PARTITION_SECTORS, PARTITION_SECTORS);
+        mbr_entry(&mut mbr, 1, 0x83, 2 * PARTITION_SECTORS, PARTITION_SECTORS);
+        mbr_entry(&mut mbr, 2, 0x83, 3 * PARTITION_SECTORS, PARTITION_SECTORS);
+        mbr_entry(&mut mbr, 3, 0x0f, extended_start, 4 * PARTITION_SECTORS);
+        mbr[510..].copy_from_slice(&MBR_SIGNATURE);
+        write_at(&mut file, 0, &mbr);
+
+        // factory: ebr at extended_start, cert: ebr at extended_start + 2 * 8
+        for (i, ebr_start) in [extended_start, extended_start + 2 * PARTITION_SECTORS]
+            .into_iter()
+            .enumerate()
+        {
+            let mut ebr = [0u8; 512];
+
+            mbr_entry(&mut ebr, 0, 0x83, PARTITION_SECTORS, PARTITION_SECTORS);
+
+            if i == 0 {
+                mbr_entry(
+                    &mut ebr,
+                    1,
+                    0x05,
+                    2 * PARTITION_SECTORS,
+                    2 * PARTITION_SECTORS,
+                );
+            }
+
+            ebr[510..].copy_from_slice(&MBR_SIGNATURE);
+            write_at(&mut file, ebr_start * SECTOR_SIZE, &ebr);
+        }
+
+        write_filesystem(&mut file, PARTITION_SECTORS, Filesystem::Vfat);
+        write_filesystem(&mut file, 2 * PARTITION_SECTORS, Filesystem::Ext);
+        write_filesystem(
+            &mut file,
+            extended_start + PARTITION_SECTORS,
+            Filesystem::Ext,
+        );
+        write_filesystem(
+            &mut file,
+            extended_start + 3 * PARTITION_SECTORS,
+            cert_filesystem,
+        );
+        file.set_len(9 * PARTITION_SECTORS * SECTOR_SIZE).unwrap();
+    }
+
+    #[test]
+    fn dos_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.wic");
+
+        dos_image(&image, Filesystem::Ext);
+        validate_partition_layout(&image).unwrap();
+
+        dos_image(&image, Filesystem::Vfat);
+        let err = validate_partition_layout(&image).unwrap_err().to_string();
+        assert!(
+            err.contains("partition 6 (cert) has no ext4 filesystem"),
+            "{err}"
+        );
+
+        std::fs::write(&image, [0u8; 1024]).unwrap();
+        let err = validate_partition_layout(&image).unwrap_err().to_string();
+        assert!(err.contains("doesn't look like an omnect image (no partition table)"));
+    }
+
+    #[test]
+    fn gpt_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.wic");
+        let mut file = File::create(&image).unwrap();
+        let mut mbr = [0u8; 512];
+        let mut header = [0u8; 92];
+
+        mbr_entry(&mut mbr, 0, MBR_PARTITION_TYPE_GPT, 1, u32::MAX as u64);
+        mbr[510..].copy_from_slice(&MBR_SIGNATURE);
+        write_at(&mut file, 0, &mbr);
+
+        header[..8].copy_from_slice(GPT_SIGNATURE);
+        header[72..80].copy_from_slice(&2u64.to_le_bytes());
+        header[80..84].copy_from_slice(&4u32.to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+        write_at(&mut file, SECTOR_SIZE, &header);
+
+        // boot, rootA, rootB and factory, but no cert partition
+        for i in 0..4u64 {
+            let start = (i + 1) * PARTITION_SECTORS;
+            let mut entry = [0u8; 128];
+
+            entry[0] = 1;
+            entry[32..40].copy_from_slice(&start.to_le_bytes());
+            write_at(&mut file, 2 * SECTOR_SIZE + i * 128, &entry);
+        }
+
+        write_filesystem(&mut file, PARTITION_SECTORS, Filesystem::Vfat);
+        write_filesystem(&mut file, 2 * PARTITION_SECTORS, Filesystem::Ext);
+        write_filesystem(&mut file, 4 * PARTITION_SECTORS, Filesystem::Ext);
+        file.set_len(6 * PARTITION_SECTORS * SECTOR_SIZE).unwrap();
+
+        let err = validate_partition_layout(&image).unwrap_err().to_string();
+        assert!(err.contains("missing partition 5 (cert)"), "{err}");
+    }
+}
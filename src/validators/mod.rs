@@ -1,3 +1,7 @@
+pub mod cert;
+pub mod config;
 pub mod device_update;
 pub mod identity;
+pub mod image;
+pub mod path;
 pub mod ssh;
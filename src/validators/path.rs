@@ -0,0 +1,102 @@
+use anyhow::{Context, Result};
+use std::fs::{self, File, OpenOptions};
+use std::path::{Component, Path, PathBuf};
+
+/// Checks that `path` is an absolute path inside a partition without `..`
+/// components, e.g. a path read from an image.
+pub fn validate_image_path(path: &Path) -> Result<()> {
+    anyhow::ensure!(
+        path.is_absolute(),
+        "{} is no absolute path inside the image",
+        path.to_string_lossy()
+    );
+    anyhow::ensure!(
+        path.components()
+            .all(|c| !matches!(c, Component::ParentDir)),
+        "{} must not contain '..'",
+        path.to_string_lossy()
+    );
+
+    Ok(())
+}
+
+/// Checks that `out_file` names a file in an existing directory, which isn't
+/// a symbolic link or anything else but a regular file. Returns the path of
+/// the file inside the canonicalized output directory.
+pub fn validate_output_file(out_file: &Path) -> Result<PathBuf> {
+    let file_name = match out_file.components().next_back() {
+        Some(Component::Normal(file_name)) => file_name,
+        _ => anyhow::bail!("{} is no valid output file", out_file.to_string_lossy()),
+    };
+    let out_dir = match out_file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let out_dir = out_dir.canonicalize().context(format!(
+        "output dir of {} does not exist",
+        out_file.to_string_lossy()
+    ))?;
+    let out_file = out_dir.join(file_name);
+
+    if let Ok(metadata) = fs::symlink_metadata(&out_file) {
+        anyhow::ensure!(
+            metadata.file_type().is_file(),
+            "{} exists and is no regular file",
+            out_file.to_string_lossy()
+        );
+    }
+
+    Ok(out_file)
+}
+
+/// Creates `out_file` validated by [`validate_output_file`] without following
+/// symbolic links. An existing regular file is replaced.
+pub fn create_output_file(out_file: &Path) -> Result<File> {
+    let out_file = validate_output_file(out_file)?;
+
+    if out_file.exists() {
+        fs::remove_file(&out_file)
+            .context(format!("cannot replace {}", out_file.to_string_lossy()))?;
+    }
+
+    // fails instead of following a symbolic link created in the meantime
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&out_file)
+        .context(format!("cannot create {}", out_file.to_string_lossy()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_paths() {
+        assert!(validate_image_path(Path::new("/etc/hostname")).is_ok());
+        assert!(validate_image_path(Path::new("etc/hostname")).is_err());
+        assert!(validate_image_path(Path::new("/etc/../../hostname")).is_err());
+    }
+
+    #[test]
+    fn output_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        let link = dir.path().join("link");
+
+        assert_eq!(
+            validate_output_file(&file).unwrap(),
+            dir.path().canonicalize().unwrap().join("file")
+        );
+        assert!(validate_output_file(&dir.path().join("..")).is_err());
+        assert!(validate_output_file(&dir.path().join("missing/file")).is_err());
+
+        std::os::unix::fs::symlink("/etc/passwd", &link).unwrap();
+        assert!(validate_output_file(&link).is_err());
+        assert!(create_output_file(&link).is_err());
+
+        fs::write(&file, "old").unwrap();
+        drop(create_output_file(&file).unwrap());
+        assert_eq!(fs::read_to_string(&file).unwrap(), "");
+    }
+}
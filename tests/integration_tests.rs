@@ -15,9 +15,10 @@ fn check_set_identity_gateway_config() {
     let tr = Testrunner::new(function_name!().split("::").last().unwrap());
     let config_file_path = tr.to_pathbuf("conf/config.toml.gateway.est.template");
     let image_path = tr.to_pathbuf("testfiles/image.wic");
-    let root_ca_file_path = tr.to_pathbuf("testfiles/root.ca.cert.pem");
-    let edge_device_identity_full_chain_file_path = tr.to_pathbuf("testfiles/full-chain.cert.pem");
-    let edge_device_identity_key_file_path = tr.to_pathbuf("testfiles/device-ca.key.pem");
+    let root_ca_file_path = tr.to_pathbuf("testfiles/test-ca.pem");
+    let edge_device_identity_full_chain_file_path =
+        tr.to_pathbuf("testfiles/test-int-ca_fullchain.pem");
+    let edge_device_identity_key_file_path = tr.to_pathbuf("testfiles/test-int-ca.key");
 
     let mut set_iotedge_gateway_config = Command::cargo_bin("omnect-cli").unwrap();
     let assert = set_iotedge_gateway_config
@@ -129,7 +130,7 @@ fn check_set_identity_leaf_config() {
     let tr = Testrunner::new(function_name!().split("::").last().unwrap());
     let config_file_path = tr.to_pathbuf("conf/config.toml.iot-leaf.template");
     let image_path = tr.to_pathbuf("testfiles/image.wic");
-    let root_ca_file_path = tr.to_pathbuf("testfiles/root.ca.cert.pem");
+    let root_ca_file_path = tr.to_pathbuf("testfiles/test-ca.pem");
 
     let mut set_iot_leaf_sas_config = Command::cargo_bin("omnect-cli").unwrap();
     let assert = set_iot_leaf_sas_config
@@ -403,6 +404,7 @@ fn check_set_device_cert_est() {
     let image_path = tr.to_pathbuf("testfiles/image.wic");
     let intermediate_full_chain_crt_path = tr.to_pathbuf("testfiles/test-int-ca_fullchain.pem");
     let intermediate_full_chain_crt_key_path = tr.to_pathbuf("testfiles/test-int-ca.key");
+    let pkcs12_path = tr.pathbuf().join("my-device-id.p12");
 
     let mut set_device_certificate = Command::cargo_bin("omnect-cli").unwrap();
     let assert = set_device_certificate
@@ -418,8 +420,16 @@ fn check_set_device_cert_est() {
         .arg("my-device-id")
         .arg("-D")
         .arg("1")
+        .arg("--export-pkcs12")
+        .arg(&pkcs12_path)
+        .arg("--password")
+        .arg("secret")
         .assert();
-    assert.success();
+    let output = String::from_utf8(assert.success().get_output().stdout.to_vec()).unwrap();
+
+    assert!(output.contains("SHA-1 thumbprint: "));
+    assert!(output.contains("SHA-256 thumbprint: "));
+    assert!(pkcs12_path.exists());
 
     let mut device_id_cert_out_path = tr.pathbuf();
     device_id_cert_out_path.push("dir1");
@@ -528,6 +538,222 @@ fn check_set_device_cert_no_est() {
     ));
 }
 
+#[test]
+fn check_set_hostname() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+
+    let mut set_hostname = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_hostname
+        .arg("identity")
+        .arg("set-hostname")
+        .arg("-n")
+        .arg("omnect-device-1")
+        .arg("-m")
+        .arg("customer-a")
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    let mut out_path = tr.pathbuf();
+    out_path.push("dir1");
+    create_dir_all(out_path.clone()).unwrap();
+
+    let hostname_out_path = out_path.join("hostname");
+    let machine_id_out_path = out_path.join("machine-id");
+
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!(
+            "factory:/etc/hostname,{}",
+            hostname_out_path.to_str().unwrap()
+        ))
+        .arg("-f")
+        .arg(format!(
+            "factory:/etc/machine-id,{}",
+            machine_id_out_path.to_str().unwrap()
+        ))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    assert_eq!(
+        std::fs::read_to_string(hostname_out_path).unwrap(),
+        "omnect-device-1"
+    );
+    assert_eq!(
+        std::fs::read_to_string(machine_id_out_path)
+            .unwrap()
+            .trim()
+            .len(),
+        32
+    );
+}
+
+#[test]
+fn check_set_connection_string() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+
+    let mut set_connection_string = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_connection_string
+        .arg("identity")
+        .arg("set-connection-string")
+        .arg("-s")
+        .arg("HostName=my-hub.azure-devices.net;DeviceId=lab-device-1;SharedAccessKey=a2V5Cg==")
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    let mut out_path = tr.pathbuf();
+    out_path.push("dir1");
+    create_dir_all(out_path.clone()).unwrap();
+
+    let config_out_path = out_path.join("config.toml");
+    let hostname_out_path = out_path.join("hostname");
+
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!(
+            "factory:/etc/aziot/config.toml,{}",
+            config_out_path.to_str().unwrap()
+        ))
+        .arg("-f")
+        .arg(format!(
+            "factory:/etc/hostname,{}",
+            hostname_out_path.to_str().unwrap()
+        ))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    let config = std::fs::read_to_string(config_out_path).unwrap();
+
+    assert!(config.contains("source = \"manual\""));
+    assert!(config.contains("DeviceId=lab-device-1;SharedAccessKey=a2V5Cg=="));
+    assert_eq!(
+        std::fs::read_to_string(hostname_out_path).unwrap(),
+        "lab-device-1"
+    );
+
+    let mut set_connection_string = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_connection_string
+        .arg("identity")
+        .arg("set-connection-string")
+        .arg("-s")
+        .arg("HostName=my-hub.azure-devices.net;DeviceId=lab-device-1;x509=true")
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.failure();
+}
+
+#[test]
+fn check_set_static_network() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+
+    let mut set_static = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_static
+        .arg("network")
+        .arg("set-static")
+        .arg("-n")
+        .arg("eth0")
+        .arg("-a")
+        .arg("192.168.0.10/24")
+        .arg("-g")
+        .arg("192.168.0.1")
+        .arg("-d")
+        .arg("192.168.0.2")
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    let mut out_path = tr.pathbuf();
+    out_path.push("dir1");
+    create_dir_all(out_path.clone()).unwrap();
+    out_path.push("10-eth0.network");
+
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!(
+            "factory:/etc/systemd/network/10-eth0.network,{}",
+            out_path.to_str().unwrap()
+        ))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    let config = std::fs::read_to_string(out_path).unwrap();
+
+    assert!(config.contains("Name=eth0"));
+    assert!(config.contains("Address=192.168.0.10/24"));
+    assert!(config.contains("Gateway=192.168.0.1"));
+}
+
+#[test]
+fn check_set_wireguard() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    let config_path = tr.pathbuf().join("wg0.conf");
+
+    std::fs::write(
+        &config_path,
+        "[Interface]\nPrivateKey = aGVsbG8=\nAddress = 10.0.0.2/24\n\n[Peer]\nPublicKey = d29ybGQ=\nEndpoint = vpn.example.com:51820\nAllowedIPs = 10.0.0.0/24\n",
+    )
+    .unwrap();
+
+    let mut set_wireguard = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_wireguard
+        .arg("network")
+        .arg("set-wireguard")
+        .arg("-c")
+        .arg(&config_path)
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    let mut out_path = tr.pathbuf();
+    out_path.push("dir1");
+    create_dir_all(out_path.clone()).unwrap();
+    out_path.push("wg0.conf");
+
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!(
+            "factory:/etc/wireguard/wg0.conf,{}",
+            out_path.to_str().unwrap()
+        ))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    assert!(file_diff::diff(
+        config_path.to_str().unwrap(),
+        out_path.to_str().unwrap()
+    ));
+}
+
 #[test]
 fn check_set_iot_hub_device_update_template() {
     let tr = Testrunner::new(function_name!().split("::").last().unwrap());
@@ -587,7 +813,7 @@ fn check_set_iot_hub_device_update_create_import_manifest() {
         .arg("create-import-manifest")
         .arg("-d")
         .arg("OMNECT-gateway-devel")
-        .arg("-v")
+        .arg("-V")
         .arg("4.0.15.0")
         .arg("-i")
         .arg(&image_path)
@@ -623,6 +849,67 @@ fn check_set_iot_hub_device_update_create_import_manifest() {
     assert_json_eq!(manifest_created, manifest_original);
 }
 
+#[test]
+fn check_create_import_manifest_from_wic_image() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+
+    let adu_config_file_path = tr.to_pathbuf("conf/du-config.json.template");
+    let wic_image_path = tr.to_pathbuf("testfiles/image.wic");
+    let wic_image_orig_path = tr.pathbuf().join("image.wic.orig");
+    let image_path = tr.to_pathbuf("testfiles/image.swu");
+    let script_path = tr.to_pathbuf("testfiles/image.swu.sh");
+    let manifest_created = tr.pathbuf().join("image.swu.importManifest.json");
+
+    let mut set_iot_hub_device_update_config = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_iot_hub_device_update_config
+        .arg("iot-hub-device-update")
+        .arg("set-device-config")
+        .arg("-c")
+        .arg(&adu_config_file_path)
+        .arg("-i")
+        .arg(&wic_image_path)
+        .assert();
+    assert.success();
+
+    std::fs::copy(&wic_image_path, &wic_image_orig_path).unwrap();
+
+    let mut create_import_manifest = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = create_import_manifest
+        .current_dir(tr.pathbuf())
+        .arg("iot-hub-device-update")
+        .arg("create-import-manifest")
+        .arg("-d")
+        .arg("OMNECT-gateway-devel")
+        .arg("-V")
+        .arg("4.0.15.0")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("-s")
+        .arg(&script_path)
+        .arg("-w")
+        .arg(&wic_image_path)
+        .assert();
+    assert.success();
+
+    // deriving the compatibility must not modify the image, e.g. by recording history
+    assert!(file_diff::diff(
+        wic_image_path.to_str().unwrap(),
+        wic_image_orig_path.to_str().unwrap()
+    ));
+
+    let manifest_created: serde_json::Value =
+        serde_json::from_reader(std::fs::File::open(manifest_created).unwrap()).unwrap();
+
+    assert_eq!(
+        manifest_created["compatibility"][0]["model"],
+        "<Place your device property model here>"
+    );
+    assert_eq!(
+        manifest_created["compatibility"][0]["compatibilityid"],
+        "<Place your compatibility id here>"
+    );
+}
+
 #[test]
 fn check_file_copy_dos_partition() {
     let tr = Testrunner::new(function_name!().split("::").last().unwrap());
@@ -746,303 +1033,1537 @@ fn check_file_copy(tr: Testrunner, partition: &str) {
 }
 
 #[test]
-fn check_bmap_generation_wic() {
+fn check_file_copy_from_image_path_safety() {
     let tr = Testrunner::new(function_name!().split("::").last().unwrap());
     let image_path = tr.to_pathbuf("testfiles/image.wic");
-    let image_path_copy = PathBuf::from(format!("{}.copy", image_path.to_str().unwrap()));
-    let bmap_path = PathBuf::from(format!("{}.bmap", image_path.to_str().unwrap()));
-    let in_file = tr.to_pathbuf("testfiles/boot.scr");
-    let in_file = in_file.to_str().unwrap();
-
-    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
-    let assert = copy_to_img
+    let target = tr.to_pathbuf("testfiles/boot.scr");
+    let target_hash = Testrunner::file_hash(&target);
+    let mut link = tr.pathbuf();
+    link.push("link");
+    std::os::unix::fs::symlink(&target, &link).unwrap();
+    let mut out_file = tr.pathbuf();
+    out_file.push("hostname");
+
+    // escaping the partition
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
         .arg("file")
-        .arg("copy-to-image")
+        .arg("copy-from-image")
         .arg("-f")
-        .arg(format!("{in_file},boot:/my-file"))
+        .arg(format!(
+            "factory:/etc/../../hostname,{}",
+            out_file.to_str().unwrap()
+        ))
         .arg("-i")
         .arg(&image_path)
         .assert();
+    assert.failure();
+    assert!(!out_file.exists());
 
-    assert.success();
-
-    assert!(!bmap_path.try_exists().is_ok_and(|exists| exists));
-
-    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
-    let assert = copy_to_img
+    // writing through a symbolic link
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
         .arg("file")
-        .arg("copy-to-image")
+        .arg("copy-from-image")
         .arg("-f")
-        .arg(format!("{in_file},boot:/my-file"))
+        .arg(format!("factory:/etc/hostname,{}", link.to_str().unwrap()))
         .arg("-i")
         .arg(&image_path)
-        .arg("-b")
         .assert();
-    assert.success();
+    assert.failure();
+    assert_eq!(target_hash, Testrunner::file_hash(&target));
+}
 
-    assert!(bmap_path.try_exists().is_ok_and(|exists| exists));
+#[test]
+fn check_image_convert_vhd() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    let mut vhd_path = tr.pathbuf();
+    vhd_path.push("image.vhd");
 
-    // use bmaptool to verify that the checksum of the bmap file and the image
-    // still match after the copy operations
-    let assert = Command::new("bmaptool")
-        .arg("copy")
-        .args(["--bmap", &bmap_path.to_string_lossy()])
+    let mut convert = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = convert
+        .arg("image")
+        .arg("convert")
+        .arg("-i")
         .arg(&image_path)
-        .arg(&image_path_copy)
+        .arg("-o")
+        .arg(&vhd_path)
+        .arg("--to")
+        .arg("vhd")
         .assert();
     assert.success();
+
+    let vhd = std::fs::read(&vhd_path).unwrap();
+    let footer = &vhd[vhd.len() - 512..];
+
+    assert_eq!((vhd.len() - 512) % (1024 * 1024), 0);
+    assert_eq!(&footer[0..8], b"conectix");
+    assert!(image_path.exists());
 }
 
 #[test]
-fn check_bmap_generation_wic_xz() {
+fn check_image_resize_partition() {
     let tr = Testrunner::new(function_name!().split("::").last().unwrap());
-    let image_path_wic_xz = tr.to_pathbuf("testfiles/image.wic.xz");
-    let image_path_wic = image_path_wic_xz.with_extension("");
-    let image_path_wic_copy = image_path_wic_xz.with_extension("copy");
-    let image_path_bmap = image_path_wic_xz.with_extension("bmap");
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
     let in_file = tr.to_pathbuf("testfiles/boot.scr");
-    let in_file = in_file.to_str().unwrap();
+    let mut out_file = tr.pathbuf();
+    out_file.push("boot.scr");
+    let image_size = std::fs::metadata(&image_path).unwrap().len();
 
     let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
     let assert = copy_to_img
         .arg("file")
         .arg("copy-to-image")
         .arg("-f")
-        .arg(format!("{in_file},boot:/my-file"))
+        .arg(format!("{},factory:/boot.scr", in_file.to_str().unwrap()))
         .arg("-i")
-        .arg(&image_path_wic_xz)
-        .arg("-b")
+        .arg(&image_path)
         .assert();
     assert.success();
 
-    // use bmaptool to verify that the checksum of the bmap file and the image
-    // still match after the copy operations
-    let assert = Command::new("bmaptool")
-        .arg("copy")
-        .args(["--bmap", &image_path_bmap.to_string_lossy()])
-        .arg(&image_path_wic)
-        .arg(&image_path_wic_copy)
+    // rootA is followed by rootB, so all following partitions are shifted
+    let mut resize = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = resize
+        .arg("image")
+        .arg("resize-partition")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("-p")
+        .arg("rootA")
+        .arg("--size")
+        .arg("4M")
+        .assert();
+    assert.success();
+
+    assert!(std::fs::metadata(&image_path).unwrap().len() > image_size);
+
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!("factory:/boot.scr,{}", out_file.to_str().unwrap()))
+        .arg("-i")
+        .arg(&image_path)
         .assert();
     assert.success();
+
+    assert!(file_diff::diff(
+        in_file.to_str().unwrap(),
+        out_file.to_str().unwrap()
+    ));
+
+    let mut shrink = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = shrink
+        .arg("image")
+        .arg("resize-partition")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("-p")
+        .arg("rootA")
+        .arg("--size")
+        .arg("1M")
+        .assert();
+    assert.failure().code(2);
 }
 
 #[test]
-fn check_image_compression() {
+fn check_file_copy_all_slots() {
     let tr = Testrunner::new(function_name!().split("::").last().unwrap());
-    let image_path_wic = tr.to_pathbuf("testfiles/image.wic");
-    let image_path_wic_xz = PathBuf::from(format!("{}.xz", image_path_wic.to_str().unwrap()));
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
     let in_file = tr.to_pathbuf("testfiles/boot.scr");
-    let in_file = in_file.to_str().unwrap();
+    let mut out_file = tr.pathbuf();
+    out_file.push("boot.scr");
 
     let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
     let assert = copy_to_img
         .arg("file")
         .arg("copy-to-image")
+        .arg("--all-slots")
         .arg("-f")
-        .arg(format!("{in_file},boot:/my-file"))
+        .arg(format!("{},rootA:/etc/boot.scr", in_file.to_str().unwrap()))
         .arg("-i")
-        .arg(&image_path_wic)
+        .arg(&image_path)
         .assert();
     assert.success();
 
-    assert!(!image_path_wic_xz.try_exists().is_ok_and(|exists| exists));
+    for partition in ["rootA", "rootB"] {
+        let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_from_img
+            .arg("file")
+            .arg("copy-from-image")
+            .arg("-f")
+            .arg(format!(
+                "{partition}:/etc/boot.scr,{}",
+                out_file.to_str().unwrap()
+            ))
+            .arg("-i")
+            .arg(&image_path)
+            .assert();
+        assert.success();
+
+        assert!(file_diff::diff(
+            in_file.to_str().unwrap(),
+            out_file.to_str().unwrap()
+        ));
+    }
+}
+
+#[test]
+fn check_image_reset_data() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+
+    let mut reset_data = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = reset_data
+        .arg("image")
+        .arg("reset-data")
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    // rootA can't be reset
+    let mut reset_root = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = reset_root
+        .arg("image")
+        .arg("reset-data")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--partition")
+        .arg("rootA")
+        .assert();
+    assert.failure().code(2);
+}
+
+#[test]
+fn check_image_shrink() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    let bmap_path = PathBuf::from(format!("{}.bmap", image_path.to_str().unwrap()));
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let mut out_file = tr.pathbuf();
+    out_file.push("boot.scr");
+    let image_size = std::fs::metadata(&image_path).unwrap().len();
 
     let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
     let assert = copy_to_img
         .arg("file")
         .arg("copy-to-image")
         .arg("-f")
-        .arg(format!("{in_file},boot:/my-file"))
+        .arg(format!("{},factory:/boot.scr", in_file.to_str().unwrap()))
         .arg("-i")
-        .arg(&image_path_wic)
-        .arg("-p")
-        .arg("xz")
+        .arg(&image_path)
         .assert();
     assert.success();
 
-    let image_path_wic_xz_hash1 = Testrunner::file_hash(&image_path_wic_xz);
+    let mut shrink = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = shrink
+        .env("CONTAINERIZED", "true")
+        .arg("image")
+        .arg("shrink")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--margin")
+        .arg("512")
+        .assert();
+    assert.success();
 
-    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
-    let assert = copy_to_img
+    assert!(std::fs::metadata(&image_path).unwrap().len() <= image_size);
+    assert!(bmap_path.exists());
+
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
         .arg("file")
-        .arg("copy-to-image")
+        .arg("copy-from-image")
         .arg("-f")
-        .arg(format!("{in_file},factory:/my-file"))
+        .arg(format!("factory:/boot.scr,{}", out_file.to_str().unwrap()))
         .arg("-i")
-        .arg(&image_path_wic_xz)
-        .arg("-p")
-        .arg("xz")
+        .arg(&image_path)
         .assert();
     assert.success();
 
-    let image_path_wic_xz_hash2 = Testrunner::file_hash(&image_path_wic_xz);
+    assert!(file_diff::diff(
+        in_file.to_str().unwrap(),
+        out_file.to_str().unwrap()
+    ));
+}
 
-    assert_ne!(image_path_wic_xz_hash1, image_path_wic_xz_hash2);
+#[test]
+fn check_image_add_partition_dos() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    let image = std::fs::read(&image_path).unwrap();
+
+    // the test image has a dos partition table
+    let mut add_partition = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = add_partition
+        .arg("image")
+        .arg("add-partition")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("-n")
+        .arg("apps")
+        .arg("-s")
+        .arg("2M")
+        .assert();
+    assert.failure().code(2);
+
+    assert!(std::fs::read(&image_path).unwrap() == image);
 }
 
 #[test]
-fn check_image_decompression() {
+fn check_image_diff() {
     let tr = Testrunner::new(function_name!().split("::").last().unwrap());
-    let image_path_wic_xz = tr.to_pathbuf("testfiles/image.wic.xz");
-    let image_path_wic = image_path_wic_xz.with_extension("");
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
     let in_file = tr.to_pathbuf("testfiles/boot.scr");
-    let in_file = in_file.to_str().unwrap();
-    let image_path_wic_xz_hash1 = Testrunner::file_hash(&image_path_wic_xz);
+    let mut base_path = tr.pathbuf();
+    base_path.push("base.wic");
+    std::fs::copy(&image_path, &base_path).unwrap();
 
     let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
     let assert = copy_to_img
         .arg("file")
         .arg("copy-to-image")
         .arg("-f")
-        .arg(format!("{in_file},boot:/my-file"))
+        .arg(format!(
+            "{},factory:/etc/boot.scr",
+            in_file.to_str().unwrap()
+        ))
         .arg("-i")
-        .arg(&image_path_wic_xz)
+        .arg(&image_path)
         .assert();
     assert.success();
 
-    assert!(image_path_wic.try_exists().is_ok_and(|exists| exists));
-
-    let image_path_wic_xz_hash2 = Testrunner::file_hash(&image_path_wic_xz);
-
-    assert_eq!(image_path_wic_xz_hash1, image_path_wic_xz_hash2);
-}
-
-#[tokio::test]
-async fn check_ssh_tunnel_setup() {
-    let tr = Testrunner::new("check_ssh_tunnel_setup");
-
-    let mock_access_token = oauth2::AccessToken::new("test_token_mock".to_string());
-
-    let mut config = ssh::Config::new("test-backend", Some(tr.pathbuf()), None, None).unwrap();
-
-    let server = MockServer::start();
-
-    let request_reply = r#"{
-	"clientBastionCert": "-----BEGIN CERTIFICATE-----\nMIIFrjCCA5agAwIBAgIBATANBgkqhkiG...",
-	"clientDeviceCert": "-----BEGIN CERTIFICATE-----\nMIIFrjCCA5agAwIBAgIBATANBgkqhkiG...",
-	"host": "132.23.0.1",
-	"port": 22,
-	"bastionUser": "bastion_user"
+    let mut diff = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = diff
+        .arg("--output")
+        .arg("json")
+        .arg("image")
+        .arg("diff")
+        .arg("-b")
+        .arg(&base_path)
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    let output = assert.success().get_output().stdout.clone();
+    let diffs: serde_json::Value = serde_json::from_slice(&output).unwrap();
+
+    assert_eq!(
+        diffs,
+        serde_json::json!([{
+            "partition": "factory",
+            "path": "/etc/boot.scr",
+            "change": "added",
+            "image": omnect_cli::provenance::sha256(&in_file).unwrap(),
+        }])
+    );
 }
-"#;
-
-    let _ = server.mock(|when, then| {
-        when.method(POST)
-            .path("/api/devices/prepareSSHConnection")
-            .header("authorization", "Bearer test_token_mock");
-        then.status(200)
-            .header("content-type", "application/json")
-            .body(request_reply);
-    });
-
-    config.set_backend(url::Url::parse(&server.base_url()).unwrap());
 
-    ssh::ssh_create_tunnel("test_device", "test_user", config, mock_access_token)
-        .await
-        .unwrap();
+#[test]
+fn check_image_verify_integrity_manifest() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let manifest_path = PathBuf::from(format!("{}.manifest.json", image_path.to_str().unwrap()));
 
-    assert!(tr
-        .pathbuf()
-        .join("config")
-        .try_exists()
-        .is_ok_and(|exists| exists));
-    assert!(tr
-        .pathbuf()
-        .join("id_ed25519")
-        .try_exists()
-        .is_ok_and(|exists| exists));
-    assert!(tr
-        .pathbuf()
-        .join("id_ed25519.pub")
-        .try_exists()
-        .is_ok_and(|exists| exists));
-    assert!(tr
-        .pathbuf()
-        .join("bastion-cert.pub")
-        .try_exists()
-        .is_ok_and(|exists| exists));
-    assert!(tr
-        .pathbuf()
-        .join("device-cert.pub")
-        .try_exists()
-        .is_ok_and(|exists| exists));
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .env("OMNECT_CLI_INTEGRITY_MANIFEST", "true")
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!(
+            "{},factory:/etc/boot.scr",
+            in_file.to_str().unwrap()
+        ))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+    assert!(manifest_path.exists());
 
-    let ssh_config = std::fs::read_to_string(tr.pathbuf().join("config")).unwrap();
-    let expected_config = format!(
-        r#"Host bastion
-	User bastion_user
-	Hostname 132.23.0.1
-	Port 22
-	IdentityFile {}/id_ed25519
-	CertificateFile {}/bastion-cert.pub
-	ProxyCommand none
+    let mut verify = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = verify
+        .arg("image")
+        .arg("verify")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("-m")
+        .arg(&manifest_path)
+        .assert();
+    assert.success();
 
-Host test_device
-	User test_user
-	IdentityFile {}/id_ed25519
-	CertificateFile {}/device-cert.pub
-	ProxyCommand ssh -F {}/config bastion
-"#,
-        tr.pathbuf().to_string_lossy(),
-        tr.pathbuf().to_string_lossy(),
-        tr.pathbuf().to_string_lossy(),
-        tr.pathbuf().to_string_lossy(),
-        tr.pathbuf().to_string_lossy()
-    );
+    // modification without updating the manifest
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!(
+            "{},factory:/etc/boot2.scr",
+            in_file.to_str().unwrap()
+        ))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
 
-    assert_eq!(ssh_config, expected_config);
+    let mut verify = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = verify
+        .arg("image")
+        .arg("verify")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("-m")
+        .arg(&manifest_path)
+        .assert();
+    let stderr = assert.failure().code(2).get_output().stderr.clone();
+    assert!(String::from_utf8_lossy(&stderr).contains("factory:/etc/boot2.scr"));
 }
 
-// currently disabled as we have no way to test this in our pipeline were we
-// don't have docker installed
-#[ignore]
 #[test]
-fn check_docker_inject_image_success() {
-    let image = std::env::var("TEST_DOCKER_IMAGE").unwrap_or("busybox".to_string());
-
+fn check_image_transcode() {
     let tr = Testrunner::new(function_name!().split("::").last().unwrap());
-
     let image_path = tr.to_pathbuf("testfiles/image.wic");
+    let zstd_path = PathBuf::from(format!("{}.zst", image_path.to_str().unwrap()));
+    let decompressed_path = tr.pathbuf().join("decompressed.wic");
 
-    let mut docker_inject_image_config = Command::cargo_bin("omnect-cli").unwrap();
-    let assert = docker_inject_image_config
-        .arg("docker")
-        .arg("inject")
-        .args(["--docker-image", &image])
-        .args(["--image", &image_path.to_string_lossy()])
-        .args(["--partition", "factory"])
-        .args(["--dest", "/some/test/dir"])
+    let mut transcode = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = transcode
+        .arg("image")
+        .arg("transcode")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("-p")
+        .arg("zstd")
         .assert();
-    let result_output = String::from_utf8(assert.get_output().stdout.to_vec()).unwrap();
-    let result_output = result_output.trim();
     assert.success();
+    assert!(zstd_path.exists());
 
-    const EXPECTED_OUTPUT: &str = "Stored some-image to factory:/some/test/dir/some-image.tar.gz";
+    let mut transcode = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = transcode
+        .arg("image")
+        .arg("transcode")
+        .arg("-i")
+        .arg(&zstd_path)
+        .arg("-o")
+        .arg(&decompressed_path)
+        .assert();
+    assert.success();
 
-    assert_eq!(EXPECTED_OUTPUT, result_output);
+    assert_eq!(
+        std::fs::read(&image_path).unwrap(),
+        std::fs::read(&decompressed_path).unwrap()
+    );
+}
 
-    let mut docker_image_out_path = tr.pathbuf();
-    docker_image_out_path.push("docker");
-    create_dir_all(docker_image_out_path.clone()).unwrap();
-    docker_image_out_path.push("some-image.tar.gz");
+#[test]
+fn check_file_hash() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
 
-    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
-    let assert = copy_from_img
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
         .arg("file")
-        .arg("copy-from-image")
+        .arg("copy-to-image")
         .arg("-f")
         .arg(format!(
-            "factory:/some/test/dir/some-image.tar.gz,{}",
-            docker_image_out_path.to_string_lossy(),
+            "{},factory:/etc/boot.scr",
+            in_file.to_str().unwrap()
         ))
         .arg("-i")
         .arg(&image_path)
         .assert();
     assert.success();
 
-    const EXPECTED_CONTENT: &str = "some test data";
+    let mut hash = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = hash
+        .arg("--output")
+        .arg("json")
+        .arg("file")
+        .arg("hash")
+        .arg("-f")
+        .arg("factory:/etc/boot.scr")
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    let output = assert.success().get_output().stdout.clone();
+    let digests: serde_json::Value = serde_json::from_slice(&output).unwrap();
+
+    assert_eq!(
+        digests,
+        serde_json::json!([{
+            "partition": "factory",
+            "path": "/etc/boot.scr",
+            "algorithm": "sha256",
+            "digest": omnect_cli::provenance::sha256(&in_file).unwrap(),
+        }])
+    );
 
-    let result_content = std::fs::read_to_string(docker_image_out_path).unwrap();
+    let mut hash = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = hash
+        .arg("file")
+        .arg("hash")
+        .arg("-f")
+        .arg("factory:/etc/missing")
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.failure();
+}
 
-    assert_eq!(EXPECTED_CONTENT, result_content);
+#[test]
+fn check_image_check() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("--fsck")
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!(
+            "{},factory:/etc/boot.scr",
+            in_file.to_str().unwrap()
+        ))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    let mut check = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = check
+        .arg("image")
+        .arg("check")
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+}
+
+#[test]
+fn check_bmap_generation_wic() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    let image_path_copy = PathBuf::from(format!("{}.copy", image_path.to_str().unwrap()));
+    let bmap_path = PathBuf::from(format!("{}.bmap", image_path.to_str().unwrap()));
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},boot:/my-file"))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+
+    assert.success();
+
+    assert!(!bmap_path.try_exists().is_ok_and(|exists| exists));
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},boot:/my-file"))
+        .arg("-i")
+        .arg(&image_path)
+        .arg("-b")
+        .assert();
+    assert.success();
+
+    assert!(bmap_path.try_exists().is_ok_and(|exists| exists));
+
+    // use bmaptool to verify that the checksum of the bmap file and the image
+    // still match after the copy operations
+    let assert = Command::new("bmaptool")
+        .arg("copy")
+        .args(["--bmap", &bmap_path.to_string_lossy()])
+        .arg(&image_path)
+        .arg(&image_path_copy)
+        .assert();
+    assert.success();
+}
+
+#[test]
+fn check_image_flash() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    let bmap_path = PathBuf::from(format!("{}.bmap", image_path.to_str().unwrap()));
+    let device_path = PathBuf::from(format!("{}.device", image_path.to_str().unwrap()));
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},boot:/my-file"))
+        .arg("-i")
+        .arg(&image_path)
+        .arg("-b")
+        .assert();
+    assert.success();
+
+    let mut flash = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = flash
+        .arg("image")
+        .arg("flash")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("-d")
+        .arg(&device_path)
+        .assert();
+    assert.success();
+
+    // unmapped blocks of the image are holes, so that both files are equal
+    assert_eq!(
+        std::fs::read(&image_path).unwrap(),
+        std::fs::read(&device_path).unwrap()
+    );
+
+    // the flashed device matches the bmap
+    let mut verify = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = verify
+        .arg("image")
+        .arg("verify-bmap")
+        .arg("-i")
+        .arg(&device_path)
+        .arg("--bmap")
+        .arg(&bmap_path)
+        .assert();
+    assert.success();
+
+    // a bmap file which doesn't match the image fails
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},boot:/my-file2"))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    let mut flash = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = flash
+        .arg("image")
+        .arg("flash")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--bmap")
+        .arg(&bmap_path)
+        .arg("-d")
+        .arg(&device_path)
+        .assert();
+    assert.failure();
+
+    // the modified image doesn't match the bmap anymore
+    let mut verify = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = verify
+        .arg("image")
+        .arg("verify-bmap")
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.failure().code(2);
+}
+
+#[test]
+fn check_bmap_generation_wic_xz() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path_wic_xz = tr.to_pathbuf("testfiles/image.wic.xz");
+    let image_path_wic = image_path_wic_xz.with_extension("");
+    let image_path_wic_copy = image_path_wic_xz.with_extension("copy");
+    let image_path_bmap = image_path_wic_xz.with_extension("bmap");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},boot:/my-file"))
+        .arg("-i")
+        .arg(&image_path_wic_xz)
+        .arg("-b")
+        .assert();
+    assert.success();
+
+    // use bmaptool to verify that the checksum of the bmap file and the image
+    // still match after the copy operations
+    let assert = Command::new("bmaptool")
+        .arg("copy")
+        .args(["--bmap", &image_path_bmap.to_string_lossy()])
+        .arg(&image_path_wic)
+        .arg(&image_path_wic_copy)
+        .assert();
+    assert.success();
+}
+
+#[test]
+fn check_image_compression() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path_wic = tr.to_pathbuf("testfiles/image.wic");
+    let image_path_wic_xz = PathBuf::from(format!("{}.xz", image_path_wic.to_str().unwrap()));
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},boot:/my-file"))
+        .arg("-i")
+        .arg(&image_path_wic)
+        .assert();
+    assert.success();
+
+    assert!(!image_path_wic_xz.try_exists().is_ok_and(|exists| exists));
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},boot:/my-file"))
+        .arg("-i")
+        .arg(&image_path_wic)
+        .arg("-p")
+        .arg("xz")
+        .assert();
+    assert.success();
+
+    let image_path_wic_xz_hash1 = Testrunner::file_hash(&image_path_wic_xz);
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},factory:/my-file"))
+        .arg("-i")
+        .arg(&image_path_wic_xz)
+        .arg("-p")
+        .arg("xz")
+        .assert();
+    assert.success();
+
+    let image_path_wic_xz_hash2 = Testrunner::file_hash(&image_path_wic_xz);
+
+    assert_ne!(image_path_wic_xz_hash1, image_path_wic_xz_hash2);
+}
+
+#[test]
+fn check_image_decompression() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path_wic_xz = tr.to_pathbuf("testfiles/image.wic.xz");
+    let image_path_wic = image_path_wic_xz.with_extension("");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+    let image_path_wic_xz_hash1 = Testrunner::file_hash(&image_path_wic_xz);
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},boot:/my-file"))
+        .arg("-i")
+        .arg(&image_path_wic_xz)
+        .assert();
+    assert.success();
+
+    assert!(image_path_wic.try_exists().is_ok_and(|exists| exists));
+
+    let image_path_wic_xz_hash2 = Testrunner::file_hash(&image_path_wic_xz);
+
+    assert_eq!(image_path_wic_xz_hash1, image_path_wic_xz_hash2);
+}
+
+#[tokio::test]
+async fn check_ssh_tunnel_setup() {
+    let tr = Testrunner::new("check_ssh_tunnel_setup");
+
+    let mock_access_token = oauth2::AccessToken::new("test_token_mock".to_string());
+
+    let mut config = ssh::Config::new("test-backend", Some(tr.pathbuf()), None, None).unwrap();
+
+    let server = MockServer::start();
+
+    let request_reply = r#"{
+	"clientBastionCert": "-----BEGIN CERTIFICATE-----\nMIIFrjCCA5agAwIBAgIBATANBgkqhkiG...",
+	"clientDeviceCert": "-----BEGIN CERTIFICATE-----\nMIIFrjCCA5agAwIBAgIBATANBgkqhkiG...",
+	"host": "132.23.0.1",
+	"port": 22,
+	"bastionUser": "bastion_user"
+}
+"#;
+
+    let _ = server.mock(|when, then| {
+        when.method(POST)
+            .path("/api/devices/prepareSSHConnection")
+            .header("authorization", "Bearer test_token_mock");
+        then.status(200)
+            .header("content-type", "application/json")
+            .body(request_reply);
+    });
+
+    config.set_backend(url::Url::parse(&server.base_url()).unwrap());
+
+    ssh::ssh_create_tunnel("test_device", "test_user", config, mock_access_token)
+        .await
+        .unwrap();
+
+    assert!(tr
+        .pathbuf()
+        .join("config")
+        .try_exists()
+        .is_ok_and(|exists| exists));
+    assert!(tr
+        .pathbuf()
+        .join("id_ed25519")
+        .try_exists()
+        .is_ok_and(|exists| exists));
+    assert!(tr
+        .pathbuf()
+        .join("id_ed25519.pub")
+        .try_exists()
+        .is_ok_and(|exists| exists));
+    assert!(tr
+        .pathbuf()
+        .join("bastion-cert.pub")
+        .try_exists()
+        .is_ok_and(|exists| exists));
+    assert!(tr
+        .pathbuf()
+        .join("device-cert.pub")
+        .try_exists()
+        .is_ok_and(|exists| exists));
+
+    let ssh_config = std::fs::read_to_string(tr.pathbuf().join("config")).unwrap();
+    let expected_config = format!(
+        r#"Host bastion
+	User bastion_user
+	Hostname 132.23.0.1
+	Port 22
+	IdentityFile {}/id_ed25519
+	CertificateFile {}/bastion-cert.pub
+	ProxyCommand none
+
+Host test_device
+	User test_user
+	IdentityFile {}/id_ed25519
+	CertificateFile {}/device-cert.pub
+	ProxyCommand ssh -F {}/config bastion
+"#,
+        tr.pathbuf().to_string_lossy(),
+        tr.pathbuf().to_string_lossy(),
+        tr.pathbuf().to_string_lossy(),
+        tr.pathbuf().to_string_lossy(),
+        tr.pathbuf().to_string_lossy()
+    );
+
+    assert_eq!(ssh_config, expected_config);
+}
+
+// currently disabled as we have no way to test this in our pipeline were we
+// don't have docker installed
+#[ignore]
+#[test]
+fn check_docker_inject_image_success() {
+    let image = std::env::var("TEST_DOCKER_IMAGE").unwrap_or("busybox".to_string());
+
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+
+    let mut docker_inject_image_config = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = docker_inject_image_config
+        .arg("docker")
+        .arg("inject")
+        .args(["--docker-image", &image])
+        .args(["--image", &image_path.to_string_lossy()])
+        .args(["--partition", "factory"])
+        .args(["--dest", "/some/test/dir"])
+        .assert();
+    let result_output = String::from_utf8(assert.get_output().stdout.to_vec()).unwrap();
+    let result_output = result_output.trim();
+    assert.success();
+
+    const EXPECTED_OUTPUT: &str = "Stored some-image to factory:/some/test/dir/some-image.tar.gz";
+
+    assert_eq!(EXPECTED_OUTPUT, result_output);
+
+    let mut docker_image_out_path = tr.pathbuf();
+    docker_image_out_path.push("docker");
+    create_dir_all(docker_image_out_path.clone()).unwrap();
+    docker_image_out_path.push("some-image.tar.gz");
+
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!(
+            "factory:/some/test/dir/some-image.tar.gz,{}",
+            docker_image_out_path.to_string_lossy(),
+        ))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    const EXPECTED_CONTENT: &str = "some test data";
+
+    let result_content = std::fs::read_to_string(docker_image_out_path).unwrap();
+
+    assert_eq!(EXPECTED_CONTENT, result_content);
+}
+
+#[test]
+fn check_image_sbom() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    let mut sbom_path = tr.pathbuf();
+    sbom_path.push("sbom.cdx.json");
+
+    let mut sbom = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = sbom
+        .arg("image")
+        .arg("sbom")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("-o")
+        .arg(&sbom_path)
+        .arg("-f")
+        .arg("cyclonedx")
+        .assert();
+    assert.success();
+
+    let sbom: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(sbom_path).unwrap()).unwrap();
+
+    assert_eq!(sbom["bomFormat"], "CycloneDX");
+    assert_eq!(sbom["metadata"]["component"]["name"], "image.wic");
+}
+
+#[test]
+fn check_image_create_swu() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    let mut sw_description_path = tr.pathbuf();
+    sw_description_path.push("sw-description");
+    let mut swu_path = tr.pathbuf();
+    swu_path.push("update.swu");
+
+    std::fs::write(
+        &sw_description_path,
+        r#"software = { version = "1.0"; images: ( { filename = "rootfs.ext4"; device = "/dev/omnect/rootCurrent"; sha256 = "$swupdate_get_sha256(rootfs.ext4)"; } ); }"#,
+    )
+    .unwrap();
+
+    let mut create_swu = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = create_swu
+        .arg("image")
+        .arg("create-swu")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("-s")
+        .arg(&sw_description_path)
+        .arg("-o")
+        .arg(&swu_path)
+        .assert();
+    assert.success();
+
+    let swu = std::fs::read(swu_path).unwrap();
+    let header = String::from_utf8_lossy(&swu[..512]);
+
+    assert!(header.starts_with("070701"));
+    assert!(header.contains("sw-description\0"));
+    assert!(!header.contains("$swupdate_get_sha256"));
+}
+
+#[test]
+fn check_set_time_config() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+
+    let mut set_time_config = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_time_config
+        .arg("system")
+        .arg("set-time-config")
+        .arg("-l")
+        .arg("de_DE.UTF-8")
+        .arg("-n")
+        .arg("ntp.customer.example")
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    let mut out_path = tr.pathbuf();
+    out_path.push("dir1");
+    create_dir_all(out_path.clone()).unwrap();
+    let locale_out_path = out_path.join("locale.conf");
+    let timesyncd_out_path = out_path.join("timesyncd.conf");
+
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!(
+            "factory:/etc/locale.conf,{}",
+            locale_out_path.to_str().unwrap()
+        ))
+        .arg("-f")
+        .arg(format!(
+            "factory:/etc/systemd/timesyncd.conf.d/10-omnect-cli.conf,{}",
+            timesyncd_out_path.to_str().unwrap()
+        ))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    assert_eq!(
+        std::fs::read_to_string(locale_out_path).unwrap(),
+        "LANG=de_DE.UTF-8\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(timesyncd_out_path).unwrap(),
+        "[Time]\nNTP=ntp.customer.example\n"
+    );
+}
+
+#[test]
+fn check_set_proxy() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+
+    let mut set_proxy = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_proxy
+        .arg("system")
+        .arg("set-proxy")
+        .arg("-u")
+        .arg("http://proxy.example.com:3128")
+        .arg("--no-proxy")
+        .arg("localhost,127.0.0.1")
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    let mut out_path = tr.pathbuf();
+    out_path.push("dir1");
+    create_dir_all(out_path.clone()).unwrap();
+    out_path.push("proxy.sh");
+
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!(
+            "factory:/etc/profile.d/proxy.sh,{}",
+            out_path.to_str().unwrap()
+        ))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    let profile = std::fs::read_to_string(out_path).unwrap();
+
+    assert!(profile.contains("export https_proxy='http://proxy.example.com:3128/'\n"));
+    assert!(profile.contains("export NO_PROXY='localhost,127.0.0.1'\n"));
+}
+
+#[test]
+fn check_copy_to_image_with_template_vars() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    let template_path = tr.pathbuf().join("motd");
+
+    std::fs::write(&template_path, "welcome to {{hostname}} of {{tenant}}\n").unwrap();
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!(
+            "{},factory:/etc/motd",
+            template_path.to_str().unwrap()
+        ))
+        .arg("--var")
+        .arg("hostname=device-0815")
+        .arg("--var")
+        .arg("tenant=customer-a")
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    let mut out_path = tr.pathbuf();
+    out_path.push("dir1");
+    create_dir_all(out_path.clone()).unwrap();
+    out_path.push("motd");
+
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!("factory:/etc/motd,{}", out_path.to_str().unwrap()))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    assert_eq!(
+        std::fs::read_to_string(out_path).unwrap(),
+        "welcome to device-0815 of customer-a\n"
+    );
+
+    // placeholders without value are rejected
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!(
+            "{},factory:/etc/motd",
+            template_path.to_str().unwrap()
+        ))
+        .arg("--var")
+        .arg("hostname=device-0815")
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.failure();
+}
+
+#[test]
+fn check_fleet_provision() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    let csv_path = tr.pathbuf().join("devices.csv");
+    let manifest_path = tr.pathbuf().join("injections.toml");
+    let output_dir = tr.pathbuf().join("out");
+
+    std::fs::write(
+        &csv_path,
+        "device_id,tenant\ndevice-1,customer-a\ndevice-2,customer-b\n",
+    )
+    .unwrap();
+    std::fs::write(tr.pathbuf().join("motd"), "{{device_id}} of {{tenant}}\n").unwrap();
+    std::fs::write(
+        &manifest_path,
+        "hostname = \"{{device_id}}\"\n\n[[files]]\nsource = \"motd\"\ndestination = \"factory:/etc/motd\"\n",
+    )
+    .unwrap();
+
+    let mut provision = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = provision
+        .arg("fleet")
+        .arg("provision")
+        .arg("--csv")
+        .arg(&csv_path)
+        .arg("--base-image")
+        .arg(&image_path)
+        .arg("--manifest")
+        .arg(&manifest_path)
+        .arg("--output-dir")
+        .arg(&output_dir)
+        .arg("--jobs")
+        .arg("2")
+        .assert();
+    assert.success();
+
+    let mut out_path = tr.pathbuf();
+    out_path.push("dir1");
+    create_dir_all(out_path.clone()).unwrap();
+
+    for (device_id, tenant) in [("device-1", "customer-a"), ("device-2", "customer-b")] {
+        let motd_out_path = out_path.join(format!("{device_id}.motd"));
+        let hostname_out_path = out_path.join(format!("{device_id}.hostname"));
+
+        let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_from_img
+            .arg("file")
+            .arg("copy-from-image")
+            .arg("-f")
+            .arg(format!(
+                "factory:/etc/motd,{}",
+                motd_out_path.to_str().unwrap()
+            ))
+            .arg("-f")
+            .arg(format!(
+                "factory:/etc/hostname,{}",
+                hostname_out_path.to_str().unwrap()
+            ))
+            .arg("-i")
+            .arg(output_dir.join(format!("{device_id}.wic")))
+            .assert();
+        assert.success();
+
+        assert_eq!(
+            std::fs::read_to_string(motd_out_path).unwrap(),
+            format!("{device_id} of {tenant}\n")
+        );
+        assert!(std::fs::read_to_string(hostname_out_path)
+            .unwrap()
+            .starts_with(device_id));
+    }
+
+    // the base image is left unchanged
+    assert!(file_diff::diff(
+        image_path.to_str().unwrap(),
+        "testfiles/image.wic"
+    ));
+}
+
+#[test]
+fn check_batch_mode() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let images = ["image-a.wic", "image-b.wic"].map(|name| tr.pathbuf().join(name));
+
+    for image in &images {
+        std::fs::copy("testfiles/image.wic", image).unwrap();
+    }
+
+    let mut set_hostname = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_hostname
+        .arg("identity")
+        .arg("set-hostname")
+        .arg("-n")
+        .arg("omnect-batch")
+        .arg("-i")
+        .arg(tr.pathbuf().join("image-*.wic"))
+        .arg("--jobs")
+        .arg("2")
+        .assert();
+    assert.success();
+
+    for (i, image) in images.iter().enumerate() {
+        let hostname_out_path = tr.pathbuf().join(format!("hostname-{i}"));
+
+        let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_from_img
+            .arg("file")
+            .arg("copy-from-image")
+            .arg("-f")
+            .arg(format!(
+                "factory:/etc/hostname,{}",
+                hostname_out_path.to_str().unwrap()
+            ))
+            .arg("-i")
+            .arg(image)
+            .assert();
+        assert.success();
+
+        assert_eq!(
+            std::fs::read_to_string(hostname_out_path).unwrap(),
+            "omnect-batch"
+        );
+    }
+}
+
+#[test]
+fn check_remote_image() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let server = MockServer::start();
+    let sha256 = Testrunner::file_hash(&PathBuf::from("testfiles/image.wic.xz"));
+
+    let download = server.mock(|when, then| {
+        when.method(GET).path("/images/omnect.wic.xz");
+        then.status(200).body_from_file("testfiles/image.wic.xz");
+    });
+
+    let mut set_hostname = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_hostname
+        .current_dir(tr.pathbuf())
+        .arg("identity")
+        .arg("set-hostname")
+        .arg("-n")
+        .arg("omnect-remote")
+        .arg("-i")
+        .arg(format!(
+            "{}#sha256={sha256}",
+            server.url("/images/omnect.wic.xz")
+        ))
+        .assert();
+    assert.success();
+    download.assert();
+
+    let hostname_out_path = tr.pathbuf().join("hostname");
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!(
+            "factory:/etc/hostname,{}",
+            hostname_out_path.to_str().unwrap()
+        ))
+        .arg("-i")
+        .arg(tr.pathbuf().join("omnect.wic"))
+        .assert();
+    assert.success();
+
+    assert_eq!(
+        std::fs::read_to_string(hostname_out_path).unwrap(),
+        "omnect-remote"
+    );
+
+    let mut check = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = check
+        .current_dir(tr.pathbuf())
+        .arg("image")
+        .arg("check")
+        .arg("-i")
+        .arg(format!(
+            "{}#sha256={}",
+            server.url("/images/omnect.wic.xz"),
+            "0".repeat(64)
+        ))
+        .assert();
+    assert.code(2);
+}
+
+#[test]
+fn check_output_device() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    let output_path = tr.pathbuf().join("device.wic");
+    let image_hash = Testrunner::file_hash(&image_path);
+
+    let mut set_hostname = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_hostname
+        .arg("--output-device")
+        .arg(&output_path)
+        .arg("identity")
+        .arg("set-hostname")
+        .arg("-n")
+        .arg("omnect-device")
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    assert_eq!(image_hash, Testrunner::file_hash(&image_path));
+
+    let hostname_out_path = tr.pathbuf().join("hostname");
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!(
+            "factory:/etc/hostname,{}",
+            hostname_out_path.to_str().unwrap()
+        ))
+        .arg("-i")
+        .arg(&output_path)
+        .assert();
+    assert.success();
+
+    assert_eq!(
+        std::fs::read_to_string(hostname_out_path).unwrap(),
+        "omnect-device"
+    );
+}
+
+#[test]
+fn check_object_storage_image() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let server = MockServer::start();
+
+    let download = server.mock(|when, then| {
+        when.method(GET).path("/images/omnect/image.wic.xz");
+        then.status(200).body_from_file("testfiles/image.wic.xz");
+    });
+    let initiate = server.mock(|when, then| {
+        when.method(POST)
+            .path_contains("/images/omnect/image.wic")
+            .query_param_exists("uploads");
+        then.status(200).body(
+            "<InitiateMultipartUploadResult><UploadId>upload-1</UploadId></InitiateMultipartUploadResult>",
+        );
+    });
+    let upload_image = server.mock(|when, then| {
+        when.method(PUT)
+            .path("/images/omnect/image.wic")
+            .query_param("partNumber", "1")
+            .query_param("uploadId", "upload-1");
+        then.status(200).header("etag", "\"etag-1\"");
+    });
+    let upload_log = server.mock(|when, then| {
+        when.method(PUT)
+            .path("/images/omnect/image.wic.modifications.json")
+            .query_param("uploadId", "upload-1");
+        then.status(200).header("etag", "\"etag-2\"");
+    });
+    let complete = server.mock(|when, then| {
+        when.method(POST)
+            .path_contains("/images/omnect/image.wic")
+            .query_param("uploadId", "upload-1")
+            .body_contains("<ETag>");
+        then.status(200)
+            .body("<CompleteMultipartUploadResult></CompleteMultipartUploadResult>");
+    });
+
+    let mut set_hostname = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_hostname
+        .env("AWS_ENDPOINT_URL", server.base_url())
+        .env("AWS_ACCESS_KEY_ID", "test-key-id")
+        .env("AWS_SECRET_ACCESS_KEY", "test-secret")
+        .env("OMNECT_CLI_WORKDIR", tr.pathbuf())
+        .env("OMNECT_CLI_MODIFICATION_LOG", "true")
+        .arg("identity")
+        .arg("set-hostname")
+        .arg("-n")
+        .arg("omnect-s3")
+        .arg("-i")
+        .arg("s3://images/omnect/image.wic.xz")
+        .assert();
+    assert.success();
+
+    download.assert();
+    initiate.assert_hits(2);
+    upload_image.assert();
+    upload_log.assert();
+    complete.assert_hits(2);
+}
+
+#[test]
+fn check_backup() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    let backup_path = tr.pathbuf().join("image.wic.bak");
+    let image_hash = Testrunner::file_hash(&image_path);
+
+    let mut set_hostname = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_hostname
+        .arg("--backup")
+        .arg("identity")
+        .arg("set-hostname")
+        .arg("-n")
+        .arg("omnect-backup")
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    assert_eq!(image_hash, Testrunner::file_hash(&backup_path));
+    assert_ne!(image_hash, Testrunner::file_hash(&image_path));
+}
+
+#[test]
+fn check_embedded_history() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let config_file_path = tr.to_pathbuf("conf/config.toml.est.template");
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+
+    for _ in 0..2 {
+        let mut set_identity_config = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = set_identity_config
+            .arg("identity")
+            .arg("set-config")
+            .arg("-c")
+            .arg(&config_file_path)
+            .arg("-i")
+            .arg(&image_path)
+            .assert();
+        assert.success();
+    }
+
+    let history = omnect_cli::provenance::history(&image_path).unwrap();
+
+    assert_eq!(history.len(), 2);
+    assert_eq!(history[0].command, "identity set-config");
+    assert_eq!(history[0].previous, None);
+    assert_eq!(history[0].inputs.len(), 1);
+    assert_eq!(history[0].inputs[0].file, "config.toml.est.template");
+    assert_eq!(
+        history[0].inputs[0].sha256,
+        omnect_cli::provenance::sha256(&config_file_path).unwrap()
+    );
+    assert_eq!(history[1].previous, Some(history[0].sha256().unwrap()));
+}
+
+#[test]
+fn check_image_history() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+
+    let mut set_hostname = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_hostname
+        .arg("identity")
+        .arg("set-hostname")
+        .arg("-n")
+        .arg("omnect-history")
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    let mut history = Command::cargo_bin("omnect-cli").unwrap();
+    let output = history
+        .arg("--output")
+        .arg("json")
+        .arg("image")
+        .arg("history")
+        .arg("-i")
+        .arg(&image_path)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let result: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+
+    assert_eq!(result["verified"], true);
+    assert_eq!(result["history"][0]["command"], "identity set-hostname");
+}
+
+#[test]
+fn check_config_validate_broken_config() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let config_dir = tr.pathbuf().join("omnect-cli");
+
+    create_dir_all(&config_dir).unwrap();
+    std::fs::write(
+        config_dir.join("config.toml"),
+        "backend = 'https://cp.omnect.conplement.cloud'\ncompression = \n",
+    )
+    .unwrap();
+
+    // the broken default configuration must be reported by "config validate"
+    // instead of failing to load it before
+    let mut validate = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = validate
+        .env("XDG_CONFIG_HOME", tr.pathbuf())
+        .arg("config")
+        .arg("validate")
+        .assert();
+    let stderr = assert.failure().code(2).get_output().stderr.clone();
+    let stderr = String::from_utf8_lossy(&stderr);
+
+    assert!(stderr.contains("is invalid"));
+    assert!(stderr.contains("line 2"));
 }